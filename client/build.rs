@@ -0,0 +1,20 @@
+fn main() {
+    #[cfg(feature = "capi")]
+    capi_header();
+}
+
+// Writes the C API's header, so hosts build against the same declarations
+// the library exports.
+#[cfg(feature = "capi")]
+fn capi_header() {
+    println!("cargo:rerun-if-changed=src/capi.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    let dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    let config = cbindgen::Config::from_file(format!("{}/cbindgen.toml", dir)).expect("reading cbindgen.toml");
+    cbindgen::Builder::new()
+        .with_crate(&dir)
+        .with_config(config)
+        .generate()
+        .expect("generating the C header")
+        .write_to_file(format!("{}/include/audio_client.h", dir));
+}
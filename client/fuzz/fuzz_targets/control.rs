@@ -0,0 +1,8 @@
+#![no_main]
+
+use audio_client::wire::{self, Format};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    wire::parse(Format::Control, data);
+});
@@ -0,0 +1,89 @@
+#ifndef AUDIO_CLIENT_H
+#define AUDIO_CLIENT_H
+
+/* Generated by cbindgen from src/capi.rs; do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// The codec the audio is sent with, passed to `streamer_start` as an int.
+typedef enum StreamerCodec {
+  STREAMER_CODEC_PCM = 0,
+  STREAMER_CODEC_OPUS = 1,
+} StreamerCodec;
+
+// A running streamer. Made by `streamer_start` and freed by
+// `streamer_stop`.
+typedef struct Streamer Streamer;
+
+// The stream's counters so far, as in the session summary.
+typedef struct StreamerStats {
+  uint64_t elapsed_ms;
+  uint64_t bytes_sent;
+  uint64_t packets_sent;
+  uint64_t drops;
+  uint64_t underruns;
+  uint64_t overruns;
+  uint64_t reconnects;
+  uint64_t volume_changes;
+  uint64_t device_errors;
+} StreamerStats;
+
+#ifdef __cplusplus
+extern "C" {
+#endif  // __cplusplus
+
+// Starts capturing from `device` and streaming to `destination`, as
+// host:port. Either may be NULL for the command-line client's defaults:
+// a loopback input if there is one, and port 8080 on this machine.
+//
+// `codec` is a `StreamerCodec`; any other value is refused with the usage
+// exit status.
+//
+// Returns NULL if the streamer cannot start, and sets `*error`, when
+// `error` is not NULL, to the exit status the command-line client would
+// have used; it is set to 0 on success.
+//
+// # Safety
+//
+// `device` and `destination` must be NULL or point to NUL-terminated
+// strings, and `error` must be NULL or valid for writes.
+struct Streamer *streamer_start(const char *device,
+                                const char *destination,
+                                int codec,
+                                int *error);
+
+// Stops the capture, waits for it to finish and frees the streamer.
+// Does nothing when `streamer` is NULL.
+//
+// # Safety
+//
+// `streamer` must be NULL or come from `streamer_start`, and must not be
+// used again.
+void streamer_stop(struct Streamer *streamer);
+
+// Sets the volume, from 0.0 to 1.0. Returns 0, or the usage exit status
+// when the level is out of range or `streamer` is NULL.
+//
+// # Safety
+//
+// `streamer` must be NULL or a live streamer from `streamer_start`.
+int streamer_set_volume(const struct Streamer *streamer, float level);
+
+// Fills `*stats` with the counters so far. Returns 0, or the usage exit
+// status when either pointer is NULL.
+//
+// # Safety
+//
+// `streamer` must be NULL or a live streamer from `streamer_start`, and
+// `stats` must be NULL or valid for writes.
+int streamer_get_stats(const struct Streamer *streamer, struct StreamerStats *stats);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* AUDIO_CLIENT_H */
@@ -0,0 +1,500 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::clock::{system_micros, unix_micros_to_ntp};
+use crate::discovery;
+
+const RAOP_SERVICE: &str = "_raop._tcp.local.";
+// RAOP streams ALAC at CD rate in fixed 352-frame packets.
+const RAOP_SAMPLE_RATE: u32 = 44_100;
+const RAOP_FRAMES_PER_PACKET: usize = 352;
+const RAOP_CHANNELS: usize = 2;
+// Audio the receiver buffers ahead of the sync point, in frames.
+const RAOP_LATENCY_FRAMES: u32 = 11_025;
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+const RTP_PAYLOAD_ALAC: u8 = 0x60;
+const RTP_MARKER: u8 = 0x80;
+const RTP_TIMING_REQUEST: u8 = 0xd2;
+const RTP_TIMING_RESPONSE: u8 = 0xd3;
+const RTP_SYNC: u8 = 0xd4;
+
+/// An AirPlay receiver found on the network.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Speaker {
+    pub name: String,
+    pub addr: SocketAddr,
+    /// Whether the receiver accepts unencrypted audio (`et=0`).
+    pub unencrypted: bool,
+}
+
+/// Browses mDNS for AirPlay receivers and returns the first whose name
+/// contains `name` (case-insensitive), or the first found when `name` is
+/// `None`.
+pub fn discover(name: Option<&str>) -> io::Result<Speaker> {
+    let found = discovery::find(RAOP_SERVICE, |service| {
+        discovery::name_matches(name, &display_name(service.instance(RAOP_SERVICE)))
+    })?;
+    let service = found.ok_or_else(|| {
+        let what = name.map_or("any AirPlay speaker".to_string(), |n| format!("AirPlay speaker '{}'", n));
+        io::Error::new(io::ErrorKind::NotFound, format!("could not find {} on the network", what))
+    })?;
+    Ok(speaker(&service))
+}
+
+/// Every AirPlay receiver that answers on the network within a few seconds.
+pub fn discover_all() -> io::Result<Vec<Speaker>> {
+    Ok(discovery::browse(RAOP_SERVICE)?.iter().map(speaker).collect())
+}
+
+fn speaker(service: &discovery::Resolved) -> Speaker {
+    Speaker {
+        name: display_name(service.instance(RAOP_SERVICE)),
+        addr: service.addr,
+        unencrypted: service
+            .property("et")
+            .is_none_or(|et| et.split(',').any(|e| e == "0")),
+    }
+}
+
+// RAOP instance names are "<MAC>@<Speaker Name>".
+fn display_name(instance: &str) -> String {
+    instance.split_once('@').map_or(instance, |(_, name)| name).to_string()
+}
+
+/// An RTSP control connection to a RAOP receiver.
+struct Rtsp {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    uri: String,
+    cseq: u32,
+    session: Option<String>,
+    client_id: String,
+}
+
+struct RtspResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+}
+
+impl RtspResponse {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl Rtsp {
+    fn request(&mut self, method: &str, headers: &[(&str, String)], body: Option<(&str, &[u8])>) -> io::Result<RtspResponse> {
+        self.cseq += 1;
+        let uri = if method == "OPTIONS" { "*" } else { self.uri.as_str() };
+        let mut request = format!(
+            "{} {} RTSP/1.0\r\nCSeq: {}\r\nUser-Agent: audio-client\r\nClient-Instance: {}\r\nDACP-ID: {}\r\n",
+            method, uri, self.cseq, self.client_id, self.client_id
+        );
+        if let Some(session) = &self.session {
+            request.push_str(&format!("Session: {}\r\n", session));
+        }
+        for (name, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if let Some((content_type, body)) = body {
+            request.push_str(&format!("Content-Type: {}\r\nContent-Length: {}\r\n", content_type, body.len()));
+        }
+        request.push_str("\r\n");
+        self.writer.write_all(request.as_bytes())?;
+        if let Some((_, body)) = body {
+            self.writer.write_all(body)?;
+        }
+
+        let response = self.read_response()?;
+        if response.status != 200 {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("AirPlay {} failed with status {}", method, response.status),
+            ));
+        }
+        Ok(response)
+    }
+
+    fn read_response(&mut self) -> io::Result<RtspResponse> {
+        let mut line = String::new();
+        self.reader.read_line(&mut line)?;
+        let status = line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed RTSP status line"))?;
+
+        let mut headers = Vec::new();
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                break;
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                headers.push((name.trim().to_string(), value.trim().to_string()));
+            }
+        }
+        let response = RtspResponse { status, headers };
+        let length: usize = response.header("Content-Length").and_then(|l| l.parse().ok()).unwrap_or(0);
+        let mut body = vec![0u8; length];
+        self.reader.read_exact(&mut body)?;
+        Ok(response)
+    }
+}
+
+// Finds `name=<port>` among the `;`-separated fields of a Transport header.
+fn transport_port(transport: &str, name: &str) -> Option<u16> {
+    transport
+        .split(';')
+        .filter_map(|field| field.split_once('='))
+        .find(|(key, _)| *key == name)
+        .and_then(|(_, value)| value.parse().ok())
+}
+
+/// Streams s16le 48kHz stereo PCM to an AirPlay receiver as uncompressed
+/// ALAC over RAOP. Writes are resampled to 44.1kHz and sent as RTP packets;
+/// background threads answer the receiver's timing requests and send a
+/// sync packet every second.
+pub struct RaopWriter {
+    rtsp: Rtsp,
+    audio: UdpSocket,
+    resampler: Resampler,
+    pending: Vec<i16>,
+    packet: Vec<u8>,
+    seq: u16,
+    rtp_time: Arc<AtomicU32>,
+    ssrc: u32,
+    first: bool,
+    running: Arc<AtomicBool>,
+}
+
+impl RaopWriter {
+    pub fn connect(addr: SocketAddr, input_rate: u32) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        let local_ip = stream.local_addr()?.ip();
+        let nonce = system_micros();
+        let session_id = (nonce as u32) & 0x7FFF_FFFF;
+        let mut rtsp = Rtsp {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            uri: format!("rtsp://{}/{}", local_ip, session_id),
+            cseq: 0,
+            session: None,
+            client_id: format!("{:016X}", nonce),
+        };
+
+        rtsp.request("OPTIONS", &[], None)?;
+        let sdp = format!(
+            "v=0\r\no=iTunes {id} 0 IN IP4 {local}\r\ns=iTunes\r\nc=IN IP4 {remote}\r\nt=0 0\r\n\
+             m=audio 0 RTP/AVP 96\r\na=rtpmap:96 AppleLossless\r\n\
+             a=fmtp:96 {frames} 0 16 40 10 14 {channels} 255 0 0 {rate}\r\n",
+            id = session_id,
+            local = local_ip,
+            remote = addr.ip(),
+            frames = RAOP_FRAMES_PER_PACKET,
+            channels = RAOP_CHANNELS,
+            rate = RAOP_SAMPLE_RATE,
+        );
+        rtsp.request("ANNOUNCE", &[], Some(("application/sdp", sdp.as_bytes())))?;
+
+        let control = UdpSocket::bind((local_ip, 0))?;
+        let timing = UdpSocket::bind((local_ip, 0))?;
+        let transport = format!(
+            "RTP/AVP/UDP;unicast;interleaved=0-1;mode=record;control_port={};timing_port={}",
+            control.local_addr()?.port(),
+            timing.local_addr()?.port()
+        );
+        let response = rtsp.request("SETUP", &[("Transport", transport)], None)?;
+        let session = response.header("Session").map(|s| s.split(';').next().unwrap_or(s).to_string());
+        let remote_transport = response.header("Transport").unwrap_or_default().to_string();
+        let server_port = transport_port(&remote_transport, "server_port")
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "AirPlay SETUP reply has no server_port"))?;
+        let control_port = transport_port(&remote_transport, "control_port").unwrap_or(server_port);
+        rtsp.session = session;
+
+        let seq = nonce as u16;
+        let rtp_start = (nonce >> 16) as u32;
+        rtsp.request(
+            "RECORD",
+            &[
+                ("Range", "npt=0-".to_string()),
+                ("RTP-Info", format!("seq={};rtptime={}", seq, rtp_start)),
+            ],
+            None,
+        )?;
+
+        let audio = UdpSocket::bind((local_ip, 0))?;
+        audio.connect((addr.ip(), server_port))?;
+        control.connect((addr.ip(), control_port))?;
+
+        let running = Arc::new(AtomicBool::new(true));
+        let rtp_time = Arc::new(AtomicU32::new(rtp_start));
+        spawn_timing_responder(timing, running.clone());
+        spawn_sync_sender(control, rtp_time.clone(), running.clone());
+
+        Ok(Self {
+            rtsp,
+            audio,
+            resampler: Resampler::new(input_rate, RAOP_SAMPLE_RATE, RAOP_CHANNELS),
+            pending: Vec::new(),
+            packet: Vec::new(),
+            seq,
+            rtp_time,
+            ssrc: session_id,
+            first: true,
+            running,
+        })
+    }
+
+    fn send_packet(&mut self) -> io::Result<()> {
+        let samples = RAOP_FRAMES_PER_PACKET * RAOP_CHANNELS;
+        let rtp_time = self.rtp_time.load(Ordering::Relaxed);
+
+        self.packet.clear();
+        self.packet.push(RTP_MARKER);
+        self.packet.push(if self.first { RTP_PAYLOAD_ALAC | RTP_MARKER } else { RTP_PAYLOAD_ALAC });
+        self.packet.extend_from_slice(&self.seq.to_be_bytes());
+        self.packet.extend_from_slice(&rtp_time.to_be_bytes());
+        self.packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        encode_alac_uncompressed(&self.pending[..samples], &mut self.packet);
+        self.pending.drain(..samples);
+
+        self.first = false;
+        self.seq = self.seq.wrapping_add(1);
+        self.rtp_time
+            .store(rtp_time.wrapping_add(RAOP_FRAMES_PER_PACKET as u32), Ordering::Relaxed);
+        // UDP loss is the receiver's problem, like the main sink.
+        match self.audio.send(&self.packet) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Write for RaopWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let input: Vec<i16> = buf.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+        self.resampler.process(&input, &mut self.pending);
+        while self.pending.len() >= RAOP_FRAMES_PER_PACKET * RAOP_CHANNELS {
+            self.send_packet()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for RaopWriter {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        let _ = self.rtsp.request("TEARDOWN", &[], None);
+    }
+}
+
+// Answers the receiver's NTP-style timing requests with our clock.
+fn spawn_timing_responder(socket: UdpSocket, running: Arc<AtomicBool>) {
+    thread::spawn(move || {
+        let _ = socket.set_read_timeout(Some(SYNC_INTERVAL));
+        let mut buf = [0u8; 128];
+        while running.load(Ordering::Relaxed) {
+            let (len, peer) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(_) => continue,
+            };
+            if len < 32 || buf[1] & 0x7f != RTP_TIMING_REQUEST & 0x7f {
+                continue;
+            }
+            let received = unix_micros_to_ntp(system_micros());
+            let mut reply = [0u8; 32];
+            reply[0] = RTP_MARKER;
+            reply[1] = RTP_TIMING_RESPONSE;
+            reply[3] = 0x07;
+            // Origin is the requester's transmit time.
+            reply[8..16].copy_from_slice(&buf[24..32]);
+            reply[16..24].copy_from_slice(&received.to_be_bytes());
+            reply[24..32].copy_from_slice(&unix_micros_to_ntp(system_micros()).to_be_bytes());
+            let _ = socket.send_to(&reply, peer);
+        }
+    });
+}
+
+// Tells the receiver which RTP timestamp is playing now.
+fn spawn_sync_sender(socket: UdpSocket, rtp_time: Arc<AtomicU32>, running: Arc<AtomicBool>) {
+    thread::spawn(move || {
+        let mut first = true;
+        while running.load(Ordering::Relaxed) {
+            let now = rtp_time.load(Ordering::Relaxed);
+            let mut packet = [0u8; 20];
+            packet[0] = if first { 0x90 } else { RTP_MARKER };
+            packet[1] = RTP_SYNC;
+            packet[3] = 0x07;
+            packet[4..8].copy_from_slice(&now.wrapping_sub(RAOP_LATENCY_FRAMES).to_be_bytes());
+            packet[8..16].copy_from_slice(&unix_micros_to_ntp(system_micros()).to_be_bytes());
+            packet[16..20].copy_from_slice(&now.to_be_bytes());
+            let _ = socket.send(&packet);
+            first = false;
+            thread::sleep(SYNC_INTERVAL);
+        }
+    });
+}
+
+/// Writes one uncompressed ALAC frame: a stereo channel-pair element with
+/// the "not compressed" flag set, big-endian samples, then the end tag.
+pub fn encode_alac_uncompressed(samples: &[i16], out: &mut Vec<u8>) {
+    let mut bits = BitWriter::new(out);
+    bits.write(1, 3); // channel pair element
+    bits.write(0, 4); // element instance
+    bits.write(0, 12); // unused
+    bits.write(0, 1); // frame size is the default from the SDP
+    bits.write(0, 2); // no shifted bytes
+    bits.write(1, 1); // not compressed
+    for &sample in samples {
+        bits.write(sample as u16 as u32, 16);
+    }
+    bits.write(7, 3); // end of frame
+    bits.finish();
+}
+
+struct BitWriter<'a> {
+    out: &'a mut Vec<u8>,
+    acc: u32,
+    len: u32,
+}
+
+impl<'a> BitWriter<'a> {
+    fn new(out: &'a mut Vec<u8>) -> Self {
+        Self { out, acc: 0, len: 0 }
+    }
+
+    fn write(&mut self, value: u32, bits: u32) {
+        for i in (0..bits).rev() {
+            self.acc = (self.acc << 1) | ((value >> i) & 1);
+            self.len += 1;
+            if self.len == 8 {
+                self.out.push(self.acc as u8);
+                self.acc = 0;
+                self.len = 0;
+            }
+        }
+    }
+
+    fn finish(mut self) {
+        if self.len > 0 {
+            self.out.push((self.acc << (8 - self.len)) as u8);
+            self.len = 0;
+        }
+    }
+}
+
+/// Linear-interpolating sample rate converter for interleaved i16 audio.
+pub struct Resampler {
+    step: f64,
+    channels: usize,
+    pos: f64,
+    prev: Vec<i16>,
+}
+
+impl Resampler {
+    pub fn new(from_rate: u32, to_rate: u32, channels: usize) -> Self {
+        Self {
+            step: from_rate as f64 / to_rate as f64,
+            channels,
+            pos: 0.0,
+            prev: Vec::new(),
+        }
+    }
+
+    /// Converts `input` and appends the result to `out`. State carries over
+    /// between calls, so blocks can be any size.
+    pub fn process(&mut self, input: &[i16], out: &mut Vec<i16>) {
+        let channels = self.channels;
+        let frames = input.len() / channels;
+        // Position 0 is the last frame of the previous block.
+        let frame_at = |prev: &[i16], index: usize, channel: usize| -> i16 {
+            if index == 0 {
+                prev.get(channel).copied().unwrap_or(input[channel])
+            } else {
+                input[(index - 1) * channels + channel]
+            }
+        };
+        while frames > 0 && self.pos + 1.0 <= frames as f64 {
+            let index = self.pos as usize;
+            let frac = self.pos - index as f64;
+            for channel in 0..channels {
+                let a = frame_at(&self.prev, index, channel) as f64;
+                let b = frame_at(&self.prev, index + 1, channel) as f64;
+                out.push((a + (b - a) * frac).round() as i16);
+            }
+            self.pos += self.step;
+        }
+        if frames > 0 {
+            self.pos -= frames as f64;
+            self.prev.clear();
+            self.prev.extend_from_slice(&input[(frames - 1) * channels..frames * channels]);
+        }
+    }
+}
+
+/// Resolves `host:port` for speakers addressed directly instead of by name.
+pub fn resolve(target: &str) -> io::Result<Speaker> {
+    let addr = target
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("could not resolve {}", target)))?;
+    Ok(Speaker {
+        name: target.to_string(),
+        addr,
+        unencrypted: true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alac_uncompressed_frame() {
+        let mut out = Vec::new();
+        encode_alac_uncompressed(&[0x1234, -1], &mut out);
+        // 23 header bits, two 16-bit samples and the 3-bit end tag, padded.
+        assert_eq!(out, vec![0x20, 0x00, 0x02, 0x24, 0x69, 0xFF, 0xFF, 0xC0]);
+    }
+
+    #[test]
+    fn test_resampler_output_rate() {
+        let mut resampler = Resampler::new(48_000, 44_100, 2);
+        let input = vec![100i16; 480 * 2];
+        let mut out = Vec::new();
+        for _ in 0..100 {
+            resampler.process(&input, &mut out);
+        }
+        // One second in, one second out, give or take a frame.
+        assert!((out.len() as i64 / 2 - 44_100).abs() <= 1);
+        assert!(out.iter().all(|&s| s == 100));
+    }
+
+    #[test]
+    fn test_display_name_and_transport() {
+        assert_eq!(display_name("AABBCCDDEEFF@Living Room"), "Living Room");
+        assert_eq!(
+            transport_port("RTP/AVP/UDP;unicast;mode=record;server_port=6000;control_port=6001", "server_port"),
+            Some(6000)
+        );
+        assert_eq!(transport_port("RTP/AVP/UDP;unicast", "server_port"), None);
+    }
+}
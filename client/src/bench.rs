@@ -0,0 +1,201 @@
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use crate::codec::{Codec, FrameDecoder, FrameEncoder, OpusOptions};
+use crate::hook::FrameHooks;
+use crate::meter::LevelMeter;
+use crate::packet::{self, Packet};
+use crate::selftest::Tone;
+use crate::spectrum::SpectrumTap;
+use crate::streamer::{encode_samples, CHANNELS, FRAMES_PER_BUFFER, SAMPLE_RATE};
+
+// Buffers of test signal the stages cycle through, about a second.
+const SIGNAL_BUFFERS: usize = 94;
+// Calls made before timing starts, so caches and codec state settle.
+const WARMUP: usize = 20;
+
+/// What one stage of the pipeline costs on this machine.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stage {
+    pub name: &'static str,
+    /// The mean time to process one capture buffer.
+    pub per_buffer: Duration,
+}
+
+impl Stage {
+    /// The share of one core the stage needs to keep up with the capture.
+    pub fn load(&self) -> f64 {
+        self.per_buffer.as_secs_f64() / buffer_duration().as_secs_f64()
+    }
+}
+
+/// The audio in one capture buffer, which is all the time the stages have
+/// between them to process it.
+pub fn buffer_duration() -> Duration {
+    Duration::from_secs_f64(FRAMES_PER_BUFFER as f64 / SAMPLE_RATE as f64)
+}
+
+/// Times each stage of the send path over `buffers` capture buffers of a
+/// tone under noise, and Opus decoding as a receiver does it. SRT's
+/// encryption happens inside the SRT library, so it is not among them.
+pub fn run(buffers: usize) -> Result<Vec<Stage>, opus::Error> {
+    let blocks = signal();
+    let pcm: Vec<Vec<u8>> = blocks
+        .iter()
+        .map(|block| {
+            let mut pcm = Vec::new();
+            encode_samples(block, 1.0, &mut pcm);
+            pcm
+        })
+        .collect();
+    let mut out = Vec::new();
+    let mut stages = Vec::new();
+
+    stages.push(Stage {
+        name: "convert f32 to s16 with gain",
+        per_buffer: time(buffers, |i| {
+            encode_samples(&blocks[i % blocks.len()], 0.8, &mut out);
+            black_box(&out);
+        }),
+    });
+    let meter = LevelMeter::default();
+    stages.push(Stage {
+        name: "level meter",
+        per_buffer: time(buffers, |i| meter.record(&pcm[i % pcm.len()])),
+    });
+    let spectrum = SpectrumTap::default();
+    stages.push(Stage {
+        name: "spectrum tap",
+        per_buffer: time(buffers, |i| spectrum.record(&pcm[i % pcm.len()])),
+    });
+    let hooks = FrameHooks::default();
+    hooks.push(Box::new(|frame| {
+        black_box(frame);
+    }));
+    stages.push(Stage {
+        name: "frame hook (empty)",
+        per_buffer: time(buffers, |i| {
+            out.clear();
+            out.extend_from_slice(&pcm[i % pcm.len()]);
+            hooks.process_pcm(&mut out);
+        }),
+    });
+
+    let fec = OpusOptions {
+        fec: true,
+        ..OpusOptions::default()
+    };
+    for (name, codec, options) in [
+        ("encode pcm", Codec::Pcm, OpusOptions::default()),
+        ("encode opus", Codec::Opus, OpusOptions::default()),
+        ("encode opus with fec", Codec::Opus, fec),
+    ] {
+        let mut encoder = FrameEncoder::new(codec, SAMPLE_RATE, CHANNELS, options)?;
+        stages.push(Stage {
+            name,
+            per_buffer: time(buffers, |i| {
+                encoder.encode(&pcm[i % pcm.len()], |packet| {
+                    black_box(packet);
+                })
+            }),
+        });
+    }
+
+    for (name, redundancy) in [("packetize", false), ("packetize with redundancy", true)] {
+        stages.push(Stage {
+            name,
+            per_buffer: time(buffers, |i| {
+                let previous = &pcm[(i + pcm.len() - 1) % pcm.len()];
+                let packet = Packet {
+                    seq: i as u32,
+                    timestamp: Some(i as u64),
+                    payload: &pcm[i % pcm.len()],
+                    redundant: redundancy.then_some(previous.as_slice()),
+                    paused: false,
+                    ended: false,
+                };
+                packet::encode(&packet, &mut out);
+                black_box(&out);
+            }),
+        });
+    }
+
+    // The packets each buffer turns into, decoded in turn as they would
+    // arrive.
+    let mut encoder = FrameEncoder::new(Codec::Opus, SAMPLE_RATE, CHANNELS, OpusOptions::default())?;
+    let packets: Vec<Vec<Vec<u8>>> = pcm
+        .iter()
+        .map(|block| {
+            let mut packets = Vec::new();
+            encoder.encode(block, |packet| packets.extend(packet.map(<[u8]>::to_vec)));
+            packets
+        })
+        .collect();
+    let mut decoder = FrameDecoder::new(Codec::Opus, SAMPLE_RATE, CHANNELS, OpusOptions::default())?;
+    let mut decoded = Vec::new();
+    stages.push(Stage {
+        name: "decode opus",
+        per_buffer: time(buffers, |i| {
+            for packet in &packets[i % packets.len()] {
+                decoded.clear();
+                decoder.decode(packet, &mut decoded);
+            }
+            black_box(&decoded);
+        }),
+    });
+    Ok(stages)
+}
+
+// A tone under quiet white noise, so Opus has some detail to code rather
+// than a pure sine.
+fn signal() -> Vec<Vec<f32>> {
+    let mut tone = Tone::new(440.0, 0.25);
+    let mut noise = 0x2545_f491_4f6c_dd1du64;
+    (0..SIGNAL_BUFFERS)
+        .map(|_| {
+            let mut block = Vec::with_capacity(FRAMES_PER_BUFFER as usize * CHANNELS as usize);
+            tone.fill(&mut block, FRAMES_PER_BUFFER as usize);
+            for sample in &mut block {
+                noise ^= noise << 13;
+                noise ^= noise >> 7;
+                noise ^= noise << 17;
+                *sample += 0.02 * ((noise >> 40) as f32 / (1u64 << 24) as f32 - 0.5);
+            }
+            block
+        })
+        .collect()
+}
+
+// The mean time of `stage` over `buffers` calls, given the buffer's index.
+fn time(buffers: usize, mut stage: impl FnMut(usize)) -> Duration {
+    for i in 0..WARMUP {
+        stage(i);
+    }
+    let started = Instant::now();
+    for i in 0..buffers {
+        stage(i);
+    }
+    started.elapsed() / buffers.max(1) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run() {
+        let stages = run(10).unwrap();
+        assert_eq!(stages.len(), 10);
+        assert!(stages.iter().any(|stage| stage.name == "encode opus"));
+        assert!(stages.iter().all(|stage| stage.load() >= 0.0));
+    }
+
+    #[test]
+    fn test_load() {
+        let stage = Stage {
+            name: "encode pcm",
+            per_buffer: buffer_duration() / 4,
+        };
+        assert!((stage.load() - 0.25).abs() < 1e-6);
+    }
+}
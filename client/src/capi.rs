@@ -0,0 +1,241 @@
+//! C bindings to the streamer, for OBS plugins and other C or C++ hosts.
+//! Building with `--features capi` writes their header to
+//! `include/audio_client.h`.
+
+use std::ffi::{c_char, c_int, CStr};
+use std::ptr;
+
+use tokio::runtime::Runtime;
+use tracing::error;
+
+use crate::codec::Codec;
+use crate::control::Status;
+use crate::error::{Error, ErrorKind};
+use crate::stats::Summary;
+use crate::streamer::StreamerClient;
+
+/// A running streamer. Made by `streamer_start` and freed by
+/// `streamer_stop`.
+pub struct Streamer {
+    // Dropped after the client, so its tasks can finish shutting down.
+    client: Option<StreamerClient>,
+    runtime: Runtime,
+}
+
+/// The codec the audio is sent with, passed to `streamer_start` as an int.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamerCodec {
+    Pcm = 0,
+    Opus = 1,
+}
+
+/// The stream's counters so far, as in the session summary.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StreamerStats {
+    pub elapsed_ms: u64,
+    pub bytes_sent: u64,
+    pub packets_sent: u64,
+    pub drops: u64,
+    pub underruns: u64,
+    pub overruns: u64,
+    pub reconnects: u64,
+    pub volume_changes: u64,
+    pub device_errors: u64,
+}
+
+impl From<Summary> for StreamerStats {
+    fn from(summary: Summary) -> Self {
+        Self {
+            elapsed_ms: summary.elapsed.as_millis() as u64,
+            bytes_sent: summary.bytes_sent,
+            packets_sent: summary.packets_sent,
+            drops: summary.drops,
+            underruns: summary.underruns,
+            overruns: summary.overruns,
+            reconnects: summary.reconnects,
+            volume_changes: summary.volume_changes,
+            device_errors: summary.device_errors,
+        }
+    }
+}
+
+/// Starts capturing from `device` and streaming to `destination`, as
+/// host:port. Either may be NULL for the command-line client's defaults:
+/// a loopback input if there is one, and port 8080 on this machine.
+///
+/// `codec` is a `StreamerCodec`; any other value is refused with the usage
+/// exit status.
+///
+/// Returns NULL if the streamer cannot start, and sets `*error`, when
+/// `error` is not NULL, to the exit status the command-line client would
+/// have used; it is set to 0 on success.
+///
+/// # Safety
+///
+/// `device` and `destination` must be NULL or point to NUL-terminated
+/// strings, and `error` must be NULL or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn streamer_start(
+    device: *const c_char,
+    destination: *const c_char,
+    codec: c_int,
+    error: *mut c_int,
+) -> *mut Streamer {
+    let result = start(string(device), string(destination), codec);
+    let (streamer, code) = match result {
+        Ok(streamer) => (Box::into_raw(Box::new(streamer)), 0),
+        Err(e) => {
+            error!("Could not start the streamer: {}", e);
+            (ptr::null_mut(), ErrorKind::of(e.as_ref()).exit_code())
+        }
+    };
+    if !error.is_null() {
+        *error = code;
+    }
+    streamer
+}
+
+/// Stops the capture, waits for it to finish and frees the streamer.
+/// Does nothing when `streamer` is NULL.
+///
+/// # Safety
+///
+/// `streamer` must be NULL or come from `streamer_start`, and must not be
+/// used again.
+#[no_mangle]
+pub unsafe extern "C" fn streamer_stop(streamer: *mut Streamer) {
+    if streamer.is_null() {
+        return;
+    }
+    let mut streamer = Box::from_raw(streamer);
+    if let Some(client) = streamer.client.take() {
+        streamer.runtime.block_on(client.shutdown());
+    }
+}
+
+/// Sets the volume, from 0.0 to 1.0. Returns 0, or the usage exit status
+/// when the level is out of range or `streamer` is NULL.
+///
+/// # Safety
+///
+/// `streamer` must be NULL or a live streamer from `streamer_start`.
+#[no_mangle]
+pub unsafe extern "C" fn streamer_set_volume(streamer: *const Streamer, level: f32) -> c_int {
+    let Some(Streamer {
+        client: Some(client),
+        runtime,
+    }) = streamer.as_ref()
+    else {
+        return ErrorKind::Usage.exit_code();
+    };
+    match runtime.block_on(client.set_volume(level)) {
+        Status::Applied => 0,
+        Status::Rejected | Status::Unsupported => ErrorKind::Usage.exit_code(),
+    }
+}
+
+/// Fills `*stats` with the counters so far. Returns 0, or the usage exit
+/// status when either pointer is NULL.
+///
+/// # Safety
+///
+/// `streamer` must be NULL or a live streamer from `streamer_start`, and
+/// `stats` must be NULL or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn streamer_get_stats(streamer: *const Streamer, stats: *mut StreamerStats) -> c_int {
+    let Some(Streamer {
+        client: Some(client), ..
+    }) = streamer.as_ref()
+    else {
+        return ErrorKind::Usage.exit_code();
+    };
+    if stats.is_null() {
+        return ErrorKind::Usage.exit_code();
+    }
+    *stats = client.summary().into();
+    0
+}
+
+fn start(
+    device: Option<String>,
+    destination: Option<String>,
+    codec: c_int,
+) -> Result<Streamer, Box<dyn std::error::Error>> {
+    // Checked as an int, since a C caller can pass any value for the enum.
+    let codec = match codec {
+        c if c == StreamerCodec::Pcm as c_int => Codec::Pcm,
+        c if c == StreamerCodec::Opus as c_int => Codec::Opus,
+        _ => return Err(Error::new(ErrorKind::Usage, format!("Unknown codec {}", codec)).into()),
+    };
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .thread_name("audio-client")
+        .build()
+        .map_err(|e| Error::new(ErrorKind::Other, format!("Could not start the runtime: {}", e)))?;
+    let mut builder = StreamerClient::builder().codec(codec);
+    if let Some(device) = device {
+        builder = builder.device(device);
+    }
+    if let Some(destination) = destination {
+        builder = builder.destination(destination);
+    }
+    let client = runtime.block_on(builder.spawn())?;
+    Ok(Streamer {
+        client: Some(client),
+        runtime,
+    })
+}
+
+// A C string argument, or None when it is NULL. Invalid UTF-8 is replaced
+// rather than refused, as device names come from the host's audio API.
+unsafe fn string(s: *const c_char) -> Option<String> {
+    (!s.is_null()).then(|| CStr::from_ptr(s).to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_stats_from_summary() {
+        let summary = Summary {
+            elapsed: Duration::from_millis(1500),
+            bytes_sent: 1000,
+            packets_sent: 10,
+            drops: 1,
+            ..Summary::default()
+        };
+        let stats = StreamerStats::from(summary);
+        assert_eq!(stats.elapsed_ms, 1500);
+        assert_eq!(stats.bytes_sent, 1000);
+        assert_eq!(stats.packets_sent, 10);
+        assert_eq!(stats.drops, 1);
+        assert_eq!(stats.reconnects, 0);
+    }
+
+    #[test]
+    fn test_null_arguments() {
+        unsafe {
+            assert_eq!(string(ptr::null()), None);
+            assert_eq!(string(c"BlackHole 2ch".as_ptr()).as_deref(), Some("BlackHole 2ch"));
+            streamer_stop(ptr::null_mut());
+            assert_eq!(streamer_set_volume(ptr::null(), 0.5), ErrorKind::Usage.exit_code());
+            let mut stats = StreamerStats::default();
+            assert_eq!(
+                streamer_get_stats(ptr::null(), &mut stats),
+                ErrorKind::Usage.exit_code()
+            );
+        }
+    }
+
+    #[test]
+    fn test_unknown_codec() {
+        let mut error = 0;
+        let streamer = unsafe { streamer_start(ptr::null(), ptr::null(), 7, &mut error) };
+        assert!(streamer.is_null());
+        assert_eq!(error, ErrorKind::Usage.exit_code());
+    }
+}
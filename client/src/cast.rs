@@ -0,0 +1,284 @@
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::thread;
+
+use native_tls::TlsConnector;
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::discovery;
+use crate::http::{self, LiveStream, StreamInfo};
+
+const CAST_SERVICE: &str = "_googlecast._tcp.local.";
+// The Default Media Receiver app, which plays any URL it's given.
+const DEFAULT_MEDIA_RECEIVER: &str = "CC1AD845";
+const NS_CONNECTION: &str = "urn:x-cast:com.google.cast.tp.connection";
+const NS_HEARTBEAT: &str = "urn:x-cast:com.google.cast.tp.heartbeat";
+const NS_RECEIVER: &str = "urn:x-cast:com.google.cast.receiver";
+const NS_MEDIA: &str = "urn:x-cast:com.google.cast.media";
+const SENDER_ID: &str = "sender-0";
+const RECEIVER_ID: &str = "receiver-0";
+const STREAM_PATH: &str = "/stream.wav";
+// Cast frames are small JSON messages; anything larger is a broken stream.
+const MAX_MESSAGE_LEN: usize = 64 * 1024;
+
+/// A Google Cast device found on the network.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CastDevice {
+    pub name: String,
+    pub addr: SocketAddr,
+}
+
+/// Browses mDNS for Cast devices and returns the first whose friendly name
+/// contains `name` (case-insensitive), or the first found.
+pub fn discover(name: Option<&str>) -> io::Result<CastDevice> {
+    let found = discovery::find(CAST_SERVICE, |service| discovery::name_matches(name, &friendly_name(service)))?;
+    let service = found.ok_or_else(|| {
+        let what = name.map_or("any Cast device".to_string(), |n| format!("Cast device '{}'", n));
+        io::Error::new(io::ErrorKind::NotFound, format!("could not find {} on the network", what))
+    })?;
+    Ok(cast_device(&service))
+}
+
+/// Every Cast device that answers on the network within a few seconds.
+pub fn discover_all() -> io::Result<Vec<CastDevice>> {
+    Ok(discovery::browse(CAST_SERVICE)?.iter().map(cast_device).collect())
+}
+
+fn cast_device(service: &discovery::Resolved) -> CastDevice {
+    CastDevice {
+        name: friendly_name(service),
+        addr: service.addr,
+    }
+}
+
+fn friendly_name(service: &discovery::Resolved) -> String {
+    service
+        .property("fn")
+        .unwrap_or_else(|| service.instance(CAST_SERVICE))
+        .to_string()
+}
+
+/// Serves the capture as a WAV stream on an embedded HTTP server and tells
+/// the Cast device to play it. The returned stream is what the sink writes
+/// to; the Cast session runs on a background thread.
+pub fn start(device: &CastDevice, sample_rate: u32, channels: u16) -> io::Result<LiveStream> {
+    let tcp = TcpStream::connect(device.addr)?;
+    let local_ip = tcp.local_addr()?.ip();
+
+    let listener = TcpListener::bind((local_ip, 0))?;
+    let stream = LiveStream::serve(
+        listener,
+        StreamInfo {
+            path: STREAM_PATH.to_string(),
+            content_type: "audio/wav".to_string(),
+            header: http::streaming_wav_header(sample_rate, channels),
+            extra_headers: Vec::new(),
+        },
+    )?;
+    let url = stream_url(local_ip, stream.local_addr().port());
+
+    // Cast devices use self-signed certificates.
+    let connector = TlsConnector::builder()
+        .danger_accept_invalid_certs(true)
+        .danger_accept_invalid_hostnames(true)
+        .build()
+        .map_err(io::Error::other)?;
+    let tls = connector
+        .connect(&device.addr.ip().to_string(), tcp)
+        .map_err(io::Error::other)?;
+
+    let name = device.name.clone();
+    thread::spawn(move || {
+        if let Err(e) = run_session(tls, &url) {
+            warn!("Cast session with {} ended: {}", name, e);
+        }
+    });
+    Ok(stream)
+}
+
+fn stream_url(ip: IpAddr, port: u16) -> String {
+    match ip {
+        IpAddr::V4(ip) => format!("http://{}:{}{}", ip, port, STREAM_PATH),
+        IpAddr::V6(ip) => format!("http://[{}]:{}{}", ip, port, STREAM_PATH),
+    }
+}
+
+// Launches the media receiver, loads the stream URL, then keeps the
+// connection alive by answering the device's heartbeats.
+fn run_session(mut conn: impl Read + Write, url: &str) -> io::Result<()> {
+    send(&mut conn, RECEIVER_ID, NS_CONNECTION, &json!({ "type": "CONNECT" }))?;
+    send(
+        &mut conn,
+        RECEIVER_ID,
+        NS_RECEIVER,
+        &json!({ "type": "LAUNCH", "requestId": 1, "appId": DEFAULT_MEDIA_RECEIVER }),
+    )?;
+
+    let mut transport: Option<String> = None;
+    loop {
+        let message = receive(&mut conn)?;
+        let payload: Value = serde_json::from_str(&message.payload).unwrap_or(Value::Null);
+        match payload["type"].as_str() {
+            Some("PING") => send(&mut conn, &message.source, NS_HEARTBEAT, &json!({ "type": "PONG" }))?,
+            Some("RECEIVER_STATUS") if transport.is_none() => {
+                let app = payload["status"]["applications"]
+                    .as_array()
+                    .and_then(|apps| apps.iter().find(|app| app["appId"] == DEFAULT_MEDIA_RECEIVER));
+                let Some(app) = app else {
+                    continue;
+                };
+                let Some(id) = app["transportId"].as_str() else {
+                    continue;
+                };
+                let id = id.to_string();
+                send(&mut conn, &id, NS_CONNECTION, &json!({ "type": "CONNECT" }))?;
+                send(
+                    &mut conn,
+                    &id,
+                    NS_MEDIA,
+                    &json!({
+                        "type": "LOAD",
+                        "requestId": 2,
+                        "sessionId": app["sessionId"],
+                        "autoplay": true,
+                        "media": {
+                            "contentId": url,
+                            "contentType": "audio/wav",
+                            "streamType": "LIVE",
+                            "metadata": { "metadataType": 0, "title": "audio-client" },
+                        },
+                    }),
+                )?;
+                info!("Cast device is loading {}", url);
+                transport = Some(id);
+            }
+            Some("LOAD_FAILED") | Some("LAUNCH_ERROR") => {
+                return Err(io::Error::other(format!("device reported {}", payload["type"])));
+            }
+            Some("CLOSE") if message.source != RECEIVER_ID => {
+                return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "playback was stopped on the device"));
+            }
+            _ => {}
+        }
+    }
+}
+
+pub(crate) struct CastMessage {
+    source: String,
+    payload: String,
+}
+
+fn send(conn: &mut impl Write, destination: &str, namespace: &str, payload: &Value) -> io::Result<()> {
+    let message = encode_message(SENDER_ID, destination, namespace, &payload.to_string());
+    conn.write_all(&(message.len() as u32).to_be_bytes())?;
+    conn.write_all(&message)?;
+    conn.flush()
+}
+
+fn receive(conn: &mut impl Read) -> io::Result<CastMessage> {
+    let mut len = [0u8; 4];
+    conn.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "oversized Cast message"));
+    }
+    let mut message = vec![0u8; len];
+    conn.read_exact(&mut message)?;
+    decode_message(&message).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed Cast message"))
+}
+
+// CastMessage protobuf: 1 protocol_version (enum, CASTV2_1_0 = 0),
+// 2 source_id, 3 destination_id, 4 namespace, 5 payload_type (STRING = 0),
+// 6 payload_utf8.
+fn encode_message(source: &str, destination: &str, namespace: &str, payload: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&[0x08, 0x00]);
+    for (field, value) in [(2, source), (3, destination), (4, namespace)] {
+        put_string(&mut out, field, value);
+    }
+    out.extend_from_slice(&[0x28, 0x00]);
+    put_string(&mut out, 6, payload);
+    out
+}
+
+fn put_string(out: &mut Vec<u8>, field: u8, value: &str) {
+    out.push(field << 3 | 2);
+    put_varint(out, value.len() as u64);
+    out.extend_from_slice(value.as_bytes());
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn get_varint(bytes: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = bytes.split_first()?;
+        *bytes = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+pub(crate) fn decode_message(mut bytes: &[u8]) -> Option<CastMessage> {
+    let mut message = CastMessage {
+        source: String::new(),
+        payload: String::new(),
+    };
+    while !bytes.is_empty() {
+        let key = get_varint(&mut bytes)?;
+        match key & 7 {
+            0 => {
+                get_varint(&mut bytes)?;
+            }
+            2 => {
+                let len = get_varint(&mut bytes)? as usize;
+                if len > bytes.len() {
+                    return None;
+                }
+                let (value, rest) = bytes.split_at(len);
+                bytes = rest;
+                match key >> 3 {
+                    2 => message.source = String::from_utf8_lossy(value).into_owned(),
+                    6 => message.payload = String::from_utf8_lossy(value).into_owned(),
+                    _ => {}
+                }
+            }
+            _ => return None,
+        }
+    }
+    Some(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_roundtrip() {
+        let payload = "x".repeat(200);
+        let bytes = encode_message(SENDER_ID, RECEIVER_ID, NS_CONNECTION, &payload);
+        let message = decode_message(&bytes).unwrap();
+        assert_eq!(message.source, SENDER_ID);
+        assert_eq!(message.payload, payload);
+        assert!(decode_message(&bytes[..bytes.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn test_stream_url() {
+        assert_eq!(
+            stream_url("192.168.1.5".parse().unwrap(), 8000),
+            "http://192.168.1.5:8000/stream.wav"
+        );
+        assert_eq!(stream_url("fe80::1".parse().unwrap(), 80), "http://[fe80::1]:80/stream.wav");
+    }
+}
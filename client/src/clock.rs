@@ -0,0 +1,278 @@
+use std::io;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tracing::{error, info, warn};
+
+// Seconds between the NTP epoch (1900) and the Unix epoch (1970).
+const NTP_UNIX_OFFSET_SECS: u64 = 2_208_988_800;
+const NTP_PACKET_LEN: usize = 48;
+const NTP_TIMEOUT: Duration = Duration::from_secs(2);
+// Queries per sync round; the lowest-delay sample wins.
+const NTP_BURST: usize = 4;
+const NTP_POLL_INTERVAL: Duration = Duration::from_secs(64);
+
+// LAN sync exchange on the control channel. A receiver sends
+// [TIME_REQUEST, t1: u64] and the sender answers [TIME_RESPONSE, t1, t2, t3].
+pub const TIME_REQUEST: u8 = 0x02;
+pub const TIME_RESPONSE: u8 = 0x03;
+pub const TIME_REQUEST_LEN: usize = 9;
+const TIME_RESPONSE_LEN: usize = 25;
+const LAN_SYNC_BURST: usize = 8;
+const LAN_SYNC_INTERVAL: Duration = Duration::from_secs(4);
+const LAN_SYNC_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// System time corrected by an offset measured against a reference clock,
+/// so packets from different machines carry comparable timestamps.
+#[derive(Debug, Default)]
+pub struct SyncedClock {
+    offset_micros: AtomicI64,
+}
+
+impl SyncedClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Microseconds since the Unix epoch on the synchronized timeline.
+    pub fn now_micros(&self) -> u64 {
+        (system_micros() as i64 + self.offset_micros()) as u64
+    }
+
+    pub fn offset_micros(&self) -> i64 {
+        self.offset_micros.load(Ordering::Relaxed)
+    }
+
+    pub fn set_offset_micros(&self, offset: i64) {
+        self.offset_micros.store(offset, Ordering::Relaxed);
+    }
+}
+
+pub fn system_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+/// One clock comparison: how far ahead the reference is, and the round trip
+/// it was measured over.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OffsetSample {
+    pub offset_micros: i64,
+    pub delay_micros: i64,
+}
+
+/// The standard four-timestamp calculation: `t1` request sent and `t4`
+/// response received on the local clock, `t2`/`t3` request received and
+/// response sent on the reference clock.
+pub fn offset_from_timestamps(t1: i64, t2: i64, t3: i64, t4: i64) -> OffsetSample {
+    OffsetSample {
+        offset_micros: ((t2 - t1) + (t3 - t4)) / 2,
+        delay_micros: (t4 - t1) - (t3 - t2),
+    }
+}
+
+pub fn ntp_to_unix_micros(ntp: u64) -> i64 {
+    let secs = (ntp >> 32) as i64 - NTP_UNIX_OFFSET_SECS as i64;
+    let frac = ((ntp & 0xFFFF_FFFF) * 1_000_000) >> 32;
+    secs * 1_000_000 + frac as i64
+}
+
+pub fn unix_micros_to_ntp(micros: u64) -> u64 {
+    let secs = micros / 1_000_000 + NTP_UNIX_OFFSET_SECS;
+    let frac = ((micros % 1_000_000) << 32) / 1_000_000;
+    (secs << 32) | frac
+}
+
+/// Sends a single SNTP request to `server` (host or host:port).
+pub async fn sntp_query(server: &str) -> io::Result<OffsetSample> {
+    let addr = if server.contains(':') {
+        server.to_string()
+    } else {
+        format!("{}:123", server)
+    };
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect(&addr).await?;
+
+    let mut request = [0u8; NTP_PACKET_LEN];
+    // LI = 0, version 4, mode 3 (client)
+    request[0] = 0x23;
+    let t1 = system_micros();
+    request[40..48].copy_from_slice(&unix_micros_to_ntp(t1).to_be_bytes());
+    socket.send(&request).await?;
+
+    let mut response = [0u8; NTP_PACKET_LEN];
+    let len = tokio::time::timeout(NTP_TIMEOUT, socket.recv(&mut response))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "NTP request timed out"))??;
+    let t4 = system_micros();
+    if len < NTP_PACKET_LEN || response[0] & 0x07 != 4 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed NTP response"));
+    }
+
+    let read = |at: usize| u64::from_be_bytes(response[at..at + 8].try_into().unwrap());
+    let t2 = ntp_to_unix_micros(read(32));
+    let t3 = ntp_to_unix_micros(read(40));
+    Ok(offset_from_timestamps(t1 as i64, t2, t3, t4 as i64))
+}
+
+/// Keeps `clock` aligned with an NTP server, re-measuring periodically.
+pub async fn discipline(clock: Arc<SyncedClock>, server: String) {
+    let mut synced = false;
+    loop {
+        let mut best: Option<OffsetSample> = None;
+        for _ in 0..NTP_BURST {
+            match sntp_query(&server).await {
+                Ok(sample) => {
+                    if !matches!(best, Some(b) if b.delay_micros <= sample.delay_micros) {
+                        best = Some(sample);
+                    }
+                }
+                Err(e) => warn!("NTP query to {} failed: {}", server, e),
+            }
+        }
+        if let Some(sample) = best {
+            if !synced {
+                info!(
+                    "Clock synchronized with {}: offset {:.3}ms, delay {:.3}ms",
+                    server,
+                    sample.offset_micros as f64 / 1000.0,
+                    sample.delay_micros as f64 / 1000.0
+                );
+            }
+            clock.set_offset_micros(sample.offset_micros);
+            synced = true;
+        }
+        tokio::time::sleep(NTP_POLL_INTERVAL).await;
+    }
+}
+
+pub fn time_request(t1: u64) -> [u8; TIME_REQUEST_LEN] {
+    let mut request = [0u8; TIME_REQUEST_LEN];
+    request[0] = TIME_REQUEST;
+    request[1..9].copy_from_slice(&t1.to_le_bytes());
+    request
+}
+
+/// Builds the reply to a time request received at `t2` and sent at `t3`,
+/// both on the responder's clock.
+pub fn time_response(request: &[u8], t2: u64, t3: u64) -> Option<[u8; TIME_RESPONSE_LEN]> {
+    if request.len() != TIME_REQUEST_LEN || request[0] != TIME_REQUEST {
+        return None;
+    }
+    let mut response = [0u8; TIME_RESPONSE_LEN];
+    response[0] = TIME_RESPONSE;
+    response[1..9].copy_from_slice(&request[1..9]);
+    response[9..17].copy_from_slice(&t2.to_le_bytes());
+    response[17..25].copy_from_slice(&t3.to_le_bytes());
+    Some(response)
+}
+
+/// Returns `(t1, t2, t3)` from a time response.
+pub fn parse_time_response(response: &[u8]) -> Option<(u64, u64, u64)> {
+    if response.len() != TIME_RESPONSE_LEN || response[0] != TIME_RESPONSE {
+        return None;
+    }
+    let read = |at: usize| u64::from_le_bytes(response[at..at + 8].try_into().unwrap());
+    Some((read(1), read(9), read(17)))
+}
+
+/// Keeps `clock` aligned with another client's clock over its control port.
+/// Each round sends a burst of requests and keeps the one with the shortest
+/// round trip, whose halved RTT is the most symmetric on a quiet LAN.
+pub async fn lan_sync(clock: Arc<SyncedClock>, master: String) {
+    let socket = match UdpSocket::bind("0.0.0.0:0").await {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("Error binding clock sync socket: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = socket.connect(&master).await {
+        error!("Error resolving clock sync peer {}: {}", master, e);
+        return;
+    }
+
+    let mut synced = false;
+    let mut buf = [0u8; 64];
+    loop {
+        let mut best: Option<OffsetSample> = None;
+        for _ in 0..LAN_SYNC_BURST {
+            let t1 = system_micros();
+            if socket.send(&time_request(t1)).await.is_err() {
+                continue;
+            }
+            let len = match tokio::time::timeout(LAN_SYNC_TIMEOUT, socket.recv(&mut buf)).await {
+                Ok(Ok(len)) => len,
+                _ => continue,
+            };
+            let t4 = system_micros();
+            match parse_time_response(&buf[..len]) {
+                Some((echo, t2, t3)) if echo == t1 => {
+                    let sample = offset_from_timestamps(t1 as i64, t2 as i64, t3 as i64, t4 as i64);
+                    if !matches!(best, Some(b) if b.delay_micros <= sample.delay_micros) {
+                        best = Some(sample);
+                    }
+                }
+                _ => {}
+            }
+        }
+        match best {
+            Some(sample) => {
+                if !synced {
+                    info!(
+                        "Clock synchronized with {}: offset {:.3}ms, round trip {:.3}ms",
+                        master,
+                        sample.offset_micros as f64 / 1000.0,
+                        sample.delay_micros as f64 / 1000.0
+                    );
+                    synced = true;
+                }
+                clock.set_offset_micros(sample.offset_micros);
+            }
+            None => warn!("No clock sync response from {}", master),
+        }
+        tokio::time::sleep(LAN_SYNC_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offset_from_timestamps() {
+        // Reference is 500us ahead, 100us each way.
+        let sample = offset_from_timestamps(1_000, 1_600, 1_650, 1_250);
+        assert_eq!(sample.offset_micros, 500);
+        assert_eq!(sample.delay_micros, 200);
+    }
+
+    #[test]
+    fn test_ntp_conversion_roundtrip() {
+        let micros = 1_700_000_000_123_456u64;
+        let back = ntp_to_unix_micros(unix_micros_to_ntp(micros));
+        assert!((back - micros as i64).abs() <= 1);
+        assert_eq!(ntp_to_unix_micros(NTP_UNIX_OFFSET_SECS << 32), 0);
+    }
+
+    #[test]
+    fn test_time_exchange_roundtrip() {
+        let request = time_request(1_000);
+        let response = time_response(&request, 2_000, 2_010).unwrap();
+        assert_eq!(parse_time_response(&response), Some((1_000, 2_000, 2_010)));
+
+        assert_eq!(time_response(&request[..8], 0, 0), None);
+        assert_eq!(parse_time_response(&request), None);
+    }
+
+    #[test]
+    fn test_synced_clock_applies_offset() {
+        let clock = SyncedClock::new();
+        clock.set_offset_micros(-1_000_000);
+        let diff = system_micros() as i64 - clock.now_micros() as i64;
+        assert!((diff - 1_000_000).abs() < 100_000);
+    }
+}
@@ -0,0 +1,306 @@
+use opus::{Application, Bitrate, Channels};
+use tracing::warn;
+
+// Opus only accepts fixed frame sizes; 10ms at 48kHz.
+pub const OPUS_FRAME_FRAMES: usize = 480;
+// Encoder's packet loss expectation, used to size in-band FEC data.
+const OPUS_EXPECTED_LOSS_PERC: i32 = 10;
+// Frames whose peak stays below this are treated as silence for DTX.
+const DTX_SILENCE_THRESHOLD: i16 = 8;
+// While in DTX, still send one frame this often so the receiver stays in sync.
+const DTX_KEEPALIVE_FRAMES: u32 = 40;
+const MAX_OPUS_PACKET: usize = 4000;
+
+/// How audio frames are encoded on the wire.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Codec {
+    /// Uncompressed 16-bit PCM
+    Pcm,
+    /// Opus at 48kHz, 10ms frames
+    Opus,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OpusOptions {
+    /// Embed recovery data for the previous frame in each packet.
+    pub fec: bool,
+    /// Stop transmitting while the input is silent.
+    pub dtx: bool,
+    /// Aim for this many bits per second rather than the encoder's own
+    /// choice for the rate and channels.
+    pub bitrate: Option<u32>,
+    /// Mix the channels down to one; receivers play it on all of theirs.
+    pub mono: bool,
+    /// Encode at this rate, which must divide the capture rate, averaging
+    /// the captured samples down to it. Opus takes 8000, 12000, 16000,
+    /// 24000 and 48000 Hz, and receivers decode at their own rate.
+    pub sample_rate: Option<u32>,
+}
+
+impl Codec {
+    /// Interleaved frames carried by one packet, or `None` when the packet
+    /// size follows the capture buffer.
+    pub fn frame_frames(self) -> Option<usize> {
+        match self {
+            Codec::Pcm => None,
+            Codec::Opus => Some(OPUS_FRAME_FRAMES),
+        }
+    }
+}
+
+pub enum FrameEncoder {
+    Pcm,
+    Opus {
+        encoder: opus::Encoder,
+        channels: usize,
+        dtx: bool,
+        pending: Vec<i16>,
+        packet: Vec<u8>,
+        silent_run: u32,
+        // Captured frames averaged into each encoded one, and whether the
+        // channels are mixed down, with the frame that makes.
+        decimation: usize,
+        mono: bool,
+        reduced: Vec<i16>,
+    },
+}
+
+impl FrameEncoder {
+    pub fn new(codec: Codec, sample_rate: u32, channels: u16, options: OpusOptions) -> Result<Self, opus::Error> {
+        match codec {
+            Codec::Pcm => Ok(FrameEncoder::Pcm),
+            Codec::Opus => {
+                let encode_rate = options.sample_rate.unwrap_or(sample_rate);
+                let encode_channels = if options.mono { 1 } else { channels };
+                let mut encoder = opus::Encoder::new(encode_rate, opus_channels(encode_channels), Application::Audio)?;
+                encoder.set_inband_fec(options.fec)?;
+                if options.fec {
+                    encoder.set_packet_loss_perc(OPUS_EXPECTED_LOSS_PERC)?;
+                }
+                if let Some(bitrate) = options.bitrate {
+                    encoder.set_bitrate(Bitrate::Bits(bitrate as i32))?;
+                }
+                let decimation = (sample_rate / encode_rate).max(1) as usize;
+                Ok(FrameEncoder::Opus {
+                    encoder,
+                    channels: channels as usize,
+                    dtx: options.dtx,
+                    pending: Vec::new(),
+                    packet: vec![0u8; MAX_OPUS_PACKET],
+                    silent_run: 0,
+                    decimation,
+                    mono: options.mono,
+                    reduced: Vec::with_capacity(OPUS_FRAME_FRAMES * channels as usize),
+                })
+            }
+        }
+    }
+
+    /// Encodes s16le PCM, calling `emit` once per outgoing packet slot.
+    /// `None` marks a slot skipped by DTX, which still consumes a sequence
+    /// number so the receiver conceals it instead of re-buffering.
+    pub fn encode(&mut self, pcm: &[u8], mut emit: impl FnMut(Option<&[u8]>)) {
+        match self {
+            FrameEncoder::Pcm => emit(Some(pcm)),
+            FrameEncoder::Opus {
+                encoder,
+                channels,
+                dtx,
+                pending,
+                packet,
+                silent_run,
+                decimation,
+                mono,
+                reduced,
+            } => {
+                pending.extend(pcm.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])));
+                let frame_len = OPUS_FRAME_FRAMES * *channels;
+                let mut offset = 0;
+                while pending.len() - offset >= frame_len {
+                    let frame = &pending[offset..offset + frame_len];
+                    offset += frame_len;
+
+                    if *dtx && frame.iter().all(|s| s.unsigned_abs() < DTX_SILENCE_THRESHOLD as u16) {
+                        *silent_run += 1;
+                        if *silent_run > 1 && *silent_run % DTX_KEEPALIVE_FRAMES != 0 {
+                            emit(None);
+                            continue;
+                        }
+                    } else {
+                        *silent_run = 0;
+                    }
+
+                    let encoded = if *decimation > 1 || *mono {
+                        reduce(frame, *channels, *decimation, *mono, reduced);
+                        encoder.encode(reduced, packet)
+                    } else {
+                        encoder.encode(frame, packet)
+                    };
+                    match encoded {
+                        Ok(len) => emit(Some(&packet[..len])),
+                        Err(e) => warn!("Opus encode error: {}", e),
+                    }
+                }
+                pending.drain(..offset);
+            }
+        }
+    }
+
+    /// Encodes the audio still held back for a whole codec frame, padded
+    /// out with silence, at the end of the stream.
+    pub fn finish(&mut self, emit: impl FnMut(Option<&[u8]>)) {
+        let padding = match self {
+            FrameEncoder::Opus { pending, channels, .. } if !pending.is_empty() => {
+                OPUS_FRAME_FRAMES * *channels - pending.len()
+            }
+            _ => return,
+        };
+        self.encode(&vec![0u8; padding * 2], emit);
+    }
+
+    /// Frames held back until a whole codec frame has been captured.
+    pub fn pending_frames(&self) -> usize {
+        match self {
+            FrameEncoder::Pcm => 0,
+            FrameEncoder::Opus { pending, channels, .. } => pending.len() / channels,
+        }
+    }
+}
+
+pub enum FrameDecoder {
+    Pcm,
+    Opus {
+        decoder: opus::Decoder,
+        channels: usize,
+        fec: bool,
+    },
+}
+
+impl FrameDecoder {
+    pub fn new(codec: Codec, sample_rate: u32, channels: u16, options: OpusOptions) -> Result<Self, opus::Error> {
+        match codec {
+            Codec::Pcm => Ok(FrameDecoder::Pcm),
+            Codec::Opus => Ok(FrameDecoder::Opus {
+                decoder: opus::Decoder::new(sample_rate, opus_channels(channels))?,
+                channels: channels as usize,
+                fec: options.fec,
+            }),
+        }
+    }
+
+    pub fn decode(&mut self, payload: &[u8], out: &mut Vec<i16>) {
+        match self {
+            FrameDecoder::Pcm => {
+                out.extend(payload.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])));
+            }
+            FrameDecoder::Opus { decoder, channels, .. } => {
+                decode_opus(decoder, *channels, payload, false, out);
+            }
+        }
+    }
+
+    /// Fills in a lost frame. Opus recovers it from the FEC data in the
+    /// following packet when available, falling back to the decoder's own
+    /// concealment. Returns `false` when the codec has no concealment of
+    /// its own.
+    pub fn conceal(&mut self, next: Option<&[u8]>, out: &mut Vec<i16>) -> bool {
+        match self {
+            FrameDecoder::Pcm => false,
+            FrameDecoder::Opus { decoder, channels, fec } => {
+                match next {
+                    Some(next) if *fec => decode_opus(decoder, *channels, next, true, out),
+                    _ => decode_opus(decoder, *channels, &[], false, out),
+                }
+                true
+            }
+        }
+    }
+}
+
+fn decode_opus(decoder: &mut opus::Decoder, channels: usize, payload: &[u8], fec: bool, out: &mut Vec<i16>) {
+    let start = out.len();
+    out.resize(start + OPUS_FRAME_FRAMES * channels, 0);
+    match decoder.decode(payload, &mut out[start..], fec) {
+        Ok(frames) => out.truncate(start + frames * channels),
+        Err(e) => {
+            warn!("Opus decode error: {}", e);
+            out.truncate(start);
+        }
+    }
+}
+
+// Averages each run of `decimation` frames of `frame` into one, and its
+// channels into one as well if `mono`, replacing what `out` held.
+fn reduce(frame: &[i16], channels: usize, decimation: usize, mono: bool, out: &mut Vec<i16>) {
+    out.clear();
+    for run in frame.chunks_exact(channels * decimation) {
+        if mono {
+            let sum: i32 = run.iter().map(|&s| s as i32).sum();
+            out.push((sum / run.len() as i32) as i16);
+        } else {
+            for channel in 0..channels {
+                let sum: i32 = run.iter().skip(channel).step_by(channels).map(|&s| s as i32).sum();
+                out.push((sum / decimation as i32) as i16);
+            }
+        }
+    }
+}
+
+fn opus_channels(channels: u16) -> Channels {
+    if channels == 1 {
+        Channels::Mono
+    } else {
+        Channels::Stereo
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reduce() {
+        let frame = [100, 300, 200, 400, -100, -300, -200, -400];
+        let mut out = Vec::new();
+        reduce(&frame, 2, 2, false, &mut out);
+        assert_eq!(out, [150, 350, -150, -350]);
+        reduce(&frame, 2, 2, true, &mut out);
+        assert_eq!(out, [250, -250]);
+        reduce(&frame, 2, 1, true, &mut out);
+        assert_eq!(out, [200, 300, -200, -300]);
+    }
+
+    #[test]
+    fn test_mono_24khz_plays_at_48khz_stereo() {
+        let options = OpusOptions {
+            bitrate: Some(32_000),
+            mono: true,
+            sample_rate: Some(24_000),
+            ..OpusOptions::default()
+        };
+        let mut encoder = FrameEncoder::new(Codec::Opus, 48_000, 2, options).unwrap();
+        let mut decoder = FrameDecoder::new(Codec::Opus, 48_000, 2, OpusOptions::default()).unwrap();
+        let pcm: Vec<u8> = (0..OPUS_FRAME_FRAMES * 20)
+            .flat_map(|i| {
+                let sample = (8000.0 * (2.0 * std::f32::consts::PI * 440.0 * i as f32 / 48_000.0).sin()) as i16;
+                [sample, sample]
+            })
+            .flat_map(i16::to_le_bytes)
+            .collect();
+        let mut packets = Vec::new();
+        encoder.encode(&pcm, |packet| packets.push(packet.unwrap().to_vec()));
+        assert_eq!(packets.len(), 20);
+        // 32 kbps is 40 bytes per 10 ms frame, give or take what VBR spends.
+        let bytes: usize = packets.iter().map(Vec::len).sum();
+        assert!(bytes <= 20 * 60, "{} bytes", bytes);
+
+        let mut played = Vec::new();
+        for packet in &packets {
+            decoder.decode(packet, &mut played);
+        }
+        assert_eq!(played.len(), OPUS_FRAME_FRAMES * 20 * 2);
+        let tail = &played[played.len() / 2..];
+        let peak = tail.iter().map(|s| s.unsigned_abs()).max().unwrap();
+        assert!((6000..10000).contains(&peak), "peak {}", peak);
+    }
+}
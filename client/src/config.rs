@@ -0,0 +1,361 @@
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use notify::{RecursiveMode, Watcher};
+use serde::Deserialize;
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+use crate::control::{Controls, Message};
+
+// Editors save in several steps (truncate, write, rename); reload once they settle.
+const SETTLE_DELAY: Duration = Duration::from_millis(200);
+const FILE_NAME: &str = "config.toml";
+// The table holding one table of settings per profile.
+const PROFILES: &str = "profiles";
+
+/// Where the client looks for a config file without `--config`:
+/// `$XDG_CONFIG_HOME/audio-streamer/config.toml`, or under `~/.config`
+/// without it, and `%APPDATA%\audio-streamer\config.toml` on Windows.
+pub fn default_path() -> Option<PathBuf> {
+    #[cfg(windows)]
+    let dir = std::env::var_os("APPDATA").map(PathBuf::from);
+    #[cfg(not(windows))]
+    let dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+    dir.map(|dir| dir.join("audio-streamer").join(FILE_NAME))
+}
+
+/// Reads the file at `path` as the command-line flags its settings stand
+/// for, to be parsed ahead of the real command line so its flags win.
+/// The settings of `profile` replace those outside any profile. Settings
+/// whose long flag name `skip` accepts are left out, as when an
+/// environment variable already sets them.
+pub fn load_args(path: &Path, profile: Option<&str>, skip: impl Fn(&str) -> bool) -> io::Result<Vec<OsString>> {
+    to_args(&read_settings(path, profile)?, skip).map_err(invalid)
+}
+
+/// The names of the profiles in the file at `path`, in sorted order.
+pub fn profile_names(path: &Path) -> io::Result<Vec<String>> {
+    let table = read_table(path)?;
+    match table.get(PROFILES) {
+        Some(toml::Value::Table(profiles)) => Ok(profiles.keys().cloned().collect()),
+        Some(_) => Err(invalid(format!("{} must be a table", PROFILES))),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Sets `key` to `value` in the file at `path`, or in the table of
+/// `profile` there, keeping the rest of the file and its comments. Creates
+/// the file if needed.
+pub fn save_setting(path: &Path, profile: Option<&str>, key: &str, value: &str) -> io::Result<()> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let text = with_setting(&text, profile, key, value).map_err(invalid)?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, text)
+}
+
+fn with_setting(text: &str, profile: Option<&str>, key: &str, value: &str) -> Result<String, toml_edit::TomlError> {
+    let mut document: toml_edit::DocumentMut = text.parse()?;
+    let table = match profile {
+        Some(name) => &mut document[PROFILES][name],
+        None => document.as_item_mut(),
+    };
+    table[key] = toml_edit::value(value);
+    Ok(document.to_string())
+}
+
+fn read_table(path: &Path) -> io::Result<toml::Table> {
+    std::fs::read_to_string(path)?.parse().map_err(invalid)
+}
+
+fn read_settings(path: &Path, profile: Option<&str>) -> io::Result<toml::Table> {
+    select_profile(read_table(path)?, profile).map_err(invalid)
+}
+
+// Drops the profiles from `table`, first laying the settings of `profile`
+// over the rest.
+fn select_profile(mut table: toml::Table, profile: Option<&str>) -> Result<toml::Table, String> {
+    let mut profiles = match table.remove(PROFILES) {
+        Some(toml::Value::Table(profiles)) => profiles,
+        Some(_) => return Err(format!("{} must be a table", PROFILES)),
+        None => toml::Table::new(),
+    };
+    if let Some(name) = profile {
+        match profiles.remove(name) {
+            Some(toml::Value::Table(settings)) => table.extend(settings),
+            Some(_) => return Err(format!("profile {} must be a table", name)),
+            None => return Err(format!("there is no profile named {}", name)),
+        }
+    }
+    Ok(table)
+}
+
+// Each key is a long flag with dashes written as underscores:
+// `device_name = "Line In"` is `--device-name=Line In`. `true` gives a bare
+// flag and `false` leaves it out; an array repeats the flag per element.
+fn to_args(table: &toml::Table, skip: impl Fn(&str) -> bool) -> Result<Vec<OsString>, String> {
+    let mut args = Vec::new();
+    for (key, value) in table {
+        match key.as_str() {
+            "config" => return Err("a config file cannot name another one".to_string()),
+            "profile" => return Err("choose a profile with --profile".to_string()),
+            _ => {}
+        }
+        let long = key.replace('_', "-");
+        if skip(&long) {
+            continue;
+        }
+        let flag = format!("--{}", long);
+        let values = match value {
+            toml::Value::Array(values) => values.as_slice(),
+            value => std::slice::from_ref(value),
+        };
+        for value in values {
+            let arg = match value {
+                toml::Value::Boolean(true) => flag.clone(),
+                toml::Value::Boolean(false) => continue,
+                toml::Value::String(text) => format!("{}={}", flag, text),
+                toml::Value::Integer(number) => format!("{}={}", flag, number),
+                toml::Value::Float(number) => format!("{}={}", flag, number),
+                _ => return Err(format!("{} must be a string, number, boolean or list", key)),
+            };
+            args.push(arg.into());
+        }
+    }
+    Ok(args)
+}
+
+/// The settings in a config file that apply while streaming, reloaded by
+/// `watch`. Settings left out keep their current values.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// A server, or a list of them with backups after the first.
+    #[serde(deserialize_with = "one_or_more")]
+    pub server: Option<Vec<String>>,
+    pub volume: Option<f32>,
+    pub muted: Option<bool>,
+    pub redundancy: Option<bool>,
+    /// A device name, or a list of them with fallbacks after the first.
+    #[serde(deserialize_with = "one_or_more")]
+    pub device_name: Option<Vec<String>>,
+}
+
+fn one_or_more<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<String>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMore {
+        One(String),
+        More(Vec<String>),
+    }
+    Ok(Some(match OneOrMore::deserialize(deserializer)? {
+        OneOrMore::One(name) => vec![name],
+        OneOrMore::More(names) => names,
+    }))
+}
+
+/// Reads the settings that apply while streaming from the TOML file at
+/// `path`, with those of `profile` in place of the others.
+pub fn load(path: &Path, profile: Option<&str>) -> io::Result<Config> {
+    toml::Value::Table(read_settings(path, profile)?)
+        .try_into()
+        .map_err(invalid)
+}
+
+fn invalid(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// The control messages that take a client configured by `old` to `new`.
+/// A new first device rebuilds the capture stream; everything else applies
+/// to the running one. The server is not a control message; see `watch`.
+pub fn changes(old: &Config, new: &Config) -> Vec<Message> {
+    let mut messages = Vec::new();
+    if let Some(volume) = new.volume.filter(|v| old.volume != Some(*v)) {
+        messages.push(Message::SetVolume(volume));
+    }
+    if let Some(muted) = new.muted.filter(|m| old.muted != Some(*m)) {
+        messages.push(Message::Mute(muted));
+    }
+    if let Some(enabled) = new.redundancy.filter(|r| old.redundancy != Some(*r)) {
+        messages.push(Message::SetRedundancy(enabled));
+    }
+    let first = |config: &Config| config.device_name.as_ref().and_then(|names| names.first().cloned());
+    if let Some(name) = first(new).filter(|name| first(old).as_ref() != Some(name)) {
+        messages.push(Message::SwitchDevice(name));
+    }
+    messages
+}
+
+/// Re-reads the file at `path`, and the settings of `profile` in it,
+/// whenever it changes or `reload` is notified, applying what differs from
+/// `current`, the configuration already in effect. A changed first server
+/// is sent to on `audio_port`. A file that fails to parse is reported and
+/// otherwise ignored.
+pub async fn watch(
+    path: PathBuf,
+    profile: Option<String>,
+    mut current: Config,
+    audio_port: u16,
+    controls: Arc<Controls>,
+    reload: Arc<Notify>,
+) -> notify::Result<()> {
+    // Watch the directory rather than the file, which editors replace
+    // instead of writing in place.
+    let events = reload.clone();
+    let name = path.file_name().map(|name| name.to_os_string());
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event {
+            if event.paths.iter().any(|changed| changed.file_name() == name.as_deref()) {
+                events.notify_one();
+            }
+        }
+    })?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    info!("Watching {} for changes", path.display());
+
+    loop {
+        reload.notified().await;
+        tokio::time::sleep(SETTLE_DELAY).await;
+        let config = match load(&path, profile.as_deref()) {
+            Ok(config) => config,
+            Err(e) => {
+                warn!("Not reloading {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        if config == current {
+            continue;
+        }
+        info!("Reloading {}", path.display());
+        let first = |config: &Config| config.server.as_ref().and_then(|servers| servers.first().cloned());
+        if let Some(server) = first(&config).filter(|server| first(&current).as_ref() != Some(server)) {
+            if let Err(e) = controls.set_destination(&format!("{}:{}", server, audio_port)).await {
+                warn!("Could not change the server to {}: {}", server, e);
+            }
+        }
+        for message in changes(&current, &config) {
+            controls.apply(message).await;
+        }
+        current = config;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config: Config = toml::from_str("server = \"10.0.0.2\"\nvolume = 0.5\nmuted = true\n").unwrap();
+        assert_eq!(config.server, Some(vec!["10.0.0.2".to_string()]));
+        assert_eq!(config.volume, Some(0.5));
+        assert_eq!(config.muted, Some(true));
+        assert_eq!(config.device_name, None);
+        let config: Config = toml::from_str("device_name = [\"USB Mic\", \"Line In\"]\n").unwrap();
+        assert_eq!(
+            config.device_name,
+            Some(vec!["USB Mic".to_string(), "Line In".to_string()])
+        );
+        let config: Config = toml::from_str("server = [\"10.0.0.2\", \"10.0.0.3\"]\n").unwrap();
+        assert_eq!(config.server, Some(vec!["10.0.0.2".to_string(), "10.0.0.3".to_string()]));
+        assert!(toml::from_str::<Config>("volume = \"loud\"\n").is_err());
+    }
+
+    #[test]
+    fn test_to_args() {
+        let table: toml::Table = "device_name = \"Line In\"\ncontrol_port = 9081\nvolume = 0.5\nredundancy = true\n\
+                                  timestamps = false\nfallback = [\"a\", \"b\"]\n"
+            .parse()
+            .unwrap();
+        let args: Vec<OsString> = [
+            "--control-port=9081",
+            "--device-name=Line In",
+            "--fallback=a",
+            "--fallback=b",
+            "--redundancy",
+            "--volume=0.5",
+        ]
+        .iter()
+        .map(OsString::from)
+        .collect();
+        assert_eq!(to_args(&table, |_| false), Ok(args.clone()));
+        assert_eq!(to_args(&table, |long| long == "device-name"), Ok(args[1..].to_vec()));
+
+        assert!(to_args(&"sink = { kind = \"udp\" }".parse().unwrap(), |_| false).is_err());
+        assert!(to_args(&"config = \"other.toml\"".parse().unwrap(), |_| false).is_err());
+    }
+
+    #[test]
+    fn test_select_profile() {
+        let table: toml::Table = "volume = 0.5\ncodec = \"pcm\"\n\n[profiles.livingroom]\nserver = \"10.0.0.2\"\n\
+                                  codec = \"opus\"\n"
+            .parse()
+            .unwrap();
+        let plain = select_profile(table.clone(), None).unwrap();
+        assert_eq!(plain, "volume = 0.5\ncodec = \"pcm\"\n".parse().unwrap());
+
+        let livingroom = select_profile(table.clone(), Some("livingroom")).unwrap();
+        assert_eq!(livingroom["codec"].as_str(), Some("opus"));
+        assert_eq!(livingroom["server"].as_str(), Some("10.0.0.2"));
+        assert_eq!(livingroom["volume"].as_float(), Some(0.5));
+
+        assert!(select_profile(table, Some("kitchen")).is_err());
+    }
+
+    #[test]
+    fn test_with_setting() {
+        let text = "# Living room\nvolume = 0.5\ndevice_name = \"Line In\"\n";
+        assert_eq!(
+            with_setting(text, None, "device_name", "USB Mic").unwrap(),
+            "# Living room\nvolume = 0.5\ndevice_name = \"USB Mic\"\n"
+        );
+        let text = with_setting("", Some("studio"), "device_name", "USB Mic").unwrap();
+        let table = select_profile(text.parse().unwrap(), Some("studio")).unwrap();
+        assert_eq!(table["device_name"].as_str(), Some("USB Mic"));
+    }
+
+    #[test]
+    fn test_changes() {
+        let old = Config {
+            volume: Some(0.5),
+            muted: Some(false),
+            device_name: Some(vec!["Line In".to_string()]),
+            ..Config::default()
+        };
+        assert_eq!(changes(&old, &old), []);
+
+        let new = Config {
+            volume: Some(0.8),
+            redundancy: Some(true),
+            device_name: Some(vec!["USB Mic".to_string(), "Line In".to_string()]),
+            ..old.clone()
+        };
+        assert_eq!(
+            changes(&old, &new),
+            [
+                Message::SetVolume(0.8),
+                Message::SetRedundancy(true),
+                Message::SwitchDevice("USB Mic".to_string()),
+            ]
+        );
+
+        // Removing a setting leaves it as it is.
+        assert_eq!(changes(&old, &Config::default()), []);
+    }
+}
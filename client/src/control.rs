@@ -0,0 +1,899 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot, watch};
+use tracing::{error, info, warn};
+
+use crate::clock::system_micros;
+use crate::rtt::RttSummary;
+use crate::sink::Sink;
+use crate::stats::StreamStats;
+use crate::transport::TransportStats;
+
+// Every typed message starts with [MAGIC, VERSION, type, seq: u32 LE,
+// payload length: u16 LE]. The magic keeps them apart from the older bare
+// packets: the 8-byte f64 volume, [0x01, on/off] redundancy and clock
+// requests.
+pub const MAGIC: [u8; 4] = *b"ACTL";
+/// Bumped only for layouts older clients cannot read. New message types
+/// and new fields appended to a payload keep the version, since decoders
+/// skip types they do not know and ignore trailing payload bytes.
+pub const VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 12;
+// How long a controller waits for an ack before resending, and how often.
+const ACK_TIMEOUT: Duration = Duration::from_millis(250);
+const MAX_ATTEMPTS: usize = 5;
+// Controllers remembered for duplicate detection before starting over.
+const MAX_PEERS: usize = 64;
+
+const SET_VOLUME: u8 = 0x01;
+const MUTE: u8 = 0x02;
+const PAUSE: u8 = 0x03;
+const SET_REDUNDANCY: u8 = 0x04;
+const STATS: u8 = 0x05;
+const SWITCH_DEVICE: u8 = 0x06;
+// Replies from the client; never acknowledged themselves.
+const ACK: u8 = 0x80;
+const STATS_REPORT: u8 = 0x81;
+// Bytes in a stats report before the device name.
+const REPORT_FIXED_LEN: usize = 30;
+// Round trip fields appended after the device name, then the end-to-end
+// latency.
+const REPORT_RTT_LEN: usize = 24;
+const REPORT_END_TO_END_LEN: usize = 4;
+
+/// A message on the control channel: a command, or a client's reply.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    /// Client volume from 0.0 to 1.0.
+    SetVolume(f32),
+    /// Silence the stream without losing the volume setting.
+    Mute(bool),
+    /// Stop sending audio, or start again.
+    Pause(bool),
+    /// Piggy-back the previous frame in each packet.
+    SetRedundancy(bool),
+    /// Ask for the client's current statistics.
+    Stats,
+    /// Capture from the device with this name instead.
+    SwitchDevice(String),
+    /// The client's answer to the command with the same sequence number.
+    Ack(Status),
+    /// The client's answer to Stats.
+    StatsReport(Report),
+}
+
+/// A snapshot of a running client, sent in reply to Stats.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Report {
+    /// Average since streaming started.
+    pub bitrate_kbps: f32,
+    /// Audio captured but not yet sent, such as a partial Opus frame.
+    pub buffered_ms: f32,
+    pub volume: f32,
+    pub muted: bool,
+    pub paused: bool,
+    pub packets_sent: u64,
+    pub drops: u64,
+    /// The capture device, or empty when reading stdin.
+    pub device: String,
+    /// Round trips to the receiver, once --ping has had a reply.
+    pub rtt: Option<RttSummary>,
+}
+
+impl Report {
+    // [bitrate f32, buffered f32, volume f32, flags u8, packets u64,
+    //  drops u64, name length u8, name], all little-endian, then with a
+    //  round trip [min u32, avg u32, max u32, jitter u32 in µs, lost u64,
+    //  end to end u32 in µs, or 0 when unknown].
+    fn encode(&self) -> Vec<u8> {
+        let device = truncate_utf8(&self.device, u8::MAX as usize);
+        let mut out = Vec::with_capacity(REPORT_FIXED_LEN + device.len() + REPORT_RTT_LEN + REPORT_END_TO_END_LEN);
+        out.extend_from_slice(&self.bitrate_kbps.to_le_bytes());
+        out.extend_from_slice(&self.buffered_ms.to_le_bytes());
+        out.extend_from_slice(&self.volume.to_le_bytes());
+        out.push(self.muted as u8 | (self.paused as u8) << 1);
+        out.extend_from_slice(&self.packets_sent.to_le_bytes());
+        out.extend_from_slice(&self.drops.to_le_bytes());
+        out.push(device.len() as u8);
+        out.extend_from_slice(device.as_bytes());
+        if let Some(rtt) = &self.rtt {
+            for value in [rtt.min, rtt.avg, rtt.max, rtt.jitter] {
+                out.extend_from_slice(&(value.as_micros().min(u32::MAX as u128) as u32).to_le_bytes());
+            }
+            out.extend_from_slice(&rtt.lost.to_le_bytes());
+            let end_to_end = rtt
+                .end_to_end
+                .map_or(0, |latency| latency.as_micros().clamp(1, u32::MAX as u128));
+            out.extend_from_slice(&(end_to_end as u32).to_le_bytes());
+        }
+        out
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        let fixed = payload.get(..REPORT_FIXED_LEN)?;
+        let f32_at = |i: usize| f32::from_le_bytes(fixed[i..i + 4].try_into().unwrap());
+        let u64_at = |i: usize| u64::from_le_bytes(fixed[i..i + 8].try_into().unwrap());
+        let name_len = fixed[29] as usize;
+        let device = payload.get(REPORT_FIXED_LEN..REPORT_FIXED_LEN + name_len)?;
+        let rtt = payload.get(REPORT_FIXED_LEN + name_len..).and_then(|rtt| {
+            let micros = |i: usize| -> Option<Duration> {
+                let bytes = rtt.get(i..i + 4)?.try_into().unwrap();
+                Some(Duration::from_micros(u32::from_le_bytes(bytes) as u64))
+            };
+            Some(RttSummary {
+                min: micros(0)?,
+                avg: micros(4)?,
+                max: micros(8)?,
+                jitter: micros(12)?,
+                lost: u64::from_le_bytes(rtt.get(16..REPORT_RTT_LEN)?.try_into().unwrap()),
+                end_to_end: micros(REPORT_RTT_LEN).filter(|latency| !latency.is_zero()),
+            })
+        });
+        Some(Report {
+            bitrate_kbps: f32_at(0),
+            buffered_ms: f32_at(4),
+            volume: f32_at(8),
+            muted: fixed[12] & 1 != 0,
+            paused: fixed[12] & 2 != 0,
+            packets_sent: u64_at(13),
+            drops: u64_at(21),
+            device: std::str::from_utf8(device).ok()?.to_string(),
+            rtt,
+        })
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let device = if self.device.is_empty() { "stdin" } else { &self.device };
+        writeln!(f, "Device:          {}", device)?;
+        writeln!(
+            f,
+            "Volume:          {:.2}{}",
+            self.volume,
+            if self.muted { " (muted)" } else { "" }
+        )?;
+        writeln!(
+            f,
+            "State:           {}",
+            if self.paused { "paused" } else { "streaming" }
+        )?;
+        writeln!(f, "Average bitrate: {:.1} kbps", self.bitrate_kbps)?;
+        writeln!(f, "Buffered:        {:.1} ms", self.buffered_ms)?;
+        writeln!(f, "Packets sent:    {}", self.packets_sent)?;
+        write!(f, "Dropped packets: {}", self.drops)?;
+        if let Some(rtt) = &self.rtt {
+            write!(f, "\nRound trip:      {}", rtt)?;
+            if let Some(latency) = rtt.end_to_end {
+                write!(f, "\nEnd to end:      {:.1} ms", latency.as_secs_f64() * 1000.0)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// The longest prefix of `text` that fits in `max` bytes.
+fn truncate_utf8(text: &str, max: usize) -> &str {
+    let mut end = text.len().min(max);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/// What became of an acknowledged command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Applied,
+    /// The command was understood but its value was invalid.
+    Rejected,
+    /// The client does not implement the command.
+    Unsupported,
+}
+
+impl Status {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Status::Applied),
+            1 => Some(Status::Rejected),
+            2 => Some(Status::Unsupported),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Applied => write!(f, "applied"),
+            Status::Rejected => write!(f, "rejected"),
+            Status::Unsupported => write!(f, "unsupported"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Not a typed message; possibly one of the older bare packets.
+    NotControl,
+    UnsupportedVersion(u8),
+    UnknownType {
+        seq: u32,
+        kind: u8,
+    },
+    /// The payload is too short or invalid for its type.
+    Malformed {
+        seq: u32,
+        kind: u8,
+    },
+}
+
+impl DecodeError {
+    /// The sequence number to acknowledge the message under, when its
+    /// header could be read.
+    pub fn seq(&self) -> Option<u32> {
+        match self {
+            DecodeError::UnknownType { seq, .. } | DecodeError::Malformed { seq, .. } => Some(*seq),
+            DecodeError::NotControl | DecodeError::UnsupportedVersion(_) => None,
+        }
+    }
+
+    /// How to acknowledge the message.
+    pub fn status(&self) -> Status {
+        match self {
+            DecodeError::Malformed { .. } => Status::Rejected,
+            _ => Status::Unsupported,
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::NotControl => write!(f, "not a control message"),
+            DecodeError::UnsupportedVersion(version) => write!(f, "unsupported control protocol version {}", version),
+            DecodeError::UnknownType { kind, .. } => write!(f, "unknown control message type 0x{:02x}", kind),
+            DecodeError::Malformed { kind, .. } => write!(f, "malformed control message of type 0x{:02x}", kind),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl Message {
+    pub fn encode(&self, seq: u32) -> Vec<u8> {
+        let (kind, payload) = match self {
+            Message::SetVolume(volume) => (SET_VOLUME, volume.to_le_bytes().to_vec()),
+            Message::Mute(muted) => (MUTE, vec![*muted as u8]),
+            Message::Pause(paused) => (PAUSE, vec![*paused as u8]),
+            Message::SetRedundancy(enabled) => (SET_REDUNDANCY, vec![*enabled as u8]),
+            Message::Stats => (STATS, Vec::new()),
+            Message::SwitchDevice(name) => (SWITCH_DEVICE, name.as_bytes().to_vec()),
+            Message::Ack(status) => (ACK, vec![*status as u8]),
+            Message::StatsReport(report) => (STATS_REPORT, report.encode()),
+        };
+        let mut out = Vec::with_capacity(HEADER_LEN + payload.len());
+        out.extend_from_slice(&MAGIC);
+        out.push(VERSION);
+        out.push(kind);
+        out.extend_from_slice(&seq.to_le_bytes());
+        out.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+        out.extend_from_slice(&payload);
+        out
+    }
+
+    /// Returns the sequence number with the message.
+    pub fn decode(bytes: &[u8]) -> Result<(u32, Self), DecodeError> {
+        if bytes.len() < HEADER_LEN || bytes[..4] != MAGIC {
+            return Err(DecodeError::NotControl);
+        }
+        let (version, kind) = (bytes[4], bytes[5]);
+        if version != VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        let seq = u32::from_le_bytes([bytes[6], bytes[7], bytes[8], bytes[9]]);
+        let malformed = DecodeError::Malformed { seq, kind };
+        let len = u16::from_le_bytes([bytes[10], bytes[11]]) as usize;
+        let payload = bytes.get(HEADER_LEN..HEADER_LEN + len).ok_or(malformed.clone())?;
+        let flag = || payload.first().map(|&b| b != 0).ok_or(malformed.clone());
+        let message = match kind {
+            SET_VOLUME => {
+                let volume = payload
+                    .get(..4)
+                    .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                    .filter(|volume| volume.is_finite())
+                    .ok_or(malformed.clone())?;
+                Message::SetVolume(volume)
+            }
+            MUTE => Message::Mute(flag()?),
+            PAUSE => Message::Pause(flag()?),
+            SET_REDUNDANCY => Message::SetRedundancy(flag()?),
+            STATS => Message::Stats,
+            SWITCH_DEVICE => match std::str::from_utf8(payload) {
+                Ok(name) if !name.is_empty() => Message::SwitchDevice(name.to_string()),
+                _ => return Err(malformed),
+            },
+            ACK => Message::Ack(
+                payload
+                    .first()
+                    .and_then(|&b| Status::from_byte(b))
+                    .ok_or(malformed.clone())?,
+            ),
+            STATS_REPORT => Message::StatsReport(Report::decode(payload).ok_or(malformed)?),
+            _ => return Err(DecodeError::UnknownType { seq, kind }),
+        };
+        Ok((seq, message))
+    }
+
+    /// Whether this is a client's reply rather than a command.
+    pub fn is_reply(&self) -> bool {
+        matches!(self, Message::Ack(_) | Message::StatsReport(_))
+    }
+}
+
+/// Sends commands to a client and waits for each to be acknowledged,
+/// resending it when the ack does not arrive in time.
+pub struct Controller {
+    socket: UdpSocket,
+    seq: u32,
+}
+
+impl Controller {
+    pub async fn connect(addr: &str) -> io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(addr).await?;
+        // A fresh starting point, so a restarted controller that happens to
+        // reuse its port is not taken for a resend.
+        Ok(Self {
+            socket,
+            seq: system_micros() as u32,
+        })
+    }
+
+    /// Returns the client's reply to `message`.
+    pub async fn send(&mut self, message: &Message) -> io::Result<Message> {
+        self.seq = self.seq.wrapping_add(1);
+        let datagram = message.encode(self.seq);
+        let mut buf = [0u8; 2048];
+        for _ in 0..MAX_ATTEMPTS {
+            self.socket.send(&datagram).await?;
+            let deadline = tokio::time::Instant::now() + ACK_TIMEOUT;
+            // Skip late replies to earlier attempts or commands.
+            while let Ok(received) = tokio::time::timeout_at(deadline, self.socket.recv(&mut buf)).await {
+                match Message::decode(&buf[..received?]) {
+                    Ok((seq, reply)) if seq == self.seq && reply.is_reply() => return Ok(reply),
+                    _ => continue,
+                }
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "the client did not acknowledge the command",
+        ))
+    }
+}
+
+/// The reply a client last sent each controller, so a command resent after
+/// its ack was lost is answered again instead of being applied twice.
+#[derive(Default)]
+pub struct ReplyCache {
+    last: HashMap<SocketAddr, (u32, Vec<u8>)>,
+}
+
+impl ReplyCache {
+    pub fn get(&self, peer: SocketAddr, seq: u32) -> Option<&[u8]> {
+        match self.last.get(&peer) {
+            Some((last_seq, reply)) if *last_seq == seq => Some(reply),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&mut self, peer: SocketAddr, seq: u32, reply: Vec<u8>) {
+        if self.last.len() >= MAX_PEERS && !self.last.contains_key(&peer) {
+            self.last.clear();
+        }
+        self.last.insert(peer, (seq, reply));
+    }
+}
+
+/// What commands set that the audio callback reads: the volume, mute and
+/// pause. Each is an atomic, so the callback never takes a lock that a
+/// lower-priority thread could be holding. Muting keeps the level, so
+/// unmuting restores it.
+pub struct ControlState {
+    // The level's bits, as atomics hold no floats.
+    level: AtomicU32,
+    muted: AtomicBool,
+    paused: AtomicBool,
+}
+
+impl ControlState {
+    pub fn new(level: f32) -> Self {
+        Self {
+            level: AtomicU32::new(level.to_bits()),
+            muted: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+        }
+    }
+
+    pub fn level(&self) -> f32 {
+        f32::from_bits(self.level.load(Ordering::Relaxed))
+    }
+
+    pub fn set_level(&self, level: f32) {
+        self.level.store(level.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted.load(Ordering::Relaxed)
+    }
+
+    pub fn set_muted(&self, muted: bool) {
+        self.muted.store(muted, Ordering::Relaxed);
+    }
+
+    /// The factor to scale samples by: the level, or zero while muted.
+    pub fn effective(&self) -> f32 {
+        if self.is_muted() {
+            0.0
+        } else {
+            self.level()
+        }
+    }
+
+    /// Set through [`Controls::set_paused`], which also tells the
+    /// streaming loop.
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+}
+
+/// A request to capture from another input device, answered once the
+/// stream has been rebuilt on it or the switch has failed.
+pub struct DeviceSwitch {
+    pub name: String,
+    done: oneshot::Sender<Result<(), String>>,
+}
+
+impl DeviceSwitch {
+    pub fn finish(self, result: Result<(), String>) {
+        let _ = self.done.send(result);
+    }
+}
+
+/// The running client's state that commands change, shared by every way
+/// of controlling it. Pausing is also signalled to the streaming loop,
+/// which stops and restarts the capture.
+pub struct Controls {
+    pub state: ControlState,
+    sink: Sink,
+    paused: watch::Sender<bool>,
+    device_switches: Option<mpsc::Sender<DeviceSwitch>>,
+    stats: Arc<StreamStats>,
+    started: Instant,
+    device: Mutex<String>,
+}
+
+impl Controls {
+    pub fn new(state: ControlState, sink: Sink, stats: Arc<StreamStats>) -> Self {
+        Self {
+            state,
+            sink,
+            paused: watch::Sender::new(false),
+            device_switches: None,
+            stats,
+            started: Instant::now(),
+            device: Mutex::new(String::new()),
+        }
+    }
+
+    /// Points the stream at another server address.
+    pub async fn set_destination(&self, server_addr: &str) -> io::Result<()> {
+        self.sink.set_destination(server_addr).await?;
+        info!("Streaming to {}", server_addr);
+        Ok(())
+    }
+
+    /// What each receiver was sent, when the sink sends to several.
+    pub fn destinations(&self) -> Vec<(String, TransportStats)> {
+        self.sink.transport().map(|transport| transport.destinations()).unwrap_or_default()
+    }
+
+    /// Records the capture device named in stats reports.
+    pub fn set_device(&self, name: &str) {
+        *self.device.lock().unwrap() = name.to_string();
+    }
+
+    pub fn report(&self) -> Report {
+        let summary = self.stats.summary(self.started.elapsed());
+        Report {
+            bitrate_kbps: summary.average_bitrate_kbps() as f32,
+            buffered_ms: self.stats.buffered().as_secs_f32() * 1000.0,
+            volume: self.state.level(),
+            muted: self.state.is_muted(),
+            paused: self.is_paused(),
+            packets_sent: summary.packets_sent,
+            drops: summary.drops,
+            device: self.device.lock().unwrap().clone(),
+            rtt: self.stats.rtt().summary(),
+        }
+    }
+
+    /// Passes SwitchDevice commands to the returned receiver, which must
+    /// finish each one. Without it they are unsupported, as when streaming
+    /// from stdin.
+    pub fn accept_device_switches(&mut self) -> mpsc::Receiver<DeviceSwitch> {
+        let (sender, receiver) = mpsc::channel(1);
+        self.device_switches = Some(sender);
+        receiver
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.is_paused()
+    }
+
+    /// Yields each change of the paused state.
+    pub fn watch_paused(&self) -> watch::Receiver<bool> {
+        self.paused.subscribe()
+    }
+
+    /// Pauses or resumes sending. On pausing, a `--listen` receiver is told
+    /// so it can play out its buffer cleanly.
+    pub async fn set_paused(&self, paused: bool) {
+        let changed = self.paused.send_if_modified(|state| {
+            self.state.paused.store(paused, Ordering::Relaxed);
+            std::mem::replace(state, paused) != paused
+        });
+        if !changed {
+            return;
+        }
+        if paused {
+            if let Err(e) = self.sink.announce_pause().await {
+                error!("Error announcing pause: {}", e);
+            }
+            info!("Streaming paused");
+        } else {
+            info!("Streaming resumed");
+        }
+    }
+
+    pub async fn apply(&self, message: Message) -> Status {
+        match message {
+            Message::SetVolume(level) if (0.0..=1.0).contains(&level) => {
+                self.state.set_level(level);
+                self.stats.record_volume_change();
+                info!("Client volume updated to: {:.2}", level);
+                Status::Applied
+            }
+            Message::SetVolume(level) => {
+                warn!("Received invalid volume: {:.2}", level);
+                Status::Rejected
+            }
+            Message::Mute(muted) => {
+                self.state.set_muted(muted);
+                self.stats.record_volume_change();
+                info!("Client {}", if muted { "muted" } else { "unmuted" });
+                Status::Applied
+            }
+            Message::Pause(paused) => {
+                self.set_paused(paused).await;
+                Status::Applied
+            }
+            Message::SetRedundancy(enabled) => match self.sink.udp() {
+                Some(udp) => {
+                    udp.set_redundancy(enabled);
+                    info!("Redundant audio {}", if enabled { "enabled" } else { "disabled" });
+                    Status::Applied
+                }
+                None => Status::Unsupported,
+            },
+            Message::SwitchDevice(name) => self.switch_device(name).await,
+            // Answered with a report by `respond`; nothing to change.
+            Message::Stats => Status::Applied,
+            Message::Ack(_) | Message::StatsReport(_) => Status::Unsupported,
+        }
+    }
+
+    async fn switch_device(&self, name: String) -> Status {
+        let Some(switches) = &self.device_switches else {
+            return Status::Unsupported;
+        };
+        let (done, result) = oneshot::channel();
+        let switch = DeviceSwitch {
+            name: name.clone(),
+            done,
+        };
+        if switches.send(switch).await.is_err() {
+            return Status::Unsupported;
+        }
+        match result.await {
+            Ok(Ok(())) => Status::Applied,
+            Ok(Err(e)) => {
+                warn!("Could not switch audio input to {}: {}", name, e);
+                Status::Rejected
+            }
+            Err(_) => Status::Unsupported,
+        }
+    }
+
+    /// Applies a command and returns the reply for the controller: a
+    /// report for Stats, otherwise an Ack.
+    pub async fn respond(&self, message: Message) -> Message {
+        match message {
+            Message::Stats => Message::StatsReport(self.report()),
+            message => Message::Ack(self.apply(message).await),
+        }
+    }
+
+    /// Applies control messages the receiver sends back over the audio
+    /// transport, for transports that carry them. No reply goes back, as
+    /// the link has no room for one beside the audio. Returns once the
+    /// transport stops carrying control.
+    pub async fn follow_transport(&self) {
+        let Some(transport) = self.sink.transport() else {
+            return;
+        };
+        loop {
+            let datagram = match transport.recv_control().await {
+                Ok(datagram) => datagram,
+                Err(e) if e.kind() == io::ErrorKind::Unsupported => return,
+                Err(e) => {
+                    warn!("Stopped reading control from the audio transport: {}", e);
+                    return;
+                }
+            };
+            match Message::decode(&datagram) {
+                Ok((_, message)) if !message.is_reply() => {
+                    self.apply(message).await;
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Ignoring control message from the audio transport: {}", e),
+            }
+        }
+    }
+
+    /// Applies a typed control datagram and returns the reply to send back.
+    /// A resent command gets its earlier reply again without being
+    /// reapplied.
+    pub async fn handle_datagram(
+        &self,
+        datagram: &[u8],
+        peer: SocketAddr,
+        replies: &mut ReplyCache,
+    ) -> Option<Vec<u8>> {
+        let (seq, message) = match Message::decode(datagram) {
+            Ok((_, message)) if message.is_reply() => return None,
+            Ok(decoded) => decoded,
+            Err(e) => {
+                warn!("Ignoring control message from {}: {}", peer, e);
+                let seq = e.seq()?;
+                return Some(Message::Ack(e.status()).encode(seq));
+            }
+        };
+        if let Some(reply) = replies.get(peer, seq) {
+            return Some(reply.to_vec());
+        }
+        let reply = self.respond(message).await.encode(seq);
+        replies.insert(peer, seq, reply.clone());
+        Some(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let messages = [
+            Message::SetVolume(0.25),
+            Message::Mute(true),
+            Message::Mute(false),
+            Message::Pause(true),
+            Message::Pause(false),
+            Message::SetRedundancy(true),
+            Message::Stats,
+            Message::SwitchDevice("USB Audio CODEC".to_string()),
+            Message::Ack(Status::Applied),
+            Message::Ack(Status::Rejected),
+            Message::Ack(Status::Unsupported),
+            Message::StatsReport(Report {
+                bitrate_kbps: 1536.0,
+                buffered_ms: 2.5,
+                volume: 0.75,
+                muted: false,
+                paused: true,
+                packets_sent: 1 << 40,
+                drops: 3,
+                device: "Stereo Mix (Realtek Audio)".to_string(),
+                rtt: Some(RttSummary {
+                    min: Duration::from_micros(1500),
+                    avg: Duration::from_micros(2250),
+                    max: Duration::from_millis(4),
+                    jitter: Duration::from_micros(500),
+                    lost: 2,
+                    end_to_end: Some(Duration::from_millis(85)),
+                }),
+            }),
+            Message::StatsReport(Report::default()),
+        ];
+        for (seq, message) in messages.into_iter().enumerate() {
+            let bytes = message.encode(seq as u32 + 1000);
+            assert_eq!(&bytes[..4], b"ACTL");
+            assert_eq!(bytes[4], VERSION);
+            assert_eq!(Message::decode(&bytes), Ok((seq as u32 + 1000, message)));
+        }
+    }
+
+    #[test]
+    fn test_wire_layout() {
+        assert_eq!(
+            Message::SetVolume(1.0).encode(7),
+            [b'A', b'C', b'T', b'L', 1, SET_VOLUME, 7, 0, 0, 0, 4, 0, 0x00, 0x00, 0x80, 0x3f]
+        );
+        assert_eq!(
+            Message::Stats.encode(0x0102_0304),
+            [b'A', b'C', b'T', b'L', 1, STATS, 4, 3, 2, 1, 0, 0]
+        );
+        assert_eq!(Message::Ack(Status::Rejected).encode(7)[12..], [1]);
+
+        let report = Report {
+            muted: true,
+            paused: true,
+            device: "é".repeat(200),
+            ..Report::default()
+        };
+        let bytes = Message::StatsReport(report).encode(7);
+        assert_eq!(bytes[12 + 12], 3);
+        // Long names are cut at a character boundary to fit the length byte.
+        assert_eq!(bytes[12 + 29], 254);
+        assert_eq!(bytes.len(), 12 + REPORT_FIXED_LEN + 254);
+    }
+
+    #[test]
+    fn test_older_packets_are_not_control() {
+        assert_eq!(Message::decode(&0.5f64.to_le_bytes()), Err(DecodeError::NotControl));
+        assert_eq!(Message::decode(&[0x01, 1]), Err(DecodeError::NotControl));
+        assert_eq!(Message::decode(&[0x02; 9]), Err(DecodeError::NotControl));
+        assert_eq!(Message::decode(b"ACTL"), Err(DecodeError::NotControl));
+    }
+
+    #[test]
+    fn test_rejects_unknown_and_malformed() {
+        let mut bytes = Message::Stats.encode(1);
+        bytes[4] = 2;
+        assert_eq!(Message::decode(&bytes), Err(DecodeError::UnsupportedVersion(2)));
+        assert_eq!(DecodeError::UnsupportedVersion(2).seq(), None);
+
+        let mut bytes = Message::Stats.encode(1);
+        bytes[5] = 0x7f;
+        let error = Message::decode(&bytes).unwrap_err();
+        assert_eq!(error, DecodeError::UnknownType { seq: 1, kind: 0x7f });
+        assert_eq!((error.seq(), error.status()), (Some(1), Status::Unsupported));
+
+        // Payload shorter than the header says, or than the type needs.
+        let malformed = |kind| Err(DecodeError::Malformed { seq: 1, kind });
+        let bytes = Message::SetVolume(0.5).encode(1);
+        assert_eq!(Message::decode(&bytes[..14]), malformed(SET_VOLUME));
+        assert_eq!(malformed(SET_VOLUME).unwrap_err().status(), Status::Rejected);
+        let mut bytes = Message::Mute(true).encode(1);
+        bytes.truncate(HEADER_LEN);
+        bytes[10] = 0;
+        assert_eq!(Message::decode(&bytes), malformed(MUTE));
+
+        assert_eq!(
+            Message::decode(&Message::SetVolume(f32::NAN).encode(1)),
+            malformed(SET_VOLUME)
+        );
+        assert_eq!(
+            Message::decode(&Message::SwitchDevice(String::new()).encode(1)),
+            malformed(SWITCH_DEVICE)
+        );
+        let mut bytes = Message::SwitchDevice("ab".to_string()).encode(1);
+        bytes[HEADER_LEN] = 0xff;
+        assert_eq!(Message::decode(&bytes), malformed(SWITCH_DEVICE));
+        let mut bytes = Message::Ack(Status::Applied).encode(1);
+        bytes[HEADER_LEN] = 9;
+        assert_eq!(Message::decode(&bytes), malformed(ACK));
+    }
+
+    #[test]
+    fn test_ignores_appended_fields() {
+        // A newer sender may append fields an older client does not know.
+        let mut bytes = Message::Pause(true).encode(1);
+        bytes[10] = 3;
+        bytes.extend_from_slice(&[9, 9]);
+        assert_eq!(Message::decode(&bytes), Ok((1, Message::Pause(true))));
+        // Bytes past the declared payload are ignored too.
+        let mut bytes = Message::Stats.encode(1);
+        bytes.push(0);
+        assert_eq!(Message::decode(&bytes), Ok((1, Message::Stats)));
+    }
+
+    #[test]
+    fn test_controller_resends_until_acked() {
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = client.local_addr().unwrap().to_string();
+        let responder = std::thread::spawn(move || {
+            let mut buf = [0u8; 64];
+            // Lose the first attempt; ack the resend.
+            let (first, _) = client.recv_from(&mut buf).unwrap();
+            let first = buf[..first].to_vec();
+            let (len, peer) = client.recv_from(&mut buf).unwrap();
+            assert_eq!(buf[..len], first);
+            let (seq, _) = Message::decode(&buf[..len]).unwrap();
+            client
+                .send_to(&Message::Ack(Status::Applied).encode(seq), peer)
+                .unwrap();
+        });
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let reply = runtime.block_on(async {
+            let mut controller = Controller::connect(&addr).await.unwrap();
+            controller.send(&Message::Mute(true)).await.unwrap()
+        });
+        assert_eq!(reply, Message::Ack(Status::Applied));
+        responder.join().unwrap();
+    }
+
+    #[test]
+    fn test_device_switch_reports_failure() {
+        let sink = Sink::Stream(Arc::new(Mutex::new(Box::new(io::sink()) as Box<dyn io::Write + Send>)));
+        let mut controls = Controls::new(ControlState::new(1.0), sink, Arc::default());
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let message = Message::SwitchDevice("Line In".to_string());
+            assert_eq!(controls.apply(message.clone()).await, Status::Unsupported);
+
+            let mut switches = controls.accept_device_switches();
+            tokio::spawn(async move {
+                while let Some(switch) = switches.recv().await {
+                    let result = if switch.name == "Line In" {
+                        Ok(())
+                    } else {
+                        Err("no such device".to_string())
+                    };
+                    switch.finish(result);
+                }
+            });
+            assert_eq!(controls.apply(message).await, Status::Applied);
+            let missing = Message::SwitchDevice("Missing".to_string());
+            assert_eq!(controls.apply(missing).await, Status::Rejected);
+        });
+    }
+
+    #[test]
+    fn test_reply_cache() {
+        let (a, b): (SocketAddr, SocketAddr) = ("10.0.0.1:5000".parse().unwrap(), "10.0.0.2:5000".parse().unwrap());
+        let mut cache = ReplyCache::default();
+        cache.insert(a, 7, vec![1]);
+        assert_eq!(cache.get(a, 7), Some(&[1u8][..]));
+        assert_eq!(cache.get(a, 8), None);
+        assert_eq!(cache.get(b, 7), None);
+        cache.insert(a, 8, vec![2]);
+        assert_eq!(cache.get(a, 7), None);
+    }
+
+    #[test]
+    fn test_control_state() {
+        let state = ControlState::new(0.8);
+        state.set_muted(true);
+        assert_eq!(state.effective(), 0.0);
+        state.set_level(0.5);
+        assert_eq!(state.effective(), 0.0);
+        state.set_muted(false);
+        assert_eq!(state.effective(), 0.5);
+    }
+}
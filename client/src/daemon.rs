@@ -0,0 +1,167 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const PID_FILE_NAME: &str = "audio-streamer.pid";
+
+/// Where the PID file goes by default: `$XDG_RUNTIME_DIR`, or the temporary
+/// directory without one, next to the control socket.
+pub fn default_pid_path() -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+        .join(PID_FILE_NAME)
+}
+
+/// The PID file of a running client, removed again when dropped.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Records this process in `path`, failing if the process already named
+    /// there is still running.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut file = open_exclusive(path)?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Detaches from the terminal with the usual double fork: the parent exits,
+/// the child starts a new session and exits too, and the grandchild, which
+/// can never regain a controlling terminal, carries on in `/` with stdio on
+/// `/dev/null`. The PID file is opened first so that a client that is
+/// already running, or a path that cannot be written, is reported in the
+/// foreground. Only the calling thread survives a fork, so this must run
+/// before any others start.
+pub fn daemonize(pid_path: &Path) -> io::Result<PidFile> {
+    let mut file = open_exclusive(pid_path)?;
+    fork_and_exit_parent()?;
+    if unsafe { libc::setsid() } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    fork_and_exit_parent()?;
+    std::env::set_current_dir("/")?;
+    redirect_stdio()?;
+    writeln!(file, "{}", std::process::id())?;
+    Ok(PidFile {
+        path: pid_path.to_path_buf(),
+    })
+}
+
+/// Asks the client in `pid_path` to shut down with SIGTERM and waits up to
+/// `timeout` for it to exit. Returns its process ID, or `None` if it was not
+/// running, in which case a stale PID file is removed.
+pub fn stop(pid_path: &Path, timeout: Duration) -> io::Result<Option<libc::pid_t>> {
+    let Some(pid) = running_pid(pid_path)? else {
+        match fs::remove_file(pid_path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => return Ok(None),
+        }
+    };
+    if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let started = Instant::now();
+    while is_alive(pid) {
+        if started.elapsed() > timeout {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("process {} is still running after {}s", pid, timeout.as_secs()),
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    Ok(Some(pid))
+}
+
+/// The process named in `pid_path`, if there is one and it is alive.
+pub fn running_pid(pid_path: &Path) -> io::Result<Option<libc::pid_t>> {
+    let contents = match fs::read_to_string(pid_path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let pid = parse_pid(&contents)?;
+    Ok(is_alive(pid).then_some(pid))
+}
+
+// Truncates a stale PID file, but not one whose process is still running.
+fn open_exclusive(path: &Path) -> io::Result<File> {
+    if let Some(pid) = running_pid(path)? {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("the client is already running as process {}", pid),
+        ));
+    }
+    OpenOptions::new().write(true).create(true).truncate(true).open(path)
+}
+
+fn parse_pid(contents: &str) -> io::Result<libc::pid_t> {
+    contents
+        .trim()
+        .parse()
+        .ok()
+        .filter(|pid| *pid > 0)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not a process ID"))
+}
+
+// Signal 0 checks for the process without touching it. EPERM means it
+// exists but belongs to someone else.
+fn is_alive(pid: libc::pid_t) -> bool {
+    let found = unsafe { libc::kill(pid, 0) } == 0;
+    found || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+fn fork_and_exit_parent() -> io::Result<()> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        // Skip the exit handlers and buffered output the child also holds.
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+fn redirect_stdio() -> io::Result<()> {
+    let null = OpenOptions::new().read(true).write(true).open("/dev/null")?;
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pid_file() {
+        let path = std::env::temp_dir().join(format!("audio-streamer-test-{}.pid", std::process::id()));
+        assert_eq!(running_pid(&path).unwrap(), None);
+        let pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(running_pid(&path).unwrap(), Some(std::process::id() as libc::pid_t));
+        assert_eq!(
+            PidFile::create(&path).err().map(|e| e.kind()),
+            Some(io::ErrorKind::AlreadyExists)
+        );
+        drop(pid_file);
+        assert!(!path.exists());
+
+        fs::write(&path, "not a pid\n").unwrap();
+        assert_eq!(running_pid(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// A service instance resolved over mDNS.
+#[derive(Clone, Debug)]
+pub struct Resolved {
+    /// Full instance name, e.g. `Kitchen._googlecast._tcp.local.`
+    pub fullname: String,
+    pub addr: SocketAddr,
+    pub properties: HashMap<String, String>,
+}
+
+impl Resolved {
+    /// The instance part of the name, without the service type.
+    pub fn instance(&self, service_type: &str) -> &str {
+        self.fullname
+            .strip_suffix(service_type)
+            .unwrap_or(&self.fullname)
+            .trim_end_matches('.')
+    }
+
+    pub fn property(&self, key: &str) -> Option<&str> {
+        self.properties.get(key).map(String::as_str)
+    }
+}
+
+/// Browses mDNS for `service_type` and returns the first resolved instance
+/// that `accept` approves, giving up after a few seconds.
+pub fn find(service_type: &str, mut accept: impl FnMut(&Resolved) -> bool) -> io::Result<Option<Resolved>> {
+    let mdns = ServiceDaemon::new().map_err(io::Error::other)?;
+    let events = mdns.browse(service_type).map_err(io::Error::other)?;
+    let deadline = Instant::now() + DISCOVERY_TIMEOUT;
+
+    let mut found = None;
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        let info = match events.recv_timeout(remaining) {
+            Ok(ServiceEvent::ServiceResolved(info)) => info,
+            Ok(_) => continue,
+            Err(_) => break,
+        };
+        let Some(ip) = info.get_addresses().iter().next().copied() else {
+            continue;
+        };
+        let resolved = Resolved {
+            fullname: info.get_fullname().to_string(),
+            addr: SocketAddr::new(IpAddr::from(ip), info.get_port()),
+            properties: info
+                .get_properties()
+                .iter()
+                .map(|p| (p.key().to_string(), p.val_str().to_string()))
+                .collect(),
+        };
+        if accept(&resolved) {
+            found = Some(resolved);
+            break;
+        }
+    }
+    let _ = mdns.shutdown();
+    Ok(found)
+}
+
+/// Browses mDNS for `service_type` for a few seconds and returns every
+/// instance resolved, each once.
+pub fn browse(service_type: &str) -> io::Result<Vec<Resolved>> {
+    let mut found: Vec<Resolved> = Vec::new();
+    find(service_type, |resolved| {
+        if !found.iter().any(|seen| seen.fullname == resolved.fullname) {
+            found.push(resolved.clone());
+        }
+        false
+    })?;
+    Ok(found)
+}
+
+/// Case-insensitive substring match used to pick a device by name; `None`
+/// accepts any device.
+pub fn name_matches(wanted: Option<&str>, name: &str) -> bool {
+    wanted.is_none_or(|wanted| name.to_lowercase().contains(&wanted.to_lowercase()))
+}
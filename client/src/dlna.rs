@@ -0,0 +1,227 @@
+use std::io;
+use std::net::{IpAddr, TcpListener, UdpSocket};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::discovery;
+use crate::http::{self, LiveStream, StreamInfo};
+
+pub(crate) const SSDP_ADDR: &str = "239.255.255.250:1900";
+const AV_TRANSPORT: &str = "urn:schemas-upnp-org:service:AVTransport:1";
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(3);
+const STREAM_PATH: &str = "/stream.wav";
+// DLNA.ORG_FLAGS for a live stream: streaming transfer mode, no seeking.
+const CONTENT_FEATURES: &str = "DLNA.ORG_OP=00;DLNA.ORG_CI=0;DLNA.ORG_FLAGS=01700000000000000000000000000000";
+
+/// A UPnP media renderer with an AVTransport service.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Renderer {
+    pub name: String,
+    pub control_url: String,
+}
+
+/// Searches for renderers over SSDP and returns the first whose friendly
+/// name contains `name` (case-insensitive), or the first that answers.
+pub fn discover(name: Option<&str>) -> io::Result<Renderer> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: {}\r\n\r\n",
+        SSDP_ADDR, AV_TRANSPORT
+    );
+    socket.send_to(search.as_bytes(), SSDP_ADDR)?;
+
+    let deadline = Instant::now() + SEARCH_TIMEOUT;
+    let mut seen = Vec::new();
+    let mut buf = [0u8; 2048];
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        socket.set_read_timeout(Some(remaining.max(Duration::from_millis(1))))?;
+        let len = match socket.recv(&mut buf) {
+            Ok(len) => len,
+            Err(_) => break,
+        };
+        let Some(location) = ssdp_location(&String::from_utf8_lossy(&buf[..len])) else {
+            continue;
+        };
+        if seen.contains(&location) {
+            continue;
+        }
+        seen.push(location.clone());
+        match describe(&location) {
+            Ok(renderer) if discovery::name_matches(name, &renderer.name) => return Ok(renderer),
+            Ok(_) => {}
+            Err(e) => warn!("Skipping renderer at {}: {}", location, e),
+        }
+    }
+
+    let what = name.map_or("any DLNA renderer".to_string(), |n| format!("DLNA renderer '{}'", n));
+    Err(io::Error::new(io::ErrorKind::NotFound, format!("could not find {} on the network", what)))
+}
+
+pub(crate) fn ssdp_location(response: &str) -> Option<String> {
+    response.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim().eq_ignore_ascii_case("location").then(|| value.trim().to_string())
+    })
+}
+
+// Reads the device description and finds its AVTransport control URL.
+fn describe(location: &str) -> io::Result<Renderer> {
+    let (status, body) = http::request("GET", location, &[], &[])?;
+    if status != 200 {
+        return Err(io::Error::other(format!("description request failed with status {}", status)));
+    }
+    let description = String::from_utf8_lossy(&body);
+    let control = av_transport_control(&description)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no AVTransport service"))?;
+    let base = xml_text(&description, "URLBase").unwrap_or(location);
+    Ok(Renderer {
+        name: xml_text(&description, "friendlyName").unwrap_or("Unknown renderer").to_string(),
+        control_url: resolve_url(base, control),
+    })
+}
+
+fn av_transport_control(description: &str) -> Option<&str> {
+    description
+        .split("<service>")
+        .skip(1)
+        .find(|service| xml_text(service, "serviceType").is_some_and(|t| t.starts_with(AV_TRANSPORT)))
+        .and_then(|service| xml_text(service, "controlURL"))
+}
+
+/// The text content of the first `<tag>` in `doc`. Device descriptions are
+/// flat enough that this avoids pulling in an XML parser.
+pub(crate) fn xml_text<'a>(doc: &'a str, tag: &str) -> Option<&'a str> {
+    let open = format!("<{}>", tag);
+    let start = doc.find(&open)? + open.len();
+    let end = start + doc[start..].find(&format!("</{}>", tag))?;
+    Some(doc[start..end].trim())
+}
+
+pub(crate) fn resolve_url(base: &str, url: &str) -> String {
+    if url.starts_with("http://") {
+        return url.to_string();
+    }
+    // Keep only scheme and authority of the base.
+    let authority_end = base
+        .strip_prefix("http://")
+        .and_then(|rest| rest.find('/'))
+        .map_or(base.len(), |slash| slash + "http://".len());
+    format!("{}/{}", &base[..authority_end], url.trim_start_matches('/'))
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Serves the capture as a live WAV stream and points the renderer at it
+/// with `SetAVTransportURI` and `Play`.
+pub fn start(renderer: &Renderer, sample_rate: u32, channels: u16) -> io::Result<LiveStream> {
+    let local_ip = local_ip_towards(&renderer.control_url)?;
+    let listener = TcpListener::bind((local_ip, 0))?;
+    let stream = LiveStream::serve(
+        listener,
+        StreamInfo {
+            path: STREAM_PATH.to_string(),
+            content_type: "audio/wav".to_string(),
+            header: http::streaming_wav_header(sample_rate, channels),
+            extra_headers: vec![
+                ("transferMode.dlna.org".to_string(), "Streaming".to_string()),
+                ("contentFeatures.dlna.org".to_string(), CONTENT_FEATURES.to_string()),
+            ],
+        },
+    )?;
+    let url = format!("http://{}{}", stream.local_addr(), STREAM_PATH);
+
+    let metadata = format!(
+        "<DIDL-Lite xmlns=\"urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/\" \
+         xmlns:dc=\"http://purl.org/dc/elements/1.1/\" xmlns:upnp=\"urn:schemas-upnp-org:metadata-1-0/upnp/\">\
+         <item id=\"0\" parentID=\"-1\" restricted=\"1\"><dc:title>audio-client</dc:title>\
+         <upnp:class>object.item.audioItem.audioBroadcast</upnp:class>\
+         <res protocolInfo=\"http-get:*:audio/wav:{}\">{}</res></item></DIDL-Lite>",
+        CONTENT_FEATURES,
+        xml_escape(&url)
+    );
+    av_transport(
+        &renderer.control_url,
+        "SetAVTransportURI",
+        &format!(
+            "<InstanceID>0</InstanceID><CurrentURI>{}</CurrentURI><CurrentURIMetaData>{}</CurrentURIMetaData>",
+            xml_escape(&url),
+            xml_escape(&metadata)
+        ),
+    )?;
+    av_transport(&renderer.control_url, "Play", "<InstanceID>0</InstanceID><Speed>1</Speed>")?;
+    Ok(stream)
+}
+
+fn av_transport(control_url: &str, action: &str, arguments: &str) -> io::Result<()> {
+    let (status, _) = soap(control_url, AV_TRANSPORT, action, arguments)?;
+    if status != 200 {
+        return Err(io::Error::other(format!("renderer rejected {} with status {}", action, status)));
+    }
+    Ok(())
+}
+
+/// Calls `action` on a UPnP service and returns the status and body of
+/// the reply.
+pub(crate) fn soap(control_url: &str, service: &str, action: &str, arguments: &str) -> io::Result<(u16, Vec<u8>)> {
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body>\
+         <u:{action} xmlns:u=\"{service}\">{arguments}</u:{action}></s:Body></s:Envelope>",
+        action = action,
+        service = service,
+        arguments = arguments
+    );
+    let soap_action = format!("\"{}#{}\"", service, action);
+    http::request(
+        "POST",
+        control_url,
+        &[("Content-Type", "text/xml; charset=\"utf-8\""), ("SOAPAction", &soap_action)],
+        body.as_bytes(),
+    )
+}
+
+// The address this machine uses to reach the renderer, which is where the
+// renderer will fetch the stream from.
+pub(crate) fn local_ip_towards(url: &str) -> io::Result<IpAddr> {
+    let (host_port, _) = http::parse_url(url)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("unsupported URL {}", url)))?;
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(host_port)?;
+    Ok(socket.local_addr()?.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DESCRIPTION: &str = "<root><device><friendlyName>Living Room TV</friendlyName><serviceList>\
+        <service><serviceType>urn:schemas-upnp-org:service:RenderingControl:1</serviceType>\
+        <controlURL>/rc</controlURL></service>\
+        <service><serviceType>urn:schemas-upnp-org:service:AVTransport:1</serviceType>\
+        <controlURL>/upnp/control/AVTransport1</controlURL></service>\
+        </serviceList></device></root>";
+
+    #[test]
+    fn test_parse_description() {
+        assert_eq!(xml_text(DESCRIPTION, "friendlyName"), Some("Living Room TV"));
+        assert_eq!(av_transport_control(DESCRIPTION), Some("/upnp/control/AVTransport1"));
+        assert_eq!(
+            resolve_url("http://192.168.1.9:49152/description.xml", "/upnp/control/AVTransport1"),
+            "http://192.168.1.9:49152/upnp/control/AVTransport1"
+        );
+        assert_eq!(resolve_url("http://tv:80", "ctl"), "http://tv:80/ctl");
+    }
+
+    #[test]
+    fn test_ssdp_location() {
+        let response = "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=1800\r\nLOCATION: http://192.168.1.9:49152/desc.xml\r\n\r\n";
+        assert_eq!(ssdp_location(response), Some("http://192.168.1.9:49152/desc.xml".to_string()));
+        assert_eq!(xml_escape("a&b<c>"), "a&amp;b&lt;c&gt;");
+    }
+}
@@ -0,0 +1,84 @@
+// Largest playback rate correction, as a fraction of nominal (±0.1%).
+const MAX_CORRECTION: f64 = 0.001;
+// Correction applied per packet of smoothed depth error.
+const GAIN: f64 = 0.0005;
+// Smoothing factor per update. Updates happen once per packet (~100/s), so
+// this averages the fill level over roughly ten seconds and ignores jitter.
+const SMOOTHING: f64 = 0.001;
+
+/// Tracks the jitter buffer fill level against its target and turns the
+/// long-term trend into a playback rate. A sender whose sound card runs
+/// fast slowly fills the buffer, so playback speeds up slightly to match,
+/// and vice versa.
+#[derive(Clone, Debug)]
+pub struct DriftCompensator {
+    smoothed_error: Option<f64>,
+}
+
+impl Default for DriftCompensator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DriftCompensator {
+    pub fn new() -> Self {
+        Self { smoothed_error: None }
+    }
+
+    /// Feeds the current depth and returns the rate at which to consume
+    /// input frames per output frame.
+    pub fn update(&mut self, depth: usize, target: usize) -> f64 {
+        let error = depth as f64 - target as f64;
+        let smoothed = match self.smoothed_error {
+            Some(prev) => prev + (error - prev) * SMOOTHING,
+            None => error,
+        };
+        self.smoothed_error = Some(smoothed);
+        self.ratio()
+    }
+
+    pub fn ratio(&self) -> f64 {
+        let correction = self.smoothed_error.unwrap_or(0.0) * GAIN;
+        1.0 + correction.clamp(-MAX_CORRECTION, MAX_CORRECTION)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ratio_follows_fill_trend() {
+        let mut drift = DriftCompensator::new();
+        assert_eq!(drift.update(5, 5), 1.0);
+
+        for _ in 0..10_000 {
+            drift.update(6, 5);
+        }
+        assert!(drift.ratio() > 1.0);
+
+        for _ in 0..20_000 {
+            drift.update(3, 5);
+        }
+        assert!(drift.ratio() < 1.0);
+    }
+
+    #[test]
+    fn test_ratio_is_bounded() {
+        let mut drift = DriftCompensator::new();
+        drift.update(200, 5);
+        assert_eq!(drift.ratio(), 1.0 + MAX_CORRECTION);
+        let mut drift = DriftCompensator::new();
+        drift.update(0, 100);
+        assert_eq!(drift.ratio(), 1.0 - MAX_CORRECTION);
+    }
+
+    #[test]
+    fn test_ignores_short_spikes() {
+        let mut drift = DriftCompensator::new();
+        drift.update(5, 5);
+        drift.update(40, 5);
+        assert!((drift.ratio() - 1.0).abs() < 0.0001);
+    }
+}
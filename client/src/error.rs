@@ -0,0 +1,168 @@
+use std::fmt;
+use std::io;
+
+/// What made the client stop with an error. Each kind exits with its own
+/// status, and the codes stay the same between releases so scripts can
+/// rely on them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Anything without a more specific kind.
+    Other,
+    /// Flags that don't work together. Flags clap can't parse at all also
+    /// exit with this status.
+    Usage,
+    /// The config file couldn't be read or parsed.
+    Config,
+    /// There is no audio device to capture from or play to.
+    NoDevice,
+    /// The audio device failed to open or run.
+    Device,
+    /// A socket couldn't be bound, e.g. because the port is in use.
+    Bind,
+    /// A peer couldn't be reached or the connection to it failed.
+    Network,
+    /// A command to a running client failed: it isn't running, or it didn't
+    /// apply the command.
+    Control,
+}
+
+impl ErrorKind {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorKind::Other => 1,
+            ErrorKind::Usage => 2,
+            ErrorKind::Config => 3,
+            ErrorKind::NoDevice => 4,
+            ErrorKind::Device => 5,
+            ErrorKind::Bind => 6,
+            ErrorKind::Network => 7,
+            ErrorKind::Control => 8,
+        }
+    }
+
+    /// The kind as --error-format json names it.
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorKind::Other => "other",
+            ErrorKind::Usage => "usage",
+            ErrorKind::Config => "config",
+            ErrorKind::NoDevice => "no_device",
+            ErrorKind::Device => "device",
+            ErrorKind::Bind => "bind",
+            ErrorKind::Network => "network",
+            ErrorKind::Control => "control",
+        }
+    }
+
+    /// The kind of any error the client stops with. Errors raised as an
+    /// [`Error`] carry theirs; I/O errors go by what failed and audio
+    /// backend errors are device errors.
+    pub fn of(error: &(dyn std::error::Error + 'static)) -> Self {
+        if let Some(error) = error.downcast_ref::<Error>() {
+            return error.kind;
+        }
+        if let Some(error) = error.downcast_ref::<io::Error>() {
+            return match error.kind() {
+                io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable => ErrorKind::Bind,
+                io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::NotConnected
+                | io::ErrorKind::TimedOut => ErrorKind::Network,
+                _ => ErrorKind::Other,
+            };
+        }
+        if error.is::<cpal::BuildStreamError>()
+            || error.is::<cpal::PlayStreamError>()
+            || error.is::<cpal::PauseStreamError>()
+            || error.is::<cpal::DefaultStreamConfigError>()
+            || error.is::<cpal::SupportedStreamConfigsError>()
+            || error.is::<cpal::DevicesError>()
+            || error.is::<cpal::DeviceNameError>()
+        {
+            return ErrorKind::Device;
+        }
+        ErrorKind::Other
+    }
+}
+
+/// An error with the kind the client should exit with.
+#[derive(Debug)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// The final error as the single JSON object --error-format json writes to
+/// stderr.
+pub fn to_json(error: &(dyn std::error::Error + 'static)) -> String {
+    let kind = ErrorKind::of(error);
+    serde_json::json!({
+        "error": {
+            "kind": kind.name(),
+            "code": kind.exit_code(),
+            "message": error.to_string(),
+        }
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kind_of() {
+        let error: Box<dyn std::error::Error> =
+            Error::new(ErrorKind::NoDevice, "No suitable input device found").into();
+        assert_eq!(ErrorKind::of(error.as_ref()), ErrorKind::NoDevice);
+        let error: Box<dyn std::error::Error> = io::Error::from(io::ErrorKind::AddrInUse).into();
+        assert_eq!(ErrorKind::of(error.as_ref()), ErrorKind::Bind);
+        let error: Box<dyn std::error::Error> = cpal::BuildStreamError::DeviceNotAvailable.into();
+        assert_eq!(ErrorKind::of(error.as_ref()), ErrorKind::Device);
+        let error: Box<dyn std::error::Error> = "unexpected reply".into();
+        assert_eq!(ErrorKind::of(error.as_ref()), ErrorKind::Other);
+    }
+
+    #[test]
+    fn test_exit_codes_are_distinct() {
+        let kinds = [
+            ErrorKind::Other,
+            ErrorKind::Usage,
+            ErrorKind::Config,
+            ErrorKind::NoDevice,
+            ErrorKind::Device,
+            ErrorKind::Bind,
+            ErrorKind::Network,
+            ErrorKind::Control,
+        ];
+        let codes: Vec<_> = kinds.iter().map(|kind| kind.exit_code()).collect();
+        assert_eq!(codes, (1..=8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_to_json() {
+        let error = Error::new(ErrorKind::Bind, "Address already in use (os error 98)");
+        let json: serde_json::Value = serde_json::from_str(&to_json(&error)).unwrap();
+        assert_eq!(json["error"]["kind"], "bind");
+        assert_eq!(json["error"]["code"], 6);
+        assert_eq!(json["error"]["message"], "Address already in use (os error 98)");
+    }
+}
@@ -0,0 +1,150 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::net::UdpSocket;
+use tracing::{info, warn};
+
+use crate::control::Controls;
+use crate::rtt;
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// When each server, in order of preference, last answered a heartbeat.
+pub struct Failover {
+    replied: Vec<Instant>,
+    after: Duration,
+}
+
+impl Failover {
+    /// Every server counts as having answered at `now`, so the first one
+    /// gets `after` to answer before the stream leaves it.
+    pub fn new(servers: usize, after: Duration, now: Instant) -> Self {
+        Self {
+            replied: vec![now; servers],
+            after,
+        }
+    }
+
+    pub fn record_reply(&mut self, server: usize, now: Instant) {
+        self.replied[server] = now;
+    }
+
+    /// The first server that answered within the failover time, or
+    /// `current` when none did, as there is nowhere better to go.
+    pub fn choose(&self, current: usize, now: Instant) -> usize {
+        self.replied
+            .iter()
+            .position(|replied| now.saturating_duration_since(*replied) < self.after)
+            .unwrap_or(current)
+    }
+}
+
+/// Sends a heartbeat, the ping the receivers answer, to `heartbeat_port` on
+/// each of `servers` every second, and points the stream at `audio_port` on
+/// the first server that answered within `after`. The stream starts on the
+/// first server; each switch is logged.
+pub async fn run(servers: Vec<String>, audio_port: u16, heartbeat_port: u16, after: Duration, controls: Arc<Controls>) {
+    let mut sockets: Vec<Option<UdpSocket>> = servers.iter().map(|_| None).collect();
+    let mut failover = Failover::new(servers.len(), after, Instant::now());
+    let mut current = 0;
+    let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+    loop {
+        interval.tick().await;
+        let beats = servers
+            .iter()
+            .zip(sockets.iter_mut())
+            .map(|(server, socket)| heartbeat(server, heartbeat_port, socket));
+        let answered = futures::future::join_all(beats).await;
+        let now = Instant::now();
+        for (server, _) in answered.iter().enumerate().filter(|(_, answered)| **answered) {
+            failover.record_reply(server, now);
+        }
+        let next = failover.choose(current, now);
+        if next == current {
+            continue;
+        }
+        if next < current {
+            info!("Server {} is answering heartbeats again; switching back to it", servers[next]);
+        } else {
+            warn!(
+                "Server {} has not answered heartbeats for {}; failing over to {}",
+                servers[current],
+                humantime::format_duration(after),
+                servers[next]
+            );
+        }
+        match controls.set_destination(&format!("{}:{}", servers[next], audio_port)).await {
+            Ok(()) => current = next,
+            Err(e) => warn!("Could not switch to {}: {}", servers[next], e),
+        }
+    }
+}
+
+// The socket is connected on first use, and again after that fails, so a
+// server whose name does not resolve yet counts as not answering.
+async fn heartbeat(server: &str, port: u16, socket: &mut Option<UdpSocket>) -> bool {
+    if socket.is_none() {
+        *socket = connect(server, port).await.ok();
+    }
+    let Some(socket) = socket else {
+        return false;
+    };
+    let mut buf = [0u8; 64];
+    rtt::round_trip(socket, &mut buf).await.is_some()
+}
+
+async fn connect(server: &str, port: u16) -> std::io::Result<UdpSocket> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect((server, port)).await?;
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock;
+
+    #[test]
+    fn test_choose() {
+        let start = Instant::now();
+        let after = Duration::from_secs(5);
+        let mut failover = Failover::new(3, after, start);
+        assert_eq!(failover.choose(0, start + Duration::from_secs(4)), 0);
+
+        // The primary goes quiet while the backups keep answering.
+        for second in 1..=6 {
+            failover.record_reply(1, start + Duration::from_secs(second));
+            failover.record_reply(2, start + Duration::from_secs(second));
+        }
+        assert_eq!(failover.choose(0, start + Duration::from_secs(6)), 1);
+
+        // Back to the primary once it answers again.
+        failover.record_reply(0, start + Duration::from_secs(7));
+        assert_eq!(failover.choose(1, start + Duration::from_secs(7)), 0);
+
+        // With every server quiet the stream stays put.
+        assert_eq!(failover.choose(2, start + Duration::from_secs(60)), 2);
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = receiver.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            while let Ok((len, peer)) = receiver.recv_from(&mut buf).await {
+                let now = clock::system_micros();
+                if let Some(response) = clock::time_response(&buf[..len], now, now) {
+                    let _ = receiver.send_to(&response, peer).await;
+                }
+            }
+        });
+        let mut socket = None;
+        assert!(heartbeat("127.0.0.1", port, &mut socket).await);
+        assert!(socket.is_some());
+
+        let mut unresolved = None;
+        assert!(!heartbeat("no-such-host.invalid", port, &mut unresolved).await);
+        assert!(unresolved.is_none());
+    }
+}
@@ -0,0 +1,171 @@
+// Fragmented MP4 (ISO BMFF) carrying a single Opus audio track, as used by
+// HLS and MSE players. Follows "Encapsulation of Opus in ISO Base Media
+// File Format" for the sample entry.
+
+const TRACK_ID: u32 = 1;
+const TIMESCALE: u32 = 48_000;
+// Tracks and movies run "until further notice" in fragmented files.
+const UNKNOWN_DURATION: u32 = 0;
+// Identity transform, required in mvhd and tkhd.
+const MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+// tfhd flag: data offsets are relative to the start of the moof box.
+const DEFAULT_BASE_IS_MOOF: u32 = 0x02_0000;
+// trun flags: data offset present, per-sample duration and size present.
+const TRUN_FLAGS: u32 = 0x000001 | 0x000100 | 0x000200;
+
+fn boxed(kind: &[u8; 4], children: &[&[u8]]) -> Vec<u8> {
+    let len = 8 + children.iter().map(|c| c.len()).sum::<usize>();
+    let mut out = Vec::with_capacity(len);
+    out.extend_from_slice(&(len as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    for child in children {
+        out.extend_from_slice(child);
+    }
+    out
+}
+
+fn full_box(kind: &[u8; 4], version: u8, flags: u32, payload: &[u8]) -> Vec<u8> {
+    let mut header = flags.to_be_bytes();
+    header[0] = version;
+    boxed(kind, &[&header, payload])
+}
+
+fn be32(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_be_bytes()).collect()
+}
+
+/// The initialization segment: `ftyp` and a `moov` describing one Opus track.
+pub fn init_segment(channels: u8, pre_skip: u16) -> Vec<u8> {
+    let ftyp = boxed(b"ftyp", &[b"iso6", &0u32.to_be_bytes(), b"iso6", b"mp41"]);
+
+    let mut mvhd = be32(&[0, 0, TIMESCALE, UNKNOWN_DURATION, 0x0001_0000]);
+    mvhd.extend_from_slice(&0x0100u16.to_be_bytes()); // volume
+    mvhd.extend_from_slice(&[0; 10]);
+    mvhd.extend_from_slice(&be32(&MATRIX));
+    mvhd.extend_from_slice(&[0; 24]);
+    mvhd.extend_from_slice(&(TRACK_ID + 1).to_be_bytes()); // next track ID
+    let mvhd = full_box(b"mvhd", 0, 0, &mvhd);
+
+    let mut tkhd = be32(&[0, 0, TRACK_ID, 0, UNKNOWN_DURATION, 0, 0]);
+    tkhd.extend_from_slice(&[0, 0, 0, 0]); // layer, alternate group
+    tkhd.extend_from_slice(&0x0100u16.to_be_bytes()); // volume
+    tkhd.extend_from_slice(&[0, 0]);
+    tkhd.extend_from_slice(&be32(&MATRIX));
+    tkhd.extend_from_slice(&be32(&[0, 0])); // width, height
+    // Flags: track enabled and used in the presentation.
+    let tkhd = full_box(b"tkhd", 0, 0x000003, &tkhd);
+
+    let mut mdhd = be32(&[0, 0, TIMESCALE, UNKNOWN_DURATION]);
+    mdhd.extend_from_slice(&0x55c4u16.to_be_bytes()); // language "und"
+    mdhd.extend_from_slice(&[0, 0]);
+    let mdhd = full_box(b"mdhd", 0, 0, &mdhd);
+
+    let mut hdlr = be32(&[0]);
+    hdlr.extend_from_slice(b"soun");
+    hdlr.extend_from_slice(&[0; 12]);
+    hdlr.extend_from_slice(b"SoundHandler\0");
+    let hdlr = full_box(b"hdlr", 0, 0, &hdlr);
+
+    let smhd = full_box(b"smhd", 0, 0, &[0; 4]);
+    // A single self-contained data reference.
+    let url = full_box(b"url ", 0, 1, &[]);
+    let dinf = boxed(b"dinf", &[&full_box(b"dref", 0, 0, &[&be32(&[1])[..], &url].concat())]);
+
+    let mut dops = vec![0, channels];
+    dops.extend_from_slice(&pre_skip.to_be_bytes());
+    dops.extend_from_slice(&TIMESCALE.to_be_bytes()); // input sample rate
+    dops.extend_from_slice(&0i16.to_be_bytes()); // output gain
+    dops.push(0); // channel mapping family
+    let dops = boxed(b"dOps", &[&dops]);
+    let mut entry = vec![0; 6];
+    entry.extend_from_slice(&1u16.to_be_bytes()); // data reference index
+    entry.extend_from_slice(&[0; 8]);
+    entry.extend_from_slice(&(channels as u16).to_be_bytes());
+    entry.extend_from_slice(&16u16.to_be_bytes()); // sample size
+    entry.extend_from_slice(&[0; 4]);
+    entry.extend_from_slice(&(TIMESCALE << 16).to_be_bytes());
+    let opus = boxed(b"Opus", &[&entry, &dops]);
+    let stsd = full_box(b"stsd", 0, 0, &[&be32(&[1])[..], &opus].concat());
+    // Sample tables are empty; samples live in the fragments.
+    let stts = full_box(b"stts", 0, 0, &be32(&[0]));
+    let stsc = full_box(b"stsc", 0, 0, &be32(&[0]));
+    let stsz = full_box(b"stsz", 0, 0, &be32(&[0, 0]));
+    let stco = full_box(b"stco", 0, 0, &be32(&[0]));
+    let stbl = boxed(b"stbl", &[&stsd, &stts, &stsc, &stsz, &stco]);
+
+    let minf = boxed(b"minf", &[&smhd, &dinf, &stbl]);
+    let mdia = boxed(b"mdia", &[&mdhd, &hdlr, &minf]);
+    let trak = boxed(b"trak", &[&tkhd, &mdia]);
+    let trex = full_box(b"trex", 0, 0, &be32(&[TRACK_ID, 1, 0, 0, 0]));
+    let mvex = boxed(b"mvex", &[&trex]);
+    let moov = boxed(b"moov", &[&mvhd, &trak, &mvex]);
+
+    [ftyp, moov].concat()
+}
+
+/// One media segment (`moof` + `mdat`) holding `packets`, each lasting
+/// `packet_frames` samples, starting at `decode_time` in 48kHz units.
+pub fn media_segment(sequence: u32, decode_time: u64, packets: &[Vec<u8>], packet_frames: u32) -> Vec<u8> {
+    let moof = |data_offset: u32| {
+        let mfhd = full_box(b"mfhd", 0, 0, &sequence.to_be_bytes());
+        let tfhd = full_box(b"tfhd", 0, DEFAULT_BASE_IS_MOOF, &TRACK_ID.to_be_bytes());
+        let tfdt = full_box(b"tfdt", 1, 0, &decode_time.to_be_bytes());
+        let mut trun = be32(&[packets.len() as u32, data_offset]);
+        for packet in packets {
+            trun.extend_from_slice(&be32(&[packet_frames, packet.len() as u32]));
+        }
+        let trun = full_box(b"trun", 0, TRUN_FLAGS, &trun);
+        let traf = boxed(b"traf", &[&tfhd, &tfdt, &trun]);
+        boxed(b"moof", &[&mfhd, &traf])
+    };
+    // The data offset points past the moof and the mdat header; the moof's
+    // size doesn't depend on the offset's value.
+    let moof_len = moof(0).len();
+    let moof = moof(moof_len as u32 + 8);
+    let payload: Vec<u8> = packets.concat();
+    let mdat = boxed(b"mdat", &[&payload]);
+    [moof, mdat].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Top-level boxes as (type, payload).
+    fn boxes(mut bytes: &[u8]) -> Vec<([u8; 4], &[u8])> {
+        let mut out = Vec::new();
+        while !bytes.is_empty() {
+            let len = u32::from_be_bytes(bytes[..4].try_into().unwrap()) as usize;
+            out.push((bytes[4..8].try_into().unwrap(), &bytes[8..len]));
+            bytes = &bytes[len..];
+        }
+        out
+    }
+
+    #[test]
+    fn test_init_segment_layout() {
+        let init = init_segment(2, 312);
+        let top = boxes(&init);
+        assert_eq!(top.len(), 2);
+        assert_eq!(&top[0].0, b"ftyp");
+        assert_eq!(&top[1].0, b"moov");
+        let moov: Vec<_> = boxes(top[1].1).into_iter().map(|(kind, _)| kind).collect();
+        assert_eq!(moov, vec![*b"mvhd", *b"trak", *b"mvex"]);
+        let dops = init.windows(4).position(|w| w == b"dOps").unwrap();
+        assert_eq!(&init[dops + 4..dops + 8], &[0, 2, 0x01, 0x38]);
+    }
+
+    #[test]
+    fn test_media_segment_data_offset() {
+        let packets = vec![vec![1, 2, 3], vec![4, 5]];
+        let segment = media_segment(7, 96_000, &packets, 960);
+        let top = boxes(&segment);
+        assert_eq!(&top[0].0, b"moof");
+        assert_eq!(&top[1].0, b"mdat");
+        assert_eq!(top[1].1, &[1, 2, 3, 4, 5]);
+
+        let trun = segment.windows(4).position(|w| w == b"trun").unwrap();
+        let offset = u32::from_be_bytes(segment[trun + 12..trun + 16].try_into().unwrap()) as usize;
+        assert_eq!(&segment[offset..offset + 3], &[1, 2, 3]);
+    }
+}
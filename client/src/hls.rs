@@ -0,0 +1,242 @@
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use opus::{Application, Bitrate, Channels};
+use tracing::warn;
+
+use crate::fmp4;
+use crate::http::{self, Response};
+use crate::ogg::OPUS_PRE_SKIP;
+
+const OPUS_BITRATE: i32 = 128_000;
+const OPUS_FRAME_FRAMES: usize = 960;
+const MAX_OPUS_PACKET: usize = 4000;
+// 150 packets of 20ms make exact 3s segments. Players start three segments
+// from the live edge, so listeners hear audio about 10s late.
+const SEGMENT_PACKETS: usize = 150;
+const SEGMENT_SECONDS: u32 = 3;
+// Segments kept for the playlist; older ones are dropped from memory.
+const PLAYLIST_SEGMENTS: usize = 6;
+const ENCODE_QUEUE: usize = 64;
+
+// Safari plays the playlist natively; other browsers go through hls.js.
+const INDEX_HTML: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>audio-client</title>
+<script src="https://cdn.jsdelivr.net/npm/hls.js@1"></script>
+</head>
+<body>
+<audio id="player" controls></audio>
+<script>
+const player = document.getElementById("player");
+if (player.canPlayType("application/vnd.apple.mpegurl")) {
+  player.src = "stream.m3u8";
+} else if (window.Hls && Hls.isSupported()) {
+  const hls = new Hls();
+  hls.loadSource("stream.m3u8");
+  hls.attachMedia(player);
+}
+</script>
+</body>
+</html>
+"#;
+
+struct Segment {
+    sequence: u32,
+    data: Arc<[u8]>,
+}
+
+// The live window shared between the encoder thread and the HTTP server.
+struct Segments {
+    init: Arc<[u8]>,
+    recent: VecDeque<Segment>,
+}
+
+/// Encodes s16le 48kHz PCM to Opus and serves it as an HLS live stream
+/// (fMP4 segments) with a small player page, so any browser can listen.
+/// Encoding runs on its own thread; writes fail with `WouldBlock` when it
+/// falls behind.
+pub struct HlsWriter {
+    queue: SyncSender<Vec<u8>>,
+    local_addr: SocketAddr,
+}
+
+impl HlsWriter {
+    pub fn serve(listener: TcpListener, channels: u16) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let mut encoder = opus::Encoder::new(48_000, opus_channels(channels), Application::Audio).map_err(io::Error::other)?;
+        encoder.set_bitrate(Bitrate::Bits(OPUS_BITRATE)).map_err(io::Error::other)?;
+
+        let segments = Arc::new(Mutex::new(Segments {
+            init: fmp4::init_segment(channels as u8, OPUS_PRE_SKIP).into(),
+            recent: VecDeque::with_capacity(PLAYLIST_SEGMENTS),
+        }));
+        let served = segments.clone();
+        http::serve(listener, move |path| route(&served.lock().unwrap(), path));
+
+        let (queue, blocks) = mpsc::sync_channel(ENCODE_QUEUE);
+        thread::spawn(move || {
+            if let Err(e) = segment(blocks, encoder, &segments, channels as usize) {
+                warn!("HLS encoder stopped: {}", e);
+            }
+        });
+        Ok(Self { queue, local_addr })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Write for HlsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.queue.try_send(buf.to_vec()) {
+            Ok(()) => Ok(buf.len()),
+            Err(TrySendError::Full(_)) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(TrySendError::Disconnected(_)) => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn segment(
+    blocks: Receiver<Vec<u8>>,
+    mut encoder: opus::Encoder,
+    segments: &Mutex<Segments>,
+    channels: usize,
+) -> io::Result<()> {
+    let frame_len = OPUS_FRAME_FRAMES * channels;
+    let mut pending: Vec<i16> = Vec::new();
+    let mut packet = vec![0u8; MAX_OPUS_PACKET];
+    let mut packets = Vec::with_capacity(SEGMENT_PACKETS);
+    let mut sequence = 0u32;
+    for block in blocks {
+        pending.extend(block.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])));
+        let mut offset = 0;
+        while pending.len() - offset >= frame_len {
+            let len = encoder
+                .encode(&pending[offset..offset + frame_len], &mut packet)
+                .map_err(io::Error::other)?;
+            packets.push(packet[..len].to_vec());
+            offset += frame_len;
+
+            if packets.len() == SEGMENT_PACKETS {
+                let decode_time = sequence as u64 * (SEGMENT_PACKETS * OPUS_FRAME_FRAMES) as u64;
+                let data = fmp4::media_segment(sequence + 1, decode_time, &packets, OPUS_FRAME_FRAMES as u32);
+                packets.clear();
+                let mut segments = segments.lock().unwrap();
+                if segments.recent.len() == PLAYLIST_SEGMENTS {
+                    segments.recent.pop_front();
+                }
+                segments.recent.push_back(Segment {
+                    sequence,
+                    data: data.into(),
+                });
+                sequence += 1;
+            }
+        }
+        pending.drain(..offset);
+    }
+    Ok(())
+}
+
+fn route(segments: &Segments, path: &str) -> Option<Response> {
+    match path {
+        "/" | "/index.html" => Some(Response {
+            content_type: "text/html; charset=utf-8",
+            body: INDEX_HTML.as_bytes().into(),
+        }),
+        "/stream.m3u8" => {
+            // Players treat an empty live playlist as an error; 404 makes
+            // them retry until the first segment is ready.
+            let first = segments.recent.front()?.sequence;
+            Some(Response {
+                content_type: "application/vnd.apple.mpegurl",
+                body: playlist(first, segments.recent.len()).into_bytes().into(),
+            })
+        }
+        "/init.mp4" => Some(Response {
+            content_type: "video/mp4",
+            body: segments.init.clone(),
+        }),
+        _ => {
+            let sequence: u32 = path.strip_prefix("/seg")?.strip_suffix(".m4s")?.parse().ok()?;
+            let segment = segments.recent.iter().find(|segment| segment.sequence == sequence)?;
+            Some(Response {
+                content_type: "video/iso.segment",
+                body: segment.data.clone(),
+            })
+        }
+    }
+}
+
+fn playlist(first_sequence: u32, count: usize) -> String {
+    let mut text = format!(
+        "#EXTM3U\n#EXT-X-VERSION:7\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:{}\n#EXT-X-MAP:URI=\"init.mp4\"\n",
+        SEGMENT_SECONDS, first_sequence
+    );
+    for sequence in first_sequence..first_sequence + count as u32 {
+        let _ = writeln!(text, "#EXTINF:{}.000,\nseg{}.m4s", SEGMENT_SECONDS, sequence);
+    }
+    text
+}
+
+fn opus_channels(channels: u16) -> Channels {
+    if channels == 1 {
+        Channels::Mono
+    } else {
+        Channels::Stereo
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(sequences: std::ops::Range<u32>) -> Segments {
+        Segments {
+            init: Arc::from(&b"init"[..]),
+            recent: sequences
+                .map(|sequence| Segment {
+                    sequence,
+                    data: Arc::from(&sequence.to_be_bytes()[..]),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_playlist() {
+        assert_eq!(
+            playlist(4, 2),
+            "#EXTM3U\n#EXT-X-VERSION:7\n#EXT-X-TARGETDURATION:3\n#EXT-X-MEDIA-SEQUENCE:4\n\
+             #EXT-X-MAP:URI=\"init.mp4\"\n#EXTINF:3.000,\nseg4.m4s\n#EXTINF:3.000,\nseg5.m4s\n"
+        );
+        assert_eq!(SEGMENT_PACKETS * OPUS_FRAME_FRAMES, SEGMENT_SECONDS as usize * 48_000);
+    }
+
+    #[test]
+    fn test_route() {
+        assert!(route(&window(0..0), "/stream.m3u8").is_none());
+
+        let segments = window(3..6);
+        let playlist = route(&segments, "/stream.m3u8").unwrap();
+        assert!(String::from_utf8_lossy(&playlist.body).contains("#EXT-X-MEDIA-SEQUENCE:3\n"));
+        assert_eq!(&*route(&segments, "/init.mp4").unwrap().body, b"init");
+        assert_eq!(&*route(&segments, "/seg4.m4s").unwrap().body, &4u32.to_be_bytes());
+        assert!(route(&segments, "/seg2.m4s").is_none());
+        assert!(route(&segments, "/segx.m4s").is_none());
+        assert_eq!(route(&segments, "/").unwrap().content_type, "text/html; charset=utf-8");
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+use crate::streamer::{AudioFrame, CHANNELS, SAMPLE_RATE};
+
+// Blocks waiting to be written to a hook command, about 0.2s at
+// FRAMES_PER_BUFFER. More than that and the command is falling behind.
+const COMMAND_BACKLOG: usize = 20;
+// The most processed audio held for the capture, about a second. A command
+// that writes more than it reads loses the oldest.
+const COMMAND_OUTPUT_LIMIT: usize = SAMPLE_RATE as usize * CHANNELS as usize * 2;
+
+/// A function the capture runs on each block before it is sent.
+pub type FrameHook = Box<dyn FnMut(&mut AudioFrame) + Send>;
+
+/// The hooks a capture runs on every block, in the order they were added.
+/// Whatever they leave in the frame is what the listeners and the sink get.
+#[derive(Default)]
+pub struct FrameHooks {
+    hooks: Mutex<Vec<FrameHook>>,
+    // The samples of the last block processed, reused for the next so
+    // the capture path doesn't allocate a frame for each.
+    samples: Mutex<Vec<i16>>,
+}
+
+impl FrameHooks {
+    pub fn push(&self, hook: FrameHook) {
+        self.hooks.lock().unwrap().push(hook);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.lock().unwrap().is_empty()
+    }
+
+    pub fn run(&self, frame: &mut AudioFrame) {
+        for hook in self.hooks.lock().unwrap().iter_mut() {
+            hook(frame);
+        }
+    }
+
+    /// Runs the hooks over a block of 16-bit little-endian PCM in the
+    /// client's format, in place. Does nothing without hooks.
+    pub fn process_pcm(&self, pcm: &mut Vec<u8>) {
+        if self.is_empty() {
+            return;
+        }
+        let mut samples = self.samples.lock().unwrap();
+        samples.clear();
+        samples.extend(pcm.chunks_exact(2).map(|pair| i16::from_le_bytes([pair[0], pair[1]])));
+        let mut frame = AudioFrame {
+            captured: std::time::SystemTime::now(),
+            sample_rate: SAMPLE_RATE,
+            channels: CHANNELS,
+            samples: std::mem::take(&mut *samples),
+        };
+        self.run(&mut frame);
+        frame.to_pcm(pcm);
+        *samples = frame.samples;
+    }
+}
+
+/// Pipes each block through a shell command for --frame-hook: the command
+/// reads interleaved 16-bit little-endian PCM at the client's rate and
+/// channel count on stdin and writes the processed audio, in the same
+/// format, to stdout. Its output is sent as it arrives; until it has
+/// written enough for a block, the block goes out silent, so a command
+/// that buffers adds its buffering to the latency. If the command exits,
+/// the audio is sent unprocessed.
+pub struct CommandHook {
+    command: String,
+    child: Child,
+    input: SyncSender<Vec<u8>>,
+    output: Arc<Mutex<VecDeque<u8>>>,
+    exited: Arc<AtomicBool>,
+    reported_exit: bool,
+    lagging: bool,
+}
+
+impl CommandHook {
+    pub fn spawn(command: &str) -> io::Result<Self> {
+        let mut child = shell(command)
+            .env("AUDIO_CLIENT_SAMPLE_RATE", SAMPLE_RATE.to_string())
+            .env("AUDIO_CLIENT_CHANNELS", CHANNELS.to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let mut stdout = child.stdout.take().expect("stdout is piped");
+        let exited = Arc::new(AtomicBool::new(false));
+        let output = Arc::new(Mutex::new(VecDeque::new()));
+
+        // Writing and reading on threads of their own keeps a command that
+        // stalls or buffers from blocking the audio callback.
+        let (input, blocks) = mpsc::sync_channel::<Vec<u8>>(COMMAND_BACKLOG);
+        let writer_exited = exited.clone();
+        std::thread::spawn(move || {
+            for block in blocks {
+                if stdin.write_all(&block).is_err() {
+                    break;
+                }
+            }
+            writer_exited.store(true, Ordering::Relaxed);
+        });
+        let reader_exited = exited.clone();
+        let reader_output = output.clone();
+        std::thread::spawn(move || {
+            let mut chunk = [0u8; 4096];
+            loop {
+                match stdout.read(&mut chunk) {
+                    Ok(0) | Err(_) => break,
+                    Ok(len) => {
+                        let mut output = reader_output.lock().unwrap();
+                        output.extend(&chunk[..len]);
+                        let excess = output.len().saturating_sub(COMMAND_OUTPUT_LIMIT);
+                        // Keep whole samples so the channels stay aligned.
+                        output.drain(..excess.div_ceil(4) * 4);
+                    }
+                }
+            }
+            reader_exited.store(true, Ordering::Relaxed);
+        });
+
+        Ok(Self {
+            command: command.to_string(),
+            child,
+            input,
+            output,
+            exited,
+            reported_exit: false,
+            lagging: false,
+        })
+    }
+
+    pub fn process(&mut self, frame: &mut AudioFrame) {
+        if self.exited.load(Ordering::Relaxed) {
+            if !self.reported_exit {
+                warn!("Frame hook `{}` exited; sending audio unprocessed", self.command);
+                self.reported_exit = true;
+            }
+            return;
+        }
+        let mut pcm = Vec::new();
+        frame.to_pcm(&mut pcm);
+        let len = pcm.len();
+        match self.input.try_send(pcm) {
+            Ok(()) => self.lagging = false,
+            Err(TrySendError::Full(_)) if !self.lagging => {
+                warn!("Frame hook `{}` is falling behind; skipping audio", self.command);
+                self.lagging = true;
+            }
+            Err(_) => {}
+        }
+        let mut output = self.output.lock().unwrap();
+        if output.len() < len {
+            frame.samples.fill(0);
+            return;
+        }
+        let processed: Vec<u8> = output.drain(..len).collect();
+        *frame = AudioFrame::from_pcm(&processed, frame.captured);
+    }
+
+    /// The hook as a [`FrameHook`], for [`FrameHooks::push`].
+    pub fn into_hook(mut self) -> FrameHook {
+        Box::new(move |frame| self.process(frame))
+    }
+}
+
+impl Drop for CommandHook {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    fn frame(samples: &[i16]) -> AudioFrame {
+        AudioFrame {
+            captured: SystemTime::UNIX_EPOCH,
+            sample_rate: SAMPLE_RATE,
+            channels: CHANNELS,
+            samples: samples.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_hooks_run_in_order() {
+        let hooks = FrameHooks::default();
+        let mut pcm = frame(&[1, 2]);
+        let mut bytes = Vec::new();
+        pcm.to_pcm(&mut bytes);
+        hooks.process_pcm(&mut bytes);
+        assert_eq!(AudioFrame::from_pcm(&bytes, SystemTime::UNIX_EPOCH), pcm);
+
+        hooks.push(Box::new(|frame| frame.samples.iter_mut().for_each(|s| *s += 1)));
+        hooks.push(Box::new(|frame| frame.samples.iter_mut().for_each(|s| *s *= 10)));
+        hooks.run(&mut pcm);
+        assert_eq!(pcm.samples, [20, 30]);
+        hooks.process_pcm(&mut bytes);
+        assert_eq!(AudioFrame::from_pcm(&bytes, SystemTime::UNIX_EPOCH).samples, [20, 30]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_command_hook_pipes_frames() {
+        let mut hook = CommandHook::spawn("cat").unwrap();
+        let input = frame(&[100, -100, 200, -200]);
+        for _ in 0..100 {
+            let mut block = input.clone();
+            hook.process(&mut block);
+            if block.samples != [0; 4] {
+                assert_eq!(block, input);
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("cat never echoed the audio back");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_command_hook_passes_audio_after_exit() {
+        let mut hook = CommandHook::spawn("true").unwrap();
+        let input = frame(&[100, -100]);
+        for _ in 0..100 {
+            let mut block = input.clone();
+            hook.process(&mut block);
+            if hook.exited.load(Ordering::Relaxed) {
+                let mut block = input.clone();
+                hook.process(&mut block);
+                assert_eq!(block, input);
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("the hook never noticed the command exit");
+    }
+}
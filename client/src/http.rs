@@ -0,0 +1,537 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tracing::warn;
+
+// Chunks queued per listener before it counts as stalled and is dropped.
+// At one chunk per capture buffer this is over half a second of audio.
+const CLIENT_QUEUE: usize = 64;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// The longest request head read before giving up on the client.
+const MAX_HEAD: u64 = 8192;
+
+/// What a live HTTP stream serves on its path.
+#[derive(Clone, Debug)]
+pub struct StreamInfo {
+    pub path: String,
+    pub content_type: String,
+    /// Sent to each listener before the live data, e.g. a WAV header.
+    pub header: Vec<u8>,
+    /// Extra response headers, e.g. the DLNA transfer mode.
+    pub extra_headers: Vec<(String, String)>,
+}
+
+/// Fans chunks out to subscribers, each with its own bounded queue.
+/// Subscribers that fall behind are dropped instead of stalling the others.
+#[derive(Clone, Default)]
+pub struct Broadcast {
+    clients: Arc<Mutex<Vec<SyncSender<Arc<[u8]>>>>>,
+}
+
+impl Broadcast {
+    /// Returns the queue of chunks sent from now on. Dropping it
+    /// unsubscribes.
+    pub fn subscribe(&self) -> Receiver<Arc<[u8]>> {
+        let (sender, receiver) = mpsc::sync_channel(CLIENT_QUEUE);
+        self.clients.lock().unwrap().push(sender);
+        receiver
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn send(&self, chunk: &[u8]) {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.is_empty() {
+            return;
+        }
+        let chunk: Arc<[u8]> = chunk.into();
+        clients.retain(|client| match client.try_send(chunk.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                warn!("Dropping a listener that fell behind");
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+}
+
+/// Serves a live audio stream to any number of HTTP listeners. Each one
+/// gets the header and then the stream from the moment it connected.
+/// Listeners that fall behind are dropped instead of stalling the others.
+#[derive(Clone)]
+pub struct LiveStream {
+    clients: Broadcast,
+    info: Arc<StreamInfo>,
+    addr: SocketAddr,
+}
+
+impl LiveStream {
+    pub fn serve(listener: TcpListener, info: StreamInfo) -> io::Result<Self> {
+        let stream = Self::new(info, listener.local_addr()?);
+        let server = stream.clone();
+        thread::spawn(move || {
+            for connection in listener.incoming().flatten() {
+                let stream = server.clone();
+                thread::spawn(move || {
+                    let mut connection = connection;
+                    let result = match read_request(&connection) {
+                        Ok(Some(request)) if request.path == stream.info.path => stream.attach(connection, &request),
+                        Ok(Some(_)) => write_status(&mut connection, "404 Not Found"),
+                        Ok(None) => write_status(&mut connection, "400 Bad Request"),
+                        Err(e) => Err(e),
+                    };
+                    report(result);
+                });
+            }
+        });
+        Ok(stream)
+    }
+
+    /// A stream without a server of its own, for servers that route
+    /// requests for its path to [`LiveStream::attach`]. `addr` is where
+    /// that server listens.
+    pub fn new(info: StreamInfo, addr: SocketAddr) -> Self {
+        Self {
+            clients: Broadcast::default(),
+            info: Arc::new(info),
+            addr,
+        }
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn listeners(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn broadcast(&self, chunk: &[u8]) {
+        self.clients.send(chunk);
+    }
+
+    /// Answers a request for the stream and feeds the connection until the
+    /// listener leaves or falls behind.
+    pub fn attach(&self, mut connection: TcpStream, request: &Request) -> io::Result<()> {
+        if request.method != "GET" && request.method != "HEAD" {
+            return write_status(&mut connection, "405 Method Not Allowed");
+        }
+
+        let mut headers = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n",
+            self.info.content_type
+        );
+        for (name, value) in &self.info.extra_headers {
+            headers.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        headers.push_str("\r\n");
+        connection.write_all(headers.as_bytes())?;
+        if request.method == "HEAD" {
+            return Ok(());
+        }
+        connection.set_nodelay(true)?;
+        connection.write_all(&self.info.header)?;
+        for chunk in self.clients.subscribe() {
+            connection.write_all(&chunk)?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for LiveStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.broadcast(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Logs a listener connection's error, except for the listener hanging
+/// up, which is how streams normally end.
+pub fn report(result: io::Result<()>) {
+    if let Err(e) = result {
+        if e.kind() != io::ErrorKind::BrokenPipe && e.kind() != io::ErrorKind::ConnectionReset {
+            warn!("HTTP listener error: {}", e);
+        }
+    }
+}
+
+/// A parsed request head. The path has its query string removed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Reads a request head. Returns `None` for a malformed request line;
+/// bodies are never read since nothing we serve takes one. A head longer
+/// than `MAX_HEAD` is `InvalidData`, and one that takes longer than
+/// `REQUEST_TIMEOUT` to arrive times out, so a client can't hold the
+/// connection's thread or grow its memory.
+pub fn read_request(connection: &TcpStream) -> io::Result<Option<Request>> {
+    connection.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(Read::take(connection, MAX_HEAD));
+    let mut head = String::new();
+    reader.read_line(&mut head)?;
+    loop {
+        let line_start = head.len();
+        if reader.read_line(&mut head)? == 0 || head[line_start..].trim_end().is_empty() {
+            break;
+        }
+    }
+    if head.len() as u64 >= MAX_HEAD {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "request head too long"));
+    }
+    // What follows the head, e.g. WebSocket frames, may be far apart.
+    connection.set_read_timeout(None)?;
+    Ok(parse_request(&head))
+}
+
+/// Parses a request head: the request line, then header lines up to the
+/// first blank one.
+pub fn parse_request(head: &str) -> Option<Request> {
+    let mut lines = head.split_inclusive('\n');
+    let (method, path) = parse_request_line(lines.next()?)?;
+    let headers = lines
+        .take_while(|line| !line.trim_end().is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+    Some(Request {
+        method: method.to_string(),
+        path: path.split('?').next().unwrap_or(path).to_string(),
+        headers,
+    })
+}
+
+/// A complete response for [`serve`].
+pub struct Response {
+    pub content_type: &'static str,
+    pub body: Arc<[u8]>,
+}
+
+/// Serves whole responses, e.g. playlists and media segments, produced by
+/// `handler` for each requested path (without query string). `None`
+/// answers 404.
+pub fn serve<F>(listener: TcpListener, handler: F)
+where
+    F: Fn(&str) -> Option<Response> + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+    thread::spawn(move || {
+        for mut connection in listener.incoming().flatten() {
+            let handler = handler.clone();
+            thread::spawn(move || {
+                let _ = match read_request(&connection) {
+                    Ok(Some(request)) => respond(&mut connection, &request, handler(&request.path)),
+                    Ok(None) => write_status(&mut connection, "400 Bad Request"),
+                    Err(e) => Err(e),
+                };
+            });
+        }
+    });
+}
+
+/// Writes a whole response to `request`, or 404 when there is none.
+pub fn respond(connection: &mut TcpStream, request: &Request, response: Option<Response>) -> io::Result<()> {
+    if request.method != "GET" && request.method != "HEAD" {
+        return write_status(connection, "405 Method Not Allowed");
+    }
+    let Some(response) = response else {
+        return write_status(connection, "404 Not Found");
+    };
+
+    // Browser players such as hls.js fetch cross-origin.
+    let headers = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-cache\r\n\
+         Access-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n",
+        response.content_type,
+        response.body.len()
+    );
+    connection.write_all(headers.as_bytes())?;
+    if request.method == "GET" {
+        connection.write_all(&response.body)?;
+    }
+    Ok(())
+}
+
+pub fn write_status(connection: &mut TcpStream, status: &str) -> io::Result<()> {
+    let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+    connection.write_all(response.as_bytes())
+}
+
+/// Splits `http://host[:port]/path` into a connectable `host:port` and the
+/// path.
+pub fn parse_url(url: &str) -> Option<(String, String)> {
+    let rest = url.strip_prefix("http://")?;
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+    if authority.is_empty() {
+        return None;
+    }
+    // A port after the last colon, unless that colon is inside an IPv6 literal.
+    let has_port = authority.rsplit_once(':').is_some_and(|(_, port)| !port.contains(']'));
+    let host_port = if has_port {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+    Some((host_port, path.to_string()))
+}
+
+/// A minimal blocking HTTP/1.1 client for talking to devices on the LAN.
+/// Returns the status code and body.
+pub fn request(method: &str, url: &str, headers: &[(&str, &str)], body: &[u8]) -> io::Result<(u16, Vec<u8>)> {
+    let (host_port, path) =
+        parse_url(url).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("unsupported URL {}", url)))?;
+    let mut connection = TcpStream::connect(&host_port)?;
+    connection.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+        method,
+        path,
+        host_port,
+        body.len()
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    connection.write_all(request.as_bytes())?;
+    connection.write_all(body)?;
+
+    let mut response = Vec::new();
+    connection.read_to_end(&mut response)?;
+    parse_response(&response).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed HTTP response"))
+}
+
+pub(crate) fn parse_response(response: &[u8]) -> Option<(u16, Vec<u8>)> {
+    let split = response.windows(4).position(|w| w == b"\r\n\r\n")?;
+    let head = std::str::from_utf8(&response[..split]).ok()?;
+    let body = &response[split + 4..];
+    let status = head.split_whitespace().nth(1)?.parse().ok()?;
+    let chunked = head.lines().any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("transfer-encoding") && value.trim().eq_ignore_ascii_case("chunked")
+        })
+    });
+    let body = if chunked { decode_chunked(body)? } else { body.to_vec() };
+    Some((status, body))
+}
+
+fn decode_chunked(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    loop {
+        let line_end = body.windows(2).position(|w| w == b"\r\n")?;
+        let size_field = std::str::from_utf8(&body[..line_end]).ok()?;
+        let size = usize::from_str_radix(size_field.split(';').next()?.trim(), 16).ok()?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Some(out);
+        }
+        out.extend_from_slice(body.get(..size)?);
+        body = body.get(size + 2..)?;
+    }
+}
+
+/// Returns the method and path from an HTTP request line.
+pub fn parse_request_line(line: &str) -> Option<(&str, &str)> {
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?;
+    let path = parts.next()?;
+    parts.next()?.starts_with("HTTP/").then_some((method, path))
+}
+
+/// Standard base64 with padding, for Basic auth and WebSocket handshakes.
+pub fn base64(input: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let triple = ((bytes[0] as u32) << 16) | ((bytes[1] as u32) << 8) | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[((triple >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// A WAV header for a stream of unknown length. Players treat the maximum
+/// sizes as "until the connection closes".
+pub fn streaming_wav_header(sample_rate: u32, channels: u16) -> Vec<u8> {
+    let block_align = channels * 2;
+    let mut header = Vec::with_capacity(44);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&u32::MAX.to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    header.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    header.extend_from_slice(&channels.to_le_bytes());
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    header.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&16u16.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&(u32::MAX - 36).to_le_bytes());
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request_line() {
+        assert_eq!(parse_request_line("GET /stream.wav HTTP/1.1\r\n"), Some(("GET", "/stream.wav")));
+        assert_eq!(parse_request_line("GET /stream.wav\r\n"), None);
+        assert_eq!(parse_request_line(""), None);
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let head = "GET /stream.wav?t=1 HTTP/1.1\r\nHost: localhost\r\nUpgrade:websocket\r\n\r\nignored: yes\r\n";
+        let request = parse_request(head).unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/stream.wav");
+        assert_eq!(request.header("upgrade"), Some("websocket"));
+        assert_eq!(request.header("ignored"), None);
+        assert_eq!(parse_request("GET /\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn test_serve_responses() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        serve(listener, |path| {
+            (path == "/hello.txt").then(|| Response {
+                content_type: "text/plain",
+                body: Arc::from(&b"hi"[..]),
+            })
+        });
+
+        let fetch = |request: &[u8]| {
+            let mut client = TcpStream::connect(addr).unwrap();
+            client.write_all(request).unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = fetch(b"GET /hello.txt?x=1 HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("Content-Length: 2"));
+        assert!(response.ends_with("\r\n\r\nhi"));
+        assert!(fetch(b"GET /missing HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn test_read_request_limits_head() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (connection, _) = listener.accept().unwrap();
+        // A request line that never ends.
+        client.write_all(&vec![b'a'; MAX_HEAD as usize + 1]).unwrap();
+        let error = read_request(&connection).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(
+            parse_url("http://192.168.1.9:49152/upnp/control/AVTransport1"),
+            Some(("192.168.1.9:49152".to_string(), "/upnp/control/AVTransport1".to_string()))
+        );
+        assert_eq!(parse_url("http://tv.local"), Some(("tv.local:80".to_string(), "/".to_string())));
+        assert_eq!(parse_url("http://[fe80::1]/x"), Some(("[fe80::1]:80".to_string(), "/x".to_string())));
+        assert_eq!(parse_url("https://tv.local/"), None);
+    }
+
+    #[test]
+    fn test_parse_chunked_response() {
+        let response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n";
+        assert_eq!(parse_response(response), Some((200, b"Wikipedia".to_vec())));
+        let response = b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 2\r\n\r\nno";
+        assert_eq!(parse_response(response), Some((500, b"no".to_vec())));
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b"source:hackme"), "c291cmNlOmhhY2ttZQ==");
+        assert_eq!(base64(b"abc"), "YWJj");
+        assert_eq!(base64(b"ab"), "YWI=");
+        assert_eq!(base64(b""), "");
+    }
+
+    #[test]
+    fn test_streaming_wav_header() {
+        let header = streaming_wav_header(48_000, 2);
+        assert_eq!(header.len(), 44);
+        assert_eq!(&header[..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(header[28..32].try_into().unwrap()), 192_000);
+        assert_eq!(&header[36..40], b"data");
+    }
+
+    #[test]
+    fn test_live_stream_serves_listener() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = LiveStream::serve(
+            listener,
+            StreamInfo {
+                path: "/live".to_string(),
+                content_type: "audio/wav".to_string(),
+                header: b"HDR".to_vec(),
+                extra_headers: vec![("transferMode.dlna.org".to_string(), "Streaming".to_string())],
+            },
+        )
+        .unwrap();
+
+        let mut client = TcpStream::connect(stream.local_addr()).unwrap();
+        client.write_all(b"GET /live HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        while stream.listeners() == 0 {
+            thread::yield_now();
+        }
+        stream.broadcast(b"audio");
+        drop(stream);
+
+        let mut response = vec![0u8; 256];
+        let mut received = Vec::new();
+        while !received.ends_with(b"audio") {
+            let len = client.read(&mut response).unwrap();
+            assert!(len > 0);
+            received.extend_from_slice(&response[..len]);
+        }
+        let text = String::from_utf8_lossy(&received);
+        assert!(text.starts_with("HTTP/1.1 200 OK"));
+        assert!(text.contains("Content-Type: audio/wav"));
+        assert!(text.contains("transferMode.dlna.org: Streaming"));
+        assert!(text.ends_with("\r\n\r\nHDRaudio"));
+    }
+}
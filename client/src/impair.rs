@@ -0,0 +1,226 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use tokio::runtime::Handle;
+
+use crate::transport::{AudioTransport, TransportStats};
+
+/// Network trouble to put on the packets a sink sends, for trying out FEC,
+/// concealment and the jitter buffer without a bad link to hand. The same
+/// seed gives the same losses, delays and reorderings on every run.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Impairment {
+    /// The share of packets lost, from 0.0 to 1.0.
+    pub loss: f64,
+    /// Each packet is held up by a random delay of at most this.
+    pub jitter: Duration,
+    /// The share of packets held back and sent after the next one.
+    pub reorder: f64,
+    pub seed: u64,
+}
+
+impl Impairment {
+    pub fn is_active(&self) -> bool {
+        self.loss > 0.0 || !self.jitter.is_zero() || self.reorder > 0.0
+    }
+}
+
+/// A seed for runs that don't ask for one. Log it, so a run that turns
+/// something up can be repeated.
+pub fn random_seed() -> u64 {
+    crate::websocket::random_u64()
+}
+
+/// Parses a share given as a percentage, such as `3%`, or a plain number
+/// of percent.
+pub fn parse_percent(s: &str) -> Result<f64, String> {
+    let percent: f64 = s
+        .trim()
+        .trim_end_matches('%')
+        .parse()
+        .map_err(|_| format!("invalid percentage '{}'", s))?;
+    if !(0.0..=100.0).contains(&percent) {
+        return Err(format!("percentage '{}' is not between 0% and 100%", s));
+    }
+    Ok(percent / 100.0)
+}
+
+/// Wraps a transport to lose, delay and reorder what it sends as
+/// [`Impairment`] says. Control messages and destination changes pass
+/// straight through. Delayed packets go out from tasks on the runtime the
+/// transport was made on, so the audio callback never waits for them.
+pub struct ImpairedTransport {
+    inner: Arc<dyn AudioTransport>,
+    impairment: Impairment,
+    runtime: Handle,
+    state: Mutex<State>,
+}
+
+struct State {
+    rng: SplitMix64,
+    // A packet held back to go out after the next one.
+    held: Option<Vec<u8>>,
+}
+
+impl ImpairedTransport {
+    /// Must be called on a Tokio runtime.
+    pub fn new(inner: Arc<dyn AudioTransport>, impairment: Impairment) -> Self {
+        Self {
+            inner,
+            impairment,
+            runtime: Handle::current(),
+            state: Mutex::new(State {
+                rng: SplitMix64(impairment.seed),
+                held: None,
+            }),
+        }
+    }
+
+    // What goes out in place of `frame`, each with its delay: nothing when
+    // it is lost or held back, and the held packet after it when one is
+    // waiting.
+    fn plan(&self, frame: &[u8]) -> Vec<(Vec<u8>, Duration)> {
+        let mut state = self.state.lock().unwrap();
+        let lost = state.rng.chance(self.impairment.loss);
+        let mut out = Vec::new();
+        if !lost {
+            if state.held.is_none() && state.rng.chance(self.impairment.reorder) {
+                state.held = Some(frame.to_vec());
+                return out;
+            }
+            let delay = state.rng.delay(self.impairment.jitter);
+            out.push((frame.to_vec(), delay));
+        }
+        if let Some(held) = state.held.take().filter(|_| !out.is_empty()) {
+            let delay = state.rng.delay(self.impairment.jitter);
+            out.push((held, delay));
+        }
+        out
+    }
+
+    fn dispatch(&self, planned: Vec<(Vec<u8>, Duration)>) -> io::Result<()> {
+        for (frame, delay) in planned {
+            if delay.is_zero() {
+                self.inner.send_frame(&frame)?;
+                continue;
+            }
+            let inner = self.inner.clone();
+            self.runtime.spawn(async move {
+                tokio::time::sleep(delay).await;
+                // A delayed packet that can't be sent is lost like any other.
+                let _ = inner.send_frame(&frame);
+            });
+        }
+        Ok(())
+    }
+}
+
+impl AudioTransport for ImpairedTransport {
+    fn send_frame(&self, frame: &[u8]) -> io::Result<()> {
+        self.dispatch(self.plan(frame))
+    }
+
+    fn recv_control(&self) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        self.inner.recv_control()
+    }
+
+    fn stats(&self) -> TransportStats {
+        self.inner.stats()
+    }
+
+    fn destinations(&self) -> Vec<(String, TransportStats)> {
+        self.inner.destinations()
+    }
+
+    fn set_destination<'a>(&'a self, server_addr: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        self.inner.set_destination(server_addr)
+    }
+}
+
+// A small, seedable generator; the simulation needs repeatable runs, not
+// strong randomness.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0, 1).
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.next_f64() < probability
+    }
+
+    fn delay(&mut self, max: Duration) -> Duration {
+        if max.is_zero() {
+            return Duration::ZERO;
+        }
+        max.mul_f64(self.next_f64())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MemoryTransport;
+
+    fn impaired(impairment: Impairment) -> (ImpairedTransport, crate::transport::MemoryPeer) {
+        let (transport, peer) = MemoryTransport::pair(1024);
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let _guard = runtime.enter();
+        (ImpairedTransport::new(Arc::new(transport), impairment), peer)
+    }
+
+    fn received(peer: &mut crate::transport::MemoryPeer) -> Vec<u8> {
+        std::iter::from_fn(|| peer.try_recv_frame())
+            .map(|frame| frame[0])
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_percent() {
+        assert_eq!(parse_percent("3%"), Ok(0.03));
+        assert_eq!(parse_percent("50"), Ok(0.5));
+        assert!(parse_percent("150%").is_err());
+        assert!(parse_percent("lots").is_err());
+    }
+
+    #[test]
+    fn test_loss_is_repeatable() {
+        let impairment = Impairment {
+            loss: 0.3,
+            seed: 7,
+            ..Impairment::default()
+        };
+        let run = || {
+            let (transport, mut peer) = impaired(impairment);
+            (0..200u8).for_each(|i| transport.send_frame(&[i]).unwrap());
+            received(&mut peer)
+        };
+        let first = run();
+        assert_eq!(first, run());
+        // About 30% are lost, and the rest arrive in order.
+        assert!((120..160).contains(&first.len()), "{} of 200 arrived", first.len());
+        assert!(first.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn test_reorder_swaps_neighbours() {
+        let (transport, mut peer) = impaired(Impairment {
+            reorder: 1.0,
+            ..Impairment::default()
+        });
+        (0..4u8).for_each(|i| transport.send_frame(&[i]).unwrap());
+        assert_eq!(received(&mut peer), [1, 0, 3, 2]);
+    }
+}
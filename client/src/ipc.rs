@@ -0,0 +1,160 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{info, warn};
+
+use crate::control::{Controls, DecodeError, Message, HEADER_LEN};
+
+#[cfg(not(windows))]
+const SOCKET_NAME: &str = "audio-streamer.sock";
+#[cfg(windows)]
+const PIPE_NAME: &str = r"\\.\pipe\audio-streamer";
+
+/// Where the local control endpoint listens by default: a Unix socket in
+/// `$XDG_RUNTIME_DIR`, or the temporary directory without one, and a
+/// named pipe on Windows.
+pub fn default_path() -> PathBuf {
+    #[cfg(windows)]
+    {
+        PathBuf::from(PIPE_NAME)
+    }
+    #[cfg(not(windows))]
+    {
+        std::env::var_os("XDG_RUNTIME_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(std::env::temp_dir)
+            .join(SOCKET_NAME)
+    }
+}
+
+/// Accepts local connections on `path` and applies the control messages
+/// they send, the same typed messages as the network control channel,
+/// writing each reply back on the connection. The socket is only
+/// accessible to the current user.
+#[cfg(unix)]
+pub async fn serve(path: PathBuf, controls: Arc<Controls>) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::net::UnixListener;
+
+    // A socket left behind by a client that did not exit cleanly refuses
+    // connections; one that accepts them belongs to a running client.
+    if path.exists() {
+        if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is in use by another client", path.display()),
+            ));
+        }
+        std::fs::remove_file(&path)?;
+    }
+    let listener = UnixListener::bind(&path)?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+    info!("Local control socket listening at {}", path.display());
+    loop {
+        let (connection, _) = listener.accept().await?;
+        let controls = controls.clone();
+        tokio::spawn(async move { log_closed(serve_connection(connection, &controls).await) });
+    }
+}
+
+/// Accepts local connections on the named pipe `path` and applies the
+/// control messages they send, the same typed messages as the network
+/// control channel, writing each reply back on the connection.
+#[cfg(windows)]
+pub async fn serve(path: PathBuf, controls: Arc<Controls>) -> io::Result<()> {
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    // Failing to be the first instance means another client owns the name.
+    let mut server = ServerOptions::new().first_pipe_instance(true).create(&path)?;
+    info!("Local control pipe listening at {}", path.display());
+    loop {
+        server.connect().await?;
+        let connection = std::mem::replace(&mut server, ServerOptions::new().create(&path)?);
+        let controls = controls.clone();
+        tokio::spawn(async move { log_closed(serve_connection(connection, &controls).await) });
+    }
+}
+
+// Connections carry messages back to back, each framed by the length in its
+// header. Being reliable, they need no duplicate detection.
+async fn serve_connection(mut connection: impl AsyncRead + AsyncWrite + Unpin, controls: &Controls) -> io::Result<()> {
+    let mut message = vec![0u8; HEADER_LEN];
+    loop {
+        message.resize(HEADER_LEN, 0);
+        match connection.read_exact(&mut message).await {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            result => result?,
+        };
+        let len = u16::from_le_bytes([message[10], message[11]]) as usize;
+        message.resize(HEADER_LEN + len, 0);
+        connection.read_exact(&mut message[HEADER_LEN..]).await?;
+
+        let reply = match Message::decode(&message) {
+            Ok((_, message)) if message.is_reply() => continue,
+            Ok((seq, message)) => controls.respond(message).await.encode(seq),
+            // Without a readable header the framing is lost.
+            Err(e @ (DecodeError::NotControl | DecodeError::UnsupportedVersion(_))) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+            Err(e) => {
+                warn!("Ignoring local control message: {}", e);
+                let seq = e.seq().unwrap_or_default();
+                Message::Ack(e.status()).encode(seq)
+            }
+        };
+        connection.write_all(&reply).await?;
+    }
+}
+
+fn log_closed(result: io::Result<()>) {
+    if let Err(e) = result {
+        warn!("Local control connection closed: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::{ControlState, Status};
+    use crate::sink::Sink;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_serve_connection() {
+        let sink = Sink::Stream(Arc::new(Mutex::new(Box::new(io::sink()) as Box<dyn io::Write + Send>)));
+        let controls = Controls::new(ControlState::new(1.0), sink, Arc::default());
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let (mut local, remote) = tokio::io::duplex(1024);
+            let server = serve_connection(remote, &controls);
+            let client = async {
+                // Two commands in one write are still read one at a time.
+                let mut commands = Message::SetVolume(0.5).encode(1);
+                commands.extend(Message::Stats.encode(2));
+                local.write_all(&commands).await.unwrap();
+
+                // The Ack takes 13 bytes; wait for the whole report after it.
+                let mut received = Vec::new();
+                let mut buf = [0u8; 1024];
+                while Message::decode(received.get(13..).unwrap_or_default()).is_err() {
+                    let len = local.read(&mut buf).await.unwrap();
+                    received.extend_from_slice(&buf[..len]);
+                }
+                drop(local);
+                received
+            };
+            let (result, received) = tokio::join!(server, client);
+            result.unwrap();
+            assert_eq!(Message::decode(&received), Ok((1, Message::Ack(Status::Applied))));
+            let Ok((2, Message::StatsReport(report))) = Message::decode(&received[13..]) else {
+                panic!("expected a stats report");
+            };
+            assert_eq!(report.volume, 0.5);
+        });
+    }
+}
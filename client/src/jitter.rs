@@ -0,0 +1,610 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant, SystemTime};
+
+// Upper bound on buffered packets, matching the Go server's channel capacity.
+const MAX_DEPTH: usize = 200;
+// A packet this far behind or ahead of the playout point means the sender
+// restarted or the sequence number wrapped.
+const RESYNC_THRESHOLD: i32 = 256;
+// Depth is sized to cover this many multiples of the measured jitter.
+const JITTER_MULTIPLIER: f64 = 4.0;
+// Ended loss bursts kept until taken; the oldest go first.
+const MAX_LOSSES: usize = 64;
+
+/// Upper bounds of the [`ArrivalHistogram`] buckets, in milliseconds.
+pub const ARRIVAL_BUCKETS_MS: [u64; 10] = [5, 10, 15, 20, 30, 40, 60, 80, 120, 250];
+
+/// What the playback side gets for the next frame slot.
+#[derive(Debug, PartialEq)]
+pub enum Playout {
+    Packet(Vec<u8>),
+    /// The packet for this slot never arrived before its deadline.
+    Missing,
+    /// Not enough audio buffered yet; play silence.
+    Buffering,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct JitterStats {
+    pub late: u64,
+    pub missing: u64,
+    pub underruns: u64,
+    pub trimmed: u64,
+    pub recovered: u64,
+}
+
+/// How far apart consecutive packets arrived, for sizing the jitter buffer
+/// from what the network actually does. Gaps left by lost packets and by
+/// the sender pausing are not counted.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ArrivalHistogram {
+    /// Deltas in each bucket of [`ARRIVAL_BUCKETS_MS`] and no lower one.
+    /// Deltas past the last bucket only count in the total.
+    pub buckets: [u64; ARRIVAL_BUCKETS_MS.len()],
+    pub count: u64,
+    pub sum: Duration,
+}
+
+impl ArrivalHistogram {
+    pub fn record(&mut self, delta: Duration) {
+        if let Some(bucket) = ARRIVAL_BUCKETS_MS
+            .iter()
+            .position(|&bound| delta <= Duration::from_millis(bound))
+        {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += delta;
+    }
+
+    /// The upper bound of the bucket that takes the share `q` of deltas
+    /// past, e.g. 0.99 for the 99th percentile. None with no deltas yet or
+    /// when the share reaches past the last bucket.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let wanted = (q * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (count, bound) in self.buckets.iter().zip(ARRIVAL_BUCKETS_MS) {
+            seen += count;
+            if seen >= wanted {
+                return Some(Duration::from_millis(bound));
+            }
+        }
+        None
+    }
+}
+
+/// A run of consecutive frames that never arrived in time to play.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LossBurst {
+    /// Sequence number of the first missing frame.
+    pub start_seq: u32,
+    pub length: u32,
+    /// The audio the missing frames would have carried.
+    pub duration: Duration,
+    /// When the first missing frame was due to play.
+    pub started: SystemTime,
+}
+
+struct Entry {
+    payload: Vec<u8>,
+    timestamp: Option<u64>,
+}
+
+/// Reorders packets by sequence number and releases them one frame slot at
+/// a time. The buffer depth follows the measured interarrival jitter but
+/// never drops below the configured target latency.
+pub struct JitterBuffer {
+    packets: BTreeMap<u32, Entry>,
+    next_seq: Option<u32>,
+    buffering: bool,
+    // The sender paused: running dry is expected, not an underrun.
+    draining: bool,
+    // The sender ended the stream: what is buffered plays out, and then
+    // the buffer starts afresh for the next sender.
+    ended: bool,
+    frame_duration: Duration,
+    min_depth: usize,
+    jitter: f64,
+    last_arrival: Option<(u32, Instant)>,
+    transit: Option<f64>,
+    stats: JitterStats,
+    arrivals: ArrivalHistogram,
+    loss: Option<LossBurst>,
+    losses: Vec<LossBurst>,
+}
+
+impl JitterBuffer {
+    pub fn new(frame_duration: Duration, target_latency: Duration) -> Self {
+        let min_depth = (target_latency.as_secs_f64() / frame_duration.as_secs_f64()).ceil() as usize;
+        Self {
+            packets: BTreeMap::new(),
+            next_seq: None,
+            buffering: true,
+            draining: false,
+            ended: false,
+            frame_duration,
+            min_depth: min_depth.clamp(1, MAX_DEPTH),
+            jitter: 0.0,
+            last_arrival: None,
+            transit: None,
+            stats: JitterStats::default(),
+            arrivals: ArrivalHistogram::default(),
+            loss: None,
+            losses: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, seq: u32, payload: Vec<u8>, arrival: Instant) {
+        self.push_stamped(seq, payload, None, arrival);
+    }
+
+    /// Buffers a packet along with its sender timestamp, so playback can be
+    /// scheduled against a synchronized clock.
+    pub fn push_stamped(&mut self, seq: u32, payload: Vec<u8>, timestamp: Option<u64>, arrival: Instant) {
+        if let Some(next) = self.next_seq {
+            let delta = seq.wrapping_sub(next) as i32;
+            if !(-RESYNC_THRESHOLD..=RESYNC_THRESHOLD).contains(&delta) {
+                self.reset();
+            } else if delta < 0 {
+                self.stats.late += 1;
+                return;
+            }
+        }
+
+        self.draining = false;
+        self.update_jitter(seq, arrival);
+        self.packets.insert(seq, Entry { payload, timestamp });
+
+        while self.packets.len() > MAX_DEPTH {
+            self.packets.pop_first();
+            self.stats.trimmed += 1;
+            self.next_seq = self.packets.keys().next().copied();
+        }
+    }
+
+    /// Offers a redundant copy of an earlier frame. It only fills a gap:
+    /// copies of frames already buffered or played are ignored.
+    pub fn push_redundant(&mut self, seq: u32, payload: Vec<u8>) {
+        let due = match self.next_seq {
+            Some(next) => (seq.wrapping_sub(next) as i32) >= 0,
+            None => true,
+        };
+        if due && !self.packets.contains_key(&seq) && self.packets.len() < MAX_DEPTH {
+            self.packets.insert(seq, Entry { payload, timestamp: None });
+            self.stats.recovered += 1;
+        }
+    }
+
+    pub fn pop(&mut self) -> Playout {
+        if self.buffering {
+            if self.packets.len() < self.target_depth() && !self.ended {
+                return Playout::Buffering;
+            }
+            self.buffering = false;
+            if self.next_seq.is_none() {
+                self.next_seq = self.packets.keys().next().copied();
+            }
+        }
+
+        if self.packets.is_empty() {
+            self.end_loss();
+            if !std::mem::take(&mut self.draining) {
+                self.stats.underruns += 1;
+            }
+            if std::mem::take(&mut self.ended) {
+                self.next_seq = None;
+                self.jitter = 0.0;
+                self.transit = None;
+            }
+            self.buffering = true;
+            return Playout::Buffering;
+        }
+
+        // Running well over target: skip a frame to bring latency back down.
+        if self.packets.len() > self.target_depth() * 2 {
+            if let Some(next) = self.next_seq {
+                self.packets.remove(&next);
+                self.next_seq = Some(next.wrapping_add(1));
+                self.stats.trimmed += 1;
+            }
+        }
+
+        let next = self.next_seq.unwrap_or_else(|| *self.packets.keys().next().unwrap());
+        self.next_seq = Some(next.wrapping_add(1));
+        match self.packets.remove(&next) {
+            Some(entry) => {
+                self.end_loss();
+                Playout::Packet(entry.payload)
+            }
+            None => {
+                self.stats.missing += 1;
+                self.record_missing(next);
+                Playout::Missing
+            }
+        }
+    }
+
+    /// Marks the sender as paused. What is buffered still plays out, and
+    /// running dry afterwards counts as neither an underrun nor jitter;
+    /// the stream buffers up again when the sender resumes.
+    pub fn drain(&mut self) {
+        self.draining = true;
+        self.last_arrival = None;
+    }
+
+    /// Marks the stream as over. What is buffered plays out, even short of
+    /// the target depth, without counting an underrun at the end; then the
+    /// sequence, jitter and transit start afresh, so the next sender is
+    /// taken as a new session.
+    pub fn end(&mut self) {
+        self.drain();
+        self.ended = true;
+    }
+
+    /// Whether the sender ended the stream and its last packets are still
+    /// playing out.
+    pub fn is_ending(&self) -> bool {
+        self.ended
+    }
+
+    /// Packets to hold before playback: enough to absorb the measured
+    /// jitter, but never less than the target latency.
+    pub fn target_depth(&self) -> usize {
+        let jitter_depth = (JITTER_MULTIPLIER * self.jitter / self.frame_duration.as_secs_f64()).ceil() as usize;
+        self.min_depth.max(jitter_depth).min(MAX_DEPTH)
+    }
+
+    /// The packet due in the next slot, if it has already arrived. Used to
+    /// recover a missing frame from redundancy carried by its successor.
+    pub fn peek_next(&self) -> Option<&[u8]> {
+        self.next_seq
+            .and_then(|next| self.packets.get(&next))
+            .map(|entry| entry.payload.as_slice())
+    }
+
+    /// Sender timestamp of the packet that plays next, if it has arrived and
+    /// was stamped.
+    pub fn head_timestamp(&self) -> Option<u64> {
+        self.packets.get(&self.head_seq()?)?.timestamp
+    }
+
+    /// Drops the packet that plays next without playing it, e.g. because
+    /// its scheduled playout time has already passed.
+    pub fn discard_head(&mut self) {
+        if let Some(seq) = self.head_seq() {
+            if self.packets.remove(&seq).is_some() {
+                self.stats.trimmed += 1;
+            }
+            self.next_seq = Some(seq.wrapping_add(1));
+        }
+    }
+
+    /// Records sender timestamp to arrival time for a packet. Only meaningful
+    /// as one-way latency when both ends share a synchronized clock.
+    pub fn record_transit(&mut self, micros: i64) {
+        let micros = micros as f64;
+        self.transit = Some(match self.transit {
+            Some(prev) => prev + (micros - prev) / 16.0,
+            None => micros,
+        });
+    }
+
+    pub fn transit(&self) -> Option<Duration> {
+        self.transit.map(|t| Duration::from_micros(t.max(0.0) as u64))
+    }
+
+    pub fn depth(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn jitter(&self) -> Duration {
+        Duration::from_secs_f64(self.jitter)
+    }
+
+    pub fn stats(&self) -> JitterStats {
+        self.stats
+    }
+
+    pub fn arrivals(&self) -> &ArrivalHistogram {
+        &self.arrivals
+    }
+
+    /// The loss bursts that have ended since the previous call.
+    pub fn take_losses(&mut self) -> Vec<LossBurst> {
+        std::mem::take(&mut self.losses)
+    }
+
+    fn record_missing(&mut self, seq: u32) {
+        match &mut self.loss {
+            Some(loss) => {
+                loss.length += 1;
+                loss.duration += self.frame_duration;
+            }
+            None => {
+                self.loss = Some(LossBurst {
+                    start_seq: seq,
+                    length: 1,
+                    duration: self.frame_duration,
+                    started: SystemTime::now(),
+                })
+            }
+        }
+    }
+
+    fn end_loss(&mut self) {
+        if let Some(loss) = self.loss.take() {
+            if self.losses.len() == MAX_LOSSES {
+                self.losses.remove(0);
+            }
+            self.losses.push(loss);
+        }
+    }
+
+    fn head_seq(&self) -> Option<u32> {
+        self.next_seq.or_else(|| self.packets.keys().next().copied())
+    }
+
+    fn reset(&mut self) {
+        self.end_loss();
+        self.packets.clear();
+        self.next_seq = None;
+        self.buffering = true;
+        self.last_arrival = None;
+    }
+
+    // RFC 3550 interarrival jitter: a running average of how far each
+    // packet's spacing deviates from the nominal frame duration.
+    fn update_jitter(&mut self, seq: u32, arrival: Instant) {
+        if let Some((prev_seq, prev_arrival)) = self.last_arrival {
+            let frames = seq.wrapping_sub(prev_seq) as i32;
+            if frames <= 0 {
+                return;
+            }
+            let delta = arrival.saturating_duration_since(prev_arrival);
+            if frames == 1 {
+                self.arrivals.record(delta);
+            }
+            let expected = frames as f64 * self.frame_duration.as_secs_f64();
+            let actual = delta.as_secs_f64();
+            self.jitter += ((actual - expected).abs() - self.jitter) / 16.0;
+        }
+        self.last_arrival = Some((seq, arrival));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: Duration = Duration::from_millis(10);
+
+    fn buffer(target_ms: u64) -> JitterBuffer {
+        JitterBuffer::new(FRAME, Duration::from_millis(target_ms))
+    }
+
+    #[test]
+    fn test_prebuffers_to_target_latency() {
+        let mut jb = buffer(30);
+        let now = Instant::now();
+        jb.push(0, vec![0], now);
+        jb.push(1, vec![1], now + FRAME);
+        assert_eq!(jb.pop(), Playout::Buffering);
+
+        jb.push(2, vec![2], now + FRAME * 2);
+        assert_eq!(jb.pop(), Playout::Packet(vec![0]));
+    }
+
+    #[test]
+    fn test_reorders_by_sequence() {
+        let mut jb = buffer(30);
+        let now = Instant::now();
+        jb.push(1, vec![1], now);
+        jb.push(0, vec![0], now);
+        jb.push(2, vec![2], now);
+
+        assert_eq!(jb.pop(), Playout::Packet(vec![0]));
+        assert_eq!(jb.pop(), Playout::Packet(vec![1]));
+        assert_eq!(jb.pop(), Playout::Packet(vec![2]));
+    }
+
+    #[test]
+    fn test_missing_and_late_packets() {
+        let mut jb = buffer(10);
+        let now = Instant::now();
+        jb.push(0, vec![0], now);
+        jb.push(2, vec![2], now);
+
+        assert_eq!(jb.pop(), Playout::Packet(vec![0]));
+        assert_eq!(jb.pop(), Playout::Missing);
+        jb.push(1, vec![1], now);
+        assert_eq!(jb.pop(), Playout::Packet(vec![2]));
+
+        let stats = jb.stats();
+        assert_eq!(stats.missing, 1);
+        assert_eq!(stats.late, 1);
+    }
+
+    #[test]
+    fn test_peek_next_after_missing() {
+        let mut jb = buffer(10);
+        let now = Instant::now();
+        jb.push(0, vec![0], now);
+        jb.push(2, vec![2], now);
+
+        assert_eq!(jb.pop(), Playout::Packet(vec![0]));
+        assert_eq!(jb.peek_next(), None);
+        assert_eq!(jb.pop(), Playout::Missing);
+        assert_eq!(jb.peek_next(), Some(&[2u8][..]));
+    }
+
+    #[test]
+    fn test_redundant_copy_fills_gap_only() {
+        let mut jb = buffer(30);
+        let now = Instant::now();
+        jb.push(0, vec![0], now);
+        jb.push(2, vec![2], now);
+        jb.push_redundant(0, vec![9]);
+        jb.push_redundant(1, vec![1]);
+
+        assert_eq!(jb.pop(), Playout::Packet(vec![0]));
+        assert_eq!(jb.pop(), Playout::Packet(vec![1]));
+        assert_eq!(jb.pop(), Playout::Packet(vec![2]));
+        assert_eq!(jb.stats().recovered, 1);
+        assert_eq!(jb.stats().missing, 0);
+    }
+
+    #[test]
+    fn test_loss_bursts() {
+        let mut jb = buffer(10);
+        let now = Instant::now();
+        for seq in [0, 3, 4, 6] {
+            jb.push(seq, vec![seq as u8], now);
+        }
+        assert_eq!(jb.pop(), Playout::Packet(vec![0]));
+        assert_eq!(jb.pop(), Playout::Missing);
+        assert_eq!(jb.pop(), Playout::Missing);
+        assert!(jb.take_losses().is_empty());
+        assert_eq!(jb.pop(), Playout::Packet(vec![3]));
+        assert_eq!(jb.pop(), Playout::Packet(vec![4]));
+        assert_eq!(jb.pop(), Playout::Missing);
+        assert_eq!(jb.pop(), Playout::Packet(vec![6]));
+
+        let losses = jb.take_losses();
+        let summary: Vec<_> = losses
+            .iter()
+            .map(|loss| (loss.start_seq, loss.length, loss.duration))
+            .collect();
+        assert_eq!(summary, [(1, 2, FRAME * 2), (5, 1, FRAME)]);
+        assert!(losses[0].started <= losses[1].started);
+        assert!(jb.take_losses().is_empty());
+    }
+
+    #[test]
+    fn test_underrun_rebuffers() {
+        let mut jb = buffer(10);
+        jb.push(0, vec![0], Instant::now());
+        assert_eq!(jb.pop(), Playout::Packet(vec![0]));
+        assert_eq!(jb.pop(), Playout::Buffering);
+        assert_eq!(jb.stats().underruns, 1);
+    }
+
+    #[test]
+    fn test_depth_grows_with_jitter() {
+        let mut jb = buffer(10);
+        let start = Instant::now();
+        // Packets arrive in bursts of two, 20ms apart.
+        for seq in 0..64u32 {
+            let arrival = start + FRAME * (seq / 2 * 2);
+            jb.push(seq, vec![], arrival);
+        }
+        assert!(jb.jitter() > Duration::from_millis(2));
+        assert!(jb.target_depth() > 1);
+    }
+
+    #[test]
+    fn test_arrival_histogram() {
+        let mut jb = buffer(10);
+        let start = Instant::now();
+        // Nine packets on time, one 50ms late, then one lost.
+        for seq in 0..10u32 {
+            jb.push(seq, vec![], start + FRAME * seq);
+        }
+        jb.push(10, vec![], start + FRAME * 9 + Duration::from_millis(50));
+        jb.push(12, vec![], start + FRAME * 12);
+
+        let arrivals = jb.arrivals();
+        assert_eq!(arrivals.count, 10);
+        assert_eq!(arrivals.buckets[1], 9);
+        assert_eq!(arrivals.buckets[6], 1);
+        assert_eq!(arrivals.sum, FRAME * 9 + Duration::from_millis(50));
+        assert_eq!(arrivals.quantile(0.5), Some(Duration::from_millis(10)));
+        assert_eq!(arrivals.quantile(0.99), Some(Duration::from_millis(60)));
+
+        let mut slow = ArrivalHistogram::default();
+        assert_eq!(slow.quantile(0.5), None);
+        slow.record(Duration::from_secs(1));
+        assert_eq!((slow.count, slow.quantile(0.5)), (1, None));
+    }
+
+    #[test]
+    fn test_head_timestamp_and_discard() {
+        let mut jb = buffer(10);
+        let now = Instant::now();
+        jb.push_stamped(0, vec![0], Some(1_000), now);
+        jb.push_stamped(1, vec![1], Some(11_000), now);
+        assert_eq!(jb.head_timestamp(), Some(1_000));
+
+        jb.discard_head();
+        assert_eq!(jb.head_timestamp(), Some(11_000));
+        assert_eq!(jb.pop(), Playout::Packet(vec![1]));
+        assert_eq!(jb.stats().trimmed, 1);
+    }
+
+    #[test]
+    fn test_resyncs_after_sender_restart() {
+        let mut jb = buffer(10);
+        let now = Instant::now();
+        jb.push(1000, vec![1], now);
+        assert_eq!(jb.pop(), Playout::Packet(vec![1]));
+
+        jb.push(0, vec![0], now);
+        assert_eq!(jb.pop(), Playout::Packet(vec![0]));
+    }
+
+    #[test]
+    fn test_resyncs_after_forward_jump() {
+        let mut jb = buffer(10);
+        let now = Instant::now();
+        jb.push(0, vec![0], now);
+        assert_eq!(jb.pop(), Playout::Packet(vec![0]));
+
+        jb.push(5000, vec![5], now + FRAME);
+        assert_eq!(jb.pop(), Playout::Packet(vec![5]));
+        assert_eq!(jb.stats().late, 0);
+    }
+
+    #[test]
+    fn test_drain_after_pause() {
+        let mut jb = buffer(20);
+        let now = Instant::now();
+        jb.push(0, vec![0], now);
+        jb.push(1, vec![1], now + FRAME);
+        jb.drain();
+        assert_eq!(jb.pop(), Playout::Packet(vec![0]));
+        assert_eq!(jb.pop(), Playout::Packet(vec![1]));
+        assert_eq!(jb.pop(), Playout::Buffering);
+        assert_eq!(jb.stats().underruns, 0);
+
+        // Resuming a minute later neither counts as jitter nor skips ahead.
+        let later = now + Duration::from_secs(60);
+        jb.push(2, vec![2], later);
+        jb.push(3, vec![3], later + FRAME);
+        assert_eq!(jb.jitter(), Duration::ZERO);
+        assert_eq!(jb.pop(), Playout::Packet(vec![2]));
+    }
+
+    #[test]
+    fn test_end_frees_the_session() {
+        let mut jb = buffer(40);
+        let now = Instant::now();
+        jb.push(500, vec![0], now);
+        jb.push(501, vec![1], now + FRAME);
+        // Short of the target depth, the tail still plays once the stream ends.
+        assert_eq!(jb.pop(), Playout::Buffering);
+        jb.end();
+        assert!(jb.is_ending());
+        assert_eq!(jb.pop(), Playout::Packet(vec![0]));
+        assert_eq!(jb.pop(), Playout::Packet(vec![1]));
+        assert_eq!(jb.pop(), Playout::Buffering);
+        assert!(!jb.is_ending());
+        assert_eq!(jb.stats().underruns, 0);
+
+        // A new sender starting over from zero is not taken as late.
+        jb.push(0, vec![2], now + Duration::from_secs(5));
+        assert_eq!(jb.stats().late, 0);
+        assert_eq!(jb.depth(), 1);
+    }
+}
\ No newline at end of file
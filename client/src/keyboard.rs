@@ -0,0 +1,184 @@
+use std::io::{self, Read, Write};
+
+/// How much `+` and `-` change the volume.
+pub const VOLUME_STEP: f32 = 0.05;
+
+/// A keypress while streaming from a device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Key {
+    VolumeUp,
+    VolumeDown,
+    Mute,
+    Pause,
+    NextDevice,
+    Quit,
+}
+
+impl Key {
+    pub fn from_byte(byte: u8) -> Option<Key> {
+        match byte {
+            b'+' | b'=' => Some(Key::VolumeUp),
+            b'-' | b'_' => Some(Key::VolumeDown),
+            b'm' | b'M' => Some(Key::Mute),
+            b'p' | b'P' | b' ' => Some(Key::Pause),
+            b'd' | b'D' => Some(Key::NextDevice),
+            b'q' | b'Q' => Some(Key::Quit),
+            _ => None,
+        }
+    }
+}
+
+pub const HELP: &str = "Keys: +/- volume, m mute, p pause, d next device, q quit";
+
+/// A keypress in `pick`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PickKey {
+    Up,
+    Down,
+    Choose,
+    Cancel,
+    /// A digit, choosing that row directly.
+    Row(usize),
+}
+
+// Reads the next key `pick` understands from `bytes`, skipping others.
+// Arrow keys arrive as the escape sequences ESC [ A and ESC [ B.
+fn read_pick_key(bytes: &mut impl Iterator<Item = u8>) -> Option<PickKey> {
+    loop {
+        let key = match bytes.next()? {
+            0x1b => match (bytes.next()?, bytes.next()?) {
+                (b'[', b'A') => PickKey::Up,
+                (b'[', b'B') => PickKey::Down,
+                _ => continue,
+            },
+            b'k' => PickKey::Up,
+            b'j' => PickKey::Down,
+            b'\n' | b'\r' | b' ' => PickKey::Choose,
+            b'q' | b'Q' => PickKey::Cancel,
+            digit @ b'0'..=b'9' => PickKey::Row((digit - b'0') as usize),
+            _ => continue,
+        };
+        return Some(key);
+    }
+}
+
+/// Lets the user choose one of `items` on the terminal with the arrow keys
+/// and Enter, starting at `selected`. Returns None if they press `q` or
+/// stdin ends. Elsewhere than Unix, type the row number and press Enter.
+pub fn pick(title: &str, items: &[String], mut selected: usize) -> io::Result<Option<usize>> {
+    let _mode = KeyMode::enable()?;
+    let mut out = io::stderr();
+    writeln!(out, "{} (arrows or j/k to move, Enter to choose, q to cancel)", title)?;
+    let mut bytes = io::stdin().lock().bytes().map_while(Result::ok);
+    loop {
+        for (i, item) in items.iter().enumerate() {
+            let marker = if i == selected { '>' } else { ' ' };
+            writeln!(out, "\x1b[2K{} [{}] {}", marker, i, item)?;
+        }
+        let Some(key) = read_pick_key(&mut bytes) else {
+            return Ok(None);
+        };
+        match key {
+            PickKey::Up => selected = selected.saturating_sub(1),
+            PickKey::Down => selected = (selected + 1).min(items.len().saturating_sub(1)),
+            PickKey::Choose => return Ok(Some(selected)),
+            PickKey::Cancel => return Ok(None),
+            PickKey::Row(row) if row < items.len() => return Ok(Some(row)),
+            PickKey::Row(_) => {}
+        }
+        // Move back up over the list to redraw it.
+        write!(out, "\x1b[{}A", items.len())?;
+    }
+}
+
+/// Asks a yes or no `question` on the terminal, taking a single key.
+pub fn confirm(question: &str) -> io::Result<bool> {
+    let _mode = KeyMode::enable()?;
+    eprint!("{} [y/N] ", question);
+    let mut byte = [0u8];
+    let yes = io::stdin().read(&mut byte)? == 1 && byte[0].eq_ignore_ascii_case(&b'y');
+    eprintln!("{}", if yes { "yes" } else { "no" });
+    Ok(yes)
+}
+
+/// Puts the terminal on stdin into a mode where each keypress is read at
+/// once and not echoed, until dropped. Output and Ctrl+C work as usual.
+/// Elsewhere than Unix keys take effect after Enter.
+pub struct KeyMode {
+    #[cfg(unix)]
+    saved: libc::termios,
+}
+
+impl KeyMode {
+    pub fn enable() -> io::Result<Self> {
+        #[cfg(unix)]
+        {
+            // SAFETY: termios is plain data, filled in by tcgetattr before use.
+            unsafe {
+                let mut saved: libc::termios = std::mem::zeroed();
+                if libc::tcgetattr(libc::STDIN_FILENO, &mut saved) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                let mut keys = saved;
+                keys.c_lflag &= !(libc::ICANON | libc::ECHO);
+                keys.c_cc[libc::VMIN] = 1;
+                keys.c_cc[libc::VTIME] = 0;
+                if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &keys) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(Self { saved })
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            Ok(Self {})
+        }
+    }
+}
+
+impl Drop for KeyMode {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        {
+            // SAFETY: restores the settings read in `enable`.
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.saved);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_bindings() {
+        assert_eq!(Key::from_byte(b'+'), Some(Key::VolumeUp));
+        assert_eq!(Key::from_byte(b'='), Some(Key::VolumeUp));
+        assert_eq!(Key::from_byte(b'-'), Some(Key::VolumeDown));
+        assert_eq!(Key::from_byte(b'M'), Some(Key::Mute));
+        assert_eq!(Key::from_byte(b'p'), Some(Key::Pause));
+        assert_eq!(Key::from_byte(b'd'), Some(Key::NextDevice));
+        assert_eq!(Key::from_byte(b'q'), Some(Key::Quit));
+        assert_eq!(Key::from_byte(b'\n'), None);
+        assert_eq!(Key::from_byte(b'x'), None);
+    }
+
+    #[test]
+    fn test_pick_keys() {
+        let mut bytes = b"\x1b[Bx\x1b[Ak\x1b[C3\n".iter().copied();
+        let keys: Vec<_> = std::iter::from_fn(|| read_pick_key(&mut bytes)).collect();
+        assert_eq!(
+            keys,
+            [
+                PickKey::Down,
+                PickKey::Up,
+                PickKey::Up,
+                PickKey::Row(3),
+                PickKey::Choose
+            ]
+        );
+        assert_eq!(read_pick_key(&mut b"\x1b[".iter().copied()), None);
+    }
+}
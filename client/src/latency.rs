@@ -0,0 +1,72 @@
+use std::time::Duration;
+
+use crate::codec::Codec;
+use crate::streamer::{FRAMES_PER_BUFFER, SAMPLE_RATE};
+
+/// The smallest capture buffer and PCM packet a budget splits into, in
+/// frames; less and the callbacks come too often for most hosts.
+pub const MIN_FRAMES: u32 = 64;
+
+/// One latency figure for the whole pipeline, shared out between the three
+/// places audio waits: the capture buffer, the packet, which can't be sent
+/// until it is full, and the receiver's jitter buffer. The network's own
+/// delay comes on top.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LatencyBudget {
+    /// The capture buffer to ask the device for, in frames.
+    pub buffer_frames: u32,
+    /// The most audio a packet carries, in frames.
+    pub packet_frames: u32,
+    /// The least the receiver buffers before playing.
+    pub jitter: Duration,
+}
+
+impl LatencyBudget {
+    /// Gives the capture buffer and the packet a quarter of `total` each,
+    /// as a power of two from 64 to 512 frames, and the jitter buffer the
+    /// rest. Opus packets always carry 10 ms. `None` if that leaves the
+    /// jitter buffer less than a packet.
+    pub fn split(total: Duration, codec: Codec) -> Option<Self> {
+        let quarter = (total.as_secs_f64() / 4.0 * SAMPLE_RATE as f64) as u32;
+        let frames = quarter
+            .checked_ilog2()
+            .map_or(0, |log| 1 << log)
+            .clamp(MIN_FRAMES, FRAMES_PER_BUFFER);
+        let packet_frames = codec.frame_frames().map_or(frames, |frames| frames as u32);
+        let jitter = total.checked_sub(duration(frames + packet_frames))?;
+        (jitter >= duration(packet_frames)).then_some(Self {
+            buffer_frames: frames,
+            packet_frames,
+            jitter,
+        })
+    }
+}
+
+fn duration(frames: u32) -> Duration {
+    Duration::from_secs_f64(frames as f64 / SAMPLE_RATE as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        let budget = LatencyBudget::split(Duration::from_millis(30), Codec::Pcm).unwrap();
+        assert_eq!((budget.buffer_frames, budget.packet_frames), (256, 256));
+        assert_eq!(budget.jitter.as_micros(), 19_333);
+
+        // Opus frames are 10 ms whatever the budget.
+        let budget = LatencyBudget::split(Duration::from_millis(30), Codec::Opus).unwrap();
+        assert_eq!((budget.buffer_frames, budget.packet_frames), (256, 480));
+        assert_eq!(LatencyBudget::split(Duration::from_millis(20), Codec::Opus), None);
+
+        // A generous budget goes to the jitter buffer.
+        let budget = LatencyBudget::split(Duration::from_millis(200), Codec::Pcm).unwrap();
+        assert_eq!((budget.buffer_frames, budget.packet_frames), (512, 512));
+        assert_eq!(budget.jitter.as_micros(), 178_666);
+
+        assert!(LatencyBudget::split(Duration::from_millis(5), Codec::Pcm).is_some());
+        assert_eq!(LatencyBudget::split(Duration::from_millis(3), Codec::Pcm), None);
+    }
+}
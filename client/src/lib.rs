@@ -1,3 +1,80 @@
+pub mod airplay;
+pub mod bench;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod cast;
+pub mod clock;
+pub mod codec;
+pub mod config;
+pub mod control;
+pub mod convert;
+#[cfg(unix)]
+pub mod daemon;
+pub mod discovery;
+pub mod dlna;
+pub mod drift;
+pub mod error;
+pub mod failover;
+pub mod fmp4;
+pub mod hls;
+pub mod hook;
+pub mod http;
+pub mod icecast;
+pub mod impair;
+pub mod ipc;
+pub mod jitter;
+pub mod keyboard;
+pub mod latency;
+pub mod logfile;
+pub mod meter;
+pub mod metrics;
+#[cfg(all(feature = "mpris", target_os = "linux"))]
+pub mod mpris;
+pub mod mqtt;
+#[cfg(feature = "ndi")]
+pub mod ndi;
+pub mod notifications;
+pub mod ogg;
+pub mod osc;
+pub mod packet;
+pub mod playback;
+pub mod portmap;
+pub mod probe;
+pub mod proxy;
+pub mod qos;
+pub mod realtime;
+pub mod reconnect;
+pub mod record;
+pub mod rendezvous;
+pub mod ring;
+pub mod roc;
+pub mod rtsp;
+pub mod rtt;
+pub mod schedule;
+pub mod selftest;
+#[cfg(windows)]
+pub mod service;
+pub mod sink;
+pub mod source;
+pub mod spectrum;
+pub mod srt;
+pub mod stats;
+pub mod statsfile;
+pub mod streamer;
+pub mod systemd;
+pub mod transport;
+#[cfg(feature = "tray")]
+pub mod tray;
+#[cfg(feature = "tui")]
+pub mod tui;
+pub mod usage;
+pub mod vban;
+pub mod web;
+pub mod websocket;
+pub mod wire;
+
+pub use streamer::{StreamerClient, StreamerClientBuilder};
+
 use cpal::traits::DeviceTrait;
 
 pub fn find_loopback_device(devices: &[cpal::Device]) -> Option<&cpal::Device> {
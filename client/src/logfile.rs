@@ -0,0 +1,121 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{Local, NaiveDate};
+
+/// Rotated files kept next to the current one, as `<path>.1` (the newest)
+/// to `<path>.7`, so a week of daily logs stays on disk.
+pub const KEPT_FILES: usize = 7;
+
+/// When a [`LogFile`] starts a new file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rotation {
+    /// Never; the file grows for as long as the client runs.
+    Never,
+    /// At the first message after local midnight.
+    Daily,
+    /// Before a message that would take the file past this many bytes.
+    Size(u64),
+}
+
+/// A log file that appends across runs and rotates itself, so a client
+/// left running for months keeps a bounded history.
+pub struct LogFile {
+    path: PathBuf,
+    rotation: Rotation,
+    file: File,
+    len: u64,
+    opened_on: NaiveDate,
+}
+
+impl LogFile {
+    /// Opens `path` for appending, creating it if needed. A relative path is
+    /// taken from the current directory, which --daemonize later leaves.
+    pub fn open(path: &Path, rotation: Rotation) -> io::Result<Self> {
+        let path = std::env::current_dir()?.join(path);
+        let file = append(&path)?;
+        let len = file.metadata()?.len();
+        Ok(Self {
+            path,
+            rotation,
+            file,
+            len,
+            opened_on: Local::now().date_naive(),
+        })
+    }
+
+    fn is_due(&self, incoming: usize, today: NaiveDate) -> bool {
+        match self.rotation {
+            Rotation::Never => false,
+            Rotation::Daily => today != self.opened_on,
+            Rotation::Size(max) => self.len > 0 && self.len + incoming as u64 > max,
+        }
+    }
+
+    // Shifts the kept files up by one, dropping the oldest, and starts an
+    // empty file at the path.
+    fn rotate(&mut self, today: NaiveDate) -> io::Result<()> {
+        self.file.flush()?;
+        for n in (1..KEPT_FILES).rev() {
+            let from = numbered(&self.path, n);
+            if from.exists() {
+                std::fs::rename(&from, numbered(&self.path, n + 1))?;
+            }
+        }
+        std::fs::rename(&self.path, numbered(&self.path, 1))?;
+        self.file = append(&self.path)?;
+        self.len = 0;
+        self.opened_on = today;
+        Ok(())
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let today = Local::now().date_naive();
+        if self.is_due(buf.len(), today) {
+            self.rotate(today)?;
+        }
+        let written = self.file.write(buf)?;
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_size_rotation() {
+        let dir = std::env::temp_dir().join(format!("audio-streamer-logfile-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("client.log");
+        let mut log = LogFile::open(&path, Rotation::Size(10)).unwrap();
+        for line in 0..(KEPT_FILES + 3) {
+            log.write_all(format!("line {}\n", line).as_bytes()).unwrap();
+        }
+        let read = |path: PathBuf| std::fs::read_to_string(path).unwrap();
+        assert_eq!(read(path.clone()), "line 9\n");
+        assert_eq!(read(numbered(&path, 1)), "line 8\n");
+        assert_eq!(read(numbered(&path, KEPT_FILES)), "line 2\n");
+        assert!(!numbered(&path, KEPT_FILES + 1).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
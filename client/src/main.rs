@@ -1,188 +1,2876 @@
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser};
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::future::Future;
 use std::sync::{Arc, Mutex};
-use tokio::net::UdpSocket;
-use byteorder::{LittleEndian, ReadBytesExt};
-use std::io::Cursor;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, UdpSocket};
+use byteorder::ReadBytesExt;
+use std::io::{Cursor, IsTerminal};
+use tracing::{error, info, info_span, warn, Instrument};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::EnvFilter;
 
-use crate::select_device;
+use audio_client::airplay;
+use audio_client::bench;
+use audio_client::cast;
+use audio_client::clock::{self, SyncedClock};
+use audio_client::codec::{Codec, FrameDecoder, FrameEncoder, OpusOptions};
+use audio_client::config::{self, Config};
+use audio_client::control::{self, ControlState, Controls, ReplyCache, Status};
+#[cfg(unix)]
+use audio_client::daemon;
+use audio_client::error::{self, Error, ErrorKind};
+use audio_client::failover;
+use audio_client::ipc;
+use audio_client::hook::{CommandHook, FrameHooks};
+use audio_client::impair::{self, Impairment};
+use audio_client::jitter::{self, ArrivalHistogram, JitterBuffer};
+use audio_client::latency::LatencyBudget;
+use audio_client::keyboard::{self, Key, KeyMode};
+use audio_client::logfile::{LogFile, Rotation};
+use audio_client::meter;
+use audio_client::metrics;
+use audio_client::mqtt::{self, MqttOptions, MQTT_DEFAULT_PORT};
+use audio_client::notifications::{self, Reachability, ReachabilityWatch};
+use audio_client::osc;
+use audio_client::playback::{self, Concealment, PlaybackCursor, PlayoutProbe, PlayoutSchedule};
+use audio_client::portmap::{PortMapping, PortProtocol};
+use audio_client::probe;
+use audio_client::proxy::Proxy;
+use audio_client::qos::{self, SocketOptions};
+use audio_client::realtime;
+use audio_client::record::WavFile;
+use audio_client::rendezvous::{self, Role, Route, Session};
+use audio_client::roc::{self, RocDepacketizer, ROC_DEFAULT_PORT};
+use audio_client::rtt;
+use audio_client::schedule::{self, ScheduleTime};
+use audio_client::select_device;
+use audio_client::selftest::{self, Tone};
+use audio_client::sink::{Protocol, Sink, SinkKind, SinkOptions, Transport};
+use audio_client::stats::{self, StreamStats};
+use audio_client::statsfile::{SessionRecord, StatsFile, StatsRow};
+use audio_client::streamer::{
+    self, build_capture, build_capture_with_sender, capture_config, deliver, encode_samples, first_available,
+    pending_audio, BufferSizer, Capture, OverflowPolicy, StreamWatchdog, CHANNELS, DEFAULT_MAX_BUFFER_FRAMES,
+    FRAMES_PER_BUFFER, SAMPLE_RATE, SERVER_AUDIO_PORT,
+};
+#[cfg(windows)]
+use audio_client::service;
+use audio_client::systemd;
+use audio_client::transport::{self, AudioTransport};
+use audio_client::vban::{self, VBAN_DEFAULT_PORT};
+use audio_client::web::{WebFormat, WebStream};
+use audio_client::source::{PcmReader, SourceKind, StdinFormat};
+use audio_client::srt::{self, SrtMode, SrtOptions};
 
 #[derive(Parser)]
 #[command(name = "audio-client")]
 #[command(about = "Captures system audio and streams over UDP")]
+// Without a subcommand the client streams, so the stream flags also work on
+// their own as they did before there were subcommands.
+#[command(args_conflicts_with_subcommands = true)]
+struct Cli {
+    /// TOML file of flag settings, overridden by the command line and reloaded when it changes or on SIGHUP
+    /// (default: ~/.config/audio-streamer/config.toml if it exists)
+    #[arg(long, global = true)]
+    config: Option<std::path::PathBuf>,
+
+    /// Use the settings of this profile from the config file's [profiles.<name>] table on top of the others
+    #[arg(long, global = true)]
+    profile: Option<String>,
+
+    #[command(flatten)]
+    log: LogArgs,
+
+    #[command(flatten)]
+    stream: Args,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Args)]
+// Flags from the config file are parsed first, so the command line's win.
+#[command(args_override_self = true)]
 struct Args {
-    /// Server IP address
+    /// Server IP address; repeat it to add backups, streamed to in order while the servers before them stop
+    /// answering heartbeats
     #[arg(long, default_value = "127.0.0.1")]
-    server: String,
+    server: Vec<String>,
+
+    /// How long a server may go without answering heartbeats on its control port before the stream fails over to
+    /// the next --server (e.g. 5s)
+    #[arg(long, default_value = "5s", value_parser = humantime::parse_duration)]
+    failover_after: Duration,
+
+    /// Send every packet to all the --server addresses at once, each over its own socket, instead of keeping the
+    /// later ones as backups
+    #[arg(long)]
+    fan_out: bool,
+
+    /// Exit when the receiver can't be reached or drops the connection, instead of retrying with backoff while the
+    /// capture goes on
+    #[arg(long)]
+    no_reconnect: bool,
+
+    /// Initial client-side volume (0.0 to 1.0)
+    #[arg(long, default_value = "1.0")]
+    volume: f32,
+
+    /// Start with the stream muted
+    #[arg(long)]
+    muted: bool,
+
+    /// Port to listen for server control messages
+    #[arg(long, default_value = "8081")]
+    control_port: u16,
+
+    /// Local control socket, or named pipe on Windows (default: $XDG_RUNTIME_DIR/audio-streamer.sock)
+    #[arg(long)]
+    ipc_path: Option<std::path::PathBuf>,
+
+    /// Do not open the local control socket
+    #[arg(long, conflicts_with = "ipc_path")]
+    no_ipc: bool,
+
+    /// Accept OSC control messages (/streamer/volume, /streamer/mute, ...) on this UDP port
+    #[arg(long)]
+    osc_port: Option<u16>,
+
+    /// MQTT broker (host[:port]) to take commands from and publish state to
+    #[arg(long)]
+    mqtt_broker: Option<String>,
+
+    /// Base MQTT topic: commands on <topic>/set/<name>, state on <topic>/state
+    #[arg(long, default_value = "audio-streamer")]
+    mqtt_topic: String,
+
+    /// Same as the list-devices subcommand
+    #[arg(long, hide = true)]
+    list_devices: bool,
+
+    /// Detach from the terminal and run in the background (Unix); stop it with the stop command
+    #[arg(long)]
+    daemonize: bool,
+
+    /// Show desktop notifications when streaming starts, the server becomes unreachable or the input device
+    /// is lost (needs the notifications feature)
+    #[arg(long)]
+    notifications: bool,
+
+    /// Run the audio callbacks with real-time scheduling, falling back to normal priority when the system
+    /// refuses it
+    #[arg(long)]
+    realtime: bool,
+
+    /// Show a tray icon with mute, volume and device controls (needs the tray feature)
+    #[arg(long)]
+    tray: bool,
+
+    /// Write the process ID to this file while running
+    /// (default with --daemonize: $XDG_RUNTIME_DIR/audio-streamer.pid)
+    #[arg(long)]
+    pid_file: Option<std::path::PathBuf>,
+
+    /// Select the device, open the stream, resolve the server and bind the sockets, report it all and exit
+    /// without capturing or sending; exits with status 1 if anything would fail
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Name of the audio input device to use; repeat it to add fallbacks, tried in order at startup and
+    /// whenever the device in use goes away
+    #[arg(long, alias = "fallback-devices")]
+    device_name: Vec<String>,
+
+    /// Index of the audio input device to use
+    #[arg(long)]
+    device_index: Option<usize>,
+
+    /// How many times in a row to rebuild the capture stream after the audio backend reports an error or the
+    /// device goes away, before giving up
+    #[arg(long, default_value_t = streamer::DEFAULT_STREAM_RESTARTS)]
+    max_stream_restarts: u32,
+
+    /// What to drop when the network can't keep up and the queue of captured audio waiting to be sent is full
+    #[arg(long, value_enum, default_value = "drop-newest")]
+    overflow_policy: OverflowPolicy,
+
+    /// Capture buffer size to ask the device for, in frames; it doubles, up to --max-buffer-frames, when the device
+    /// refuses it or callbacks keep missing audio
+    #[arg(long, default_value_t = FRAMES_PER_BUFFER, value_parser = clap::value_parser!(u32).range(16..=16384))]
+    buffer_frames: u32,
+
+    /// The largest the capture buffer grows to, in frames; the same as --buffer-frames keeps the size fixed
+    #[arg(long, default_value_t = DEFAULT_MAX_BUFFER_FRAMES, value_parser = clap::value_parser!(u32).range(16..=16384))]
+    max_buffer_frames: u32,
+
+    /// Where to read audio from
+    #[arg(long, value_enum, default_value = "device")]
+    source: SourceKind,
+
+    /// Raw PCM layout for --source stdin (<s16le|f32le>:<rate>:<channels>)
+    #[arg(long, default_value = "s16le:48000:2")]
+    stdin_format: StdinFormat,
+
+    /// Where to deliver processed audio (udp, stdout, snapcast[:<fifo>|:tcp://<host>:<port>])
+    #[arg(long, default_value = "udp")]
+    sink: SinkKind,
+
+    /// Pipe the audio through this shell command before sending: s16le at 48000 Hz stereo on its stdin,
+    /// processed audio in the same format on its stdout (repeat to chain commands)
+    #[arg(long)]
+    frame_hook: Vec<String>,
+
+    /// Start streaming at this time (HH:MM[:SS] local, daily, or RFC3339)
+    #[arg(long)]
+    start_at: Option<ScheduleTime>,
+
+    /// Stop streaming at this time (HH:MM[:SS] local, daily, or RFC3339)
+    #[arg(long)]
+    stop_at: Option<ScheduleTime>,
+
+    /// Stop after streaming for this long (e.g. 90s, 45m, 2h) and print a summary
+    #[arg(long, value_parser = humantime::parse_duration)]
+    duration: Option<Duration>,
+
+    /// Receive a stream on this UDP port and play it instead of capturing
+    #[arg(long)]
+    listen: Option<u16>,
+
+    /// Join this multicast group when receiving (e.g. 239.255.0.1)
+    #[arg(long, requires = "listen")]
+    multicast_group: Option<std::net::Ipv4Addr>,
+
+    /// Play each packet this long after its sender timestamp on the synchronized clock,
+    /// so receivers sharing a clock and delay play in lockstep (needs --timestamps on the sender)
+    #[arg(long, requires = "listen")]
+    playout_delay_ms: Option<u64>,
+
+    /// Ask the router to forward the --listen port here with NAT-PMP or UPnP and log the address senders on the
+    /// internet should use, instead of forwarding it by hand
+    #[arg(long, requires = "listen")]
+    map_port: bool,
+
+    /// Meet the other end through this relay (host[:port], see the relay command) instead of at --server, punching
+    /// through both NATs or else relaying the audio; works for sending and --listen over --transport udp
+    #[arg(long, value_name = "HOST[:PORT]", value_parser = rendezvous::parse_relay, requires = "session")]
+    relay: Option<String>,
+
+    /// Session code shared by the two ends meeting at --relay
+    #[arg(long, value_parser = rendezvous::parse_code, requires = "relay")]
+    session: Option<String>,
+
+    /// Minimum playback buffering when receiving; raised automatically under jitter
+    #[arg(long, default_value = "50")]
+    target_latency_ms: u64,
+
+    /// Latency to aim for end to end, less the network's own, split between the capture buffer, the packets and
+    /// the receiver's buffering; give both ends the same value
+    #[arg(long, conflicts_with_all = ["target_latency_ms", "buffer_frames"])]
+    latency_ms: Option<u64>,
+
+    /// How to fill packets that miss their playout deadline when receiving
+    #[arg(long, value_enum, default_value = "repeat")]
+    concealment: Concealment,
+
+    /// Disable micro-resampling that keeps receive latency stable against clock drift
+    #[arg(long)]
+    no_drift_compensation: bool,
+
+    /// Audio codec used on the wire, which must match on both ends (default: pcm, or opus with --preset low-bandwidth)
+    #[arg(long, value_enum)]
+    codec: Option<Codec>,
+
+    /// Embed Opus in-band FEC so the receiver can recover single lost packets
+    #[arg(long)]
+    opus_fec: bool,
+
+    /// Stop transmitting Opus frames while the input is silent
+    #[arg(long)]
+    opus_dtx: bool,
+
+    /// Encode Opus at this many kbit/s instead of the encoder's own choice
+    #[arg(long, value_name = "KBPS", value_parser = clap::value_parser!(u32).range(6..=510))]
+    opus_bitrate: Option<u32>,
+
+    /// Mix the audio down to one channel before encoding it with Opus
+    #[arg(long)]
+    opus_mono: bool,
+
+    /// Encode Opus at this sample rate, in Hz, instead of the capture's 48000; receivers decode it as usual
+    #[arg(long, value_name = "HZ", value_parser = opus_sample_rate)]
+    opus_sample_rate: Option<u32>,
+
+    /// Set the codec and its options for a kind of network in one flag; flags given as well still apply
+    #[arg(long, value_enum)]
+    preset: Option<Preset>,
+
+    /// Piggy-back the previous frame in each packet (can be toggled at runtime)
+    #[arg(long)]
+    redundancy: bool,
+
+    /// Gather this many packets and send them together, in one system call on Linux, to save CPU on small boards;
+    /// adds up to that many packets of latency, and each packet keeps its own timestamp
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u32).range(1..=64))]
+    batch_packets: u32,
+
+    /// Mark audio packets with this DSCP (0-63, or a class such as EF, AF41 or CS5) so managed switches and Wi-Fi
+    /// WMM put them ahead of bulk traffic; EF suits audio
+    #[arg(long, value_parser = qos::parse_dscp)]
+    dscp: Option<u8>,
+
+    /// Size the audio socket's send buffer to this many bytes instead of the system default; Linux doubles it and
+    /// caps it at net.core.wmem_max
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u32).range(1024..))]
+    so_sndbuf: Option<u32>,
+
+    /// Spread packets evenly over the audio they carry instead of sending each callback's worth in a burst, which
+    /// cuts loss on congested Wi-Fi
+    #[arg(long)]
+    pace: bool,
+
+    /// Send the audio from this local address (ip or ip:port), so a machine on several networks can keep the stream
+    /// on the wired one rather than a VPN or Wi-Fi
+    #[arg(long, value_name = "IP[:PORT]", value_parser = qos::parse_bind)]
+    bind: Option<std::net::SocketAddr>,
+
+    /// Reach the receiver through a proxy, for networks that only let traffic out through one:
+    /// socks5://, socks5h:// or http:// followed by [user:password@]host[:port]; only for --transport tcp or ws
+    #[arg(long, value_name = "URL")]
+    proxy: Option<Proxy>,
+
+    /// Stamp each packet with its capture time on the synchronized clock
+    #[arg(long)]
+    timestamps: bool,
+
+    /// NTP server used to discipline the clock for timestamps (e.g. pool.ntp.org)
+    #[arg(long)]
+    ntp_server: Option<String>,
+
+    /// Synchronize the clock with another client's control port (host:port) for sub-millisecond LAN agreement
+    #[arg(long, conflicts_with = "ntp_server")]
+    sync_with: Option<String>,
+
+    /// Ping a receiver's control port (host:port) every second and report the round trip in `ctl stats` and metrics,
+    /// with the capture-to-speaker latency when --timestamps is on
+    #[arg(long)]
+    ping: Option<String>,
+
+    /// How packets travel between sender and receiver (must match on both ends)
+    #[arg(long, value_enum, default_value = "udp")]
+    transport: Transport,
+
+    /// SRT connection side (default: caller when sending, listener when receiving)
+    #[arg(long, value_enum)]
+    srt_mode: Option<SrtMode>,
+
+    /// SRT receive window for retransmissions; raise it for long or lossy paths
+    #[arg(long, default_value = "120")]
+    srt_latency_ms: u64,
+
+    /// Encrypt the SRT stream with this passphrase (10-79 characters, same on both ends)
+    #[arg(long, value_parser = srt::parse_passphrase)]
+    srt_passphrase: Option<String>,
+
+    /// Drop this share of the packets sent, as a lossy network would (e.g. 3%)
+    #[arg(long, value_parser = impair::parse_percent)]
+    simulate_loss: Option<f64>,
+
+    /// Delay each packet sent by a random time up to this (e.g. 20ms), which also reorders some
+    #[arg(long, value_parser = humantime::parse_duration)]
+    simulate_jitter: Option<Duration>,
+
+    /// Hold back this share of the packets sent until after the next one (default: 5%)
+    #[arg(long, value_parser = impair::parse_percent, num_args = 0..=1, default_missing_value = "5%")]
+    simulate_reorder: Option<f64>,
+
+    /// Seed for --simulate-loss, --simulate-jitter and --simulate-reorder, to repeat a run exactly
+    #[arg(long)]
+    simulate_seed: Option<u64>,
+
+    /// Packet format for --sink udp and --listen; vban sends to port 6980 for Voicemeeter/VBAN Receptor, roc to
+    /// port 10001 for roc-recv
+    #[arg(long, value_enum, default_value = "native")]
+    protocol: Protocol,
+
+    /// VBAN stream name the receiver listens for (up to 16 ASCII characters)
+    #[arg(long, default_value = "Stream1", value_parser = vban::parse_stream_name)]
+    stream_name: String,
+
+    /// Also serve the capture to HTTP listeners at /stream, with a browser player at /, on this address (e.g. 0.0.0.0:9000)
+    #[arg(long)]
+    http_listen: Option<std::net::SocketAddr>,
+
+    /// Format served by --http-listen
+    #[arg(long, value_enum, default_value = "wav")]
+    http_format: WebFormat,
+
+    /// Show a live peak/RMS level meter per channel on stderr while streaming, if it is a terminal
+    #[arg(long)]
+    meter: bool,
+
+    /// Show a full-screen dashboard with the stream's state, bitrate, levels, spectrum and log, taking the same keys as
+    /// the keyboard controls (needs --features tui)
+    #[arg(long)]
+    tui: bool,
+
+    /// Log the packets and bytes sent, bitrate, send failures and capture callback sizes this often (e.g. 10s)
+    #[arg(long, value_parser = humantime::parse_duration)]
+    stats_interval: Option<Duration>,
+
+    /// Append the bitrate, loss, round trip and buffer depth for each --stats-interval (default: 10s) to this CSV
+    /// file, or as JSON lines to a .json or .jsonl file
+    #[arg(long)]
+    stats_file: Option<std::path::PathBuf>,
+
+    /// Write the session summary as JSON to this file when the stream stops
+    #[arg(long)]
+    summary_file: Option<std::path::PathBuf>,
+
+    /// Serve Prometheus metrics at /metrics on this address (e.g. 0.0.0.0:9102)
+    #[arg(long)]
+    metrics_listen: Option<std::net::SocketAddr>,
+}
+
+impl Args {
+    fn codec(&self) -> Codec {
+        self.codec.unwrap_or(Codec::Pcm)
+    }
+}
+
+#[derive(Clone, clap::Args)]
+struct LogArgs {
+    /// Least severe messages to log: trace, debug, info, warn or error. Without it, RUST_LOG's filter applies if set
+    /// (default: info)
+    #[arg(long, global = true)]
+    log_level: Option<tracing::Level>,
+
+    /// How to write log messages
+    #[arg(long, global = true, value_enum, default_value = "text")]
+    log_format: LogFormat,
+
+    /// Append log messages to this file instead of writing them to stderr
+    #[arg(long, global = true)]
+    log_file: Option<std::path::PathBuf>,
+
+    /// Start a new log file every day or once it reaches --log-max-size, keeping the last 7
+    #[arg(long, global = true, value_enum, requires = "log_file")]
+    log_rotate: Option<LogRotate>,
+
+    /// Size in MiB at which --log-rotate size starts a new file
+    #[arg(long, global = true, default_value_t = 10)]
+    log_max_size: u64,
+
+    /// How to write the error the client stops with, if any
+    #[arg(long, global = true, value_enum, default_value = "text")]
+    error_format: ErrorFormat,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Preset {
+    /// Mono Opus at 24kHz and 32kbit/s with DTX, for LTE hotspots and other slow or metered links
+    LowBandwidth,
+    /// PCM at 48kHz in stereo with redundancy, for a wired LAN with bandwidth to spare
+    LosslessLan,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum LogRotate {
+    Daily,
+    Size,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum LogFormat {
+    /// One line per message, for people
+    Text,
+    /// One JSON object per message with its timestamp, level, module, fields and spans, for log collectors
+    Json,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum ErrorFormat {
+    /// An "Error:" line, for people
+    Text,
+    /// A single JSON object with the error's kind, exit code and message, for scripts
+    Json,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum ListFormat {
+    /// One line per device
+    Text,
+    /// A JSON array with each device's supported configurations, for scripts
+    Json,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Capture audio and stream it, as when the flags are given without a subcommand
+    Stream(Box<Args>),
+    /// List the audio input devices
+    ListDevices {
+        /// How to print the devices
+        #[arg(long, value_enum, default_value = "text")]
+        format: ListFormat,
+    },
+    /// Find AirPlay speakers and Cast devices on the network
+    Discover,
+    /// Record an input device to a WAV file
+    Record {
+        /// WAV file to write
+        output: std::path::PathBuf,
+
+        /// Name of the audio input device to record; repeat it to add fallbacks
+        #[arg(long)]
+        device_name: Vec<String>,
+
+        /// Index of the audio input device to record
+        #[arg(long)]
+        device_index: Option<usize>,
+
+        /// Stop after recording for this long (e.g. 90s, 45m); otherwise record until Ctrl+C
+        #[arg(long, value_parser = humantime::parse_duration)]
+        duration: Option<Duration>,
+    },
+    /// Send a test tone to a server to check that audio gets through
+    Test {
+        /// Server IP address
+        #[arg(long, default_value = "127.0.0.1")]
+        server: String,
+
+        /// How long to play the tone
+        #[arg(long, default_value = "5s", value_parser = humantime::parse_duration)]
+        duration: Duration,
+
+        /// Pitch of the tone in Hz
+        #[arg(long, default_value = "440")]
+        frequency: f32,
+    },
+    /// Stream a tone to a receiver inside the client over localhost and check what it decodes
+    SelfTest {
+        /// Only check this codec (default: all of them)
+        #[arg(long, value_enum)]
+        codec: Option<Codec>,
+
+        /// Only check this transport (default: udp, tcp and ws)
+        #[arg(long, value_enum)]
+        transport: Option<Transport>,
+    },
+    /// Run a relay where senders and receivers behind NATs meet with --relay and --session
+    Relay {
+        /// UDP port to listen on
+        #[arg(long, default_value_t = rendezvous::DEFAULT_RELAY_PORT)]
+        port: u16,
+    },
+    /// Time each stage of the audio pipeline on this machine
+    Bench {
+        /// Capture buffers to time each stage over
+        #[arg(long, default_value = "2000")]
+        buffers: usize,
+    },
+    /// List the profiles in the config file
+    ListProfiles,
+    /// Print a systemd service unit that streams with the given flags
+    GenerateSystemdUnit {
+        /// Stream flags for the service, as given to the stream command
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        flags: Vec<std::ffi::OsString>,
+    },
+    /// Install, remove or run the client as a Windows service
+    Service {
+        #[command(subcommand)]
+        action: ServiceAction,
+    },
+    /// Stop a client started with --daemonize
+    Stop {
+        /// PID file the client was started with (default: $XDG_RUNTIME_DIR/audio-streamer.pid)
+        #[arg(long)]
+        pid_file: Option<std::path::PathBuf>,
+    },
+    /// Show what an input device supports and whether the client's stream setup works on it
+    Probe {
+        /// Name of the input device (default: the one the client would pick)
+        #[arg(long)]
+        device_name: Option<String>,
+
+        /// Index of the input device, as in list-devices
+        #[arg(long, conflicts_with = "device_name")]
+        device_index: Option<usize>,
+    },
+    /// Send a control command to a running client and print its reply
+    Ctl {
+        /// Control address of the client (host:port)
+        #[arg(long, default_value = "127.0.0.1:8081")]
+        target: String,
+
+        #[command(subcommand)]
+        action: CtlAction,
+    },
+}
+
+#[derive(clap::Subcommand)]
+enum ServiceAction {
+    /// Register a service that starts at boot and streams with the given flags
+    Install {
+        /// Stream flags for the service, as given to the stream command
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        flags: Vec<std::ffi::OsString>,
+    },
+    /// Stop the service and remove it
+    Uninstall,
+    /// Stream as the service; the Service Control Manager runs this
+    #[command(hide = true)]
+    Run(Box<Args>),
+}
+
+#[derive(clap::Subcommand)]
+enum CtlAction {
+    /// Set the client volume (0.0 to 1.0)
+    SetVolume { level: f32 },
+    /// Silence the stream, keeping the volume
+    Mute,
+    Unmute,
+    /// Stop sending audio until resumed
+    Pause,
+    Resume,
+    /// Turn redundant audio on or off
+    SetRedundancy {
+        #[arg(value_parser = clap::builder::BoolishValueParser::new())]
+        enabled: bool,
+    },
+    /// Capture from the input device with this name
+    SwitchDevice { name: String },
+    /// Print the client's current statistics
+    Stats,
+}
+
+impl CtlAction {
+    fn message(self) -> control::Message {
+        match self {
+            CtlAction::SetVolume { level } => control::Message::SetVolume(level),
+            CtlAction::Mute => control::Message::Mute(true),
+            CtlAction::Unmute => control::Message::Mute(false),
+            CtlAction::Pause => control::Message::Pause(true),
+            CtlAction::Resume => control::Message::Pause(false),
+            CtlAction::SetRedundancy { enabled } => control::Message::SetRedundancy(enabled),
+            CtlAction::SwitchDevice { name } => control::Message::SwitchDevice(name),
+            CtlAction::Stats => control::Message::Stats,
+        }
+    }
+}
+
+// Every flag can also be set with an AUDIO_STREAMER_* variable named after
+// it, e.g. --device-name as AUDIO_STREAMER_DEVICE_NAME. Switches take
+// true/false, yes/no, on/off or 1/0 there.
+fn command() -> clap::Command {
+    Cli::command()
+        .mut_args(with_env)
+        .mut_subcommand("stream", |stream| stream.mut_args(with_env))
+}
+
+fn with_env(arg: clap::Arg) -> clap::Arg {
+    let Some(var) = arg.get_long().filter(|long| *long != "help").map(env_var) else {
+        return arg;
+    };
+    let switch = matches!(arg.get_action(), clap::ArgAction::SetTrue);
+    let arg = arg.env(var);
+    if switch {
+        arg.value_parser(clap::builder::FalseyValueParser::new())
+    } else {
+        arg
+    }
+}
+
+fn env_var(long: &str) -> String {
+    format!("AUDIO_STREAMER_{}", long.replace('-', "_").to_uppercase())
+}
+
+fn parse_args(
+    command_line: impl IntoIterator<Item = impl Into<std::ffi::OsString> + Clone>,
+) -> Result<Cli, clap::Error> {
+    Cli::from_arg_matches(&command().try_get_matches_from(command_line)?)
+}
+
+/// Where the stream flags start on a command line that runs `subcommand` (e.g. ["service", "run"]): just after its
+/// last name, however many global flags such as --config or --log-level come before it.
+fn stream_flags_at(command_line: &[std::ffi::OsString], subcommand: &[&str]) -> usize {
+    let command = command();
+    // Whether a global flag takes the next argument as its value.
+    let takes_value = |flag: &str| {
+        let arg = match flag.strip_prefix("--") {
+            Some(long) if !long.contains('=') => command.get_arguments().find(|arg| arg.get_long() == Some(long)),
+            Some(_) => None,
+            None if flag.len() == 2 => {
+                let short = flag.chars().nth(1);
+                command.get_arguments().find(|arg| arg.get_short() == short)
+            }
+            None => None,
+        };
+        arg.is_some_and(|arg| arg.get_action().takes_values())
+    };
+    let mut remaining = subcommand;
+    let mut at = 1;
+    while let Some((name, rest)) = remaining.split_first() {
+        let Some(arg) = command_line.get(at) else {
+            break;
+        };
+        let arg = arg.to_string_lossy();
+        if arg == *name {
+            remaining = rest;
+        } else if arg.starts_with('-') && takes_value(&arg) {
+            at += 1;
+        }
+        at += 1;
+    }
+    at.min(command_line.len())
+}
+
+// How often the level meter is redrawn.
+const METER_REFRESH: Duration = Duration::from_millis(200);
+// How often --stats-file gets a row without --stats-interval.
+const STATS_FILE_INTERVAL: Duration = Duration::from_secs(10);
+// How soon a loss burst on the receiving side is logged after it ends.
+const LOSS_LOG_INTERVAL: Duration = Duration::from_secs(1);
+// How often the send counters are checked for an unreachable server.
+const REACHABILITY_CHECK: Duration = Duration::from_secs(1);
+// How long stop waits for the daemon to finish its session summary and exit.
+#[cfg(unix)]
+const DAEMON_STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Older bare control packets, still accepted alongside control::Message:
+// [CONTROL_REDUNDANCY, 0 | 1], and eight bytes of f64 volume.
+const CONTROL_REDUNDANCY: u8 = 0x01;
+
+// Set from --error-format once the flags are parsed, for main to report
+// whatever error run stops with.
+static JSON_ERRORS: AtomicBool = AtomicBool::new(false);
+
+// Builds a capture stream that discards its input, to see whether the
+// device accepts it. Dropping it unstarted captures nothing.
+fn test_capture(
+    device: &cpal::Device,
+    format: cpal::SampleFormat,
+    frames: u32,
+) -> Result<cpal::Stream, cpal::BuildStreamError> {
+    device.build_input_stream_raw(
+        &capture_config(frames),
+        format,
+        |_: &cpal::Data, _: &cpal::InputCallbackInfo| {},
+        |err| warn!("Stream error: {}", err),
+        None,
+    )
+}
+
+// Acts on single keypresses while capturing from a device, printing the
+// resulting state after each. Only when stdin is a terminal; the returned
+// guard puts the terminal back.
+fn spawn_keyboard(controls: Arc<Controls>, quit: tokio::sync::mpsc::Sender<()>) -> Option<KeyMode> {
+    use std::io::Read;
+
+    if !std::io::stdin().is_terminal() {
+        return None;
+    }
+    let mode = match KeyMode::enable() {
+        Ok(mode) => mode,
+        Err(e) => {
+            warn!("Keyboard controls unavailable: {}", e);
+            return None;
+        }
+    };
+    eprintln!("{}", keyboard::HELP);
+    let handle = tokio::runtime::Handle::current();
+    // A plain thread rather than a blocking task, so the pending read does
+    // not hold up exit.
+    std::thread::spawn(move || {
+        for byte in std::io::stdin().lock().bytes() {
+            let Ok(byte) = byte else {
+                break;
+            };
+            let Some(key) = Key::from_byte(byte) else {
+                continue;
+            };
+            let level = controls.state.level();
+            let message = match key {
+                Key::VolumeUp => control::Message::SetVolume((level + keyboard::VOLUME_STEP).min(1.0)),
+                Key::VolumeDown => control::Message::SetVolume((level - keyboard::VOLUME_STEP).max(0.0)),
+                Key::Mute => control::Message::Mute(!controls.state.is_muted()),
+                Key::Pause => control::Message::Pause(!controls.is_paused()),
+                Key::NextDevice => match next_input_device(&controls.report().device) {
+                    Some(name) => control::Message::SwitchDevice(name),
+                    None => {
+                        eprintln!("No other input device");
+                        continue;
+                    }
+                },
+                Key::Quit => {
+                    let _ = quit.blocking_send(());
+                    break;
+                }
+            };
+            handle.block_on(controls.apply(message));
+            let report = controls.report();
+            eprintln!(
+                "Volume {:.2}{} | {} | {}",
+                report.volume,
+                if report.muted { " (muted)" } else { "" },
+                if report.paused { "paused" } else { "streaming" },
+                report.device
+            );
+        }
+    });
+    Some(mode)
+}
+
+#[cfg(feature = "tui")]
+fn start_dashboard(
+    controls: Arc<Controls>,
+    stats: Arc<StreamStats>,
+    destination: String,
+    quit: tokio::sync::mpsc::Sender<()>,
+) -> std::io::Result<audio_client::tui::Dashboard> {
+    audio_client::tui::start(audio_client::tui::Sources {
+        controls,
+        stats,
+        destination,
+        next_device: next_input_device,
+        quit,
+    })
+}
+
+#[cfg(not(feature = "tui"))]
+fn start_dashboard(
+    _: Arc<Controls>,
+    _: Arc<StreamStats>,
+    _: String,
+    _: tokio::sync::mpsc::Sender<()>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    Err("--tui needs a build with --features tui".into())
+}
+
+// The input device after `current` in the host's list, wrapping around.
+fn next_input_device(current: &str) -> Option<String> {
+    let names: Vec<String> = cpal::default_host()
+        .input_devices()
+        .ok()?
+        .filter_map(|device| device.name().ok())
+        .collect();
+    let next = names.iter().position(|name| name == current).map_or(0, |i| i + 1);
+    names.get(next % names.len().max(1)).filter(|name| *name != current).cloned()
+}
+
+// Lets the user choose the input device on the terminal, starting at the
+// detected loopback device, and offers to save the choice in the config
+// file. Returns None, for the usual automatic choice, with a single device.
+fn pick_device(
+    devices: &[cpal::Device],
+    config_path: Option<std::path::PathBuf>,
+    profile: Option<&str>,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let names: Vec<String> = devices
+        .iter()
+        .filter(|device| device.supported_input_configs().map(|c| c.count() > 0).unwrap_or(false))
+        .filter_map(|device| device.name().ok())
+        .collect();
+    if names.len() < 2 {
+        return Ok(None);
+    }
+    let loopback = audio_client::find_loopback_device(devices).and_then(|device| device.name().ok());
+    let items: Vec<_> = names
+        .iter()
+        .map(|name| match &loopback {
+            Some(loopback) if loopback == name => format!("{} (loopback)", name),
+            _ => name.clone(),
+        })
+        .collect();
+    let selected = loopback.and_then(|loopback| names.iter().position(|name| *name == loopback)).unwrap_or(0);
+    let Some(choice) = keyboard::pick("Choose an audio input", &items, selected)? else {
+        return Err(Error::new(ErrorKind::NoDevice, "No audio input chosen").into());
+    };
+    let name = names[choice].clone();
+    if let Some(path) = config_path.or_else(config::default_path) {
+        if keyboard::confirm(&format!("Always use {} (saved to {})?", name, path.display()))? {
+            config::save_setting(&path, profile, "device_name", &name)?;
+        }
+    }
+    Ok(Some(name))
+}
+
+// Prints what the input device supports, whether the client's stream setup
+// fits it, and the outcome of actually building that stream.
+fn probe_device(
+    host: &cpal::Host,
+    device_index: Option<usize>,
+    device_name: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let devices: Vec<_> = host.devices()?.collect();
+    let Some(device) = select_device(&devices, device_index, device_name) else {
+        return Err(Error::new(ErrorKind::NoDevice, "No such input device").into());
+    };
+    println!("Device: {} (Host: {})", device.name()?, host.id().name());
+    let default = device.default_input_config()?;
+    println!(
+        "Default input config: {} channels, {}, {} Hz, buffers {}",
+        default.channels(),
+        default.sample_format(),
+        default.sample_rate().0,
+        probe::describe_buffer(default.buffer_size())
+    );
+    let configs: Vec<_> = device.supported_input_configs()?.collect();
+    println!("Supported input configs:");
+    for config in &configs {
+        println!("  {}", probe::describe(config));
+    }
+
+    let setup = probe::Setup {
+        format: default.sample_format(),
+        channels: CHANNELS,
+        sample_rate: SAMPLE_RATE,
+        frames: FRAMES_PER_BUFFER,
+    };
+    println!(
+        "Client setup: {} channels, {}, {} Hz, {}-frame buffers",
+        setup.channels, setup.format, setup.sample_rate, setup.frames
+    );
+    let problems = probe::problems(&configs, setup);
+    for problem in &problems {
+        println!("  Problem: {}", problem);
+    }
+    match test_capture(device, setup.format, setup.frames) {
+        Ok(_) if problems.is_empty() => println!("Result: the stream builds; the client can capture from this device"),
+        Ok(_) => println!("Result: the stream builds, though the device does not advertise this setup"),
+        Err(e) => {
+            println!("Result: building the stream fails: {}", e);
+            return Err(Error::new(ErrorKind::Device, "The client cannot capture from this device").into());
+        }
+    }
+    Ok(())
+}
+
+// Goes through the steps of streaming up to the first packet, printing
+// what each would use. Returns whether all of them worked. The capture
+// stream is tried with `buffer_frames`, the size streaming starts at.
+async fn dry_run(args: &Args, host: &cpal::Host, server_addr: &str, buffer_frames: u32) -> bool {
+    let mut checks: Vec<(&str, Result<String, String>)> = Vec::new();
+
+    if args.source == SourceKind::Stdin {
+        checks.push(("Source", Ok(format!("stdin as {}", args.stdin_format))));
+    } else {
+        let devices: Vec<_> = host.devices().map(|devices| devices.collect()).unwrap_or_default();
+        let device = first_available(&devices, args.device_index, &args.device_name);
+        checks.push((
+            "Input device",
+            device
+                .ok_or_else(|| "no suitable input device found".to_string())
+                .map(|device| device.name().unwrap_or_default()),
+        ));
+        if let Some(device) = device {
+            let stream = device
+                .default_input_config()
+                .map_err(|e| e.to_string())
+                .and_then(|default| {
+                    let format = default.sample_format();
+                    test_capture(device, format, buffer_frames).map_err(|e| e.to_string()).map(|_| {
+                        format!(
+                            "{} channels, {} Hz, {}-frame buffers of {}",
+                            CHANNELS, SAMPLE_RATE, buffer_frames, format
+                        )
+                    })
+                });
+            checks.push(("Capture stream", stream));
+        }
+    }
+
+    checks.push((
+        "Codec",
+        Ok(format!(
+            "{:?}, {:?} transport, {:?} protocol",
+            args.codec(), args.transport, args.protocol
+        )),
+    ));
+    if args.sink == SinkKind::Udp {
+        let resolved = tokio::net::lookup_host(server_addr)
+            .await
+            .map(|addrs| addrs.map(|addr| addr.to_string()).collect::<Vec<_>>().join(", "))
+            .map_err(|e| e.to_string());
+        checks.push(("Server", resolved.map(|addrs| format!("{} ({})", server_addr, addrs))));
+        let socket = match UdpSocket::bind("0.0.0.0:0").await {
+            Ok(socket) => socket.connect(server_addr).await.map(|()| socket),
+            Err(e) => Err(e),
+        };
+        checks.push((
+            "Audio socket",
+            socket
+                .and_then(|socket| socket.local_addr())
+                .map(|addr| format!("sends from {}", addr))
+                .map_err(|e| e.to_string()),
+        ));
+    } else {
+        checks.push(("Sink", Ok(format!("{} (not contacted in a dry run)", args.sink))));
+    }
+
+    let bound =
+        |result: std::io::Result<std::net::SocketAddr>| result.map(|addr| addr.to_string()).map_err(|e| e.to_string());
+    let control = UdpSocket::bind(("0.0.0.0", args.control_port))
+        .await
+        .and_then(|socket| socket.local_addr());
+    checks.push(("Control port", bound(control)));
+    if let Some(port) = args.osc_port {
+        let osc = UdpSocket::bind(("0.0.0.0", port))
+            .await
+            .and_then(|socket| socket.local_addr());
+        checks.push(("OSC port", bound(osc)));
+    }
+    if let Some(addr) = args.http_listen {
+        let http = std::net::TcpListener::bind(addr).and_then(|listener| listener.local_addr());
+        checks.push(("HTTP listener", bound(http)));
+    }
+    if let Some(addr) = args.metrics_listen {
+        let metrics = std::net::TcpListener::bind(addr).and_then(|listener| listener.local_addr());
+        checks.push(("Metrics listener", bound(metrics)));
+    }
+    if !args.no_ipc {
+        let path = args.ipc_path.clone().unwrap_or_else(ipc::default_path);
+        checks.push(("Local control socket", Ok(path.display().to_string())));
+    }
+    if let Some(broker) = &args.mqtt_broker {
+        checks.push(("MQTT broker", Ok(format!("{} (not contacted in a dry run)", broker))));
+    }
+
+    let mut passed = true;
+    for (what, result) in checks {
+        match result {
+            Ok(detail) => println!("ok    {}: {}", what, detail),
+            Err(e) => {
+                println!("FAIL  {}: {}", what, e);
+                passed = false;
+            }
+        }
+    }
+    passed
+}
+
+// Prints the input devices with the indices --device-index takes.
+fn list_devices(host: &cpal::Host, format: ListFormat) -> Result<(), Box<dyn std::error::Error>> {
+    let devices: Vec<_> = host.devices()?.collect();
+    let loopback = audio_client::find_loopback_device(&devices).and_then(|device| device.name().ok());
+    let mut listed = Vec::new();
+    if format == ListFormat::Text {
+        println!("Available Audio Input Devices:");
+    }
+    for (i, device) in devices.iter().enumerate() {
+        let Ok(configs) = device.supported_input_configs() else {
+            continue;
+        };
+        let configs: Vec<_> = configs.collect();
+        let Ok(name) = device.name() else {
+            continue;
+        };
+        if configs.is_empty() {
+            continue;
+        }
+        match format {
+            ListFormat::Text => println!("  [{}] {} (Host: {})", i, name, host.id().name()),
+            ListFormat::Json => {
+                let configs: Vec<_> = configs
+                    .iter()
+                    .map(|config| {
+                        serde_json::json!({
+                            "channels": config.channels(),
+                            "sample_format": config.sample_format().to_string(),
+                            "min_sample_rate": config.min_sample_rate().0,
+                            "max_sample_rate": config.max_sample_rate().0,
+                        })
+                    })
+                    .collect();
+                listed.push(serde_json::json!({
+                    "index": i,
+                    "name": name,
+                    "host": host.id().name(),
+                    "loopback": loopback.as_ref() == Some(&name),
+                    "configs": configs,
+                }));
+            }
+        }
+    }
+    if format == ListFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&listed)?);
+    }
+    Ok(())
+}
+
+// Lists the AirPlay speakers and Cast devices that answer within the browse
+// window, searching for both at once.
+fn discover() -> Result<(), Box<dyn std::error::Error>> {
+    let (speakers, cast_devices) = std::thread::scope(|scope| {
+        let speakers = scope.spawn(airplay::discover_all);
+        let cast_devices = cast::discover_all();
+        (speakers.join().expect("AirPlay discovery panicked"), cast_devices)
+    });
+    let (speakers, cast_devices) = (speakers?, cast_devices?);
+    if speakers.is_empty() && cast_devices.is_empty() {
+        eprintln!("No AirPlay speakers or Cast devices found");
+    }
+    for speaker in speakers {
+        println!("airplay\t{}\t{}", speaker.name, speaker.addr);
+    }
+    for device in cast_devices {
+        println!("cast\t{}\t{}", device.name, device.addr);
+    }
+    Ok(())
+}
+
+// Captures an input device into a WAV file through the same path as
+// streaming, until the duration runs out or Ctrl+C.
+async fn record(
+    output: &std::path::Path,
+    device_names: &[String],
+    device_index: Option<usize>,
+    duration: Option<Duration>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let host = cpal::default_host();
+    let devices: Vec<_> = host.devices()?.collect();
+    let device = first_available(&devices, device_index, device_names)
+        .ok_or_else(|| Error::new(ErrorKind::NoDevice, "No suitable input device found"))?;
+    let wav = WavFile::create(output, SAMPLE_RATE, CHANNELS).map_err(|e| format!("{}: {}", output.display(), e))?;
+    let sink = Sink::Stream(Arc::new(Mutex::new(Box::new(wav.clone()))));
+    let stats = Arc::new(StreamStats::default());
+    let (events, _events) = tokio::sync::mpsc::unbounded_channel();
+    let capture = Capture {
+        sink: sink.clone(),
+        web: None,
+        encoder: Arc::new(Mutex::new(FrameEncoder::new(
+            Codec::Pcm,
+            SAMPLE_RATE,
+            CHANNELS,
+            OpusOptions::default(),
+        )?)),
+        controls: Arc::new(Controls::new(ControlState::new(1.0), sink, stats.clone())),
+        active: Arc::new(AtomicBool::new(true)),
+        stats,
+        events,
+        frames: None,
+        hooks: Arc::default(),
+        overflow: OverflowPolicy::default(),
+        buffer: Arc::default(),
+        packet_frames: FRAMES_PER_BUFFER as usize,
+    };
+    let (stream, sender) = build_capture_with_sender(device, &capture)?;
+    stream.play()?;
+    info!("Recording {} to {}... Press Ctrl+C to stop.", device.name()?, output.display());
+    // A timeout far in the future is clamped, so no duration means Ctrl+C only.
+    let _ = tokio::time::timeout(duration.unwrap_or(Duration::MAX), tokio::signal::ctrl_c()).await;
+    drop(stream);
+    // The last blocks may still be queued for the file.
+    let _ = sender.await;
+    let bytes = wav.finish()?;
+    let length = Duration::from_secs_f64(bytes as f64 / (SAMPLE_RATE as f64 * CHANNELS as f64 * 2.0));
+    info!("Recorded {:.1}s to {}", length.as_secs_f64(), output.display());
+    Ok(())
+}
+
+// Loud enough to hear, quiet enough not to startle.
+const TEST_TONE_LEVEL: f32 = 0.25;
+
+// Sends a sine tone to the server's audio port, paced like a capture, to
+// check the path end to end without an input device.
+async fn send_test_tone(server: &str, frequency: f32, duration: Duration) -> Result<(), Box<dyn std::error::Error>> {
+    let server_addr = format!("{}:{}", server, SERVER_AUDIO_PORT);
+    let sink = Sink::connect(SinkKind::Udp, &server_addr, SinkOptions::default()).await?;
+    let mut encoder = FrameEncoder::new(Codec::Pcm, SAMPLE_RATE, CHANNELS, OpusOptions::default())?;
+    let stats = StreamStats::default();
+    let mut tone = Tone::new(frequency, TEST_TONE_LEVEL);
+    let mut samples = Vec::with_capacity(FRAMES_PER_BUFFER as usize * CHANNELS as usize);
+    let mut buffer = Vec::new();
+    let mut ticks = tokio::time::interval(Duration::from_secs_f64(FRAMES_PER_BUFFER as f64 / SAMPLE_RATE as f64));
+    info!("Sending a {} Hz tone to {} for {}", frequency, server_addr, humantime::format_duration(duration));
+    let started = Instant::now();
+    while started.elapsed() < duration {
+        ticks.tick().await;
+        samples.clear();
+        tone.fill(&mut samples, FRAMES_PER_BUFFER as usize);
+        encode_samples(&samples, 1.0, &mut buffer);
+        encoder.encode(&buffer, |packet| {
+            deliver(&sink, packet, &stats);
+        });
+    }
+    eprintln!("{}", stats.summary(started.elapsed()));
+    Ok(())
+}
+
+// Runs the self-test over each path asked for, printing a line for each,
+// and fails if any of them did.
+async fn self_test(codec: Option<Codec>, transport: Option<Transport>) -> Result<(), Box<dyn std::error::Error>> {
+    let paths: Vec<_> = selftest::Path::all()
+        .into_iter()
+        .filter(|path| codec.is_none() || codec == Some(path.codec))
+        .filter(|path| transport.is_none() || transport == Some(path.transport))
+        .collect();
+    if paths.is_empty() {
+        return Err(Error::new(ErrorKind::Usage, "The self-test does not cover SRT").into());
+    }
+    let mut failed = 0;
+    for path in &paths {
+        match selftest::run(*path, selftest::TONE_DURATION).await {
+            Ok(outcome) => {
+                println!("{}", outcome);
+                if !outcome.passed() {
+                    failed += 1;
+                }
+            }
+            Err(e) => {
+                println!("FAIL {}: {}", path, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        let message = format!("{} of {} self-test checks failed", failed, paths.len());
+        return Err(Error::new(ErrorKind::Network, message).into());
+    }
+    println!("All {} checks passed", paths.len());
+    Ok(())
+}
+
+// Runs a rendezvous relay on `port` until Ctrl+C: it introduces the two
+// ends of each session code, and forwards their audio when punching fails.
+async fn run_relay(port: u16) -> Result<(), Box<dyn std::error::Error>> {
+    let socket = UdpSocket::bind(("0.0.0.0", port)).await?;
+    info!("Relay listening on UDP port {}", port);
+    tokio::select! {
+        result = rendezvous::serve_relay(socket) => result?,
+        result = tokio::signal::ctrl_c() => result?,
+    }
+    Ok(())
+}
+
+// Prints what each pipeline stage costs per capture buffer, and how much
+// of a core that is when streaming.
+fn run_bench(buffers: usize) -> Result<(), Box<dyn std::error::Error>> {
+    let budget = bench::buffer_duration();
+    println!(
+        "Timing each stage over {} buffers of {} frames ({:.2} ms of audio each)",
+        buffers,
+        FRAMES_PER_BUFFER,
+        budget.as_secs_f64() * 1000.0
+    );
+    println!("{:<30} {:>12} {:>8}", "Stage", "Per buffer", "Load");
+    for stage in bench::run(buffers)? {
+        println!(
+            "{:<30} {:>9.1} µs {:>7.2}%",
+            stage.name,
+            stage.per_buffer.as_secs_f64() * 1e6,
+            stage.load() * 100.0
+        );
+    }
+    Ok(())
+}
+
+// SIGUSR1 toggles mute, SIGUSR2 prints the current stats and SIGHUP
+// reloads the configuration file, so scripts and service managers can poke
+// the running client.
+#[cfg(unix)]
+async fn handle_signals(controls: Arc<Controls>, reload: Option<Arc<tokio::sync::Notify>>) -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut usr1 = signal(SignalKind::user_defined1())?;
+    let mut usr2 = signal(SignalKind::user_defined2())?;
+    let mut hup = signal(SignalKind::hangup())?;
+    loop {
+        tokio::select! {
+            Some(()) = usr1.recv() => {
+                controls.apply(control::Message::Mute(!controls.state.is_muted())).await;
+            }
+            Some(()) = usr2.recv() => eprintln!("{}", controls.report()),
+            Some(()) = hup.recv() => match &reload {
+                Some(reload) => reload.notify_one(),
+                None => warn!("Received SIGHUP, but there is no configuration file to reload"),
+            },
+        }
+    }
+}
+
+// Sends one command to a running client, waiting for its reply. Fails
+// with a control error if the client did not apply it.
+async fn run_ctl(target: &str, action: CtlAction) -> Result<(), Box<dyn std::error::Error>> {
+    let mut controller = control::Controller::connect(target).await?;
+    match controller.send(&action.message()).await? {
+        control::Message::StatsReport(report) => println!("{}", report),
+        control::Message::Ack(Status::Applied) => {}
+        control::Message::Ack(status) => {
+            let message = format!("The client did not apply the command: {}", status);
+            return Err(Error::new(ErrorKind::Control, message).into());
+        }
+        reply => return Err(format!("unexpected reply from {}: {:?}", target, reply).into()),
+    }
+    Ok(())
+}
+
+// Resolves when the session should end: Ctrl+C, SIGTERM (as sent by stop),
+// the end of the schedule, or the --duration limit, counted from when the
+// stream `began`.
+async fn shutdown_signal(
+    schedule: tokio::task::JoinHandle<()>,
+    duration: Option<Duration>,
+    mut began: tokio::sync::watch::Receiver<Option<Instant>>,
+) {
+    let limit = async {
+        let Some(duration) = duration else {
+            return std::future::pending().await;
+        };
+        // The schedule ended without ever opening.
+        if began.wait_for(Option::is_some).await.is_err() {
+            return std::future::pending().await;
+        }
+        tokio::time::sleep(duration).await
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminated() => {}
+        _ = schedule => {}
+        _ = limit => info!("Duration limit reached."),
+    }
+}
+
+// Notifies the desktop when packets to the server stop getting through, and
+// when they flow again.
+async fn watch_reachability(stats: Arc<StreamStats>, server_addr: String) {
+    let mut watch = ReachabilityWatch::default();
+    let mut interval = tokio::time::interval(REACHABILITY_CHECK);
+    loop {
+        interval.tick().await;
+        match watch.update(&stats.summary(Duration::ZERO)) {
+            Some(Reachability::Lost) => {
+                let body = format!("Packets to {} are not getting through", server_addr);
+                notifications::show("Server unreachable", &body);
+            }
+            Some(Reachability::Restored) => {
+                notifications::show("Server reachable again", &format!("Streaming to {}", server_addr));
+            }
+            None => {}
+        }
+    }
+}
+
+// Tells systemd or the Windows Service Control Manager the client is up,
+// when it runs as a service.
+fn notify_ready(status: &str) {
+    if let Err(e) = systemd::notify(&format!("READY=1\nSTATUS={}", status)) {
+        error!("Error notifying systemd: {}", e);
+    }
+    #[cfg(windows)]
+    service::set_running();
+}
+
+fn ping_watchdog() {
+    let _ = systemd::notify("WATCHDOG=1");
+}
+
+// Redraws the level meter in place a few times a second. The line ends
+// with the cursor back at its start, so other messages print over it.
+fn spawn_meter(stats: Arc<StreamStats>) {
+    if !std::io::stderr().is_terminal() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(METER_REFRESH);
+        loop {
+            interval.tick().await;
+            eprint!("{}\x1b[K\r", meter::render(&stats.take_levels()));
+        }
+    });
+}
+
+// Every `period`, logs the counters with the bitrate over the period if
+// `log` is set, and appends a row to the stats file if there is one.
+fn spawn_stats_interval(stats: Arc<StreamStats>, period: Duration, log: bool, mut file: Option<StatsFile>) {
+    tokio::spawn(async move {
+        let started = Instant::now();
+        let mut previous = stats.summary(Duration::ZERO);
+        let mut interval = tokio::time::interval(period);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let summary = stats.summary(started.elapsed());
+            if log {
+                info!("{}", stats::interval_line(&previous, &summary, stats.take_callback_frames()));
+            }
+            if let Some(writer) = &mut file {
+                let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+                let rtt = stats.rtt().summary();
+                let row = StatsRow {
+                    buffered_ms: ms(stats.buffered()),
+                    rtt_ms: rtt.map(|rtt| ms(rtt.avg)),
+                    rtt_jitter_ms: rtt.map(|rtt| ms(rtt.jitter)),
+                    end_to_end_ms: rtt.and_then(|rtt| rtt.end_to_end).map(ms),
+                    ..StatsRow::between(&previous, &summary)
+                };
+                // A full disk should not bring down the stream.
+                if let Err(e) = writer.append(&row) {
+                    warn!("Stopped writing the stats file: {}", e);
+                    file = None;
+                }
+            }
+            previous = summary;
+        }
+    });
+}
+
+// The spacing most packets arrive within, for the receiver's stats line.
+// Anything past the histogram's last bucket shows as longer than it.
+fn arrival_percentiles(arrivals: &ArrivalHistogram) -> String {
+    if arrivals.count == 0 {
+        return String::new();
+    }
+    let bound = |q| match arrivals.quantile(q) {
+        Some(bound) => format!("{}", bound.as_millis()),
+        None => format!(">{}", jitter::ARRIVAL_BUCKETS_MS[jitter::ARRIVAL_BUCKETS_MS.len() - 1]),
+    };
+    format!(", Arrivals p50/p99: {}/{}ms", bound(0.5), bound(0.99))
+}
+
+// Prints the session summary and, with --summary-file, writes it as JSON.
+fn finish_session(summary: &stats::Summary, transport: Option<&dyn AudioTransport>, file: Option<&std::path::Path>) {
+    eprintln!("{}", summary);
+    // Each --fan-out receiver, with what it was sent.
+    for (addr, sent) in transport.map(|transport| transport.destinations()).unwrap_or_default() {
+        eprintln!(
+            "  To {}: {} packets, {} bytes, {} failed",
+            addr, sent.frames_sent, sent.bytes_sent, sent.send_errors
+        );
+    }
+    if let Some(path) = file {
+        if let Err(e) = SessionRecord::new(summary).write(path) {
+            error!("Error writing the session summary to {}: {}", path.display(), e);
+        }
+    }
+}
+
+// The stdin and receive paths have no loop of their own to ping from.
+fn spawn_watchdog() {
+    if let Some(period) = systemd::watchdog_interval() {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                ping_watchdog();
+            }
+        });
+    }
+}
+
+#[cfg(unix)]
+async fn terminated() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut term) => {
+            term.recv().await;
+        }
+        Err(_) => std::future::pending().await,
+    }
+}
+
+#[cfg(windows)]
+async fn terminated() {
+    service::stop_requested().await
+}
+
+#[cfg(not(any(unix, windows)))]
+async fn terminated() {
+    std::future::pending().await
+}
+
+#[allow(clippy::too_many_arguments)]
+// Whether stdin is a regular file, such as `< file.wav`, rather than a pipe
+// or terminal.
+#[cfg(unix)]
+fn stdin_is_file() -> bool {
+    use std::os::fd::AsFd;
+    let file = std::io::stdin().as_fd().try_clone_to_owned().map(std::fs::File::from);
+    file.and_then(|file| file.metadata()).is_ok_and(|metadata| metadata.is_file())
+}
+
+#[cfg(windows)]
+fn stdin_is_file() -> bool {
+    use std::os::windows::io::AsHandle;
+    let file = std::io::stdin().as_handle().try_clone_to_owned().map(std::fs::File::from);
+    file.and_then(|file| file.metadata()).is_ok_and(|metadata| metadata.is_file())
+}
+
+async fn stream_stdin(
+    format: StdinFormat,
+    sink: Sink,
+    web: Option<WebStream>,
+    mut encoder: FrameEncoder,
+    hooks: Arc<FrameHooks>,
+    controls: Arc<Controls>,
+    active: Arc<AtomicBool>,
+    stats: Arc<StreamStats>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if format.sample_rate != SAMPLE_RATE || format.channels > CHANNELS {
+        let message = format!(
+            "Unsupported stdin format {}: expected {} Hz with at most {} channels",
+            format, SAMPLE_RATE, CHANNELS
+        );
+        return Err(Error::new(ErrorKind::Usage, message).into());
+    }
+
+    info!("Using audio input: stdin ({})", format);
+    info!("Streaming... Press Ctrl+C to stop.");
+    notify_ready("Streaming from stdin");
+    notifications::show("Streaming started", "Reading audio from stdin");
+    spawn_watchdog();
+
+    let end = sink.clone();
+    let handle = tokio::runtime::Handle::current();
+    let reader = tokio::task::spawn_blocking(move || -> std::io::Result<()> {
+        let stdin = std::io::stdin();
+        let mut reader = PcmReader::new(stdin.lock(), format, FRAMES_PER_BUFFER as usize);
+        let mut samples = Vec::new();
+        let mut buffer = Vec::new();
+        // A file reads far faster than it plays, so its blocks go out at
+        // the pace of the audio they carry; a live pipe sets its own pace.
+        let mut due = stdin_is_file().then(Instant::now);
+        while reader.read_block(&mut samples)? {
+            // Keep draining the pipe while paused or outside the schedule window,
+            // so live producers don't stall.
+            if !active.load(Ordering::Relaxed) || controls.is_paused() {
+                continue;
+            }
+            let vol = controls.state.effective();
+            encode_samples(&samples, vol, &mut buffer);
+            if let Some(due) = &mut due {
+                let now = Instant::now();
+                if *due > now {
+                    std::thread::sleep(*due - now);
+                }
+                let frames = buffer.len() / (CHANNELS as usize * 2);
+                // From now at the latest, so a pause doesn't leave a burst to catch up.
+                *due = (*due).max(now) + Duration::from_secs_f64(frames as f64 / SAMPLE_RATE as f64);
+            }
+            hooks.process_pcm(&mut buffer);
+            if let Some(web) = &web {
+                web.push(&buffer);
+            }
+            stats.record_levels(&buffer);
+            let mut closed = false;
+            encoder.encode(&buffer, |packet| {
+                let packet = match packet {
+                    Some(packet) => packet,
+                    None => return sink.skip(),
+                };
+                match handle.block_on(sink.send(packet)) {
+                    Ok(()) => stats.record_sent(packet.len()),
+                    Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => closed = true,
+                    Err(e) => {
+                        stats.record_drop();
+                        error!("Error sending audio: {}", e);
+                    }
+                }
+            });
+            stats.record_buffered(pending_audio(&encoder));
+            if closed {
+                break;
+            }
+        }
+        // The tail of the input, padded out to a whole frame.
+        encoder.finish(|packet| {
+            if let Some(packet) = packet {
+                if handle.block_on(sink.send(packet)).is_ok() {
+                    stats.record_sent(packet.len());
+                }
+            }
+        });
+        if let Err(e) = handle.block_on(sink.flush()) {
+            error!("Error sending audio: {}", e);
+        }
+        Ok(())
+    });
+
+    tokio::select! {
+        result = reader => {
+            result??;
+            info!("End of stdin input.");
+        }
+        _ = shutdown => {}
+    }
+    if let Err(e) = end.announce_end().await {
+        warn!("Error announcing the end of the stream: {}", e);
+    }
+    Ok(())
+}
+
+struct ReceiverConfig {
+    port: u16,
+    transport: Transport,
+    /// Set for SRT, with the sender's address when calling out.
+    srt: Option<(SrtOptions, String)>,
+    /// Take RTP from roc-send instead of our own packets.
+    roc: bool,
+    multicast_group: Option<std::net::Ipv4Addr>,
+    playout_delay: Option<Duration>,
+    target_latency: Duration,
+    concealment: Concealment,
+    decoder: FrameDecoder,
+    frame_frames: usize,
+    drift_compensation: bool,
+    clock: Arc<SyncedClock>,
+    /// Where senders' pings are answered.
+    control_port: u16,
+    metrics_listen: Option<std::net::SocketAddr>,
+    /// Forward the port on the router while receiving.
+    map_port: bool,
+    /// Meet the sender through a relay.
+    session: Option<Session>,
+}
+
+fn output_latency(info: &cpal::OutputCallbackInfo) -> Duration {
+    let timestamp = info.timestamp();
+    timestamp.playback.duration_since(&timestamp.callback).unwrap_or_default()
+}
+
+// A receiver answers clock requests on its control port like a sender
+// does, so a sender's --ping reaches it, and tells it what is playing.
+async fn answer_pings(port: u16, clock: Arc<SyncedClock>, probe: Arc<PlayoutProbe>) {
+    let socket = match UdpSocket::bind(("0.0.0.0", port)).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("Not answering pings on control port {}: {}", port, e);
+            return;
+        }
+    };
+    let mut buf = [0u8; 64];
+    loop {
+        let Ok((len, peer)) = socket.recv_from(&mut buf).await else {
+            continue;
+        };
+        if len == clock::TIME_REQUEST_LEN && buf[0] == clock::TIME_REQUEST {
+            let t2 = clock.now_micros();
+            if let Some(response) = clock::time_response(&buf[..len], t2, clock.now_micros()) {
+                let _ = socket.send_to(&response, peer).await;
+            }
+        } else if let Some(response) = playback::playout_response(&buf[..len], probe.playing()) {
+            let _ = socket.send_to(&response, peer).await;
+        }
+    }
+}
+
+async fn run_receiver(host: &cpal::Host, receiver: ReceiverConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let ReceiverConfig {
+        port,
+        transport,
+        srt,
+        roc,
+        multicast_group,
+        playout_delay,
+        target_latency,
+        concealment,
+        decoder,
+        frame_frames,
+        drift_compensation,
+        clock,
+        control_port,
+        metrics_listen,
+        map_port,
+        session,
+    } = receiver;
+
+    let device = match host.default_output_device() {
+        Some(d) => d,
+        None => return Err(Error::new(ErrorKind::NoDevice, "No output device found").into()),
+    };
+    info!("Using audio output: {}", device.name()?);
+
+    let frame_duration = Duration::from_secs_f64(frame_frames as f64 / SAMPLE_RATE as f64);
+    // Scheduled playout holds packets for the whole delay, so the buffer
+    // must not trim them as excess latency.
+    let buffer_latency = target_latency.max(playout_delay.unwrap_or_default());
+    let jitter_buffer = Arc::new(Mutex::new(JitterBuffer::new(frame_duration, buffer_latency)));
+    if let Some(addr) = metrics_listen {
+        let listener = std::net::TcpListener::bind(addr)?;
+        info!("Serving metrics at http://{}{}", listener.local_addr()?, metrics::METRICS_PATH);
+        metrics::serve_receiver(listener, jitter_buffer.clone(), Instant::now());
+    }
+
+    let schedule = playout_delay.map(|delay| PlayoutSchedule::new(clock.clone(), delay, SAMPLE_RATE));
+    let probe = Arc::new(PlayoutProbe::default());
+    let pings = answer_pings(control_port, clock.clone(), probe.clone());
+    tokio::spawn(pings.instrument(info_span!("control", via = "udp")));
+    let mapping = if map_port {
+        let protocol = match transport {
+            Transport::Tcp | Transport::Ws => PortProtocol::Tcp,
+            Transport::Udp | Transport::Srt => PortProtocol::Udp,
+        };
+        match tokio::task::spawn_blocking(move || PortMapping::request(protocol, port)).await? {
+            Ok(mapping) => {
+                info!(
+                    "Mapped port {} on the router with {}; senders on the internet reach this receiver at {}",
+                    port,
+                    mapping.method(),
+                    mapping.external
+                );
+                if mapping.behind_another_nat() {
+                    warn!(
+                        "The router's own address {} is private, so another router or the provider's NAT sits in \
+                         front of it and senders on the internet still can't reach this one",
+                        mapping.external.ip()
+                    );
+                }
+                Some(mapping.keep())
+            }
+            Err(e) => {
+                warn!("Could not map port {} on the router: {}", port, e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let receiver = match (srt, transport) {
+        (Some((options, remote)), _) => {
+            let socket = srt::connect(&options, &remote, port).await?;
+            info!("SRT connection established");
+            tokio::spawn(srt::receive(socket, jitter_buffer.clone(), clock))
+        }
+        (None, Transport::Tcp) => {
+            let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+            info!("Listening for audio on TCP port {}", port);
+            tokio::spawn(transport::receive_tcp(listener, jitter_buffer.clone(), clock))
+        }
+        (None, Transport::Ws) => {
+            let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+            info!("Listening for audio at ws://<this host>:{}{}", port, transport::WS_PATH);
+            tokio::spawn(transport::receive_ws(listener, jitter_buffer.clone(), clock))
+        }
+        (None, _) => {
+            let mut socket = UdpSocket::bind(format!("0.0.0.0:{}", port)).await?;
+            if let Some(group) = multicast_group {
+                socket.join_multicast_v4(group, std::net::Ipv4Addr::UNSPECIFIED)?;
+                info!("Joined multicast group {}", group);
+            }
+            if let Some(session) = &session {
+                let route = rendezvous::meet(&socket, session, Role::Receiver).await?;
+                let (audio, keepalive) = rendezvous::clone_socket(socket)?;
+                let relay = tokio::net::lookup_host(&session.relay).await?.find(|addr| addr.is_ipv4());
+                let targets = relay.into_iter().chain(matches!(route, Route::Direct(_)).then(|| route.addr()));
+                rendezvous::spawn_keepalive(keepalive, targets.collect());
+                socket = audio;
+            }
+            info!("Listening for audio on UDP port {}", port);
+            if roc {
+                let depacketizer = RocDepacketizer::new(SAMPLE_RATE, CHANNELS, frame_frames);
+                tokio::spawn(roc::receive(socket, jitter_buffer.clone(), depacketizer))
+            } else {
+                tokio::spawn(playback::receive(socket, jitter_buffer.clone(), clock))
+            }
+        }
+    };
+
+    let sample_format = device.default_output_config()?.sample_format();
+    let config = cpal::StreamConfig {
+        channels: CHANNELS,
+        sample_rate: cpal::SampleRate(SAMPLE_RATE),
+        buffer_size: cpal::BufferSize::Fixed(FRAMES_PER_BUFFER),
+    };
+    let frame_samples = frame_frames * CHANNELS as usize;
+    let mut cursor = PlaybackCursor::new(frame_samples, CHANNELS as usize, concealment, decoder);
+    cursor.set_drift_compensation(drift_compensation);
+    cursor.set_schedule(schedule);
+    let buffer_clone = jitter_buffer.clone();
+    // Only one of the callbacks below is built.
+    let callback_probe = probe;
+    let mut promoted = false;
+    let err_fn = |err| warn!("Stream error: {}", err);
 
-    /// Initial client-side volume (0.0 to 1.0)
-    #[arg(long, default_value = "1.0")]
-    volume: f32,
+    let stream = match sample_format {
+        cpal::SampleFormat::F32 => device.build_output_stream(
+            &config,
+            move |data: &mut [f32], info: &cpal::OutputCallbackInfo| {
+                realtime::promote_once(&mut promoted);
+                if let Some(schedule) = cursor.schedule_mut() {
+                    schedule.set_output_latency(output_latency(info));
+                }
+                callback_probe.record(cursor.next_timestamp(SAMPLE_RATE), Instant::now() + output_latency(info));
+                cursor.fill_f32(&buffer_clone, data);
+            },
+            err_fn,
+            None,
+        )?,
+        cpal::SampleFormat::I16 => device.build_output_stream(
+            &config,
+            move |data: &mut [i16], info: &cpal::OutputCallbackInfo| {
+                realtime::promote_once(&mut promoted);
+                if let Some(schedule) = cursor.schedule_mut() {
+                    schedule.set_output_latency(output_latency(info));
+                }
+                callback_probe.record(cursor.next_timestamp(SAMPLE_RATE), Instant::now() + output_latency(info));
+                cursor.fill_i16(&buffer_clone, data);
+            },
+            err_fn,
+            None,
+        )?,
+        _ => {
+            let message = format!("Unsupported sample format: {:?}", sample_format);
+            return Err(Error::new(ErrorKind::Device, message).into());
+        }
+    };
+    stream.play()?;
+    info!("Playing... Press Ctrl+C to stop.");
+    notify_ready(&format!("Playing from port {}", port));
+    spawn_watchdog();
 
-    /// Port to listen for server control messages
-    #[arg(long, default_value = "8081")]
-    control_port: u16,
+    // Log each loss burst once it ends, with when it began, so glitches can
+    // be lined up with Wi-Fi roaming and other network events.
+    let loss_buffer = jitter_buffer.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(LOSS_LOG_INTERVAL);
+        loop {
+            interval.tick().await;
+            let losses = loss_buffer.lock().unwrap().take_losses();
+            for loss in losses {
+                let started = chrono::DateTime::<chrono::Local>::from(loss.started);
+                warn!(
+                    start_seq = loss.start_seq,
+                    length = loss.length,
+                    duration_ms = loss.duration.as_millis() as u64,
+                    started = %started.to_rfc3339_opts(chrono::SecondsFormat::Millis, false),
+                    "Lost {} packets from seq {}, {} ms of audio",
+                    loss.length,
+                    loss.start_seq,
+                    loss.duration.as_millis()
+                );
+            }
+        }
+    });
 
-    /// List available audio input devices and exit
-    #[arg(long)]
-    list_devices: bool,
+    // Periodically log buffer statistics
+    let stats_buffer = jitter_buffer.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(10));
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let jb = stats_buffer.lock().unwrap();
+            let stats = jb.stats();
+            info!(
+                "Jitter buffer - Depth: {}/{}, Jitter: {:.1}ms, Missing: {}, Late: {}, Underruns: {}, Trimmed: {}, Recovered: {}{}{}",
+                jb.depth(),
+                jb.target_depth(),
+                jb.jitter().as_secs_f64() * 1000.0,
+                stats.missing,
+                stats.late,
+                stats.underruns,
+                stats.trimmed,
+                stats.recovered,
+                jb.transit()
+                    .map(|t| format!(", Transit: {:.1}ms", t.as_secs_f64() * 1000.0))
+                    .unwrap_or_default(),
+                arrival_percentiles(jb.arrivals())
+            );
+        }
+    });
 
-    /// Name of the audio input device to use
-    #[arg(long)]
-    device_name: Option<String>,
+    tokio::select! {
+        result = receiver => result??,
+        result = tokio::signal::ctrl_c() => result?,
+        _ = terminated() => {}
+    }
+    let _ = systemd::notify("STOPPING=1");
+    stream.pause()?;
+    if let Some(mapping) = mapping {
+        tokio::task::spawn_blocking(move || mapping.release()).await?;
+    }
+    Ok(())
+}
 
-    /// Index of the audio input device to use
-    #[arg(long)]
-    device_index: Option<usize>,
+fn main() {
+    if let Err(e) = run() {
+        let kind = ErrorKind::of(e.as_ref());
+        if JSON_ERRORS.load(Ordering::Relaxed) {
+            eprintln!("{}", error::to_json(e.as_ref()));
+        } else {
+            eprintln!("Error: {}", e);
+        }
+        std::process::exit(kind.exit_code());
+    }
+}
+
+fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = parse_args(std::env::args_os()).unwrap_or_else(|e| e.exit());
+    let mut log = cli.log;
+    JSON_ERRORS.store(log.error_format == ErrorFormat::Json, Ordering::Relaxed);
+    let mut config_path = cli.config.clone().or_else(|| config::default_path().filter(|path| path.exists()));
+    let profile = cli.profile.clone();
+    let (mut args, subcommand, as_service): (_, &[&str], _) = match cli.command {
+        None => (cli.stream, &[], false),
+        Some(Command::Stream(args)) => (*args, &["stream"], false),
+        Some(Command::Service {
+            action: ServiceAction::Run(args),
+        }) => (*args, &["service", "run"], true),
+        Some(command) => {
+            init_logging(log, false)?;
+            return tokio::runtime::Runtime::new()?.block_on(run_command(command, config_path, profile));
+        }
+    };
+    if profile.is_some() && config_path.is_none() {
+        return Err(Error::new(ErrorKind::Usage, "--profile needs a config file; name one with --config").into());
+    }
+
+    let mut file_config = Config::default();
+    if let Some(path) = &config_path {
+        let mut command_line: Vec<_> = std::env::args_os().collect();
+        let flags_at = stream_flags_at(&command_line, subcommand);
+        let in_file = |e: std::io::Error| Error::new(ErrorKind::Config, format!("{}: {}", path.display(), e));
+        let from_env = |long: &str| std::env::var_os(env_var(long)).is_some();
+        // Servers on the command line replace the file's rather than becoming their backups.
+        let on_command_line = |long: &str| {
+            let flag = format!("--{}", long);
+            command_line[flags_at..].iter().any(|arg| {
+                let arg = arg.to_string_lossy();
+                arg == flag || arg.starts_with(&format!("{}=", flag))
+            })
+        };
+        let skip = |long: &str| from_env(long) || (long == "server" && on_command_line(long));
+        let file_args = config::load_args(path, profile.as_deref(), skip).map_err(in_file)?;
+        file_config = config::load(path, profile.as_deref()).map_err(in_file)?;
+        // The file's flags go ahead of the command line's stream flags.
+        command_line.splice(flags_at..flags_at, file_args);
+        let cli = parse_args(command_line).unwrap_or_else(|e| {
+            eprintln!("With the settings from {}:", path.display());
+            e.exit()
+        });
+        log = cli.log;
+        JSON_ERRORS.store(log.error_format == ErrorFormat::Json, Ordering::Relaxed);
+        args = match cli.command {
+            Some(Command::Stream(args) | Command::Service {
+                action: ServiceAction::Run(args),
+            }) => *args,
+            _ => cli.stream,
+        };
+    }
+    // After the config file, which can set the level too.
+    init_logging(log, args.tui)?;
+    apply_preset(&mut args)?;
+
+    if args.volume < 0.0 || args.volume > 1.0 {
+        return Err(Error::new(ErrorKind::Usage, "Volume must be between 0.0 and 1.0").into());
+    }
+
+    if args.notifications {
+        notifications::enable()?;
+    }
+    if args.realtime {
+        realtime::enable();
+    }
+    if args.tui && !cfg!(feature = "tui") {
+        return Err(Error::new(ErrorKind::Usage, "--tui needs a build with --features tui").into());
+    }
+    if args.stats_interval.is_some_and(|period| period.is_zero()) {
+        return Err(Error::new(ErrorKind::Usage, "--stats-interval must be longer than zero").into());
+    }
+    absolute_paths(&mut args, &mut config_path)?;
+
+    // Forking has to happen before the runtime starts its threads. The PID
+    // file is removed when the client stops.
+    let _pid_file = pid_file(&args)?;
+    if as_service {
+        return run_service(args, config_path, profile, file_config);
+    }
+    if args.tray {
+        return run_with_tray(args, config_path, profile, file_config);
+    }
+    let stream = run_stream(args, config_path, profile, file_config);
+    tokio::runtime::Runtime::new()?.block_on(stream)
+}
+
+// Fills in the settings --preset stands for, leaving those given as flags
+// or in the config file.
+fn apply_preset(args: &mut Args) -> Result<(), Box<dyn std::error::Error>> {
+    match args.preset {
+        None => {}
+        Some(Preset::LowBandwidth) => {
+            if args.codec == Some(Codec::Pcm) {
+                return Err(Error::new(ErrorKind::Usage, "--preset low-bandwidth sends Opus, not --codec pcm").into());
+            }
+            args.codec = Some(Codec::Opus);
+            args.opus_mono = true;
+            args.opus_dtx = true;
+            args.opus_sample_rate.get_or_insert(24_000);
+            args.opus_bitrate.get_or_insert(32);
+        }
+        Some(Preset::LosslessLan) => {
+            if args.codec == Some(Codec::Opus) {
+                return Err(Error::new(ErrorKind::Usage, "--preset lossless-lan sends PCM, not --codec opus").into());
+            }
+            args.redundancy = true;
+        }
+    }
+    Ok(())
 }
 
-const SAMPLE_RATE: u32 = 48000;
-const CHANNELS: u16 = 2;
-const FRAMES_PER_BUFFER: u32 = 512;
-const SERVER_AUDIO_PORT: u16 = 8080;
+// How --latency-ms is shared out for the codec in use, if it is given.
+fn latency_budget(args: &Args) -> Result<Option<LatencyBudget>, Box<dyn std::error::Error>> {
+    let Some(ms) = args.latency_ms else {
+        return Ok(None);
+    };
+    let Some(budget) = LatencyBudget::split(Duration::from_millis(ms), args.codec()) else {
+        let message = format!(
+            "--latency-ms {} leaves the receiver no room to buffer; use at least 5, or 26 with --codec opus",
+            ms
+        );
+        return Err(Error::new(ErrorKind::Usage, message).into());
+    };
+    info!(
+        "Latency budget of {} ms: {}-frame capture buffers, packets of up to {} frames and {:.1} ms of buffering \
+         at the receiver",
+        ms,
+        budget.buffer_frames,
+        budget.packet_frames,
+        budget.jitter.as_secs_f64() * 1000.0
+    );
+    Ok(Some(budget))
+}
 
+fn opus_sample_rate(value: &str) -> Result<u32, String> {
+    match value.parse() {
+        Ok(rate @ (8000 | 12000 | 16000 | 24000 | 48000)) => Ok(rate),
+        _ => Err("Opus takes 8000, 12000, 16000, 24000 or 48000".to_string()),
+    }
+}
 
+// Logs go to stderr, which --daemonize points at /dev/null, or into the
+// dashboard's log pane while it shows. --log-level sets the client's own
+// level, with dependencies kept to warnings; RUST_LOG takes a full filter
+// such as "audio_client=debug,mdns_sd=info".
+fn init_logging(log: LogArgs, dashboard: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let filter = match log.log_level {
+        Some(level) => EnvFilter::new(format!("warn,audio_client={}", level)),
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn,audio_client=info")),
+    };
+    let writer = match &log.log_file {
+        Some(path) => {
+            let rotation = match log.log_rotate {
+                None => Rotation::Never,
+                Some(LogRotate::Daily) => Rotation::Daily,
+                Some(LogRotate::Size) => Rotation::Size(log.log_max_size * 1024 * 1024),
+            };
+            let in_file = |e: std::io::Error| format!("{}: {}", path.display(), e);
+            let file = LogFile::open(path, rotation).map_err(in_file)?;
+            BoxMakeWriter::new(Mutex::new(file))
         }
+        #[cfg(feature = "tui")]
+        None if dashboard => BoxMakeWriter::new(|| audio_client::tui::LogLines),
+        None => BoxMakeWriter::new(std::io::stderr),
+    };
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(writer)
+        .with_env_filter(filter)
+        .with_ansi(log.log_file.is_none() && !dashboard);
+    match log.log_format {
+        LogFormat::Text => subscriber.with_target(false).init(),
+        // The target is the module the message came from.
+        LogFormat::Json => subscriber.json().init(),
     }
-    None
+    Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
+#[cfg(unix)]
+fn pid_file(args: &Args) -> Result<Option<daemon::PidFile>, Box<dyn std::error::Error>> {
+    let path = match (&args.pid_file, args.daemonize) {
+        (Some(path), _) => path.clone(),
+        (None, true) => daemon::default_pid_path(),
+        (None, false) => return Ok(None),
+    };
+    let in_file = |e: std::io::Error| format!("{}: {}", path.display(), e);
+    let pid_file = if args.daemonize {
+        daemon::daemonize(&path)
+    } else {
+        daemon::PidFile::create(&path)
+    };
+    Ok(Some(pid_file.map_err(in_file)?))
+}
 
-    if args.volume < 0.0 || args.volume > 1.0 {
-        eprintln!("Volume must be between 0.0 and 1.0");
-        std::process::exit(1);
+// Makes the paths the stream uses after starting absolute, as --daemonize
+// leaves the current directory: the PID file, which is removed on exit,
+// the IPC socket, the output files and the config file, which is watched.
+fn absolute_paths(args: &mut Args, config_path: &mut Option<std::path::PathBuf>) -> std::io::Result<()> {
+    let paths = [
+        &mut args.stats_file,
+        &mut args.summary_file,
+        &mut args.pid_file,
+        &mut args.ipc_path,
+        config_path,
+    ];
+    for path in paths.into_iter().flatten() {
+        *path = std::env::current_dir()?.join(&path);
     }
+    Ok(())
+}
 
-    let host = cpal::default_host();
-    let devices: Vec<_> = host.devices()?.collect();
+#[cfg(not(unix))]
+fn pid_file(args: &Args) -> Result<Option<()>, Box<dyn std::error::Error>> {
+    if args.daemonize || args.pid_file.is_some() {
+        return Err(Error::new(ErrorKind::Usage, "--daemonize and --pid-file are only supported on Unix").into());
+    }
+    Ok(None)
+}
 
-    if args.list_devices {
-        println!("Available Audio Input Devices:");
-        for (i, device) in devices.iter().enumerate() {
-            if let Ok(configs) = device.supported_input_configs() {
-                if configs.count() > 0 {
-                    if let Ok(name) = device.name() {
-                        println!("  [{}] {} (Host: {})", i, name, host.id().name());
-                    }
-                }
+// Runs the subcommands other than stream.
+async fn run_command(
+    command: Command,
+    config_path: Option<std::path::PathBuf>,
+    profile: Option<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        Command::Stream(_) => unreachable!("streaming is handled in main"),
+        Command::ListDevices { format } => list_devices(&cpal::default_host(), format),
+        Command::Discover => discover(),
+        Command::Record {
+            output,
+            device_name,
+            device_index,
+            duration,
+        } => record(&output, &device_name, device_index, duration).await,
+        Command::Test {
+            server,
+            duration,
+            frequency,
+        } => send_test_tone(&server, frequency, duration).await,
+        Command::SelfTest { codec, transport } => self_test(codec, transport).await,
+        Command::Relay { port } => run_relay(port).await,
+        Command::Bench { buffers } => run_bench(buffers),
+        Command::ListProfiles => {
+            let no_file = || Error::new(ErrorKind::Config, "There is no config file; name one with --config");
+            let path = config_path.ok_or_else(no_file)?;
+            let in_file = |e: std::io::Error| Error::new(ErrorKind::Config, format!("{}: {}", path.display(), e));
+            for name in config::profile_names(&path).map_err(in_file)? {
+                println!("{}", name);
             }
+            Ok(())
         }
-        return Ok(());
+        Command::Probe {
+            device_name,
+            device_index,
+        } => probe_device(&cpal::default_host(), device_index, device_name.as_deref()),
+        Command::Ctl { target, action } => run_ctl(&target, action).await,
+        Command::GenerateSystemdUnit { flags } => generate_systemd_unit(flags, config_path, profile),
+        Command::Service { action } => service_command(action, config_path, profile),
+        Command::Stop { pid_file } => stop_daemon(pid_file),
     }
+}
 
-    let selected_device = select_device(&devices, args.device_index, args.device_name.as_deref());
+#[cfg(windows)]
+fn service_command(
+    action: ServiceAction,
+    config_path: Option<std::path::PathBuf>,
+    profile: Option<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match action {
+        ServiceAction::Install { flags } => {
+            let mut command_line = stream_command_line(&["service", "run"], flags, config_path, profile)?;
+            let program = command_line.remove(0);
+            service::install(program, command_line)?;
+            eprintln!("Installed the {} service; start it with: sc start {0}", service::SERVICE_NAME);
+        }
+        ServiceAction::Uninstall => {
+            service::uninstall()?;
+            eprintln!("Removed the {} service", service::SERVICE_NAME);
+        }
+        ServiceAction::Run(_) => unreachable!("the service runs from main"),
+    }
+    Ok(())
+}
 
-    let device = match selected_device {
-        Some(d) => d,
-        None => {
-            eprintln!("No suitable input device found");
-            std::process::exit(1);
+#[cfg(not(windows))]
+fn service_command(
+    _: ServiceAction,
+    _: Option<std::path::PathBuf>,
+    _: Option<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    Err("services are only supported on Windows; on Linux, see generate-systemd-unit".into())
+}
+
+// The tray's event loop takes the main thread, so the stream runs on
+// another.
+#[cfg(feature = "tray")]
+fn run_with_tray(
+    args: Args,
+    config_path: Option<std::path::PathBuf>,
+    profile: Option<String>,
+    file_config: Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    audio_client::tray::run(move || {
+        let result = tokio::runtime::Runtime::new()
+            .map_err(Into::into)
+            .and_then(|runtime| runtime.block_on(run_stream(args, config_path, profile, file_config)));
+        match result {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                1
+            }
+        }
+    })
+}
+
+#[cfg(not(feature = "tray"))]
+fn run_with_tray(
+    _: Args,
+    _: Option<std::path::PathBuf>,
+    _: Option<String>,
+    _: Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    Err("--tray needs a build with --features tray".into())
+}
+
+// Streams under the Service Control Manager until it stops the service.
+#[cfg(windows)]
+fn run_service(
+    args: Args,
+    config_path: Option<std::path::PathBuf>,
+    profile: Option<String>,
+    file_config: Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    service::run(move || {
+        let runtime = tokio::runtime::Runtime::new().map_err(|e| e.to_string())?;
+        let stream = run_stream(args, config_path, profile, file_config);
+        runtime.block_on(stream).map_err(|e| e.to_string())
+    })?;
+    Ok(())
+}
+
+#[cfg(not(windows))]
+fn run_service(
+    _: Args,
+    _: Option<std::path::PathBuf>,
+    _: Option<String>,
+    _: Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    Err("services are only supported on Windows".into())
+}
+
+// The command line a service manager runs: this binary, `subcommand`,
+// the config file and profile if there are any, and `flags`. The flags are
+// checked first so the service does not fail at boot.
+fn stream_command_line(
+    subcommand: &[&str],
+    flags: Vec<std::ffi::OsString>,
+    config_path: Option<std::path::PathBuf>,
+    profile: Option<String>,
+) -> Result<Vec<std::ffi::OsString>, Box<dyn std::error::Error>> {
+    let mut command_line = vec![std::env::current_exe()?.into_os_string()];
+    command_line.extend(subcommand.iter().map(std::ffi::OsString::from));
+    if let Some(path) = config_path {
+        command_line.extend(["--config".into(), std::env::current_dir()?.join(path).into_os_string()]);
+    }
+    if let Some(profile) = profile {
+        command_line.extend(["--profile".into(), profile.into()]);
+    }
+    command_line.extend(flags);
+    let cli = parse_args(&command_line).unwrap_or_else(|e| e.exit());
+    if let Some(Command::Stream(args) | Command::Service {
+        action: ServiceAction::Run(args),
+    }) = cli.command
+    {
+        if args.daemonize {
+            let message = "the service manager keeps the client in the foreground; leave out --daemonize";
+            return Err(Error::new(ErrorKind::Usage, message).into());
         }
+    }
+    Ok(command_line)
+}
+
+// Prints a unit whose ExecStart runs this binary's stream command with
+// `flags`.
+fn generate_systemd_unit(
+    flags: Vec<std::ffi::OsString>,
+    config_path: Option<std::path::PathBuf>,
+    profile: Option<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let command_line = stream_command_line(&["stream"], flags, config_path, profile)?;
+    let command: Vec<_> = command_line
+        .into_iter()
+        .map(|arg| arg.into_string().map_err(|arg| format!("{} is not valid UTF-8", arg.to_string_lossy())))
+        .collect::<Result<_, _>>()?;
+    print!("{}", systemd::unit_file(&command));
+    Ok(())
+}
+
+#[cfg(unix)]
+fn stop_daemon(pid_file: Option<std::path::PathBuf>) -> Result<(), Box<dyn std::error::Error>> {
+    let path = pid_file.unwrap_or_else(daemon::default_pid_path);
+    match daemon::stop(&path, DAEMON_STOP_TIMEOUT).map_err(|e| format!("{}: {}", path.display(), e))? {
+        Some(pid) => eprintln!("Stopped the client (process {})", pid),
+        None => return Err(Error::new(ErrorKind::Control, "The client is not running").into()),
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn stop_daemon(_: Option<std::path::PathBuf>) -> Result<(), Box<dyn std::error::Error>> {
+    Err("stop is only supported on Unix".into())
+}
+
+// Captures or receives audio until the session ends.
+async fn run_stream(
+    args: Args,
+    config_path: Option<std::path::PathBuf>,
+    profile: Option<String>,
+    file_config: Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let host = cpal::default_host();
+    let opus_options = OpusOptions {
+        fec: args.opus_fec,
+        dtx: args.opus_dtx,
+        bitrate: args.opus_bitrate.map(|kbps| kbps * 1000),
+        mono: args.opus_mono,
+        sample_rate: args.opus_sample_rate,
     };
+    let latency = latency_budget(&args)?;
+    let buffer_frames = latency.map_or(args.buffer_frames, |budget| budget.buffer_frames);
+
+    let clock = Arc::new(SyncedClock::new());
+    if let Some(server) = args.ntp_server.clone() {
+        tokio::spawn(clock::discipline(clock.clone(), server).instrument(info_span!("clock")));
+    }
+    if let Some(master) = args.sync_with.clone() {
+        tokio::spawn(clock::lan_sync(clock.clone(), master).instrument(info_span!("clock")));
+    }
 
-    println!("Using audio input: {}", device.name()?);
+    let srt_options = |default_mode| SrtOptions {
+        mode: args.srt_mode.unwrap_or(default_mode),
+        latency: Duration::from_millis(args.srt_latency_ms),
+        passphrase: args.srt_passphrase.clone(),
+    };
 
-    let config = device.default_input_config()?;
-    let sample_format = config.sample_format();
-    let config = cpal::StreamConfig {
-        channels: CHANNELS,
-        sample_rate: cpal::SampleRate(SAMPLE_RATE),
-        buffer_size: cpal::BufferSize::Fixed(FRAMES_PER_BUFFER),
+    // The first server is the one streamed to; the others are its backups.
+    let primary = args.server.first().cloned().unwrap_or_else(|| "127.0.0.1".to_string());
+    let rendezvous_session = args.relay.clone().zip(args.session.clone()).map(|(relay, code)| Session { relay, code });
+    if rendezvous_session.is_some()
+        && (args.transport != Transport::Udp
+            || args.protocol != Protocol::Native
+            || args.multicast_group.is_some()
+            || (args.listen.is_none() && args.sink != SinkKind::Udp))
+    {
+        let message = "--relay needs --sink udp and --transport udp with the native protocol, and no --multicast-group";
+        return Err(Error::new(ErrorKind::Usage, message).into());
+    }
+    if let Some(port) = args.listen {
+        if args.server.len() > 1 {
+            return Err(Error::new(ErrorKind::Usage, "--listen takes one --server").into());
+        }
+        if args.protocol == Protocol::Vban {
+            return Err(Error::new(ErrorKind::Usage, "--listen receives the native or roc protocol").into());
+        }
+        if args.protocol == Protocol::Roc && (args.transport != Transport::Udp || args.codec() != Codec::Pcm) {
+            return Err(Error::new(ErrorKind::Usage, "--protocol roc needs --transport udp and --codec pcm").into());
+        }
+        if args.transport != Transport::Udp && args.multicast_group.is_some() {
+            return Err(Error::new(ErrorKind::Usage, "--multicast-group needs --transport udp").into());
+        }
+        if args.playout_delay_ms.is_some() && args.ntp_server.is_none() && args.sync_with.is_none() {
+            warn!("--playout-delay-ms without --ntp-server or --sync-with relies on the system clock being in sync");
+        }
+        let receiver = ReceiverConfig {
+            port,
+            transport: args.transport,
+            srt: (args.transport == Transport::Srt)
+                .then(|| (srt_options(SrtMode::Listener), format!("{}:{}", primary, port))),
+            roc: args.protocol == Protocol::Roc,
+            multicast_group: args.multicast_group,
+            playout_delay: args.playout_delay_ms.map(Duration::from_millis),
+            target_latency: latency.map_or(Duration::from_millis(args.target_latency_ms), |budget| budget.jitter),
+            concealment: args.concealment,
+            decoder: FrameDecoder::new(args.codec(), SAMPLE_RATE, CHANNELS, opus_options)?,
+            frame_frames: match latency {
+                Some(budget) => budget.packet_frames as usize,
+                None => args.codec().frame_frames().unwrap_or(FRAMES_PER_BUFFER as usize),
+            },
+            drift_compensation: !args.no_drift_compensation,
+            clock,
+            control_port: args.control_port,
+            metrics_listen: args.metrics_listen,
+            map_port: args.map_port,
+            session: rendezvous_session.clone(),
+        };
+        return run_receiver(&host, receiver).await;
+    }
+
+    if args.list_devices {
+        return list_devices(&host, ListFormat::Text);
+    }
+
+    if let Some(ScheduleTime::At(stop)) = args.stop_at {
+        if stop <= chrono::Local::now() {
+            return Err(Error::new(ErrorKind::Usage, "--stop-at is in the past").into());
+        }
+    }
+
+    if args.codec() != Codec::Pcm && args.sink.is_raw_pcm() {
+        let message = format!("--sink {} takes raw PCM; use --codec pcm", args.sink);
+        return Err(Error::new(ErrorKind::Usage, message).into());
+    }
+    if args.transport != Transport::Udp && args.sink != SinkKind::Udp {
+        return Err(Error::new(ErrorKind::Usage, "--transport srt, tcp and ws only apply to --sink udp").into());
+    }
+    if args.batch_packets > 1 && args.sink != SinkKind::Udp {
+        return Err(Error::new(ErrorKind::Usage, "--batch-packets only applies to --sink udp").into());
+    }
+    if (args.dscp.is_some() || args.so_sndbuf.is_some() || args.bind.is_some())
+        && (args.sink != SinkKind::Udp || args.transport == Transport::Srt || args.protocol != Protocol::Native)
+    {
+        let message = "--dscp, --so-sndbuf and --bind apply to --sink udp with the native protocol over the udp, tcp \
+                       or ws transport";
+        return Err(Error::new(ErrorKind::Usage, message).into());
+    }
+    if args.proxy.is_some()
+        && (args.sink != SinkKind::Udp
+            || !matches!(args.transport, Transport::Tcp | Transport::Ws)
+            || args.protocol != Protocol::Native)
+    {
+        let message = "--proxy applies to --sink udp with the native protocol over the tcp or ws transport";
+        return Err(Error::new(ErrorKind::Usage, message).into());
+    }
+    if args.pace && (args.sink != SinkKind::Udp || args.protocol != Protocol::Native) {
+        return Err(Error::new(ErrorKind::Usage, "--pace only applies to --sink udp with the native protocol").into());
+    }
+    if args.pace && args.batch_packets > 1 {
+        return Err(Error::new(ErrorKind::Usage, "--pace spreads packets out and --batch-packets gathers them").into());
+    }
+    if args.fan_out
+        && (args.sink != SinkKind::Udp
+            || args.transport == Transport::Srt
+            || args.protocol != Protocol::Native
+            || rendezvous_session.is_some())
+    {
+        let message = "--fan-out needs --sink udp with the native protocol over the udp, tcp or ws transport, and no \
+                       --relay";
+        return Err(Error::new(ErrorKind::Usage, message).into());
+    }
+    if args.server.len() > 1
+        && !args.fan_out
+        && (args.sink != SinkKind::Udp
+            || args.transport != Transport::Udp
+            || args.protocol != Protocol::Native
+            || rendezvous_session.is_some())
+    {
+        let message = "backup servers need --sink udp and --transport udp with the native protocol, and no --relay";
+        return Err(Error::new(ErrorKind::Usage, message).into());
+    }
+    let (vban, roc) = (args.protocol == Protocol::Vban, args.protocol == Protocol::Roc);
+    if (vban || roc) && (args.sink != SinkKind::Udp || args.transport != Transport::Udp || args.codec() != Codec::Pcm) {
+        let name = if vban { "vban" } else { "roc" };
+        let message = format!("--protocol {} needs --sink udp, --transport udp and --codec pcm", name);
+        return Err(Error::new(ErrorKind::Usage, message).into());
+    }
+    let impairment = Impairment {
+        loss: args.simulate_loss.unwrap_or_default(),
+        jitter: args.simulate_jitter.unwrap_or_default(),
+        reorder: args.simulate_reorder.unwrap_or_default(),
+        seed: args.simulate_seed.unwrap_or_else(impair::random_seed),
+    };
+    if impairment.is_active() {
+        if args.sink != SinkKind::Udp || vban || roc {
+            let message = "--simulate-loss, --simulate-jitter and --simulate-reorder only apply to --sink udp";
+            return Err(Error::new(ErrorKind::Usage, message).into());
+        }
+        warn!(
+            "Simulating {:.1}% loss, {:?} jitter and {:.1}% reordering (--simulate-seed {})",
+            impairment.loss * 100.0,
+            impairment.jitter,
+            impairment.reorder * 100.0,
+            impairment.seed
+        );
+    }
+    if args.tui && (args.source == SourceKind::Stdin || !std::io::stderr().is_terminal()) {
+        let message = "--tui needs a terminal and an input device, so it does not apply to --source stdin";
+        return Err(Error::new(ErrorKind::Usage, message).into());
+    }
+    let encoder = FrameEncoder::new(args.codec(), SAMPLE_RATE, CHANNELS, opus_options)?;
+
+    let audio_port = match args.protocol {
+        Protocol::Native => SERVER_AUDIO_PORT,
+        Protocol::Vban => VBAN_DEFAULT_PORT,
+        Protocol::Roc => ROC_DEFAULT_PORT,
+    };
+    let server_addr = format!("{}:{}", primary, audio_port);
+    if args.dry_run {
+        if !dry_run(&args, &host, &server_addr, buffer_frames).await {
+            return Err("Some checks failed".into());
+        }
+        return Ok(());
+    }
+    let sink_options = SinkOptions {
+        redundancy: args.redundancy,
+        clock: args.timestamps.then(|| clock.clone()),
+        transport: args.transport,
+        srt: (args.transport == Transport::Srt).then(|| srt_options(SrtMode::Caller)),
+        vban_stream: vban.then(|| args.stream_name.clone()),
+        roc,
+        impairment,
+        batch_packets: args.batch_packets as usize,
+        socket: SocketOptions {
+            bind: args.bind,
+            dscp: args.dscp,
+            send_buffer: args.so_sndbuf.map(|bytes| bytes as usize),
+        },
+        proxy: args.proxy.clone(),
+        rendezvous: rendezvous_session.clone(),
+        fan_out: if args.fan_out {
+            args.server.iter().skip(1).map(|server| format!("{}:{}", server, audio_port)).collect()
+        } else {
+            Vec::new()
+        },
+        pace: args.pace,
+        reconnect: !args.no_reconnect,
+    };
+    let sink = Sink::connect(args.sink.clone(), &server_addr, sink_options).await?;
+    let transport = sink.transport();
+    let web = match args.http_listen {
+        Some(addr) => {
+            let web = WebStream::serve(std::net::TcpListener::bind(addr)?, args.http_format, SAMPLE_RATE, CHANNELS)?;
+            info!("Serving audio at http://{0}/stream and a player page at http://{0}/", web.local_addr());
+            Some(web)
+        }
+        None => None,
     };
 
-    let volume = Arc::new(Mutex::new(args.volume));
-    let server_addr = format!("{}:{}", args.server, SERVER_AUDIO_PORT);
-    let socket = UdpSocket::bind("0.0.0.0:0").await?;
-    socket.connect(&server_addr).await?;
+    let stats = Arc::new(StreamStats::default());
+    if args.notifications {
+        tokio::spawn(watch_reachability(stats.clone(), server_addr.clone()));
+    }
+    let mut controls = Controls::new(ControlState::new(args.volume), sink.clone(), stats.clone());
+    controls.state.set_muted(args.muted);
+    let mut device_switches = controls.accept_device_switches();
+    let controls = Arc::new(controls);
+    {
+        let controls = controls.clone();
+        let follow = async move { controls.follow_transport().await };
+        tokio::spawn(follow.instrument(info_span!("control", via = "transport")));
+    }
+    if let Some(addr) = args.metrics_listen {
+        let listener = std::net::TcpListener::bind(addr)?;
+        info!("Serving metrics at http://{}{}", listener.local_addr()?, metrics::METRICS_PATH);
+        metrics::serve(listener, controls.clone(), stats.clone(), Instant::now());
+    }
+    if args.server.len() > 1 && !args.fan_out {
+        let servers = args.server.clone();
+        let failover = failover::run(servers, audio_port, args.control_port, args.failover_after, controls.clone());
+        tokio::spawn(failover.instrument(info_span!("failover")));
+    }
+    if let Some(peer) = args.ping.clone() {
+        // Stamped packets let the receiver say how far behind it plays.
+        let playout_clock = args.timestamps.then(|| clock.clone());
+        tokio::spawn(rtt::ping(peer, stats.clone(), playout_clock).instrument(info_span!("ping")));
+    }
+    // Pausing the service pauses the stream, as the pause command does.
+    #[cfg(windows)]
+    if service::is_service() {
+        let controls = controls.clone();
+        let mut paused = service::watch_paused();
+        tokio::spawn(async move {
+            while paused.changed().await.is_ok() {
+                let pause = *paused.borrow_and_update();
+                controls.apply(control::Message::Pause(pause)).await;
+            }
+        });
+    }
+    let reload = config_path.clone().map(|path| {
+        let reload = Arc::new(tokio::sync::Notify::new());
+        let profile = profile.clone();
+        let watch = config::watch(path, profile, file_config, audio_port, controls.clone(), reload.clone());
+        let watch = async move {
+            if let Err(e) = watch.await {
+                error!("Error watching the configuration file: {}", e);
+            }
+        };
+        tokio::spawn(watch.instrument(info_span!("config")));
+        reload
+    });
+    // Only SIGHUP on Unix asks for a reload besides the file watcher.
+    #[cfg(not(unix))]
+    let _ = reload;
+    if !args.no_ipc {
+        let path = args.ipc_path.clone().unwrap_or_else(ipc::default_path);
+        let controls = controls.clone();
+        let serve = async move {
+            if let Err(e) = ipc::serve(path, controls).await {
+                error!("Error serving the local control socket: {}", e);
+            }
+        };
+        tokio::spawn(serve.instrument(info_span!("control", via = "ipc")));
+    }
+    if let Some(port) = args.osc_port {
+        let socket = UdpSocket::bind(("0.0.0.0", port)).await?;
+        info!("OSC control listener started on :{}", port);
+        tokio::spawn(osc::serve(socket, controls.clone()).instrument(info_span!("control", via = "osc")));
+    }
+    if let Some(broker) = args.mqtt_broker.clone() {
+        let broker = if broker.contains(':') {
+            broker
+        } else {
+            format!("{}:{}", broker, MQTT_DEFAULT_PORT)
+        };
+        let options = MqttOptions {
+            broker,
+            topic: args.mqtt_topic.clone(),
+            client_id: format!("audio-client-{}", std::process::id()),
+        };
+        tokio::spawn(mqtt::run(options, controls.clone()).instrument(info_span!("control", via = "mqtt")));
+    }
+    #[cfg(unix)]
+    {
+        let controls = controls.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_signals(controls, reload).await {
+                error!("Error installing signal handlers: {}", e);
+            }
+        });
+    }
+    #[cfg(all(feature = "mpris", target_os = "linux"))]
+    {
+        let controls = controls.clone();
+        tokio::spawn(async move {
+            if let Err(e) = audio_client::mpris::serve(controls).await {
+                warn!("MPRIS player unavailable: {}", e);
+            }
+        });
+    }
+    let control_state = controls.clone();
+    let control_clock = clock.clone();
+    let control_port = args.control_port;
 
-    let socket_clone = socket.clone();
-    let volume_clone = volume.clone();
+    let active = Arc::new(AtomicBool::new(args.start_at.is_none()));
+    let (began, started) = tokio::sync::watch::channel(None);
+    let schedule = tokio::spawn(schedule::run(args.start_at, args.stop_at, active.clone(), began));
+    let shutdown = shutdown_signal(schedule, args.duration, started.clone());
+    // Counted from when streaming began, which --start-at can put off.
+    let elapsed = move || started.borrow().map_or(Duration::ZERO, |began| began.elapsed());
 
     // Control listener
-    tokio::spawn(async move {
-        let control_addr = format!("0.0.0.0:{}", args.control_port);
-        let control_socket = match UdpSocket::bind(&control_addr).await {
+    let listen = async move {
+        let control_addr = format!("0.0.0.0:{}", control_port);
+        // A socket from systemd socket activation takes the place of the port.
+        let bound = match systemd::activated_udp_socket() {
+            Some(socket) => socket.set_nonblocking(true).and_then(|()| UdpSocket::from_std(socket)),
+            None => UdpSocket::bind(&control_addr).await,
+        };
+        let control_socket = match bound {
             Ok(s) => s,
             Err(e) => {
-                eprintln!("Error binding control socket: {}", e);
+                error!("Error binding control socket: {}", e);
                 return;
             }
         };
 
-        println!("Client control listener started on :{}", args.control_port);
+        let port = control_socket.local_addr().map_or(control_port, |addr| addr.port());
+        info!("Client control listener started on :{}", port);
 
-        let mut buf = [0u8; 8];
+        let mut buf = [0u8; 512];
+        let mut replies = ReplyCache::default();
         loop {
             match control_socket.recv_from(&mut buf).await {
-                Ok((len, _)) => {
-                    if len == 8 {
+                Ok((len, peer)) => {
+                    if len == clock::TIME_REQUEST_LEN && buf[0] == clock::TIME_REQUEST {
+                        let t2 = control_clock.now_micros();
+                        if let Some(response) = clock::time_response(&buf[..len], t2, control_clock.now_micros()) {
+                            let _ = control_socket.send_to(&response, peer).await;
+                        }
+                    } else if buf[..len].starts_with(&control::MAGIC) {
+                        if let Some(reply) = control_state.handle_datagram(&buf[..len], peer, &mut replies).await {
+                            let _ = control_socket.send_to(&reply, peer).await;
+                        }
+                    } else if len == 8 {
                         let mut cursor = Cursor::new(&buf);
                         if let Ok(received_volume) = cursor.read_f64::<byteorder::LittleEndian>() {
-                            if received_volume >= 0.0 && received_volume <= 1.0 {
-                                *volume_clone.lock().unwrap() = received_volume as f32;
-                                println!("Client volume updated to: {:.2f}", received_volume);
-                            } else {
-                                eprintln!("Received invalid volume: {:.2f}", received_volume);
-                            }
+                            control_state.apply(control::Message::SetVolume(received_volume as f32)).await;
                         }
+                    } else if len == 2 && buf[0] == CONTROL_REDUNDANCY {
+                        control_state.apply(control::Message::SetRedundancy(buf[1] != 0)).await;
                     }
                 }
-                Err(e) => eprintln!("Error receiving control: {}", e),
+                Err(e) => error!("Error receiving control: {}", e),
             }
         }
-    });
+    };
+    tokio::spawn(listen.instrument(info_span!("control", via = "udp")));
+    if args.meter && !args.tui {
+        spawn_meter(stats.clone());
+    }
+    if args.stats_interval.is_some() || args.stats_file.is_some() {
+        let file = match &args.stats_file {
+            Some(path) => Some(StatsFile::open(path).map_err(|e| format!("{}: {}", path.display(), e))?),
+            None => None,
+        };
+        let period = args.stats_interval.unwrap_or(STATS_FILE_INTERVAL);
+        spawn_stats_interval(stats.clone(), period, args.stats_interval.is_some(), file);
+    }
 
-    let err_fn = |err| eprintln!("Stream error: {}", err);
+    let hooks = Arc::new(FrameHooks::default());
+    for command in &args.frame_hook {
+        let hook = CommandHook::spawn(command)
+            .map_err(|e| Error::new(ErrorKind::Usage, format!("--frame-hook `{}`: {}", command, e)))?;
+        info!("Piping audio through `{}`", command);
+        hooks.push(hook.into_hook());
+    }
 
-    let stream = match sample_format {
-        cpal::SampleFormat::F32 => device.build_input_stream(
-            &config,
-            move |data: &[f32], _: &cpal::InputCallbackInfo| {
-                let vol = *volume.lock().unwrap();
-                let mut buffer = Vec::new();
-                for &sample in data {
-                    let adjusted = (sample * vol).clamp(-1.0, 1.0);
-                    let int_sample = (adjusted * i16::MAX as f32) as i16;
-                    buffer.extend_from_slice(&int_sample.to_le_bytes());
-                }
-                if !buffer.is_empty() {
-                    // Note: In async context, this should be handled differently, but for simplicity
-                    // We'll ignore send errors here
-                    let _ = socket_clone.try_send(&buffer);
-                }
-            },
-            err_fn,
-        )?,
-        cpal::SampleFormat::I16 => device.build_input_stream(
-            &config,
-            move |data: &[i16], _: &cpal::InputCallbackInfo| {
-                let vol = *volume.lock().unwrap();
-                let mut buffer = Vec::new();
-                for &sample in data {
-                    let adjusted = ((sample as f32 / i16::MAX as f32) * vol).clamp(-1.0, 1.0);
-                    let int_sample = (adjusted * i16::MAX as f32) as i16;
-                    buffer.extend_from_slice(&int_sample.to_le_bytes());
-                }
-                if !buffer.is_empty() {
-                    let _ = socket_clone.try_send(&buffer);
-                }
-            },
-            err_fn,
-        )?,
-        _ => {
-            eprintln!("Unsupported sample format: {:?}", sample_format);
-            std::process::exit(1);
-        }
+    if args.source == SourceKind::Stdin {
+        // There is no device to switch, so SwitchDevice is unsupported.
+        drop(device_switches);
+        stream_stdin(args.stdin_format, sink, web, encoder, hooks, controls, active, stats.clone(), shutdown)
+            .instrument(info_span!("capture", device = "stdin"))
+            .await?;
+        let _ = systemd::notify("STOPPING=1");
+        finish_session(&stats.summary(elapsed()), transport.as_deref(), args.summary_file.as_deref());
+        return Ok(());
+    }
+
+    let devices: Vec<_> = host.devices()?.collect();
+    let mut device_names = args.device_name.clone();
+    let interactive = std::io::stdin().is_terminal() && std::io::stderr().is_terminal();
+    if args.device_index.is_none() && device_names.is_empty() && interactive {
+        device_names.extend(pick_device(&devices, config_path.clone(), profile.as_deref())?);
+    }
+    let selected_device = first_available(&devices, args.device_index, &device_names);
+
+    let device = match selected_device {
+        Some(d) => d,
+        None => return Err(Error::new(ErrorKind::NoDevice, "No suitable input device found").into()),
     };
 
+    let device_name = device.name()?;
+    info!("Using audio input: {}", device_name);
+    controls.set_device(&device_name);
+
+    let (events, mut capture_events) = tokio::sync::mpsc::unbounded_channel();
+    let capture = Capture {
+        sink,
+        web: web.map(Arc::new),
+        encoder: Arc::new(Mutex::new(encoder)),
+        controls: controls.clone(),
+        active,
+        stats: stats.clone(),
+        events,
+        frames: None,
+        hooks,
+        overflow: args.overflow_policy,
+        buffer: Arc::new(BufferSizer::new(buffer_frames, args.max_buffer_frames)),
+        packet_frames: latency.map_or(FRAMES_PER_BUFFER, |budget| budget.packet_frames) as usize,
+    };
+    let mut stream = build_capture(device, &capture)?;
     stream.play()?;
-    println!("Streaming... Press Ctrl+C to stop.");
+    info!("Streaming... Press Ctrl+C to stop.");
+    notify_ready(&format!("Streaming from {}", device_name));
+    notifications::show("Streaming started", &format!("Capturing from {}", device_name));
+    let (quit_sender, mut quit) = tokio::sync::mpsc::channel(1);
+    #[cfg(feature = "tray")]
+    {
+        let names = host
+            .input_devices()
+            .map(|devices| devices.filter_map(|device| device.name().ok()).collect())
+            .unwrap_or_default();
+        audio_client::tray::attach(controls.clone(), names, quit_sender.clone());
+    }
+    // The dashboard takes the keys itself.
+    let dashboard = if args.tui {
+        let destination = match args.sink {
+            SinkKind::Udp if args.fan_out => format!("{} to {}", args.sink, args.server.join(", ")),
+            SinkKind::Udp => format!("{} to {}", args.sink, server_addr),
+            _ => args.sink.to_string(),
+        };
+        Some(start_dashboard(controls.clone(), stats.clone(), destination, quit_sender.clone())?)
+    } else {
+        None
+    };
+    let _keys = if args.tui { None } else { spawn_keyboard(controls.clone(), quit_sender) };
 
-    // Keep the main thread alive
-    tokio::signal::ctrl_c().await?;
-    stream.pause()?;
+    // Keep the main thread alive until Ctrl+C, the end of the schedule, the duration limit or
+    // the output closing, stopping the capture while paused
+    spawn_watchdog();
+    let stop = async {
+        tokio::select! {
+            _ = shutdown => {}
+            _ = quit.recv() => {}
+        }
+    };
+    let captured = streamer::run_capture(
+        &host,
+        &device_names,
+        &capture,
+        &mut stream,
+        &mut capture_events,
+        &mut device_switches,
+        StreamWatchdog::new(args.max_stream_restarts),
+        stop,
+    )
+    .await;
+    let _ = systemd::notify("STOPPING=1");
+    streamer::finish_capture(capture, stream, &mut capture_events).await;
+    // Hand the terminal back before the summary.
+    drop(dashboard);
+    finish_session(&stats.summary(elapsed()), transport.as_deref(), args.summary_file.as_deref());
+    captured?;
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command_line(args: &[&str]) -> Vec<std::ffi::OsString> {
+        args.iter().map(Into::into).collect()
+    }
+
+    #[test]
+    fn test_stream_flags_at_skips_global_flags() {
+        let line = command_line(&["audio-client", "--config", "x.toml", "stream", "--server", "10.0.0.2"]);
+        assert_eq!(stream_flags_at(&line, &["stream"]), 4);
+        let line = command_line(&["audio-client", "--log-level", "debug", "stream", "--server", "10.0.0.2"]);
+        assert_eq!(stream_flags_at(&line, &["stream"]), 4);
+        let line = command_line(&["audio-client", "--log-level=debug", "service", "--config", "x.toml", "run"]);
+        assert_eq!(stream_flags_at(&line, &["service", "run"]), 6);
+        let line = command_line(&["audio-client", "--config", "x.toml", "--server", "10.0.0.2"]);
+        assert_eq!(stream_flags_at(&line, &[]), 1);
+    }
+
+    #[test]
+    fn test_low_bandwidth_preset_keeps_to_opus() {
+        let cli = parse_args(["audio-client", "--preset", "low-bandwidth"]).unwrap();
+        let mut args = cli.stream;
+        apply_preset(&mut args).unwrap();
+        assert_eq!(args.codec(), Codec::Opus);
+        assert_eq!(args.opus_sample_rate, Some(24_000));
+
+        let cli = parse_args(["audio-client", "--preset", "low-bandwidth", "--codec", "pcm"]).unwrap();
+        let mut args = cli.stream;
+        assert!(apply_preset(&mut args).is_err());
+        assert_eq!(args.codec(), Codec::Pcm);
+    }
+
+    #[test]
+    fn test_absolute_paths() {
+        let mut args = parse_args(["audio-client", "--pid-file", "client.pid"]).unwrap().stream;
+        let mut config_path = Some(std::path::PathBuf::from("client.toml"));
+        absolute_paths(&mut args, &mut config_path).unwrap();
+        let dir = std::env::current_dir().unwrap();
+        assert_eq!(args.pid_file, Some(dir.join("client.pid")));
+        assert_eq!(config_path, Some(dir.join("client.toml")));
+        assert_eq!(args.ipc_path, None);
+    }
+
+    #[test]
+    fn test_config_flags_splice_after_subcommand() {
+        let mut line = command_line(&["audio-client", "--log-level", "debug", "stream", "--volume", "0.8"]);
+        let at = stream_flags_at(&line, &["stream"]);
+        line.splice(at..at, command_line(&["--volume", "0.5", "--server", "10.0.0.1"]));
+        let Some(Command::Stream(args)) = parse_args(line).unwrap().command else {
+            panic!("expected the stream command");
+        };
+        // The command line's flags override the file's.
+        assert_eq!(args.volume, 0.8);
+        assert_eq!(args.server, ["10.0.0.1"]);
+    }
+}
@@ -0,0 +1,121 @@
+use std::sync::Mutex;
+
+const CHANNELS: usize = 2;
+// The quietest level the meter shows; anything below reads as silence.
+const FLOOR_DB: f32 = -60.0;
+// Characters in each channel's bar.
+const BAR_WIDTH: usize = 16;
+
+/// Peak and RMS levels of the interleaved stereo s16le PCM being sent,
+/// gathered in the capture callback and read a few times a second.
+#[derive(Default)]
+pub struct LevelMeter {
+    channels: Mutex<[Accumulator; CHANNELS]>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Accumulator {
+    peak: f32,
+    sum_squares: f64,
+    samples: u64,
+}
+
+/// One channel's levels from 0.0 to 1.0 of full scale.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ChannelLevel {
+    pub peak: f32,
+    pub rms: f32,
+}
+
+impl LevelMeter {
+    pub fn record(&self, pcm: &[u8]) {
+        let mut channels = self.channels.lock().unwrap();
+        for (i, bytes) in pcm.chunks_exact(2).enumerate() {
+            let sample = i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / i16::MAX as f32;
+            let channel = &mut channels[i % CHANNELS];
+            channel.peak = channel.peak.max(sample.abs());
+            channel.sum_squares += (sample * sample) as f64;
+            channel.samples += 1;
+        }
+    }
+
+    /// The levels since the previous call, which start again from silence.
+    pub fn take(&self) -> [ChannelLevel; CHANNELS] {
+        let channels = std::mem::take(&mut *self.channels.lock().unwrap());
+        channels.map(|channel| ChannelLevel {
+            peak: channel.peak.min(1.0),
+            rms: match channel.samples {
+                0 => 0.0,
+                n => (channel.sum_squares / n as f64).sqrt() as f32,
+            },
+        })
+    }
+}
+
+/// A level in dBFS, no lower than the meter's floor.
+pub fn db(level: f32) -> f32 {
+    (20.0 * level.log10()).max(FLOOR_DB)
+}
+
+/// How far a level reaches up the meter's dB scale, from 0.0 at the floor
+/// to 1.0 at full scale.
+pub fn scale(level: f32) -> f32 {
+    (db(level) - FLOOR_DB) / -FLOOR_DB
+}
+
+/// One line with a bar per channel: the RMS level fills the bar, `|` marks
+/// the peak, and the number is the peak in dBFS.
+pub fn render(levels: &[ChannelLevel]) -> String {
+    let names = ["L", "R"];
+    let parts: Vec<_> = levels
+        .iter()
+        .zip(names)
+        .map(|(level, name)| {
+            let filled = bar_position(level.rms);
+            let peak = bar_position(level.peak);
+            let bar: String = (0..BAR_WIDTH)
+                .map(|i| match i {
+                    i if i < filled => '=',
+                    i if i + 1 == peak => '|',
+                    _ => ' ',
+                })
+                .collect();
+            format!("{} [{}] {:>3.0} dB", name, bar, db(level.peak))
+        })
+        .collect();
+    parts.join("  ")
+}
+
+// How many of the bar's characters a level covers, on the dB scale.
+fn bar_position(level: f32) -> usize {
+    (scale(level) * BAR_WIDTH as f32).round() as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pcm(samples: &[i16]) -> Vec<u8> {
+        samples.iter().flat_map(|sample| sample.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn test_take_levels() {
+        let meter = LevelMeter::default();
+        meter.record(&pcm(&[i16::MAX, 0, -i16::MAX, 0, i16::MAX / 2, 0, -i16::MAX / 2, 0]));
+        let [left, right] = meter.take();
+        assert_eq!(left.peak, 1.0);
+        assert!((left.rms - 0.79).abs() < 0.01);
+        assert_eq!(right, ChannelLevel::default());
+        assert_eq!(meter.take(), [ChannelLevel::default(); 2]);
+    }
+
+    #[test]
+    fn test_render() {
+        let levels = [ChannelLevel { peak: 1.0, rms: 0.1 }, ChannelLevel::default()];
+        assert_eq!(
+            render(&levels),
+            "L [===========    |]   0 dB  R [                ] -60 dB"
+        );
+    }
+}
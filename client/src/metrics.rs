@@ -0,0 +1,395 @@
+use std::fmt::Write;
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::control::{Controls, Report};
+use crate::http::{self, Response};
+use crate::jitter::{JitterBuffer, ARRIVAL_BUCKETS_MS};
+use crate::stats::{StreamStats, Summary};
+use crate::transport::TransportStats;
+
+pub const METRICS_PATH: &str = "/metrics";
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Serves the stream's counters at `/metrics` in the Prometheus text
+/// format, measuring uptime from `started`.
+pub fn serve(listener: TcpListener, controls: Arc<Controls>, stats: Arc<StreamStats>, started: Instant) {
+    http::serve(listener, move |path| {
+        if path != METRICS_PATH {
+            return None;
+        }
+        let text = render(&stats.summary(started.elapsed()), &controls.report(), &controls.destinations());
+        Some(Response {
+            content_type: CONTENT_TYPE,
+            body: Arc::from(text.into_bytes()),
+        })
+    });
+}
+
+/// Serves a --listen receiver's jitter buffer at `/metrics`, measuring
+/// uptime from `started`.
+pub fn serve_receiver(listener: TcpListener, buffer: Arc<Mutex<JitterBuffer>>, started: Instant) {
+    http::serve(listener, move |path| {
+        if path != METRICS_PATH {
+            return None;
+        }
+        let text = render_receiver(&buffer.lock().unwrap(), started.elapsed());
+        Some(Response {
+            content_type: CONTENT_TYPE,
+            body: Arc::from(text.into_bytes()),
+        })
+    });
+}
+
+/// The metrics page for one scrape, with a labelled sample for each
+/// destination of a transport that sends to several.
+pub fn render(summary: &Summary, report: &Report, destinations: &[(String, TransportStats)]) -> String {
+    let mut out = String::new();
+    let mut metric =
+        |name: &str, kind: &str, help: &str, sample: String| write_metric(&mut out, name, kind, help, &[sample]);
+    metric(
+        "packets_sent_total",
+        "counter",
+        "Packets handed to the sink.",
+        format!(" {}", summary.packets_sent),
+    );
+    metric(
+        "bytes_sent_total",
+        "counter",
+        "Bytes handed to the sink.",
+        format!(" {}", summary.bytes_sent),
+    );
+    metric(
+        "send_errors_total",
+        "counter",
+        "Packets the sink failed to send.",
+        format!(" {}", summary.drops),
+    );
+    metric(
+        "callback_underruns_total",
+        "counter",
+        "Capture callbacks that started late enough for input to have been lost.",
+        format!(" {}", summary.underruns),
+    );
+    metric(
+        "callback_overruns_total",
+        "counter",
+        "Capture callbacks that took longer than the audio they carried.",
+        format!(" {}", summary.overruns),
+    );
+    metric(
+        "queue_dropped_frames_total",
+        "counter",
+        "Frames of captured audio dropped because the send queue was full.",
+        format!(" {}", summary.queue_drops),
+    );
+    if let Some(frames) = summary.buffer_frames {
+        metric(
+            "capture_buffer_frames",
+            "gauge",
+            "The capture buffer size, which grows while callbacks keep missing audio.",
+            format!(" {}", frames),
+        );
+    }
+    if let Some(cpu_time) = summary.cpu_time {
+        metric(
+            "process_cpu_seconds_total",
+            "counter",
+            "CPU time the client has used, user and system together.",
+            format!(" {}", cpu_time.as_secs_f64()),
+        );
+    }
+    if let Some(bytes) = summary.resident_bytes {
+        metric(
+            "process_resident_memory_bytes",
+            "gauge",
+            "The client's resident memory.",
+            format!(" {}", bytes),
+        );
+    }
+    metric(
+        "volume",
+        "gauge",
+        "Client volume from 0 to 1.",
+        format!(" {}", report.volume),
+    );
+    metric(
+        "muted",
+        "gauge",
+        "1 while the stream is muted.",
+        format!(" {}", report.muted as u8),
+    );
+    metric(
+        "paused",
+        "gauge",
+        "1 while the stream is paused.",
+        format!(" {}", report.paused as u8),
+    );
+    metric(
+        "buffered_seconds",
+        "gauge",
+        "Captured audio not yet sent.",
+        format!(" {}", report.buffered_ms / 1000.0),
+    );
+    // Round trips appear once --ping has had a reply.
+    if let Some(rtt) = &report.rtt {
+        for (name, help, value) in [
+            (
+                "rtt_min_seconds",
+                "Shortest recent round trip to the receiver.",
+                rtt.min,
+            ),
+            ("rtt_avg_seconds", "Average recent round trip to the receiver.", rtt.avg),
+            ("rtt_max_seconds", "Longest recent round trip to the receiver.", rtt.max),
+            (
+                "rtt_jitter_seconds",
+                "Mean change between consecutive round trips.",
+                rtt.jitter,
+            ),
+        ] {
+            metric(name, "gauge", help, format!(" {}", value.as_secs_f64()));
+        }
+        metric(
+            "pings_lost_total",
+            "counter",
+            "Pings the receiver did not answer in time.",
+            format!(" {}", rtt.lost),
+        );
+        if let Some(latency) = rtt.end_to_end {
+            metric(
+                "end_to_end_latency_seconds",
+                "gauge",
+                "Time from capture to the receiver's speaker, with --timestamps.",
+                format!(" {}", latency.as_secs_f64()),
+            );
+        }
+    }
+    metric(
+        "uptime_seconds",
+        "gauge",
+        "Seconds since the stream started.",
+        format!(" {}", summary.elapsed.as_secs_f64()),
+    );
+    metric(
+        "info",
+        "gauge",
+        "The capture device, empty when reading stdin.",
+        format!("{{device=\"{}\"}} 1", escape(&report.device)),
+    );
+    if !destinations.is_empty() {
+        let mut counter = |name: &str, help: &str, value: fn(&TransportStats) -> u64| {
+            let samples: Vec<_> = destinations
+                .iter()
+                .map(|(addr, sent)| format!("{{destination=\"{}\"}} {}", escape(addr), value(sent)))
+                .collect();
+            write_metric(&mut out, name, "counter", help, &samples);
+        };
+        counter("destination_packets_sent_total", "Packets sent to each receiver.", |sent| sent.frames_sent);
+        counter("destination_bytes_sent_total", "Bytes sent to each receiver.", |sent| sent.bytes_sent);
+        counter(
+            "destination_send_errors_total",
+            "Packets that could not be sent to each receiver.",
+            |sent| sent.send_errors,
+        );
+    }
+    out
+}
+
+/// The receiver's metrics page for one scrape.
+pub fn render_receiver(buffer: &JitterBuffer, uptime: Duration) -> String {
+    let mut out = String::new();
+    let mut metric =
+        |name: &str, kind: &str, help: &str, sample: String| write_metric(&mut out, name, kind, help, &[sample]);
+    let stats = buffer.stats();
+    metric(
+        "jitter_buffer_depth",
+        "gauge",
+        "Packets waiting to play.",
+        format!(" {}", buffer.depth()),
+    );
+    metric(
+        "jitter_buffer_target_depth",
+        "gauge",
+        "Packets the jitter buffer aims to hold.",
+        format!(" {}", buffer.target_depth()),
+    );
+    metric(
+        "interarrival_jitter_seconds",
+        "gauge",
+        "Running average of how far packet spacing strays from the frame duration.",
+        format!(" {}", buffer.jitter().as_secs_f64()),
+    );
+    for (name, help, value) in [
+        (
+            "packets_missing_total",
+            "Packets that had not arrived when due to play.",
+            stats.missing,
+        ),
+        (
+            "packets_late_total",
+            "Packets that arrived after their slot had played.",
+            stats.late,
+        ),
+        (
+            "packets_recovered_total",
+            "Lost packets rebuilt from redundant copies.",
+            stats.recovered,
+        ),
+        (
+            "packets_trimmed_total",
+            "Packets dropped to bring latency back down.",
+            stats.trimmed,
+        ),
+        (
+            "playback_underruns_total",
+            "Times the jitter buffer ran dry.",
+            stats.underruns,
+        ),
+    ] {
+        metric(name, "counter", help, format!(" {}", value));
+    }
+    metric(
+        "uptime_seconds",
+        "gauge",
+        "Seconds since the receiver started.",
+        format!(" {}", uptime.as_secs_f64()),
+    );
+
+    let arrivals = buffer.arrivals();
+    let mut samples = Vec::new();
+    let mut cumulative = 0;
+    for (count, bound) in arrivals.buckets.iter().zip(ARRIVAL_BUCKETS_MS) {
+        cumulative += count;
+        samples.push(format!("_bucket{{le=\"{}\"}} {}", bound as f64 / 1000.0, cumulative));
+    }
+    samples.push(format!("_bucket{{le=\"+Inf\"}} {}", arrivals.count));
+    samples.push(format!("_sum {}", arrivals.sum.as_secs_f64()));
+    samples.push(format!("_count {}", arrivals.count));
+    write_metric(
+        &mut out,
+        "packet_interarrival_seconds",
+        "histogram",
+        "Time between consecutive packets arriving.",
+        &samples,
+    );
+    out
+}
+
+// Each sample is what follows the name: any suffix and labels, then the
+// value.
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[String]) {
+    let _ = writeln!(out, "# HELP audio_client_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE audio_client_{} {}", name, kind);
+    for sample in samples {
+        let _ = writeln!(out, "audio_client_{}{}", name, sample);
+    }
+}
+
+// Label values escape backslashes, quotes and newlines.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtt::RttSummary;
+
+    #[test]
+    fn test_render() {
+        let summary = Summary {
+            elapsed: Duration::from_secs(90),
+            bytes_sent: 192_000,
+            packets_sent: 100,
+            drops: 2,
+            underruns: 3,
+            overruns: 4,
+            queue_drops: 512,
+            buffer_frames: Some(1024),
+            cpu_time: Some(Duration::from_millis(4500)),
+            resident_bytes: Some(24_100_000),
+            ..Summary::default()
+        };
+        let report = Report {
+            volume: 0.5,
+            muted: true,
+            device: "USB \"Pro\" Audio".to_string(),
+            rtt: Some(RttSummary {
+                min: Duration::from_millis(2),
+                avg: Duration::from_millis(3),
+                max: Duration::from_millis(5),
+                jitter: Duration::from_micros(500),
+                lost: 1,
+                end_to_end: Some(Duration::from_millis(85)),
+            }),
+            ..Report::default()
+        };
+        let text = render(&summary, &report, &[]);
+        assert!(text.contains("# TYPE audio_client_packets_sent_total counter\naudio_client_packets_sent_total 100\n"));
+        assert!(text.contains("\naudio_client_bytes_sent_total 192000\n"));
+        assert!(text.contains("\naudio_client_send_errors_total 2\n"));
+        assert!(text.contains("\naudio_client_callback_underruns_total 3\n"));
+        assert!(text.contains("\naudio_client_callback_overruns_total 4\n"));
+        assert!(text.contains("\naudio_client_queue_dropped_frames_total 512\n"));
+        assert!(text.contains("\naudio_client_capture_buffer_frames 1024\n"));
+        assert!(text.contains("\naudio_client_process_cpu_seconds_total 4.5\n"));
+        assert!(text.contains("\naudio_client_process_resident_memory_bytes 24100000\n"));
+        assert!(text.contains("\naudio_client_volume 0.5\n"));
+        assert!(text.contains("\naudio_client_muted 1\n"));
+        assert!(text.contains("\naudio_client_uptime_seconds 90\n"));
+        assert!(text.contains("\naudio_client_rtt_avg_seconds 0.003\n"));
+        assert!(text.contains("\naudio_client_rtt_jitter_seconds 0.0005\n"));
+        assert!(text.contains("\naudio_client_pings_lost_total 1\n"));
+        assert!(text.contains("\naudio_client_end_to_end_latency_seconds 0.085\n"));
+        assert!(
+            text.ends_with("# TYPE audio_client_info gauge\naudio_client_info{device=\"USB \\\"Pro\\\" Audio\"} 1\n")
+        );
+    }
+
+    #[test]
+    fn test_render_destinations() {
+        let kitchen = TransportStats {
+            frames_sent: 100,
+            bytes_sent: 192_000,
+            ..TransportStats::default()
+        };
+        let office = TransportStats {
+            frames_sent: 90,
+            send_errors: 10,
+            ..TransportStats::default()
+        };
+        let destinations = [("kitchen:8080".to_string(), kitchen), ("office:8080".to_string(), office)];
+        let text = render(&Summary::default(), &Report::default(), &destinations);
+        assert!(text.contains(
+            "# TYPE audio_client_destination_packets_sent_total counter\n\
+             audio_client_destination_packets_sent_total{destination=\"kitchen:8080\"} 100\n\
+             audio_client_destination_packets_sent_total{destination=\"office:8080\"} 90\n"
+        ));
+        assert!(text.contains("\naudio_client_destination_bytes_sent_total{destination=\"kitchen:8080\"} 192000\n"));
+        assert!(text.contains("\naudio_client_destination_send_errors_total{destination=\"office:8080\"} 10\n"));
+        assert!(!render(&Summary::default(), &Report::default(), &[]).contains("destination"));
+    }
+
+    #[test]
+    fn test_render_receiver() {
+        let frame = Duration::from_millis(10);
+        let mut buffer = JitterBuffer::new(frame, Duration::from_millis(20));
+        let start = Instant::now();
+        buffer.push(0, vec![0], start);
+        buffer.push(1, vec![1], start + frame);
+        buffer.push(2, vec![2], start + frame * 4);
+        let text = render_receiver(&buffer, Duration::from_secs(90));
+        assert!(text.contains("\naudio_client_jitter_buffer_depth 3\n"));
+        assert!(text.contains("\naudio_client_packets_missing_total 0\n"));
+        assert!(text.contains("\naudio_client_uptime_seconds 90\n"));
+        assert!(text.contains("# TYPE audio_client_packet_interarrival_seconds histogram\n"));
+        assert!(text.contains("\naudio_client_packet_interarrival_seconds_bucket{le=\"0.005\"} 0\n"));
+        assert!(text.contains("\naudio_client_packet_interarrival_seconds_bucket{le=\"0.01\"} 1\n"));
+        assert!(text.contains("\naudio_client_packet_interarrival_seconds_bucket{le=\"0.03\"} 2\n"));
+        assert!(text.contains("\naudio_client_packet_interarrival_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(text.ends_with(
+            "\naudio_client_packet_interarrival_seconds_sum 0.04\naudio_client_packet_interarrival_seconds_count 2\n"
+        ));
+    }
+}
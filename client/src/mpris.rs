@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use tracing::info;
+use zbus::interface;
+use zbus::zvariant::{ObjectPath, Value};
+
+use crate::control::{Controls, Message};
+
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+const NO_TRACK: &str = "/org/mpris/MediaPlayer2/TrackList/NoTrack";
+
+/// Registers the client as an MPRIS player on the session bus, so media
+/// keys and desktop widgets can pause, resume and set its volume. Runs
+/// until the connection fails.
+pub async fn serve(controls: Arc<Controls>) -> zbus::Result<()> {
+    // The instance suffix lets several clients register side by side.
+    let name = format!("org.mpris.MediaPlayer2.audio_client.instance{}", std::process::id());
+    let connection = zbus::connection::Builder::session()?
+        .name(name)?
+        .serve_at(OBJECT_PATH, Root)?
+        .serve_at(
+            OBJECT_PATH,
+            Player {
+                controls: controls.clone(),
+            },
+        )?
+        .build()
+        .await?;
+    info!("Registered as an MPRIS media player");
+
+    // Tell widgets when another control channel pauses or resumes.
+    let player = connection.object_server().interface::<_, Player>(OBJECT_PATH).await?;
+    let mut paused = controls.watch_paused();
+    while paused.changed().await.is_ok() {
+        player
+            .get()
+            .await
+            .playback_status_changed(player.signal_context())
+            .await?;
+    }
+    Ok(())
+}
+
+struct Root;
+
+#[interface(name = "org.mpris.MediaPlayer2")]
+impl Root {
+    fn raise(&self) {}
+
+    fn quit(&self) {}
+
+    #[zbus(property)]
+    fn can_quit(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_raise(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn has_track_list(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn identity(&self) -> &str {
+        "audio-client"
+    }
+
+    #[zbus(property)]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    #[zbus(property)]
+    fn supported_mime_types(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+// Play and pause map onto the client's pause state, whose changes `serve`
+// announces; there are no tracks to skip or seek in.
+struct Player {
+    controls: Arc<Controls>,
+}
+
+#[interface(name = "org.mpris.MediaPlayer2.Player")]
+impl Player {
+    async fn play(&self) {
+        self.controls.apply(Message::Pause(false)).await;
+    }
+
+    async fn pause(&self) {
+        self.controls.apply(Message::Pause(true)).await;
+    }
+
+    async fn play_pause(&self) {
+        self.controls.apply(Message::Pause(!self.controls.is_paused())).await;
+    }
+
+    async fn stop(&self) {
+        self.controls.apply(Message::Pause(true)).await;
+    }
+
+    fn next(&self) {}
+
+    fn previous(&self) {}
+
+    fn seek(&self, _offset: i64) {}
+
+    fn set_position(&self, _track_id: ObjectPath<'_>, _position: i64) {}
+
+    fn open_uri(&self, _uri: &str) {}
+
+    #[zbus(property)]
+    fn playback_status(&self) -> &str {
+        if self.controls.is_paused() {
+            "Paused"
+        } else {
+            "Playing"
+        }
+    }
+
+    #[zbus(property)]
+    fn volume(&self) -> f64 {
+        self.controls.state.level() as f64
+    }
+
+    #[zbus(property)]
+    async fn set_volume(&mut self, volume: f64) {
+        let volume = volume.clamp(0.0, 1.0) as f32;
+        self.controls.apply(Message::SetVolume(volume)).await;
+    }
+
+    #[zbus(property)]
+    fn metadata(&self) -> HashMap<&'static str, Value<'static>> {
+        let device = self.controls.report().device;
+        let title = if device.is_empty() { "stdin".to_string() } else { device };
+        HashMap::from([
+            (
+                "mpris:trackid",
+                Value::from(ObjectPath::from_static_str_unchecked(NO_TRACK)),
+            ),
+            ("xesam:title", Value::from(title)),
+        ])
+    }
+
+    #[zbus(property)]
+    fn position(&self) -> i64 {
+        0
+    }
+
+    #[zbus(property)]
+    fn rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn minimum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn maximum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn can_go_next(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_go_previous(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_play(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_pause(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_seek(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
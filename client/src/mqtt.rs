@@ -0,0 +1,313 @@
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::control::{Controls, Message, Report};
+
+pub const MQTT_DEFAULT_PORT: u16 = 1883;
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+// How often state and stats are published, which also keeps the connection alive.
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(10);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const SUBSCRIBE: u8 = 0x82;
+const RETAIN: u8 = 0x01;
+// Largest packet accepted from the broker; commands are tiny.
+const MAX_PACKET: usize = 64 * 1024;
+
+/// Where to connect and which topics to use. Commands arrive on
+/// `<topic>/set/<name>`; the client publishes `<topic>/state`,
+/// `<topic>/stats` and `<topic>/availability`.
+#[derive(Clone, Debug)]
+pub struct MqttOptions {
+    pub broker: String,
+    pub topic: String,
+    pub client_id: String,
+}
+
+/// Maps a command topic below `<topic>/set/` and its payload to a control
+/// message. Switches take `ON`/`OFF` as Home Assistant sends them, or
+/// `true`/`false` and `1`/`0`.
+pub fn command(name: &str, payload: &str) -> Option<Message> {
+    let payload = payload.trim();
+    match name {
+        "volume" => payload
+            .parse()
+            .ok()
+            .filter(|v: &f32| v.is_finite())
+            .map(Message::SetVolume),
+        "mute" => switch(payload).map(Message::Mute),
+        "streaming" => switch(payload).map(|on| Message::Pause(!on)),
+        "redundancy" => switch(payload).map(Message::SetRedundancy),
+        "device" if !payload.is_empty() => Some(Message::SwitchDevice(payload.to_string())),
+        _ => None,
+    }
+}
+
+fn switch(payload: &str) -> Option<bool> {
+    match payload.to_ascii_lowercase().as_str() {
+        "on" | "true" | "1" | "start" => Some(true),
+        "off" | "false" | "0" | "stop" => Some(false),
+        _ => None,
+    }
+}
+
+fn state_json(report: &Report) -> String {
+    serde_json::json!({
+        "volume": report.volume,
+        "mute": if report.muted { "ON" } else { "OFF" },
+        "streaming": if report.paused { "OFF" } else { "ON" },
+        "device": report.device,
+    })
+    .to_string()
+}
+
+fn stats_json(report: &Report) -> String {
+    serde_json::json!({
+        "bitrate_kbps": report.bitrate_kbps,
+        "buffered_ms": report.buffered_ms,
+        "packets_sent": report.packets_sent,
+        "drops": report.drops,
+    })
+    .to_string()
+}
+
+/// Keeps a connection to the broker, applying commands and publishing the
+/// client's state, reconnecting whenever the connection is lost.
+pub async fn run(options: MqttOptions, controls: Arc<Controls>) {
+    loop {
+        match session(&options, &controls).await {
+            Ok(()) => warn!("MQTT broker {} closed the connection", options.broker),
+            Err(e) => warn!("MQTT connection to {} failed: {}", options.broker, e),
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn session(options: &MqttOptions, controls: &Controls) -> io::Result<()> {
+    let stream = TcpStream::connect(&options.broker).await?;
+    let (mut reader, mut writer) = stream.into_split();
+    let availability = format!("{}/availability", options.topic);
+    writer
+        .write_all(&connect_packet(
+            &options.client_id,
+            KEEP_ALIVE,
+            &availability,
+            b"offline",
+        ))
+        .await?;
+    match read_packet(&mut reader).await? {
+        (CONNACK, body) if body.get(1) == Some(&0) => {}
+        (CONNACK, body) => {
+            return Err(io::Error::other(format!(
+                "broker refused connection: code {:?}",
+                body.get(1)
+            )))
+        }
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "expected CONNACK")),
+    }
+    let prefix = format!("{}/set/", options.topic);
+    writer.write_all(&subscribe_packet(1, &format!("{}#", prefix))).await?;
+    writer
+        .write_all(&publish_packet(&availability, b"online", true))
+        .await?;
+    info!("Connected to MQTT broker {} as {}", options.broker, options.topic);
+
+    // Reads happen on their own task, since a read cut short by select!
+    // would lose its place in the stream.
+    let (packets, mut incoming) = mpsc::channel(16);
+    let read_task = tokio::spawn(async move {
+        loop {
+            let packet = read_packet(&mut reader).await;
+            let failed = packet.is_err();
+            if packets.send(packet).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    let mut tick = tokio::time::interval(PUBLISH_INTERVAL);
+    let result = loop {
+        tokio::select! {
+            _ = tick.tick() => {
+                let report = controls.report();
+                let state = publish_packet(&format!("{}/state", options.topic), state_json(&report).as_bytes(), true);
+                let stats = publish_packet(&format!("{}/stats", options.topic), stats_json(&report).as_bytes(), false);
+                if let Err(e) = writer.write_all(&[state, stats].concat()).await {
+                    break Err(e);
+                }
+            }
+            packet = incoming.recv() => {
+                let (kind, body) = match packet {
+                    Some(Ok(packet)) => packet,
+                    Some(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break Ok(()),
+                    Some(Err(e)) => break Err(e),
+                    None => break Ok(()),
+                };
+                if kind & 0xf0 != PUBLISH {
+                    continue;
+                }
+                let Some((topic, payload)) = parse_publish(kind, &body) else {
+                    continue;
+                };
+                let name = topic.strip_prefix(&prefix).unwrap_or(&topic);
+                match command(name, &String::from_utf8_lossy(&payload)) {
+                    Some(message) => {
+                        controls.apply(message).await;
+                        // Publish the new state straight away.
+                        tick.reset_immediately();
+                    }
+                    None => warn!("Ignoring MQTT message on {}", topic),
+                }
+            }
+        }
+    };
+    read_task.abort();
+    result
+}
+
+/// A CONNECT packet for MQTT 3.1.1 with a clean session and a retained
+/// last will, so the broker marks the client offline if it vanishes.
+fn connect_packet(client_id: &str, keep_alive: Duration, will_topic: &str, will_payload: &[u8]) -> Vec<u8> {
+    // Clean session, will flag, will retain.
+    const FLAGS: u8 = 0x02 | 0x04 | 0x20;
+    let mut body = Vec::new();
+    put_string(&mut body, b"MQTT");
+    body.push(4);
+    body.push(FLAGS);
+    body.extend_from_slice(&(keep_alive.as_secs().min(u16::MAX as u64) as u16).to_be_bytes());
+    put_string(&mut body, client_id.as_bytes());
+    put_string(&mut body, will_topic.as_bytes());
+    put_string(&mut body, will_payload);
+    packet(CONNECT, &body)
+}
+
+// Subscribes at QoS 0; commands are idempotent, so a lost one can simply be sent again.
+fn subscribe_packet(packet_id: u16, filter: &str) -> Vec<u8> {
+    let mut body = packet_id.to_be_bytes().to_vec();
+    put_string(&mut body, filter.as_bytes());
+    body.push(0);
+    packet(SUBSCRIBE, &body)
+}
+
+fn publish_packet(topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_string(&mut body, topic.as_bytes());
+    body.extend_from_slice(payload);
+    packet(PUBLISH | if retain { RETAIN } else { 0 }, &body)
+}
+
+// Returns the topic and payload of a PUBLISH, skipping the packet id that
+// QoS 1 and 2 messages carry.
+pub(crate) fn parse_publish(kind: u8, body: &[u8]) -> Option<(String, Vec<u8>)> {
+    let len = u16::from_be_bytes([*body.first()?, *body.get(1)?]) as usize;
+    let topic = std::str::from_utf8(body.get(2..2 + len)?).ok()?.to_string();
+    let qos = (kind >> 1) & 0x03;
+    let payload_start = 2 + len + if qos > 0 { 2 } else { 0 };
+    Some((topic, body.get(payload_start..)?.to_vec()))
+}
+
+fn put_string(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+    out.extend_from_slice(bytes);
+}
+
+fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![kind];
+    // The remaining length: seven bits per byte, low bits first.
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        out.push(if len > 0 { byte | 0x80 } else { byte });
+        if len == 0 {
+            break;
+        }
+    }
+    out.extend_from_slice(body);
+    out
+}
+
+async fn read_packet(reader: &mut (impl AsyncRead + Unpin)) -> io::Result<(u8, Vec<u8>)> {
+    let kind = reader.read_u8().await?;
+    let mut len = 0usize;
+    for shift in (0..28).step_by(7) {
+        let byte = reader.read_u8().await?;
+        len |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    if len > MAX_PACKET {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "MQTT packet too large"));
+    }
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body).await?;
+    Ok((kind, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commands() {
+        assert_eq!(command("volume", "0.4\n"), Some(Message::SetVolume(0.4)));
+        assert_eq!(command("volume", "loud"), None);
+        assert_eq!(command("mute", "ON"), Some(Message::Mute(true)));
+        assert_eq!(command("mute", "false"), Some(Message::Mute(false)));
+        assert_eq!(command("streaming", "OFF"), Some(Message::Pause(true)));
+        assert_eq!(command("streaming", "start"), Some(Message::Pause(false)));
+        assert_eq!(
+            command("device", "Line In"),
+            Some(Message::SwitchDevice("Line In".to_string()))
+        );
+        assert_eq!(command("mute", "maybe"), None);
+        assert_eq!(command("bass", "ON"), None);
+    }
+
+    #[test]
+    fn test_packet_encoding() {
+        assert_eq!(
+            publish_packet("a/b", b"ON", true),
+            [PUBLISH | RETAIN, 7, 0, 3, b'a', b'/', b'b', b'O', b'N']
+        );
+        assert_eq!(
+            subscribe_packet(1, "a/#"),
+            [SUBSCRIBE, 8, 0, 1, 0, 3, b'a', b'/', b'#', 0]
+        );
+        // Remaining lengths over 127 take a second byte.
+        let long = publish_packet("t", &[0; 200], false);
+        assert_eq!(&long[..3], &[PUBLISH, 0xcb, 0x01]);
+        assert_eq!(long.len(), 3 + 203);
+
+        let connect = connect_packet("client", Duration::from_secs(30), "t/availability", b"offline");
+        assert_eq!(&connect[2..10], &[0, 4, b'M', b'Q', b'T', b'T', 4, 0x26]);
+        assert_eq!(&connect[10..12], &[0, 30]);
+        assert_eq!(connect[1] as usize, connect.len() - 2);
+    }
+
+    #[test]
+    fn test_parse_publish() {
+        let packet = publish_packet("streamer/set/mute", b"ON", false);
+        assert_eq!(
+            parse_publish(packet[0], &packet[2..]),
+            Some(("streamer/set/mute".to_string(), b"ON".to_vec()))
+        );
+        // QoS 1: a packet id follows the topic.
+        let body = [0, 1, b't', 0, 9, b'x'];
+        assert_eq!(
+            parse_publish(PUBLISH | 0x02, &body),
+            Some(("t".to_string(), b"x".to_vec()))
+        );
+        assert_eq!(parse_publish(PUBLISH, &[0, 5, b't']), None);
+    }
+}
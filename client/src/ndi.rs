@@ -0,0 +1,192 @@
+use std::ffi::{c_char, c_int, c_void, CString};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::ptr;
+
+use libloading::Library;
+
+// NDI asks applications to load its runtime from the directory it installs
+// rather than linking against it.
+#[cfg(target_os = "windows")]
+const RUNTIME_DIR_VARS: [&str; 2] = ["NDI_RUNTIME_DIR_V6", "NDI_RUNTIME_DIR_V5"];
+#[cfg(target_os = "windows")]
+const LIBRARY_NAMES: [&str; 1] = ["Processing.NDI.Lib.x64.dll"];
+#[cfg(target_os = "macos")]
+const RUNTIME_DIR_VARS: [&str; 1] = ["NDI_RUNTIME_DIR_V6"];
+#[cfg(target_os = "macos")]
+const LIBRARY_NAMES: [&str; 2] = ["libndi.dylib", "/usr/local/lib/libndi.dylib"];
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+const RUNTIME_DIR_VARS: [&str; 2] = ["NDI_RUNTIME_DIR_V6", "NDI_RUNTIME_DIR_V5"];
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+const LIBRARY_NAMES: [&str; 3] = ["libndi.so.6", "libndi.so.5", "libndi.so"];
+
+// Lets the SDK stamp frames as they are sent.
+const TIMECODE_SYNTHESIZE: i64 = i64::MAX;
+
+#[repr(C)]
+struct SendCreate {
+    p_ndi_name: *const c_char,
+    p_groups: *const c_char,
+    clock_video: bool,
+    clock_audio: bool,
+}
+
+#[repr(C)]
+struct AudioFrameV2 {
+    sample_rate: c_int,
+    no_channels: c_int,
+    no_samples: c_int,
+    timecode: i64,
+    p_data: *mut f32,
+    channel_stride_in_bytes: c_int,
+    p_metadata: *const c_char,
+    timestamp: i64,
+}
+
+type Initialize = unsafe extern "C" fn() -> bool;
+type Destroy = unsafe extern "C" fn();
+type SendCreateFn = unsafe extern "C" fn(*const SendCreate) -> *mut c_void;
+type SendDestroy = unsafe extern "C" fn(*mut c_void);
+type SendAudioV2 = unsafe extern "C" fn(*mut c_void, *const AudioFrameV2);
+
+/// Publishes s16le PCM as an NDI audio source, so NDI receivers such as
+/// OBS and vMix can pick it up from the LAN. Needs the NDI runtime, loaded
+/// at startup.
+pub struct NdiSender {
+    instance: *mut c_void,
+    send_audio: SendAudioV2,
+    send_destroy: SendDestroy,
+    destroy: Destroy,
+    sample_rate: u32,
+    channels: usize,
+    planar: Vec<f32>,
+    // Keeps the function pointers above valid; dropped last.
+    _library: Library,
+}
+
+// The NDI SDK allows a send instance to be used from any one thread at a
+// time, which the sink's mutex guarantees.
+unsafe impl Send for NdiSender {}
+
+impl NdiSender {
+    pub fn new(name: &str, sample_rate: u32, channels: u16) -> io::Result<Self> {
+        let library = load_runtime()?;
+        let c_name = CString::new(name).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "NDI name contains NUL"))?;
+        // SAFETY: the symbols have these signatures in every NDI 5 and 6
+        // runtime, and `library` outlives every use of them.
+        unsafe {
+            let initialize = *library.get::<Initialize>(b"NDIlib_initialize\0").map_err(io::Error::other)?;
+            let destroy = *library.get::<Destroy>(b"NDIlib_destroy\0").map_err(io::Error::other)?;
+            let send_create = *library.get::<SendCreateFn>(b"NDIlib_send_create\0").map_err(io::Error::other)?;
+            let send_destroy = *library.get::<SendDestroy>(b"NDIlib_send_destroy\0").map_err(io::Error::other)?;
+            let send_audio = *library.get::<SendAudioV2>(b"NDIlib_send_send_audio_v2\0").map_err(io::Error::other)?;
+            if !initialize() {
+                return Err(io::Error::new(io::ErrorKind::Unsupported, "this CPU is not supported by NDI"));
+            }
+            let settings = SendCreate {
+                p_ndi_name: c_name.as_ptr(),
+                p_groups: ptr::null(),
+                clock_video: false,
+                // The capture device already paces the audio.
+                clock_audio: false,
+            };
+            let instance = send_create(&settings);
+            if instance.is_null() {
+                destroy();
+                return Err(io::Error::other("could not create the NDI sender"));
+            }
+            Ok(Self {
+                instance,
+                send_audio,
+                send_destroy,
+                destroy,
+                sample_rate,
+                channels: channels as usize,
+                planar: Vec::new(),
+                _library: library,
+            })
+        }
+    }
+}
+
+impl Write for NdiSender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let frames = buf.len() / (2 * self.channels);
+        if frames == 0 {
+            return Ok(buf.len());
+        }
+        deinterleave(buf, self.channels, &mut self.planar);
+        let frame = AudioFrameV2 {
+            sample_rate: self.sample_rate as c_int,
+            no_channels: self.channels as c_int,
+            no_samples: frames as c_int,
+            timecode: TIMECODE_SYNTHESIZE,
+            p_data: self.planar.as_mut_ptr(),
+            channel_stride_in_bytes: (frames * std::mem::size_of::<f32>()) as c_int,
+            p_metadata: ptr::null(),
+            timestamp: 0,
+        };
+        // SAFETY: `planar` holds `channels` planes of `frames` samples and
+        // stays alive for the call, which copies it.
+        unsafe { (self.send_audio)(self.instance, &frame) };
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for NdiSender {
+    fn drop(&mut self) {
+        // SAFETY: the instance came from NDIlib_send_create and is destroyed
+        // once, before the library is unloaded.
+        unsafe {
+            (self.send_destroy)(self.instance);
+            (self.destroy)();
+        }
+    }
+}
+
+fn load_runtime() -> io::Result<Library> {
+    let mut candidates: Vec<PathBuf> = RUNTIME_DIR_VARS
+        .iter()
+        .filter_map(|var| std::env::var_os(var))
+        .flat_map(|dir| LIBRARY_NAMES.iter().map(move |name| PathBuf::from(&dir).join(name)))
+        .collect();
+    candidates.extend(LIBRARY_NAMES.iter().map(PathBuf::from));
+    for candidate in &candidates {
+        // SAFETY: loading the NDI runtime runs no initialisers beyond its own.
+        if let Ok(library) = unsafe { Library::new(candidate) } {
+            return Ok(library);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "NDI runtime not found; install NDI Tools or the NDI SDK (https://ndi.video)",
+    ))
+}
+
+// Interleaved s16le to the planar float layout NDI takes.
+fn deinterleave(buf: &[u8], channels: usize, planar: &mut Vec<f32>) {
+    let frames = buf.len() / (2 * channels);
+    planar.clear();
+    planar.resize(frames * channels, 0.0);
+    for (i, sample) in buf.chunks_exact(2).take(frames * channels).enumerate() {
+        let value = i16::from_le_bytes([sample[0], sample[1]]) as f32 / 32768.0;
+        planar[(i % channels) * frames + i / channels] = value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deinterleave() {
+        let buf: Vec<u8> = [16384i16, -16384, 0, 32767].iter().flat_map(|s| s.to_le_bytes()).collect();
+        let mut planar = Vec::new();
+        deinterleave(&buf, 2, &mut planar);
+        assert_eq!(planar, vec![0.5, 0.0, -0.5, 32767.0 / 32768.0]);
+    }
+}
@@ -0,0 +1,108 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::stats::Summary;
+
+// Drops within one look at the counters that mean the server is not taking
+// packets rather than a passing hiccup.
+const LOST_DROPS: u64 = 5;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turns desktop notifications on; fails if the client was built without
+/// them.
+pub fn enable() -> Result<(), &'static str> {
+    if cfg!(feature = "notifications") {
+        ENABLED.store(true, Ordering::Relaxed);
+        Ok(())
+    } else {
+        Err("--notifications needs a build with --features notifications")
+    }
+}
+
+/// Shows a desktop notification if they are enabled. It is sent from its
+/// own thread, since some platforms block until the desktop answers, and a
+/// failure is only logged.
+pub fn show(summary: &str, body: &str) {
+    #[cfg(feature = "notifications")]
+    if ENABLED.load(Ordering::Relaxed) {
+        let (summary, body) = (summary.to_string(), body.to_string());
+        std::thread::spawn(move || {
+            let shown = notify_rust::Notification::new()
+                .appname("audio-client")
+                .summary(&summary)
+                .body(&body)
+                .show();
+            if let Err(e) = shown {
+                tracing::error!("Error showing a desktop notification: {}", e);
+            }
+        });
+    }
+    #[cfg(not(feature = "notifications"))]
+    let _ = (summary, body);
+}
+
+/// A change in whether the server is taking packets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reachability {
+    Lost,
+    Restored,
+}
+
+/// Follows the send counters to tell when the server stops taking packets
+/// and when it starts again. Connected UDP sockets report an unreachable
+/// port as failed sends, which the counters record as drops.
+#[derive(Debug, Default)]
+pub struct ReachabilityWatch {
+    packets_sent: u64,
+    drops: u64,
+    unreachable: bool,
+}
+
+impl ReachabilityWatch {
+    /// Takes the latest counters and returns the change since the previous
+    /// look, if any. A look with nothing sent, as while paused, changes
+    /// nothing.
+    pub fn update(&mut self, summary: &Summary) -> Option<Reachability> {
+        let sent = summary.packets_sent - self.packets_sent.min(summary.packets_sent);
+        let dropped = summary.drops - self.drops.min(summary.drops);
+        self.packets_sent = summary.packets_sent;
+        self.drops = summary.drops;
+        if dropped >= LOST_DROPS && !self.unreachable {
+            self.unreachable = true;
+            Some(Reachability::Lost)
+        } else if sent > 0 && dropped == 0 && self.unreachable {
+            self.unreachable = false;
+            Some(Reachability::Restored)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn summary(packets_sent: u64, drops: u64) -> Summary {
+        Summary {
+            elapsed: Duration::ZERO,
+            bytes_sent: 0,
+            packets_sent,
+            drops,
+            ..Summary::default()
+        }
+    }
+
+    #[test]
+    fn test_reachability_watch() {
+        let mut watch = ReachabilityWatch::default();
+        assert_eq!(watch.update(&summary(90, 0)), None);
+        assert_eq!(watch.update(&summary(180, 2)), None);
+        assert_eq!(watch.update(&summary(220, 50)), Some(Reachability::Lost));
+        assert_eq!(watch.update(&summary(260, 90)), None);
+        assert_eq!(watch.update(&summary(260, 90)), None);
+        assert_eq!(watch.update(&summary(350, 90)), Some(Reachability::Restored));
+        assert_eq!(watch.update(&summary(440, 90)), None);
+    }
+}
@@ -0,0 +1,233 @@
+use std::io::{self, Write};
+
+// Ogg pages hold at most 255 lacing segments.
+const MAX_SEGMENTS: usize = 255;
+const HEADER_BOS: u8 = 0x02;
+// Samples the decoder discards at the start, matching libopus' encoder
+// lookahead at 48kHz.
+pub const OPUS_PRE_SKIP: u16 = 312;
+// 20ms Opus frames.
+const OPUS_FRAME_FRAMES: usize = 960;
+const MAX_OPUS_PACKET: usize = 4000;
+
+/// Writes Opus packets into an Ogg stream (RFC 7845): an `OpusHead` page,
+/// an `OpusTags` page, then audio pages. Packets are grouped into pages of
+/// `packets_per_page` so a live listener never waits long for a page.
+pub struct OggOpusWriter<W: Write> {
+    out: W,
+    serial: u32,
+    sequence: u32,
+    granule: u64,
+    packets_per_page: usize,
+    segments: Vec<u8>,
+    body: Vec<u8>,
+    packets: usize,
+}
+
+/// The `OpusHead` and `OpusTags` pages that open a stream. Live servers
+/// send them to each listener before the running audio pages.
+pub fn header_pages(serial: u32, channels: u8) -> Vec<u8> {
+    let mut head = Vec::with_capacity(19);
+    head.extend_from_slice(b"OpusHead");
+    head.push(1); // version
+    head.push(channels);
+    head.extend_from_slice(&OPUS_PRE_SKIP.to_le_bytes());
+    head.extend_from_slice(&48_000u32.to_le_bytes()); // original input rate
+    head.extend_from_slice(&0i16.to_le_bytes()); // output gain
+    head.push(0); // channel mapping family
+
+    let vendor = b"audio-client";
+    let mut tags = Vec::new();
+    tags.extend_from_slice(b"OpusTags");
+    tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    tags.extend_from_slice(vendor);
+    tags.extend_from_slice(&0u32.to_le_bytes()); // no user comments
+
+    let mut pages = Vec::new();
+    // Writing to a Vec can't fail.
+    let _ = write_page(&mut pages, HEADER_BOS, 0, serial, 0, &[&head]);
+    let _ = write_page(&mut pages, 0, 0, serial, 1, &[&tags]);
+    pages
+}
+
+impl<W: Write> OggOpusWriter<W> {
+    pub fn new(mut out: W, serial: u32, channels: u8, packets_per_page: usize) -> io::Result<Self> {
+        out.write_all(&header_pages(serial, channels))?;
+        Ok(Self::continuing(out, serial, packets_per_page))
+    }
+
+    /// A writer for the audio pages only, following [`header_pages`] sent
+    /// separately with the same serial.
+    pub fn continuing(out: W, serial: u32, packets_per_page: usize) -> Self {
+        Self {
+            out,
+            serial,
+            sequence: 2,
+            granule: 0,
+            packets_per_page: packets_per_page.max(1),
+            segments: Vec::new(),
+            body: Vec::new(),
+            packets: 0,
+        }
+    }
+
+    /// Queues one Opus packet covering `frames` samples per channel.
+    pub fn write_packet(&mut self, packet: &[u8], frames: u64) -> io::Result<()> {
+        if self.segments.len() + packet.len() / 255 + 1 > MAX_SEGMENTS {
+            self.flush_page()?;
+        }
+        let mut remaining = packet.len();
+        while remaining >= 255 {
+            self.segments.push(255);
+            remaining -= 255;
+        }
+        self.segments.push(remaining as u8);
+        self.body.extend_from_slice(packet);
+        self.granule += frames;
+        self.packets += 1;
+        if self.packets >= self.packets_per_page {
+            self.flush_page()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush_page(&mut self) -> io::Result<()> {
+        if self.packets == 0 {
+            return Ok(());
+        }
+        let mut page = page_header(0, self.granule, self.serial, self.sequence, &self.segments);
+        page.extend_from_slice(&self.body);
+        set_checksum(&mut page);
+        self.out.write_all(&page)?;
+        self.out.flush()?;
+        self.sequence += 1;
+        self.segments.clear();
+        self.body.clear();
+        self.packets = 0;
+        Ok(())
+    }
+}
+
+/// Encodes blocks of s16le PCM into 20ms Opus packets on `ogg` until the
+/// sender side of `blocks` hangs up.
+pub fn encode_blocks<W: Write>(
+    blocks: impl IntoIterator<Item = Vec<u8>>,
+    encoder: &mut opus::Encoder,
+    ogg: &mut OggOpusWriter<W>,
+    channels: usize,
+) -> io::Result<()> {
+    let frame_len = OPUS_FRAME_FRAMES * channels;
+    let mut pending: Vec<i16> = Vec::new();
+    let mut packet = vec![0u8; MAX_OPUS_PACKET];
+    for block in blocks {
+        pending.extend(block.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])));
+        let mut offset = 0;
+        while pending.len() - offset >= frame_len {
+            let len = encoder
+                .encode(&pending[offset..offset + frame_len], &mut packet)
+                .map_err(io::Error::other)?;
+            ogg.write_packet(&packet[..len], OPUS_FRAME_FRAMES as u64)?;
+            offset += frame_len;
+        }
+        pending.drain(..offset);
+    }
+    Ok(())
+}
+
+fn write_page(out: &mut impl Write, flags: u8, granule: u64, serial: u32, sequence: u32, packets: &[&[u8]]) -> io::Result<()> {
+    let mut segments = Vec::new();
+    for packet in packets {
+        segments.extend(std::iter::repeat_n(255, packet.len() / 255));
+        segments.push((packet.len() % 255) as u8);
+    }
+    let mut page = page_header(flags, granule, serial, sequence, &segments);
+    for packet in packets {
+        page.extend_from_slice(packet);
+    }
+    set_checksum(&mut page);
+    out.write_all(&page)
+}
+
+fn page_header(flags: u8, granule: u64, serial: u32, sequence: u32, segments: &[u8]) -> Vec<u8> {
+    let mut page = Vec::with_capacity(27 + segments.len());
+    page.extend_from_slice(b"OggS");
+    page.push(0); // version
+    page.push(flags);
+    page.extend_from_slice(&granule.to_le_bytes());
+    page.extend_from_slice(&serial.to_le_bytes());
+    page.extend_from_slice(&sequence.to_le_bytes());
+    page.extend_from_slice(&0u32.to_le_bytes()); // checksum, filled in later
+    page.push(segments.len() as u8);
+    page.extend_from_slice(segments);
+    page
+}
+
+fn set_checksum(page: &mut [u8]) {
+    let crc = crc32(page);
+    page[22..26].copy_from_slice(&crc.to_le_bytes());
+}
+
+// CRC-32 with polynomial 0x04c11db7, no reflection and zero initial value,
+// as the Ogg framing spec requires.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0u32;
+    for &byte in data {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pages(mut bytes: &[u8]) -> Vec<(u8, u64, Vec<u8>)> {
+        let mut pages = Vec::new();
+        while !bytes.is_empty() {
+            assert_eq!(&bytes[..4], b"OggS");
+            let count = bytes[26] as usize;
+            let body_len: usize = bytes[27..27 + count].iter().map(|&s| s as usize).sum();
+            let len = 27 + count + body_len;
+            let mut page = bytes[..len].to_vec();
+            let crc = u32::from_le_bytes(page[22..26].try_into().unwrap());
+            page[22..26].copy_from_slice(&[0; 4]);
+            assert_eq!(crc32(&page), crc);
+            let granule = u64::from_le_bytes(bytes[6..14].try_into().unwrap());
+            pages.push((bytes[5], granule, bytes[27 + count..len].to_vec()));
+            bytes = &bytes[len..];
+        }
+        pages
+    }
+
+    #[test]
+    fn test_headers_and_audio_pages() {
+        let mut out = Vec::new();
+        let mut writer = OggOpusWriter::new(&mut out, 7, 2, 2).unwrap();
+        writer.write_packet(&[1; 300], 960).unwrap();
+        writer.write_packet(&[2; 10], 960).unwrap();
+        writer.write_packet(&[3; 10], 960).unwrap();
+        writer.flush_page().unwrap();
+
+        let pages = pages(&out);
+        assert_eq!(pages.len(), 4);
+        assert_eq!(pages[0].0, HEADER_BOS);
+        assert!(pages[0].2.starts_with(b"OpusHead"));
+        assert!(pages[1].2.starts_with(b"OpusTags"));
+        assert_eq!(pages[2].1, 1920);
+        assert_eq!(pages[2].2.len(), 310);
+        assert_eq!(pages[3].1, 2880);
+    }
+
+    #[test]
+    fn test_crc32() {
+        // Check value for the standard test input with Ogg's parameters.
+        assert_eq!(crc32(b"123456789"), 0x89a1_897f);
+    }
+}
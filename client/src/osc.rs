@@ -0,0 +1,262 @@
+use std::sync::Arc;
+
+use tokio::net::UdpSocket;
+use tracing::{error, warn};
+
+use crate::control::{Controls, Message};
+
+/// Every address the client answers starts with this.
+pub const ADDRESS_PREFIX: &str = "/streamer/";
+const BUNDLE_TAG: &[u8] = b"#bundle\0";
+// Bundles nested deeper than this are ignored.
+const MAX_BUNDLE_DEPTH: usize = 8;
+
+/// An OSC 1.0 argument. Types the client has no use for are skipped.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Arg {
+    Int(i32),
+    Float(f32),
+    Str(String),
+    Bool(bool),
+}
+
+impl Arg {
+    // Buttons and toggles send 1.0/0.0 or 1/0 depending on the controller.
+    fn is_on(&self) -> Option<bool> {
+        match self {
+            Arg::Int(value) => Some(*value != 0),
+            Arg::Float(value) => Some(*value >= 0.5),
+            Arg::Bool(value) => Some(*value),
+            Arg::Str(_) => None,
+        }
+    }
+
+    fn as_f32(&self) -> Option<f32> {
+        match self {
+            Arg::Int(value) => Some(*value as f32),
+            Arg::Float(value) => Some(*value),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OscMessage {
+    pub address: String,
+    pub args: Vec<Arg>,
+}
+
+impl OscMessage {
+    /// The control command this message maps to:
+    /// `/streamer/volume f`, `/streamer/mute i`, `/streamer/pause i`,
+    /// `/streamer/redundancy i` and `/streamer/device s`.
+    pub fn command(&self) -> Option<Message> {
+        let first = self.args.first()?;
+        match self.address.strip_prefix(ADDRESS_PREFIX)? {
+            "volume" => first.as_f32().map(Message::SetVolume),
+            "mute" => first.is_on().map(Message::Mute),
+            "pause" => first.is_on().map(Message::Pause),
+            "redundancy" => first.is_on().map(Message::SetRedundancy),
+            "device" => match first {
+                Arg::Str(name) if !name.is_empty() => Some(Message::SwitchDevice(name.clone())),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Parses an OSC packet, a single message or a bundle of them, ignoring
+/// bundle time tags. Returns None if the packet is malformed.
+pub fn parse_packet(packet: &[u8]) -> Option<Vec<OscMessage>> {
+    let mut messages = Vec::new();
+    parse_into(packet, 0, &mut messages)?;
+    Some(messages)
+}
+
+fn parse_into(packet: &[u8], depth: usize, messages: &mut Vec<OscMessage>) -> Option<()> {
+    if !packet.starts_with(BUNDLE_TAG) {
+        messages.push(parse_message(packet)?);
+        return Some(());
+    }
+    if depth >= MAX_BUNDLE_DEPTH {
+        return None;
+    }
+    // Skip the tag and the 8-byte time tag; then size-prefixed elements.
+    let mut rest = packet.get(BUNDLE_TAG.len() + 8..)?;
+    while !rest.is_empty() {
+        let size = u32::from_be_bytes(rest.get(..4)?.try_into().ok()?) as usize;
+        let element = rest.get(4..4 + size)?;
+        parse_into(element, depth + 1, messages)?;
+        rest = &rest[4 + size..];
+    }
+    Some(())
+}
+
+fn parse_message(packet: &[u8]) -> Option<OscMessage> {
+    let mut reader = Reader { bytes: packet };
+    let address = reader.string()?;
+    if !address.starts_with('/') {
+        return None;
+    }
+    // Very old senders omit the type tags; such messages carry no usable arguments.
+    let tags = if reader.bytes.first() == Some(&b',') {
+        reader.string()?
+    } else {
+        String::new()
+    };
+    let mut args = Vec::new();
+    for tag in tags.chars().skip(1) {
+        match tag {
+            'i' => args.push(Arg::Int(i32::from_be_bytes(reader.take(4)?.try_into().ok()?))),
+            'f' => args.push(Arg::Float(f32::from_be_bytes(reader.take(4)?.try_into().ok()?))),
+            's' | 'S' => args.push(Arg::Str(reader.string()?)),
+            'T' => args.push(Arg::Bool(true)),
+            'F' => args.push(Arg::Bool(false)),
+            'h' | 't' | 'd' => {
+                reader.take(8)?;
+            }
+            'c' | 'r' | 'm' => {
+                reader.take(4)?;
+            }
+            'b' => {
+                let size = u32::from_be_bytes(reader.take(4)?.try_into().ok()?) as usize;
+                reader.take(padded(size))?;
+            }
+            'N' | 'I' | '[' | ']' => {}
+            _ => return None,
+        }
+    }
+    Some(OscMessage { address, args })
+}
+
+// OSC pads strings and blobs with zeros to a multiple of four bytes.
+fn padded(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let taken = self.bytes.get(..len)?;
+        self.bytes = &self.bytes[len..];
+        Some(taken)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = self.bytes.iter().position(|&b| b == 0)?;
+        let text = std::str::from_utf8(&self.bytes[..len]).ok()?.to_string();
+        self.take(padded(len + 1).min(self.bytes.len()))?;
+        Some(text)
+    }
+}
+
+/// Applies the commands in OSC packets arriving on `socket`. OSC has no
+/// replies, so the outcome is only logged.
+pub async fn serve(socket: UdpSocket, controls: Arc<Controls>) {
+    let mut buf = [0u8; 2048];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                error!("Error receiving OSC: {}", e);
+                continue;
+            }
+        };
+        let Some(messages) = parse_packet(&buf[..len]) else {
+            warn!("Ignoring malformed OSC packet from {}", peer);
+            continue;
+        };
+        for message in messages {
+            match message.command() {
+                Some(command) => {
+                    controls.apply(command).await;
+                }
+                None => warn!(
+                    "Ignoring OSC message {} {:?} from {}",
+                    message.address, message.args, peer
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(address: &str, tags: &str, args: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::new();
+        for text in [address, tags] {
+            packet.extend_from_slice(text.as_bytes());
+            packet.resize(padded(packet.len() + 1), 0);
+        }
+        packet.extend_from_slice(args);
+        packet
+    }
+
+    #[test]
+    fn test_parse_message() {
+        let packet = message("/streamer/volume", ",f", &0.8f32.to_be_bytes());
+        assert_eq!(packet.len(), 20 + 4 + 4);
+        let messages = parse_packet(&packet).unwrap();
+        assert_eq!(messages[0].args, [Arg::Float(0.8)]);
+        assert_eq!(messages[0].command(), Some(Message::SetVolume(0.8)));
+
+        let packet = message("/streamer/mute", ",i", &1i32.to_be_bytes());
+        assert_eq!(parse_packet(&packet).unwrap()[0].command(), Some(Message::Mute(true)));
+
+        let mut args = b"Line In\0".to_vec();
+        args.extend_from_slice(&7i32.to_be_bytes());
+        let packet = message("/streamer/device", ",si", &args);
+        let parsed = &parse_packet(&packet).unwrap()[0];
+        assert_eq!(parsed.args, [Arg::Str("Line In".to_string()), Arg::Int(7)]);
+        assert_eq!(parsed.command(), Some(Message::SwitchDevice("Line In".to_string())));
+    }
+
+    #[test]
+    fn test_toggle_values() {
+        let pause =
+            |tags: &str, args: &[u8]| parse_packet(&message("/streamer/pause", tags, args)).unwrap()[0].command();
+        assert_eq!(pause(",f", &1.0f32.to_be_bytes()), Some(Message::Pause(true)));
+        assert_eq!(pause(",f", &0.0f32.to_be_bytes()), Some(Message::Pause(false)));
+        assert_eq!(pause(",T", &[]), Some(Message::Pause(true)));
+        assert_eq!(pause(",", &[]), None);
+        assert_eq!(
+            parse_packet(&message("/other/pause", ",i", &[0, 0, 0, 1])).unwrap()[0].command(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_bundle() {
+        let volume = message("/streamer/volume", ",f", &0.5f32.to_be_bytes());
+        let mute = message("/streamer/mute", ",i", &0i32.to_be_bytes());
+        let mut bundle = BUNDLE_TAG.to_vec();
+        bundle.extend_from_slice(&1u64.to_be_bytes());
+        for element in [&volume, &mute] {
+            bundle.extend_from_slice(&(element.len() as u32).to_be_bytes());
+            bundle.extend_from_slice(element);
+        }
+        let commands: Vec<_> = parse_packet(&bundle)
+            .unwrap()
+            .iter()
+            .filter_map(|m| m.command())
+            .collect();
+        assert_eq!(commands, [Message::SetVolume(0.5), Message::Mute(false)]);
+
+        bundle.push(0);
+        assert_eq!(parse_packet(&bundle), None);
+    }
+
+    #[test]
+    fn test_rejects_malformed() {
+        assert_eq!(parse_packet(b"streamer\0\0\0\0"), None);
+        assert_eq!(parse_packet(&message("/streamer/volume", ",f", &[0, 0])), None);
+        assert_eq!(parse_packet(&message("/streamer/volume", ",x", &[])), None);
+        assert_eq!(parse_packet(b"/streamer/volume"), None);
+    }
+}
@@ -0,0 +1,195 @@
+/// Wire format shared with the Go server: a little-endian `u32` sequence
+/// number followed by the encoded frame.
+///
+/// Setting the top bit of the sequence word marks an extended header: a
+/// flags byte follows, then the optional fields it announces, in order:
+///
+/// - `FLAG_TIMESTAMP`: `u64` capture time, microseconds since the Unix epoch
+///   on the sender's synchronized clock
+/// - `FLAG_REDUNDANT`: `u16` primary length; the previous frame follows the
+///   primary one, RFC 2198 style
+/// - `FLAG_PAUSE`: no field; the sender paused and sends nothing more until
+///   it resumes with the next sequence number. Such packets carry no frame.
+/// - `FLAG_END`: no field; the sender stopped for good after the audio
+///   before this packet. Such packets carry no frame either.
+///
+/// The Go server only understands plain packets.
+pub const HEADER_LEN: usize = 4;
+
+pub const SEQ_MASK: u32 = 0x7FFF_FFFF;
+const EXTENDED: u32 = 0x8000_0000;
+const FLAG_TIMESTAMP: u8 = 0x01;
+const FLAG_REDUNDANT: u8 = 0x02;
+const FLAG_PAUSE: u8 = 0x04;
+const FLAG_END: u8 = 0x08;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Packet<'a> {
+    pub seq: u32,
+    pub timestamp: Option<u64>,
+    pub payload: &'a [u8],
+    /// The frame for `seq - 1`, when the sender piggy-backed it.
+    pub redundant: Option<&'a [u8]>,
+    /// The sender is pausing; `seq` is the one it will resume with.
+    pub paused: bool,
+    /// The sender is done; `seq` follows its last frame.
+    pub ended: bool,
+}
+
+pub fn encode(packet: &Packet, out: &mut Vec<u8>) {
+    out.clear();
+    let seq = packet.seq & SEQ_MASK;
+    if packet.timestamp.is_none() && packet.redundant.is_none() && !packet.paused && !packet.ended {
+        out.extend_from_slice(&seq.to_le_bytes());
+        out.extend_from_slice(packet.payload);
+        return;
+    }
+
+    let mut flags = 0;
+    if packet.timestamp.is_some() {
+        flags |= FLAG_TIMESTAMP;
+    }
+    if packet.redundant.is_some() {
+        flags |= FLAG_REDUNDANT;
+    }
+    if packet.paused {
+        flags |= FLAG_PAUSE;
+    }
+    if packet.ended {
+        flags |= FLAG_END;
+    }
+    out.extend_from_slice(&(seq | EXTENDED).to_le_bytes());
+    out.push(flags);
+    if let Some(timestamp) = packet.timestamp {
+        out.extend_from_slice(&timestamp.to_le_bytes());
+    }
+    if packet.redundant.is_some() {
+        out.extend_from_slice(&(packet.payload.len() as u16).to_le_bytes());
+    }
+    out.extend_from_slice(packet.payload);
+    if let Some(previous) = packet.redundant {
+        out.extend_from_slice(previous);
+    }
+}
+
+pub fn decode(bytes: &[u8]) -> Option<Packet<'_>> {
+    let word = u32::from_le_bytes(bytes.get(..HEADER_LEN)?.try_into().ok()?);
+    let mut body = &bytes[HEADER_LEN..];
+    let seq = word & SEQ_MASK;
+
+    if word & EXTENDED == 0 {
+        return Some(Packet {
+            seq,
+            payload: body,
+            ..Packet::default()
+        });
+    }
+
+    let flags = *body.first()?;
+    body = &body[1..];
+    let paused = flags & FLAG_PAUSE != 0;
+    let ended = flags & FLAG_END != 0;
+
+    let mut timestamp = None;
+    if flags & FLAG_TIMESTAMP != 0 {
+        timestamp = Some(u64::from_le_bytes(body.get(..8)?.try_into().ok()?));
+        body = &body[8..];
+    }
+
+    if flags & FLAG_REDUNDANT == 0 {
+        return Some(Packet {
+            seq,
+            timestamp,
+            payload: body,
+            redundant: None,
+            paused,
+            ended,
+        });
+    }
+
+    let primary_len = u16::from_le_bytes(body.get(..2)?.try_into().ok()?) as usize;
+    body = &body[2..];
+    if primary_len > body.len() {
+        return None;
+    }
+    let (payload, previous) = body.split_at(primary_len);
+    Some(Packet {
+        seq,
+        timestamp,
+        payload,
+        redundant: Some(previous),
+        paused,
+        ended,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode_roundtrip() {
+        let mut bytes = Vec::new();
+        let packet = Packet {
+            seq: 42,
+            payload: &[1, 2, 3, 4],
+            ..Packet::default()
+        };
+        encode(&packet, &mut bytes);
+        assert_eq!(bytes.len(), HEADER_LEN + 4);
+        assert_eq!(decode(&bytes), Some(packet));
+    }
+
+    #[test]
+    fn test_extended_roundtrip() {
+        let mut bytes = Vec::new();
+        let packet = Packet {
+            seq: 7,
+            timestamp: Some(1_700_000_000_000_000),
+            payload: &[1, 2],
+            redundant: Some(&[3, 4, 5]),
+            paused: false,
+            ended: false,
+        };
+        encode(&packet, &mut bytes);
+        assert_eq!(decode(&bytes), Some(packet));
+
+        let packet = Packet {
+            seq: 8,
+            timestamp: Some(5),
+            payload: &[1, 2],
+            redundant: None,
+            paused: false,
+            ended: false,
+        };
+        encode(&packet, &mut bytes);
+        assert_eq!(decode(&bytes), Some(packet));
+
+        let packet = Packet {
+            seq: 9,
+            paused: true,
+            ..Packet::default()
+        };
+        encode(&packet, &mut bytes);
+        assert_eq!(bytes, [9, 0, 0, 0x80, FLAG_PAUSE]);
+        assert_eq!(decode(&bytes), Some(packet));
+
+        let packet = Packet {
+            seq: 10,
+            ended: true,
+            ..Packet::default()
+        };
+        encode(&packet, &mut bytes);
+        assert_eq!(bytes, [10, 0, 0, 0x80, FLAG_END]);
+        assert_eq!(decode(&bytes), Some(packet));
+    }
+
+    #[test]
+    fn test_decode_short_packet() {
+        assert_eq!(decode(&[1, 2, 3]), None);
+        // Extended header without its flags byte.
+        assert_eq!(decode(&[0, 0, 0, 0x80]), None);
+        // Redundant flag set but the primary length overruns the packet.
+        assert_eq!(decode(&[0, 0, 0, 0x80, FLAG_REDUNDANT, 9, 0, 1]), None);
+    }
+}
@@ -0,0 +1,586 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tracing::{info, warn};
+
+use crate::clock::SyncedClock;
+use crate::codec::FrameDecoder;
+use crate::drift::DriftCompensator;
+use crate::jitter::{JitterBuffer, Playout};
+use crate::packet;
+use crate::rendezvous;
+
+/// Reads sequenced packets off the socket into the jitter buffer. Punches
+/// and keepalives from a rendezvous peer are skipped.
+pub async fn receive(socket: UdpSocket, buffer: Arc<Mutex<JitterBuffer>>, clock: Arc<SyncedClock>) -> io::Result<()> {
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let (len, _) = socket.recv_from(&mut buf).await?;
+        if rendezvous::is_message(&buf[..len]) {
+            continue;
+        }
+        accept(&buf[..len], &buffer, &clock);
+    }
+}
+
+/// Decodes one received packet into the jitter buffer, whatever transport
+/// carried it.
+pub fn accept(datagram: &[u8], buffer: &Mutex<JitterBuffer>, clock: &SyncedClock) {
+    let packet = match packet::decode(datagram) {
+        Some(packet) => packet,
+        None => {
+            warn!("Received packet of unexpected size: {} bytes", datagram.len());
+            return;
+        }
+    };
+    let mut jb = buffer.lock().unwrap();
+    if packet.ended {
+        // The marker comes more than once.
+        if !jb.is_ending() {
+            info!("Sender ended the stream");
+            jb.end();
+        }
+        return;
+    }
+    if packet.paused {
+        info!("Sender paused");
+        jb.drain();
+        return;
+    }
+    if let Some(timestamp) = packet.timestamp {
+        // Wrapping, as a timestamp is whatever the packet says.
+        jb.record_transit((clock.now_micros() as i64).wrapping_sub(timestamp as i64));
+    }
+    jb.push_stamped(packet.seq, packet.payload.to_vec(), packet.timestamp, Instant::now());
+    if let Some(previous) = packet.redundant {
+        jb.push_redundant(packet.seq.wrapping_sub(1) & packet::SEQ_MASK, previous.to_vec());
+    }
+}
+
+// End-to-end latency exchange on a receiver's control port. A sender sends
+// [PLAYOUT_REQUEST, t1: u64] and the receiver answers [PLAYOUT_RESPONSE,
+// t1, playing: u64], where `playing` is the sender timestamp of the audio
+// reaching its speaker, or 0 when that audio carries none.
+pub const PLAYOUT_REQUEST: u8 = 0x04;
+pub const PLAYOUT_RESPONSE: u8 = 0x05;
+pub const PLAYOUT_REQUEST_LEN: usize = 9;
+const PLAYOUT_RESPONSE_LEN: usize = 17;
+
+/// What a receiver is playing, kept by the output callback for senders
+/// that measure capture-to-speaker latency.
+#[derive(Debug, Default)]
+pub struct PlayoutProbe {
+    position: Mutex<Option<(u64, Instant)>>,
+}
+
+impl PlayoutProbe {
+    /// Records that audio stamped `timestamp` by the sender reaches the
+    /// speaker at `at`; None while playing unstamped audio or silence.
+    pub fn record(&self, timestamp: Option<u64>, at: Instant) {
+        *self.position.lock().unwrap() = timestamp.map(|timestamp| (timestamp, at));
+    }
+
+    /// The sender timestamp of the audio at the speaker now.
+    pub fn playing(&self) -> Option<u64> {
+        let (timestamp, at) = (*self.position.lock().unwrap())?;
+        let now = Instant::now();
+        Some(if now >= at {
+            timestamp.saturating_add((now - at).as_micros() as u64)
+        } else {
+            timestamp.saturating_sub((at - now).as_micros() as u64)
+        })
+    }
+}
+
+pub fn playout_request(t1: u64) -> [u8; PLAYOUT_REQUEST_LEN] {
+    let mut request = [0u8; PLAYOUT_REQUEST_LEN];
+    request[0] = PLAYOUT_REQUEST;
+    request[1..9].copy_from_slice(&t1.to_le_bytes());
+    request
+}
+
+/// Builds the reply to a playout request from what the receiver plays.
+pub fn playout_response(request: &[u8], playing: Option<u64>) -> Option<[u8; PLAYOUT_RESPONSE_LEN]> {
+    if request.len() != PLAYOUT_REQUEST_LEN || request[0] != PLAYOUT_REQUEST {
+        return None;
+    }
+    let mut response = [0u8; PLAYOUT_RESPONSE_LEN];
+    response[0] = PLAYOUT_RESPONSE;
+    response[1..9].copy_from_slice(&request[1..9]);
+    response[9..17].copy_from_slice(&playing.unwrap_or(0).to_le_bytes());
+    Some(response)
+}
+
+/// Returns `(t1, playing)` from a playout response.
+pub fn parse_playout_response(response: &[u8]) -> Option<(u64, Option<u64>)> {
+    if response.len() != PLAYOUT_RESPONSE_LEN || response[0] != PLAYOUT_RESPONSE {
+        return None;
+    }
+    let read = |at: usize| u64::from_le_bytes(response[at..at + 8].try_into().unwrap());
+    Some((read(1), Some(read(9)).filter(|&playing| playing != 0)))
+}
+
+// Concealed frames fade by this factor each, reaching silence after the cap.
+const CONCEAL_DECAY: f32 = 0.5;
+const MAX_CONCEALED_FRAMES: u32 = 4;
+
+// Rate correction per second of smoothed playout error, and its bound.
+const SYNC_GAIN: f64 = 0.2;
+const MAX_SYNC_CORRECTION: f64 = 0.001;
+// Smoothing per packet for the playout error, which is measured against the
+// callback's wall-clock start and so inherits its scheduling jitter.
+const SYNC_SMOOTHING: f64 = 0.05;
+
+/// Plays each packet when the synchronized clock reaches its sender
+/// timestamp plus a fixed delay. Receivers sharing a clock and a delay
+/// stay in lockstep: packets that are early wait behind silence, packets
+/// that are late are dropped, and the remaining sub-packet error is pulled
+/// in by resampling.
+pub struct PlayoutSchedule {
+    clock: Arc<SyncedClock>,
+    delay_micros: i64,
+    frame_micros: f64,
+    output_latency_micros: i64,
+    callback_start: i64,
+    rendered_frames: u64,
+    smoothed_error: Option<f64>,
+}
+
+impl PlayoutSchedule {
+    pub fn new(clock: Arc<SyncedClock>, delay: Duration, sample_rate: u32) -> Self {
+        Self {
+            clock,
+            delay_micros: delay.as_micros() as i64,
+            frame_micros: 1_000_000.0 / sample_rate as f64,
+            output_latency_micros: 0,
+            callback_start: 0,
+            rendered_frames: 0,
+            smoothed_error: None,
+        }
+    }
+
+    /// Time between a callback running and its first frame reaching the
+    /// speaker, as reported by the audio backend.
+    pub fn set_output_latency(&mut self, latency: Duration) {
+        self.output_latency_micros = latency.as_micros() as i64;
+    }
+
+    fn begin_callback(&mut self) {
+        self.callback_start = self.clock.now_micros() as i64;
+        self.rendered_frames = 0;
+    }
+
+    /// How far behind schedule a packet stamped `timestamp` would be if it
+    /// started playing now; negative when it is early.
+    fn lateness(&self, timestamp: u64) -> i64 {
+        let plays_at = self.callback_start
+            + self.output_latency_micros
+            + (self.rendered_frames as f64 * self.frame_micros) as i64;
+        plays_at.saturating_sub((timestamp as i64).saturating_add(self.delay_micros))
+    }
+
+    fn update(&mut self, lateness: i64) -> f64 {
+        let error = lateness as f64 / 1_000_000.0;
+        let smoothed = match self.smoothed_error {
+            Some(prev) => prev + (error - prev) * SYNC_SMOOTHING,
+            None => error,
+        };
+        self.smoothed_error = Some(smoothed);
+        1.0 + (smoothed * SYNC_GAIN).clamp(-MAX_SYNC_CORRECTION, MAX_SYNC_CORRECTION)
+    }
+}
+
+/// How a packet that missed its playout deadline is filled in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Concealment {
+    /// Replay the last good frame with a fading gain, or use the codec's own
+    /// concealment when it has one
+    Repeat,
+    /// Insert silence
+    Silence,
+}
+
+/// Feeds the output callback from the jitter buffer, pulling one packet
+/// whenever the previous one has been played out. With drift compensation
+/// on, input frames are consumed slightly faster or slower than real time
+/// (linear interpolation between neighbouring frames) to hold the buffer at
+/// its target depth.
+pub struct PlaybackCursor {
+    samples: Vec<i16>,
+    pos: usize,
+    frame_samples: usize,
+    channels: usize,
+    concealment: Concealment,
+    decoder: FrameDecoder,
+    // Sender timestamp of the first sample in `samples`.
+    timestamp: Option<u64>,
+    last_frame: Vec<i16>,
+    lost_run: u32,
+    drift: Option<DriftCompensator>,
+    schedule: Option<PlayoutSchedule>,
+    ratio: f64,
+    frac: f64,
+    prev: Vec<i16>,
+    next: Vec<i16>,
+    frame: Vec<i16>,
+}
+
+impl PlaybackCursor {
+    pub fn new(frame_samples: usize, channels: usize, concealment: Concealment, decoder: FrameDecoder) -> Self {
+        Self {
+            samples: Vec::with_capacity(frame_samples),
+            pos: 0,
+            frame_samples,
+            channels,
+            concealment,
+            decoder,
+            timestamp: None,
+            last_frame: Vec::with_capacity(frame_samples),
+            lost_run: 0,
+            drift: None,
+            schedule: None,
+            ratio: 1.0,
+            frac: 0.0,
+            prev: Vec::with_capacity(channels),
+            next: Vec::with_capacity(channels),
+            frame: Vec::with_capacity(channels),
+        }
+    }
+
+    pub fn set_drift_compensation(&mut self, enabled: bool) {
+        self.drift = enabled.then(DriftCompensator::new);
+        self.ratio = 1.0;
+    }
+
+    /// Schedules playout against sender timestamps. Takes over rate control
+    /// from drift compensation, since holding the schedule already tracks
+    /// the sender's clock.
+    pub fn set_schedule(&mut self, schedule: Option<PlayoutSchedule>) {
+        self.schedule = schedule;
+        self.ratio = 1.0;
+    }
+
+    pub fn schedule_mut(&mut self) -> Option<&mut PlayoutSchedule> {
+        self.schedule.as_mut()
+    }
+
+    /// The sender timestamp of the next frame to play, when the packet it
+    /// came in was stamped.
+    pub fn next_timestamp(&self, sample_rate: u32) -> Option<u64> {
+        let frames = (self.pos / self.channels) as u64;
+        Some(self.timestamp?.saturating_add(frames * 1_000_000 / sample_rate as u64))
+    }
+
+    pub fn fill_i16(&mut self, buffer: &Mutex<JitterBuffer>, out: &mut [i16]) {
+        if let Some(schedule) = &mut self.schedule {
+            schedule.begin_callback();
+        }
+        for chunk in out.chunks_mut(self.channels) {
+            self.render_frame(buffer);
+            chunk.copy_from_slice(&self.frame[..chunk.len()]);
+        }
+    }
+
+    pub fn fill_f32(&mut self, buffer: &Mutex<JitterBuffer>, out: &mut [f32]) {
+        if let Some(schedule) = &mut self.schedule {
+            schedule.begin_callback();
+        }
+        for chunk in out.chunks_mut(self.channels) {
+            self.render_frame(buffer);
+            for (sample, &value) in chunk.iter_mut().zip(&self.frame) {
+                *sample = value as f32 / i16::MAX as f32;
+            }
+        }
+    }
+
+    fn render_frame(&mut self, buffer: &Mutex<JitterBuffer>) {
+        if self.prev.is_empty() {
+            for _ in 0..self.channels {
+                let sample = self.next_sample(buffer);
+                self.prev.push(sample);
+            }
+            for _ in 0..self.channels {
+                let sample = self.next_sample(buffer);
+                self.next.push(sample);
+            }
+        }
+
+        self.frame.clear();
+        for (&a, &b) in self.prev.iter().zip(&self.next) {
+            let value = a as f64 + (b as f64 - a as f64) * self.frac;
+            self.frame.push(value.round() as i16);
+        }
+
+        if let Some(schedule) = &mut self.schedule {
+            schedule.rendered_frames += 1;
+        }
+        self.frac += self.ratio;
+        while self.frac >= 1.0 {
+            self.frac -= 1.0;
+            std::mem::swap(&mut self.prev, &mut self.next);
+            for c in 0..self.channels {
+                self.next[c] = self.next_sample(buffer);
+            }
+        }
+    }
+
+    fn next_sample(&mut self, buffer: &Mutex<JitterBuffer>) -> i16 {
+        if self.pos >= self.samples.len() {
+            self.refill(buffer);
+        }
+        let sample = self.samples[self.pos];
+        self.pos += 1;
+        sample
+    }
+
+    fn refill(&mut self, buffer: &Mutex<JitterBuffer>) {
+        self.samples.clear();
+        self.pos = 0;
+        self.timestamp = None;
+        let mut jb = buffer.lock().unwrap();
+        if self.hold_for_schedule(&mut jb) {
+            drop(jb);
+            self.samples.resize(self.frame_samples, 0);
+            return;
+        }
+        let timestamp = jb.head_timestamp();
+        match jb.pop() {
+            Playout::Packet(bytes) => {
+                self.timestamp = timestamp;
+                if let (None, Some(drift)) = (&self.schedule, &mut self.drift) {
+                    self.ratio = drift.update(jb.depth(), jb.target_depth());
+                }
+                let last = jb.is_ending() && jb.depth() == 0;
+                drop(jb);
+                self.decoder.decode(&bytes, &mut self.samples);
+                if last {
+                    self.fade_out();
+                }
+                self.last_frame.clear();
+                self.last_frame.extend_from_slice(&self.samples);
+                self.lost_run = 0;
+            }
+            Playout::Missing => {
+                if self.concealment == Concealment::Silence
+                    || !self.decoder.conceal(jb.peek_next(), &mut self.samples)
+                {
+                    drop(jb);
+                    self.conceal();
+                }
+            }
+            Playout::Buffering => self.lost_run = 0,
+        }
+        // Anything not filled in above plays out as one frame of silence.
+        if self.samples.is_empty() {
+            self.samples.resize(self.frame_samples, 0);
+        }
+    }
+
+    // Drops packets whose slot has passed and reports whether the next one
+    // is still early, in which case the caller plays silence and retries.
+    fn hold_for_schedule(&mut self, jb: &mut JitterBuffer) -> bool {
+        let Some(schedule) = &mut self.schedule else {
+            return false;
+        };
+        let packet_micros = (self.frame_samples / self.channels) as f64 * schedule.frame_micros;
+        while let Some(timestamp) = jb.head_timestamp() {
+            let lateness = schedule.lateness(timestamp);
+            if lateness as f64 > packet_micros {
+                jb.discard_head();
+            } else if (lateness as f64) < -packet_micros {
+                return true;
+            } else {
+                self.ratio = schedule.update(lateness);
+                return false;
+            }
+        }
+        false
+    }
+
+    // Ramps the last frame of an ended stream down to silence, so it stops
+    // without a click.
+    fn fade_out(&mut self) {
+        let frames = (self.samples.len() / self.channels).max(1) as f32;
+        for (i, frame) in self.samples.chunks_mut(self.channels).enumerate() {
+            let gain = 1.0 - (i + 1) as f32 / frames;
+            for sample in frame {
+                *sample = (*sample as f32 * gain) as i16;
+            }
+        }
+    }
+
+    // Waveform repetition: replay the last good frame, ramping the gain down
+    // across it so consecutive losses fade out instead of buzzing.
+    fn conceal(&mut self) {
+        if self.concealment == Concealment::Repeat && self.lost_run < MAX_CONCEALED_FRAMES {
+            let start = CONCEAL_DECAY.powi(self.lost_run as i32);
+            let end = CONCEAL_DECAY.powi(self.lost_run as i32 + 1);
+            let len = self.last_frame.len().max(1) as f32;
+            self.samples.extend(self.last_frame.iter().enumerate().map(|(i, &s)| {
+                let gain = start + (end - start) * i as f32 / len;
+                (s as f32 * gain) as i16
+            }));
+        }
+        self.lost_run += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_end_of_stream_fades_out() {
+        let buffer = Mutex::new(JitterBuffer::new(Duration::from_millis(10), Duration::from_millis(10)));
+        let clock = SyncedClock::new();
+        let payload: Vec<u8> = [1000i16; 8].iter().flat_map(|s| s.to_le_bytes()).collect();
+        let audio = packet::Packet {
+            seq: 0,
+            payload: &payload,
+            ..packet::Packet::default()
+        };
+        let end = packet::Packet {
+            seq: 1,
+            ended: true,
+            ..packet::Packet::default()
+        };
+        let mut datagram = Vec::new();
+        packet::encode(&audio, &mut datagram);
+        accept(&datagram, &buffer, &clock);
+        packet::encode(&end, &mut datagram);
+        accept(&datagram, &buffer, &clock);
+        accept(&datagram, &buffer, &clock);
+
+        let mut cursor = PlaybackCursor::new(8, 2, Concealment::Repeat, FrameDecoder::Pcm);
+        let mut out = [0i16; 8];
+        cursor.fill_i16(&buffer, &mut out);
+        assert_eq!(out, [750, 750, 500, 500, 250, 250, 0, 0]);
+        assert!(!buffer.lock().unwrap().is_ending());
+    }
+
+    #[test]
+    fn test_cursor_plays_packets_then_silence() {
+        let buffer = Mutex::new(JitterBuffer::new(Duration::from_millis(10), Duration::from_millis(10)));
+        let payload: Vec<u8> = [100i16, -100].iter().flat_map(|s| s.to_le_bytes()).collect();
+        buffer.lock().unwrap().push(0, payload, Instant::now());
+
+        let mut cursor = PlaybackCursor::new(2, 2, Concealment::Repeat, FrameDecoder::Pcm);
+        let mut out = [1i16; 4];
+        cursor.fill_i16(&buffer, &mut out);
+        assert_eq!(out, [100, -100, 0, 0]);
+    }
+
+    #[test]
+    fn test_cursor_conceals_missing_packet() {
+        let buffer = Mutex::new(JitterBuffer::new(Duration::from_millis(10), Duration::from_millis(10)));
+        let payload: Vec<u8> = [1000i16, 1000].iter().flat_map(|s| s.to_le_bytes()).collect();
+        let now = Instant::now();
+        buffer.lock().unwrap().push(0, payload.clone(), now);
+        buffer.lock().unwrap().push(2, payload, now);
+
+        let mut cursor = PlaybackCursor::new(2, 2, Concealment::Repeat, FrameDecoder::Pcm);
+        let mut out = [0i16; 6];
+        cursor.fill_i16(&buffer, &mut out);
+        assert_eq!(&out[..2], &[1000, 1000]);
+        assert_eq!(out[2], 1000);
+        assert_eq!(out[3], 750);
+        assert_eq!(&out[4..], &[1000, 1000]);
+
+        let buffer = Mutex::new(JitterBuffer::new(Duration::from_millis(10), Duration::from_millis(10)));
+        let payload: Vec<u8> = [1000i16, 1000].iter().flat_map(|s| s.to_le_bytes()).collect();
+        buffer.lock().unwrap().push(0, payload.clone(), now);
+        buffer.lock().unwrap().push(2, payload, now);
+        let mut cursor = PlaybackCursor::new(2, 2, Concealment::Silence, FrameDecoder::Pcm);
+        cursor.fill_i16(&buffer, &mut out);
+        assert_eq!(&out[2..4], &[0, 0]);
+    }
+
+    #[test]
+    fn test_cursor_speeds_up_when_buffer_runs_full() {
+        let buffer = Mutex::new(JitterBuffer::new(Duration::from_millis(10), Duration::from_millis(10)));
+        let now = Instant::now();
+        for seq in 0..400u32 {
+            let payload: Vec<u8> = [seq as i16, seq as i16].iter().flat_map(|s| s.to_le_bytes()).collect();
+            buffer.lock().unwrap().push(seq, payload, now);
+        }
+
+        let mut cursor = PlaybackCursor::new(2, 2, Concealment::Repeat, FrameDecoder::Pcm);
+        cursor.set_drift_compensation(true);
+        let mut out = [0i16; 2];
+        cursor.fill_i16(&buffer, &mut out);
+        assert!(cursor.ratio > 1.0);
+    }
+
+    #[test]
+    fn test_schedule_drops_late_and_holds_early_packets() {
+        let clock = Arc::new(SyncedClock::new());
+        let delay = Duration::from_millis(100);
+        let frame = |value: i16| -> Vec<u8> { [value; 960].iter().flat_map(|s| s.to_le_bytes()).collect() };
+        let now = clock.now_micros();
+
+        let buffer = Mutex::new(JitterBuffer::new(Duration::from_millis(10), Duration::from_millis(10)));
+        buffer.lock().unwrap().push_stamped(0, frame(100), Some(now - 1_000_000), Instant::now());
+        buffer.lock().unwrap().push_stamped(1, frame(200), Some(now - 100_000), Instant::now());
+        let mut cursor = PlaybackCursor::new(960, 2, Concealment::Repeat, FrameDecoder::Pcm);
+        cursor.set_schedule(Some(PlayoutSchedule::new(clock.clone(), delay, 48_000)));
+        let mut out = [0i16; 4];
+        cursor.fill_i16(&buffer, &mut out);
+        assert_eq!(out, [200; 4]);
+        assert_eq!(buffer.lock().unwrap().stats().trimmed, 1);
+
+        let buffer = Mutex::new(JitterBuffer::new(Duration::from_millis(10), Duration::from_millis(10)));
+        buffer.lock().unwrap().push_stamped(0, frame(100), Some(now + 1_000_000), Instant::now());
+        let mut cursor = PlaybackCursor::new(960, 2, Concealment::Repeat, FrameDecoder::Pcm);
+        cursor.set_schedule(Some(PlayoutSchedule::new(clock, delay, 48_000)));
+        cursor.fill_i16(&buffer, &mut out);
+        assert_eq!(out, [0; 4]);
+        assert_eq!(buffer.lock().unwrap().depth(), 1);
+    }
+
+    #[test]
+    fn test_cursor_tracks_playing_timestamp() {
+        let buffer = Mutex::new(JitterBuffer::new(Duration::from_millis(10), Duration::from_millis(10)));
+        let payload: Vec<u8> = [0i16; 1920].iter().flat_map(|s| s.to_le_bytes()).collect();
+        buffer.lock().unwrap().push_stamped(0, payload, Some(1_000_000), Instant::now());
+        let mut cursor = PlaybackCursor::new(1920, 2, Concealment::Repeat, FrameDecoder::Pcm);
+        assert_eq!(cursor.next_timestamp(48_000), None);
+        // 5 ms in, give or take the frames read ahead for interpolation.
+        let mut out = [0i16; 480];
+        cursor.fill_i16(&buffer, &mut out);
+        let next = cursor.next_timestamp(48_000).unwrap();
+        assert!((1_005_000..1_005_100).contains(&next), "{}", next);
+    }
+
+    #[test]
+    fn test_timestamps_near_the_end_saturate() {
+        let buffer = Mutex::new(JitterBuffer::new(Duration::from_millis(10), Duration::from_millis(10)));
+        let payload: Vec<u8> = [0i16; 1920].iter().flat_map(|s| s.to_le_bytes()).collect();
+        buffer.lock().unwrap().push_stamped(0, payload, Some(u64::MAX - 1), Instant::now());
+        let mut cursor = PlaybackCursor::new(1920, 2, Concealment::Repeat, FrameDecoder::Pcm);
+        let mut out = [0i16; 480];
+        cursor.fill_i16(&buffer, &mut out);
+        assert_eq!(cursor.next_timestamp(48_000), Some(u64::MAX));
+
+        let probe = PlayoutProbe::default();
+        probe.record(Some(u64::MAX - 1), Instant::now() - Duration::from_millis(20));
+        assert_eq!(probe.playing(), Some(u64::MAX));
+    }
+
+    #[test]
+    fn test_playout_exchange() {
+        let request = playout_request(1_000);
+        let response = playout_response(&request, Some(2_000)).unwrap();
+        assert_eq!(parse_playout_response(&response), Some((1_000, Some(2_000))));
+        let response = playout_response(&request, None).unwrap();
+        assert_eq!(parse_playout_response(&response), Some((1_000, None)));
+        assert_eq!(playout_response(&request[..8], None), None);
+        assert_eq!(parse_playout_response(&request), None);
+
+        let probe = PlayoutProbe::default();
+        assert_eq!(probe.playing(), None);
+        probe.record(Some(1_000_000), Instant::now() - Duration::from_millis(20));
+        let playing = probe.playing().unwrap();
+        assert!((1_020_000..1_030_000).contains(&playing), "{}", playing);
+    }
+}
@@ -0,0 +1,454 @@
+//! Port mappings on the home router, so a receiver behind it can be
+//! reached from the internet without forwarding the port by hand. NAT-PMP
+//! is tried first, as it is quick to rule out, and UPnP IGD after it.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+use crate::dlna;
+use crate::http;
+
+const NAT_PMP_PORT: u16 = 5351;
+// The first reply is awaited 250ms and each retry twice as long, so a
+// router that doesn't speak NAT-PMP costs under two seconds.
+const NAT_PMP_FIRST_WAIT: Duration = Duration::from_millis(250);
+const NAT_PMP_TRIES: u32 = 3;
+const IGD: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+const WAN_SERVICES: [&str; 2] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:",
+    "urn:schemas-upnp-org:service:WANPPPConnection:",
+];
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(3);
+// UPnP's error for routers that only keep mappings without a lease.
+const ONLY_PERMANENT_LEASES: u32 = 725;
+const DESCRIPTION: &str = "audio-client";
+/// How long each mapping is leased for. It is renewed at half that, and
+/// lapses on its own if the receiver dies without removing it.
+pub const LEASE: Duration = Duration::from_secs(3600);
+
+/// What the mapped port carries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PortProtocol {
+    Udp,
+    Tcp,
+}
+
+impl PortProtocol {
+    fn nat_pmp_opcode(self) -> u8 {
+        match self {
+            PortProtocol::Udp => 1,
+            PortProtocol::Tcp => 2,
+        }
+    }
+
+    fn upnp_name(self) -> &'static str {
+        match self {
+            PortProtocol::Udp => "UDP",
+            PortProtocol::Tcp => "TCP",
+        }
+    }
+}
+
+// The router that made a mapping, and how to talk to it again.
+#[derive(Clone, Debug)]
+enum Gateway {
+    NatPmp(Ipv4Addr),
+    Upnp {
+        control_url: String,
+        service: String,
+        internal: Ipv4Addr,
+    },
+}
+
+/// A port on the router forwarded to the same port on this machine.
+#[derive(Clone, Debug)]
+pub struct PortMapping {
+    pub protocol: PortProtocol,
+    pub port: u16,
+    /// Where senders on the internet reach this machine.
+    pub external: SocketAddr,
+    gateway: Gateway,
+}
+
+impl PortMapping {
+    /// Asks the router to forward `port` to this machine, with NAT-PMP and
+    /// then UPnP. Blocks for a few seconds when the router speaks neither.
+    pub fn request(protocol: PortProtocol, port: u16) -> io::Result<Self> {
+        let nat_pmp_error = match default_gateway().and_then(|gateway| nat_pmp_map(gateway, protocol, port, LEASE)) {
+            Ok(mapping) => return Ok(mapping),
+            Err(e) => e,
+        };
+        debug!("NAT-PMP port mapping failed: {}", nat_pmp_error);
+        upnp_map(protocol, port).map_err(|e| {
+            let message = format!("the router mapped no port: NAT-PMP: {}; UPnP: {}", nat_pmp_error, e);
+            io::Error::new(e.kind(), message)
+        })
+    }
+
+    /// Which protocol made the mapping.
+    pub fn method(&self) -> &'static str {
+        match self.gateway {
+            Gateway::NatPmp(_) => "NAT-PMP",
+            Gateway::Upnp { .. } => "UPnP",
+        }
+    }
+
+    /// Whether the router's own address is a private one, as behind a
+    /// second router or a carrier-grade NAT, where the mapping alone
+    /// doesn't make this machine reachable.
+    pub fn behind_another_nat(&self) -> bool {
+        match self.external.ip() {
+            IpAddr::V4(ip) => {
+                let [a, b, ..] = ip.octets();
+                ip.is_private() || ip.is_unspecified() || (a == 100 && (64..128).contains(&b))
+            }
+            IpAddr::V6(_) => false,
+        }
+    }
+
+    /// Extends the lease. A NAT-PMP router may move the mapping to
+    /// another external port when it does.
+    pub fn renew(&mut self) -> io::Result<()> {
+        match &self.gateway {
+            Gateway::NatPmp(gateway) => {
+                let renewed = nat_pmp_map(*gateway, self.protocol, self.port, LEASE)?;
+                if renewed.external != self.external {
+                    warn!("The router moved the port mapping to {}", renewed.external);
+                }
+                self.external = renewed.external;
+                Ok(())
+            }
+            Gateway::Upnp {
+                control_url,
+                service,
+                internal,
+            } => upnp_add(control_url, service, self.protocol, self.port, *internal),
+        }
+    }
+
+    /// Takes the mapping off the router.
+    pub fn remove(&self) -> io::Result<()> {
+        match &self.gateway {
+            Gateway::NatPmp(gateway) => {
+                // A zero lifetime and external port delete the mapping.
+                let request = nat_pmp_request(self.protocol, self.port, 0, Duration::ZERO);
+                nat_pmp_call(*gateway, &request, 16).map(|_| ())
+            }
+            Gateway::Upnp {
+                control_url, service, ..
+            } => {
+                let arguments = format!(
+                    "<NewRemoteHost></NewRemoteHost><NewExternalPort>{}</NewExternalPort><NewProtocol>{}</NewProtocol>",
+                    self.external.port(),
+                    self.protocol.upnp_name()
+                );
+                let (status, body) = dlna::soap(control_url, service, "DeletePortMapping", &arguments)?;
+                upnp_result("DeletePortMapping", status, &body)
+            }
+        }
+    }
+
+    /// Renews the mapping at half its lease on a thread of its own, until
+    /// [`MappingKeeper::release`] removes it.
+    pub fn keep(self) -> MappingKeeper {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let mut mapping = self;
+        let thread = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(LEASE / 2) {
+                if let Err(e) = mapping.renew() {
+                    warn!("Could not renew the port mapping: {}", e);
+                }
+            }
+            match mapping.remove() {
+                Ok(()) => info!("Removed the port mapping for {}", mapping.external),
+                Err(e) => warn!("Could not remove the port mapping: {}", e),
+            }
+        });
+        MappingKeeper { stop, thread }
+    }
+}
+
+/// Keeps a [`PortMapping`] leased; see [`PortMapping::keep`].
+pub struct MappingKeeper {
+    stop: mpsc::Sender<()>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl MappingKeeper {
+    /// Stops renewing the mapping and removes it, waiting for the router.
+    pub fn release(self) {
+        drop(self.stop);
+        let _ = self.thread.join();
+    }
+}
+
+// The router: the default route's gateway where the routing table can be
+// read, and otherwise the first address of this machine's /24, which is
+// the router on most home networks.
+fn default_gateway() -> io::Result<Ipv4Addr> {
+    if let Some(gateway) = std::fs::read_to_string("/proc/net/route")
+        .ok()
+        .and_then(|table| route_table_gateway(&table))
+    {
+        return Ok(gateway);
+    }
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    // Connecting a UDP socket sends nothing; it only picks the route.
+    socket.connect("192.0.2.1:9")?;
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(local) => {
+            let [a, b, c, _] = local.octets();
+            Ok(Ipv4Addr::new(a, b, c, 1))
+        }
+        IpAddr::V6(_) => Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "no IPv4 route")),
+    }
+}
+
+// The gateway of the default route in Linux's /proc/net/route, whose
+// addresses are hex in the machine's byte order.
+fn route_table_gateway(table: &str) -> Option<Ipv4Addr> {
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (destination, gateway) = (fields.get(1)?, fields.get(2)?);
+        let gateway = u32::from_str_radix(gateway, 16).ok()?;
+        (*destination == "00000000" && gateway != 0).then(|| Ipv4Addr::from(gateway.to_ne_bytes()))
+    })
+}
+
+fn nat_pmp_request(protocol: PortProtocol, internal: u16, external: u16, lease: Duration) -> [u8; 12] {
+    let mut request = [0u8; 12];
+    request[1] = protocol.nat_pmp_opcode();
+    request[4..6].copy_from_slice(&internal.to_be_bytes());
+    request[6..8].copy_from_slice(&external.to_be_bytes());
+    request[8..12].copy_from_slice(&(lease.as_secs() as u32).to_be_bytes());
+    request
+}
+
+// Sends a NAT-PMP request until the router answers it, and returns the
+// answer once it is at least `reply_len` bytes and successful.
+fn nat_pmp_call(gateway: Ipv4Addr, request: &[u8], reply_len: usize) -> io::Result<Vec<u8>> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect((gateway, NAT_PMP_PORT))?;
+    let mut wait = NAT_PMP_FIRST_WAIT;
+    let mut buf = [0u8; 16];
+    for _ in 0..NAT_PMP_TRIES {
+        socket.send(request)?;
+        let deadline = Instant::now() + wait;
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            socket.set_read_timeout(Some(remaining.max(Duration::from_millis(1))))?;
+            let len = match socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => break,
+                Err(e) => return Err(e),
+            };
+            // Replies echo the opcode with the top bit set.
+            if len < reply_len || buf[0] != 0 || buf[1] != (request[1] | 0x80) {
+                continue;
+            }
+            return match u16::from_be_bytes([buf[2], buf[3]]) {
+                0 => Ok(buf[..len].to_vec()),
+                code => Err(io::Error::other(format!("the router refused with result code {}", code))),
+            };
+        }
+        wait *= 2;
+    }
+    Err(io::Error::new(io::ErrorKind::TimedOut, format!("no answer from {}", gateway)))
+}
+
+fn nat_pmp_map(gateway: Ipv4Addr, protocol: PortProtocol, port: u16, lease: Duration) -> io::Result<PortMapping> {
+    let reply = nat_pmp_call(gateway, &nat_pmp_request(protocol, port, port, lease), 16)?;
+    let external_port = u16::from_be_bytes([reply[10], reply[11]]);
+    let address = nat_pmp_call(gateway, &[0, 0], 12)?;
+    let external_ip = Ipv4Addr::new(address[8], address[9], address[10], address[11]);
+    Ok(PortMapping {
+        protocol,
+        port,
+        external: SocketAddr::from((external_ip, external_port)),
+        gateway: Gateway::NatPmp(gateway),
+    })
+}
+
+fn upnp_map(protocol: PortProtocol, port: u16) -> io::Result<PortMapping> {
+    let (control_url, service) = find_igd()?;
+    let internal = match dlna::local_ip_towards(&control_url)? {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(_) => return Err(io::Error::new(io::ErrorKind::Unsupported, "the router is reached over IPv6")),
+    };
+    upnp_add(&control_url, &service, protocol, port, internal)?;
+    let (status, body) = dlna::soap(&control_url, &service, "GetExternalIPAddress", "")?;
+    upnp_result("GetExternalIPAddress", status, &body)?;
+    let external_ip = dlna::xml_text(&String::from_utf8_lossy(&body), "NewExternalIPAddress")
+        .and_then(|ip| ip.parse::<Ipv4Addr>().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "the router did not say its external address"))?;
+    Ok(PortMapping {
+        protocol,
+        port,
+        external: SocketAddr::from((external_ip, port)),
+        gateway: Gateway::Upnp {
+            control_url,
+            service,
+            internal,
+        },
+    })
+}
+
+fn upnp_add(control_url: &str, service: &str, protocol: PortProtocol, port: u16, internal: Ipv4Addr) -> io::Result<()> {
+    let arguments = |lease: u64| {
+        format!(
+            "<NewRemoteHost></NewRemoteHost><NewExternalPort>{port}</NewExternalPort>\
+             <NewProtocol>{protocol}</NewProtocol><NewInternalPort>{port}</NewInternalPort>\
+             <NewInternalClient>{internal}</NewInternalClient><NewEnabled>1</NewEnabled>\
+             <NewPortMappingDescription>{DESCRIPTION}</NewPortMappingDescription>\
+             <NewLeaseDuration>{lease}</NewLeaseDuration>",
+            protocol = protocol.upnp_name(),
+        )
+    };
+    let (mut status, mut body) = dlna::soap(control_url, service, "AddPortMapping", &arguments(LEASE.as_secs()))?;
+    if upnp_error(&body).is_some_and(|(code, _)| code == ONLY_PERMANENT_LEASES) {
+        (status, body) = dlna::soap(control_url, service, "AddPortMapping", &arguments(0))?;
+    }
+    upnp_result("AddPortMapping", status, &body)
+}
+
+fn upnp_result(action: &str, status: u16, body: &[u8]) -> io::Result<()> {
+    if status == 200 {
+        return Ok(());
+    }
+    let message = match upnp_error(body) {
+        Some((code, description)) => format!("the router refused {}: {} ({})", action, description, code),
+        None => format!("the router refused {} with status {}", action, status),
+    };
+    Err(io::Error::other(message))
+}
+
+// The code and description of a UPnP fault.
+fn upnp_error(body: &[u8]) -> Option<(u32, String)> {
+    let body = String::from_utf8_lossy(body);
+    let code = dlna::xml_text(&body, "errorCode")?.parse().ok()?;
+    let description = dlna::xml_text(&body, "errorDescription").unwrap_or("no description");
+    Some((code, description.to_string()))
+}
+
+// Searches for an Internet Gateway Device over SSDP and returns the
+// control URL and type of its WAN connection service.
+fn find_igd() -> io::Result<(String, String)> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: {}\r\n\r\n",
+        dlna::SSDP_ADDR,
+        IGD
+    );
+    socket.send_to(search.as_bytes(), dlna::SSDP_ADDR)?;
+
+    let deadline = Instant::now() + SEARCH_TIMEOUT;
+    let mut seen = Vec::new();
+    let mut buf = [0u8; 2048];
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        socket.set_read_timeout(Some(remaining.max(Duration::from_millis(1))))?;
+        let len = match socket.recv(&mut buf) {
+            Ok(len) => len,
+            Err(_) => break,
+        };
+        let Some(location) = dlna::ssdp_location(&String::from_utf8_lossy(&buf[..len])) else {
+            continue;
+        };
+        if seen.contains(&location) {
+            continue;
+        }
+        seen.push(location.clone());
+        let description = match http::request("GET", &location, &[], &[]) {
+            Ok((200, body)) => String::from_utf8_lossy(&body).into_owned(),
+            Ok((status, _)) => {
+                warn!("Skipping gateway at {}: description request failed with status {}", location, status);
+                continue;
+            }
+            Err(e) => {
+                warn!("Skipping gateway at {}: {}", location, e);
+                continue;
+            }
+        };
+        if let Some((service, control)) = wan_service(&description) {
+            let base = dlna::xml_text(&description, "URLBase").unwrap_or(&location);
+            return Ok((dlna::resolve_url(base, control), service.to_string()));
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::NotFound, "no UPnP Internet gateway answered"))
+}
+
+// The type and control URL of the WAN connection service in a gateway's
+// description.
+fn wan_service(description: &str) -> Option<(&str, &str)> {
+    description.split("<service>").skip(1).find_map(|service| {
+        let service_type = dlna::xml_text(service, "serviceType")?;
+        if !WAN_SERVICES.iter().any(|prefix| service_type.starts_with(prefix)) {
+            return None;
+        }
+        Some((service_type, dlna::xml_text(service, "controlURL")?))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_table_gateway() {
+        let table = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+                     eth0\t0001A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\n\
+                     eth0\t00000000\t0101A8C0\t0003\t0\t0\t100\t00000000\n";
+        let expected = Ipv4Addr::from(0x0101_A8C0u32.to_ne_bytes());
+        assert_eq!(route_table_gateway(table), Some(expected));
+        assert_eq!(route_table_gateway("Iface\tDestination\tGateway\n"), None);
+    }
+
+    #[test]
+    fn test_nat_pmp_request() {
+        let request = nat_pmp_request(PortProtocol::Udp, 9000, 9000, LEASE);
+        assert_eq!(request, [0, 1, 0, 0, 0x23, 0x28, 0x23, 0x28, 0, 0, 0x0e, 0x10]);
+        let delete = nat_pmp_request(PortProtocol::Tcp, 9000, 0, Duration::ZERO);
+        assert_eq!(delete, [0, 2, 0, 0, 0x23, 0x28, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_wan_service() {
+        let description = "<root><URLBase>http://192.168.1.1:5000</URLBase><device><serviceList>\
+            <service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+            <controlURL>/l3f</controlURL></service>\
+            <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:2</serviceType>\
+            <controlURL>/ctl/IPConn</controlURL></service>\
+            </serviceList></device></root>";
+        assert_eq!(
+            wan_service(description),
+            Some(("urn:schemas-upnp-org:service:WANIPConnection:2", "/ctl/IPConn"))
+        );
+        assert_eq!(wan_service("<root></root>"), None);
+    }
+
+    #[test]
+    fn test_upnp_error() {
+        let fault = b"<s:Envelope><s:Body><s:Fault><detail><UPnPError><errorCode>725</errorCode>\
+            <errorDescription>OnlyPermanentLeasesSupported</errorDescription></UPnPError></detail>\
+            </s:Fault></s:Body></s:Envelope>";
+        assert_eq!(upnp_error(fault), Some((725, "OnlyPermanentLeasesSupported".to_string())));
+        let error = upnp_result("AddPortMapping", 500, fault).unwrap_err();
+        assert!(error.to_string().contains("OnlyPermanentLeasesSupported"), "{}", error);
+        assert!(upnp_result("AddPortMapping", 200, b"").is_ok());
+    }
+
+    #[test]
+    fn test_behind_another_nat() {
+        let mapping = |ip: [u8; 4]| PortMapping {
+            protocol: PortProtocol::Udp,
+            port: 9000,
+            external: SocketAddr::from((ip, 9000)),
+            gateway: Gateway::NatPmp(Ipv4Addr::new(192, 168, 1, 1)),
+        };
+        assert!(!mapping([203, 0, 113, 7]).behind_another_nat());
+        assert!(mapping([192, 168, 0, 2]).behind_another_nat());
+        assert!(mapping([100, 72, 1, 1]).behind_another_nat());
+    }
+}
@@ -0,0 +1,161 @@
+use cpal::{SampleFormat, SupportedBufferSize, SupportedStreamConfigRange};
+
+/// The sample formats the capture callbacks convert from.
+pub const CAPTURE_FORMATS: [SampleFormat; 2] = [SampleFormat::F32, SampleFormat::I16];
+
+/// The stream the client asks an input device for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Setup {
+    pub format: SampleFormat,
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub frames: u32,
+}
+
+/// The reasons none of `configs` can give `setup`; empty if one can. An
+/// empty list does not promise the stream will build, since drivers may
+/// refuse combinations they advertise.
+pub fn problems(configs: &[SupportedStreamConfigRange], setup: Setup) -> Vec<String> {
+    let mut problems = Vec::new();
+    if !CAPTURE_FORMATS.contains(&setup.format) {
+        problems.push(format!(
+            "the default sample format {} is not one the client captures (f32 or i16)",
+            setup.format
+        ));
+    }
+    let with_format: Vec<_> = configs.iter().filter(|c| c.sample_format() == setup.format).collect();
+    let with_channels: Vec<_> = with_format.iter().filter(|c| c.channels() == setup.channels).collect();
+    let with_rate: Vec<_> = with_channels
+        .iter()
+        .filter(|c| (c.min_sample_rate().0..=c.max_sample_rate().0).contains(&setup.sample_rate))
+        .collect();
+    if with_format.is_empty() {
+        problems.push(format!("no configuration uses {} samples", setup.format));
+    } else if with_channels.is_empty() {
+        problems.push(format!(
+            "no {} configuration has {} channels",
+            setup.format, setup.channels
+        ));
+    } else if with_rate.is_empty() {
+        problems.push(format!(
+            "no {}-channel {} configuration runs at {} Hz",
+            setup.channels, setup.format, setup.sample_rate
+        ));
+    } else if !with_rate.iter().any(|c| fits_buffer(c.buffer_size(), setup.frames)) {
+        problems.push(format!(
+            "{}-frame buffers are outside the supported range{}",
+            setup.frames,
+            with_rate
+                .iter()
+                .map(|c| format!(" {}", describe_buffer(c.buffer_size())))
+                .collect::<String>()
+        ));
+    }
+    problems
+}
+
+// A driver that does not say what it supports may still take the size.
+fn fits_buffer(buffer: &SupportedBufferSize, frames: u32) -> bool {
+    match buffer {
+        SupportedBufferSize::Range { min, max } => (*min..=*max).contains(&frames),
+        SupportedBufferSize::Unknown => true,
+    }
+}
+
+/// The buffer sizes a device supports, in frames.
+pub fn describe_buffer(buffer: &SupportedBufferSize) -> String {
+    match buffer {
+        SupportedBufferSize::Range { min, max } => format!("{}-{} frames", min, max),
+        SupportedBufferSize::Unknown => "unknown".to_string(),
+    }
+}
+
+/// One line describing a supported configuration range.
+pub fn describe(config: &SupportedStreamConfigRange) -> String {
+    let rates = if config.min_sample_rate() == config.max_sample_rate() {
+        format!("{} Hz", config.min_sample_rate().0)
+    } else {
+        format!("{}-{} Hz", config.min_sample_rate().0, config.max_sample_rate().0)
+    };
+    format!(
+        "{} channels, {}, {}, buffers {}",
+        config.channels(),
+        config.sample_format(),
+        rates,
+        describe_buffer(config.buffer_size())
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpal::SampleRate;
+
+    fn range(
+        channels: u16,
+        min: u32,
+        max: u32,
+        buffer: SupportedBufferSize,
+        format: SampleFormat,
+    ) -> SupportedStreamConfigRange {
+        SupportedStreamConfigRange::new(channels, SampleRate(min), SampleRate(max), buffer, format)
+    }
+
+    #[test]
+    fn test_problems() {
+        let setup = Setup {
+            format: SampleFormat::F32,
+            channels: 2,
+            sample_rate: 48000,
+            frames: 512,
+        };
+        let buffers = SupportedBufferSize::Range { min: 64, max: 4096 };
+        let good = range(2, 44100, 48000, buffers.clone(), SampleFormat::F32);
+        assert!(problems(&[good.clone()], setup).is_empty());
+        assert!(problems(
+            &[range(2, 48000, 48000, SupportedBufferSize::Unknown, SampleFormat::F32)],
+            setup
+        )
+        .is_empty());
+
+        let mono = range(1, 44100, 48000, buffers.clone(), SampleFormat::F32);
+        assert_eq!(problems(&[mono], setup), ["no f32 configuration has 2 channels"]);
+        let slow = range(2, 44100, 44100, buffers, SampleFormat::F32);
+        assert_eq!(
+            problems(&[slow], setup),
+            ["no 2-channel f32 configuration runs at 48000 Hz"]
+        );
+        let small = range(
+            2,
+            48000,
+            48000,
+            SupportedBufferSize::Range { min: 1024, max: 2048 },
+            SampleFormat::F32,
+        );
+        assert_eq!(
+            problems(&[small], setup),
+            ["512-frame buffers are outside the supported range 1024-2048 frames"]
+        );
+
+        let unsupported = Setup {
+            format: SampleFormat::U8,
+            ..setup
+        };
+        assert_eq!(problems(&[good], unsupported).len(), 2);
+    }
+
+    #[test]
+    fn test_describe() {
+        let config = range(
+            2,
+            44100,
+            48000,
+            SupportedBufferSize::Range { min: 64, max: 4096 },
+            SampleFormat::I16,
+        );
+        assert_eq!(
+            describe(&config),
+            "2 channels, i16, 44100-48000 Hz, buffers 64-4096 frames"
+        );
+    }
+}
@@ -0,0 +1,216 @@
+//! Tuning of the sockets that carry the audio: the address they send from,
+//! the DSCP that marks their packets for the network, and the size of
+//! their send buffers.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tracing::warn;
+
+/// Expedited Forwarding, the class meant for voice and other audio that
+/// can't wait.
+pub const DSCP_EF: u8 = 46;
+
+/// Parses a DSCP value for --dscp: a number from 0 to 63, or the name of a
+/// standard class such as `EF`, `AF41` or `CS5`.
+pub fn parse_dscp(s: &str) -> Result<u8, String> {
+    let name = s.trim().to_ascii_uppercase();
+    let invalid = || format!("invalid DSCP value '{}': use 0-63, EF, AF11-AF43 or CS0-CS7", s);
+    let dscp = match name.as_str() {
+        "EF" => DSCP_EF,
+        _ if name.starts_with("CS") => match name[2..].parse::<u8>() {
+            Ok(class @ 0..=7) => class << 3,
+            _ => return Err(invalid()),
+        },
+        _ if name.starts_with("AF") => match name.as_bytes()[2..] {
+            [class @ b'1'..=b'4', drop @ b'1'..=b'3'] => ((class - b'0') << 3) | ((drop - b'0') << 1),
+            _ => return Err(invalid()),
+        },
+        _ => name.parse().ok().filter(|dscp| *dscp < 64).ok_or_else(invalid)?,
+    };
+    Ok(dscp)
+}
+
+/// Parses a local address for --bind: an IP address, with a port or
+/// without one, in which case the system picks it. IPv6 addresses with a
+/// port go in brackets, as in `[fe80::1]:9000`.
+pub fn parse_bind(s: &str) -> Result<SocketAddr, String> {
+    let s = s.trim();
+    if let Ok(ip) = s.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, 0));
+    }
+    s.parse().map_err(|_| {
+        format!(
+            "invalid local address '{}': use an IP address, optionally with :port",
+            s
+        )
+    })
+}
+
+/// How the transports set up their sockets.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Sends from this local address, so a machine with several networks
+    /// can choose the one the audio leaves through. Any address and port
+    /// otherwise.
+    pub bind: Option<SocketAddr>,
+    /// Marks the packets with this DSCP, so switches and Wi-Fi access
+    /// points that honour it put the audio ahead of bulk traffic.
+    pub dscp: Option<u8>,
+    /// The socket's send buffer, in bytes, in place of the system's
+    /// default.
+    pub send_buffer: Option<usize>,
+}
+
+impl SocketOptions {
+    /// The address to bind the socket to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.bind
+            .unwrap_or(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0))
+    }
+
+    /// Applies the options to `socket`, bound at `local`. An option the
+    /// system refuses is logged and left at its default, since the audio
+    /// still gets through without it.
+    #[cfg(unix)]
+    pub fn apply(&self, socket: &impl std::os::fd::AsRawFd, local: io::Result<SocketAddr>) {
+        let fd = socket.as_raw_fd();
+        if let Some(dscp) = self.dscp {
+            match local.and_then(|local| set_dscp(fd, local, dscp)) {
+                Ok(()) => tracing::info!("Marking audio packets with DSCP {}", dscp),
+                Err(e) => warn!("Sending audio packets unmarked; could not set DSCP {}: {}", dscp, e),
+            }
+        }
+        if let Some(size) = self.send_buffer {
+            match set_send_buffer(fd, size) {
+                // Linux doubles what it is asked for and caps it at
+                // net.core.wmem_max, so say what the socket ended up with.
+                Ok(actual) => tracing::info!("Socket send buffer is {} bytes", actual),
+                Err(e) => warn!("Could not set the socket send buffer to {} bytes: {}", size, e),
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn apply<S>(&self, _socket: &S, _local: io::Result<SocketAddr>) {
+        if let Some(dscp) = self.dscp {
+            // Windows ignores the TOS byte set on a socket and takes DSCP
+            // from Group Policy QoS rules instead.
+            warn!(
+                "Sending audio packets unmarked; set DSCP {} with a QoS policy on this system",
+                dscp
+            );
+        }
+        if self.send_buffer.is_some() {
+            warn!("Setting the socket send buffer is not supported on this platform");
+        }
+    }
+}
+
+// The DSCP is the top six bits of the IPv4 TOS byte and the IPv6 traffic
+// class.
+#[cfg(unix)]
+fn set_dscp(fd: std::os::fd::RawFd, local: SocketAddr, dscp: u8) -> io::Result<()> {
+    let (level, name) = match local {
+        SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_TOS),
+        SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_TCLASS),
+    };
+    set_option(fd, level, name, libc::c_int::from(dscp) << 2)
+}
+
+// Sets SO_SNDBUF and returns the size the socket reports afterwards.
+#[cfg(unix)]
+fn set_send_buffer(fd: std::os::fd::RawFd, size: usize) -> io::Result<usize> {
+    let size = libc::c_int::try_from(size)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "the send buffer size is too large"))?;
+    set_option(fd, libc::SOL_SOCKET, libc::SO_SNDBUF, size)?;
+    let actual = get_option(fd, libc::SOL_SOCKET, libc::SO_SNDBUF)?;
+    Ok(actual.max(0) as usize)
+}
+
+#[cfg(unix)]
+fn set_option(fd: std::os::fd::RawFd, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn get_option(fd: std::os::fd::RawFd, level: libc::c_int, name: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            name,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dscp() {
+        assert_eq!(parse_dscp("ef"), Ok(46));
+        assert_eq!(parse_dscp("46"), Ok(46));
+        assert_eq!(parse_dscp("AF41"), Ok(34));
+        assert_eq!(parse_dscp("af11"), Ok(10));
+        assert_eq!(parse_dscp("CS5"), Ok(40));
+        assert_eq!(parse_dscp("0"), Ok(0));
+        for bad in ["64", "AF51", "AF14", "CS8", "CS", "loud", "-1"] {
+            assert!(parse_dscp(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_parse_bind() {
+        assert_eq!(parse_bind("192.168.1.20"), Ok("192.168.1.20:0".parse().unwrap()));
+        assert_eq!(
+            parse_bind("192.168.1.20:9000"),
+            Ok("192.168.1.20:9000".parse().unwrap())
+        );
+        assert_eq!(parse_bind("fe80::1"), Ok("[fe80::1]:0".parse().unwrap()));
+        assert_eq!(parse_bind("[fe80::1]:9000"), Ok("[fe80::1]:9000".parse().unwrap()));
+        assert!(parse_bind("eth0").is_err());
+        assert!(parse_bind("10.0.0.1:port").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_apply_sets_tos_and_send_buffer() {
+        use std::os::fd::AsRawFd;
+
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let options = SocketOptions {
+            dscp: Some(DSCP_EF),
+            send_buffer: Some(64 * 1024),
+            ..SocketOptions::default()
+        };
+        options.apply(&socket, socket.local_addr());
+        let fd = socket.as_raw_fd();
+        assert_eq!(get_option(fd, libc::IPPROTO_IP, libc::IP_TOS).unwrap(), 46 << 2);
+        // The system may round the size or cap it, but it changes.
+        assert_ne!(
+            set_send_buffer(fd, 4096).unwrap(),
+            set_send_buffer(fd, 64 * 1024).unwrap()
+        );
+    }
+}
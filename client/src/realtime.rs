@@ -0,0 +1,97 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{info, warn};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Asks for real-time scheduling of the audio callback threads, for
+/// --realtime. Streams opened from now on promote their threads.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Called at the top of an audio callback: on a stream's first callback,
+/// if real-time scheduling is enabled, promotes the thread it runs on.
+/// `promoted` belongs to the callback and records that this was done. A
+/// thread that can't be promoted is logged once and runs at its normal
+/// priority.
+pub fn promote_once(promoted: &mut bool) {
+    if *promoted || !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    *promoted = true;
+    match promote_current_thread() {
+        Ok(()) => info!("Audio thread running with real-time priority"),
+        Err(e) => warn!("Audio thread left at normal priority: {}", e),
+    }
+}
+
+/// Gives the calling thread real-time scheduling: SCHED_FIFO at the middle
+/// of its priority range on Linux and other Unix systems, which needs
+/// CAP_SYS_NICE or an rtprio limit.
+#[cfg(all(unix, not(target_os = "macos")))]
+pub fn promote_current_thread() -> io::Result<()> {
+    let policy = libc::SCHED_FIFO;
+    let min = unsafe { libc::sched_get_priority_min(policy) };
+    let max = unsafe { libc::sched_get_priority_max(policy) };
+    if min < 0 || max < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let param = libc::sched_param {
+        sched_priority: min + (max - min) / 2,
+    };
+    match unsafe { libc::pthread_setschedparam(libc::pthread_self(), policy, &param) } {
+        0 => Ok(()),
+        libc::EPERM => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "real-time scheduling needs CAP_SYS_NICE or an rtprio limit in /etc/security/limits.conf",
+        )),
+        code => Err(io::Error::from_raw_os_error(code)),
+    }
+}
+
+/// Core Audio already runs the callbacks on a time-constraint thread of
+/// its own, so there is nothing to raise on macOS.
+#[cfg(target_os = "macos")]
+pub fn promote_current_thread() -> io::Result<()> {
+    Ok(())
+}
+
+/// Raises the calling thread to time-critical priority on Windows.
+#[cfg(windows)]
+pub fn promote_current_thread() -> io::Result<()> {
+    use std::ffi::c_void;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetCurrentThread() -> *mut c_void;
+        fn SetThreadPriority(thread: *mut c_void, priority: i32) -> i32;
+    }
+    const THREAD_PRIORITY_TIME_CRITICAL: i32 = 15;
+
+    if unsafe { SetThreadPriority(GetCurrentThread(), THREAD_PRIORITY_TIME_CRITICAL) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn promote_current_thread() -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "real-time scheduling is not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_promote_once_does_nothing_unless_enabled() {
+        // Nothing in the tests enables it, so the thread is left alone.
+        let mut promoted = false;
+        promote_once(&mut promoted);
+        assert!(!promoted);
+    }
+}
@@ -0,0 +1,312 @@
+use std::future::Future;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{info, warn, Instrument};
+
+use crate::transport::{AudioTransport, TransportStats};
+
+/// Delays between connection attempts, doubling from the first up to a
+/// limit, and starting over once a connection is made.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Backoff {
+    first: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new(first: Duration, max: Duration) -> Self {
+        Self { first, max, next: first }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.next = self.first;
+    }
+}
+
+/// Half a second at first, and at most 30 seconds.
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(Duration::from_millis(500), Duration::from_secs(30))
+    }
+}
+
+/// A transport that opens its link to the receiver again whenever it is
+/// lost, waiting longer after each failed attempt. While there is no link
+/// packets are dropped with `NotConnected`, so the capture goes on and the
+/// stream resumes by itself once the receiver is back.
+pub struct ReconnectingTransport {
+    shared: Arc<Shared>,
+    task: JoinHandle<()>,
+}
+
+struct Shared {
+    // Where the next attempt connects to.
+    addr: Mutex<String>,
+    link: watch::Sender<Option<Arc<dyn AudioTransport>>>,
+    // What earlier links carried, and the packets dropped without one.
+    retired: Mutex<TransportStats>,
+}
+
+impl ReconnectingTransport {
+    /// Opens the first link to `addr` with `connect`. If the receiver can't
+    /// be reached yet the error is logged and the attempts go on in the
+    /// background; errors that no retry will fix, such as a bad address,
+    /// are returned. Must be called on a Tokio runtime.
+    pub async fn connect<F, Fut>(addr: &str, backoff: Backoff, connect: F) -> io::Result<Self>
+    where
+        F: Fn(String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = io::Result<Arc<dyn AudioTransport>>> + Send + 'static,
+    {
+        let link = match connect(addr.to_string()).await {
+            Ok(link) => Some(link),
+            Err(e) if is_permanent(&e) => return Err(e),
+            Err(e) => {
+                warn!("Could not connect to {}: {}; retrying in the background", addr, e);
+                None
+            }
+        };
+        let shared = Arc::new(Shared {
+            addr: Mutex::new(addr.to_string()),
+            link: watch::Sender::new(link),
+            retired: Mutex::new(TransportStats::default()),
+        });
+        let task = tokio::spawn(keep_connected(shared.clone(), backoff, connect).in_current_span());
+        Ok(Self { shared, task })
+    }
+
+    fn link(&self, frames: usize) -> io::Result<Arc<dyn AudioTransport>> {
+        match self.shared.link.borrow().clone() {
+            Some(link) => Ok(link),
+            None => {
+                self.shared.retired.lock().unwrap().send_errors += frames as u64;
+                Err(io::Error::new(io::ErrorKind::NotConnected, "reconnecting to the receiver"))
+            }
+        }
+    }
+}
+
+impl Shared {
+    // Passes on what `link` returned, taking the link down first if the
+    // error says it is gone. Errors that would end the stream, like a
+    // broken pipe, become `NotConnected`, as the stream goes on.
+    fn check<T>(&self, link: &Arc<dyn AudioTransport>, result: io::Result<T>) -> io::Result<T> {
+        match result {
+            Err(e) if is_lost(&e) => {
+                let lost = self.link.send_if_modified(|current| {
+                    let same = matches!(current, Some(open) if Arc::ptr_eq(open, link));
+                    if same {
+                        *current = None;
+                    }
+                    same
+                });
+                if lost {
+                    *self.retired.lock().unwrap() += link.stats();
+                    warn!("Lost the connection to {}: {}; reconnecting", self.addr.lock().unwrap(), e);
+                }
+                Err(io::Error::new(io::ErrorKind::NotConnected, e))
+            }
+            result => result,
+        }
+    }
+}
+
+impl Drop for ReconnectingTransport {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl AudioTransport for ReconnectingTransport {
+    fn send_frame(&self, frame: &[u8]) -> io::Result<()> {
+        let link = self.link(1)?;
+        self.shared.check(&link, link.send_frame(frame))
+    }
+
+    fn send<'a>(&'a self, frame: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let link = self.link(1)?;
+            self.shared.check(&link, link.send(frame).await)
+        })
+    }
+
+    fn send_batch<'a>(&'a self, frames: &'a [Vec<u8>]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let link = self.link(frames.len())?;
+            self.shared.check(&link, link.send_batch(frames).await)
+        })
+    }
+
+    // Waits out the time without a link rather than failing, so whoever
+    // reads control hears from the receiver again once it is back.
+    fn recv_control(&self) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        Box::pin(async move {
+            let mut links = self.shared.link.subscribe();
+            loop {
+                let link = links
+                    .wait_for(Option::is_some)
+                    .await
+                    .map_err(|_| io::ErrorKind::NotConnected)?
+                    .clone()
+                    .expect("waited for a link");
+                match self.shared.check(&link, link.recv_control().await) {
+                    Err(e) if e.kind() == io::ErrorKind::NotConnected => continue,
+                    result => return result,
+                }
+            }
+        })
+    }
+
+    fn stats(&self) -> TransportStats {
+        let retired = *self.shared.retired.lock().unwrap();
+        match self.shared.link.borrow().as_ref() {
+            Some(link) => retired + link.stats(),
+            None => retired,
+        }
+    }
+
+    // The next attempt goes to the new address if there is no link now.
+    fn set_destination<'a>(&'a self, server_addr: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            *self.shared.addr.lock().unwrap() = server_addr.to_string();
+            let link = self.shared.link.borrow().clone();
+            match link {
+                Some(link) => link.set_destination(server_addr).await,
+                None => Ok(()),
+            }
+        })
+    }
+}
+
+async fn keep_connected<F, Fut>(shared: Arc<Shared>, mut backoff: Backoff, connect: F)
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = io::Result<Arc<dyn AudioTransport>>>,
+{
+    let mut links = shared.link.subscribe();
+    loop {
+        if links.wait_for(Option::is_none).await.is_err() {
+            return;
+        }
+        tokio::time::sleep(backoff.next_delay()).await;
+        let addr = shared.addr.lock().unwrap().clone();
+        match connect(addr.clone()).await {
+            Ok(link) => {
+                info!("Reconnected to {}", addr);
+                shared.link.send_replace(Some(link));
+                backoff.reset();
+            }
+            Err(e) => warn!("Could not reconnect to {}: {}", addr, e),
+        }
+    }
+}
+
+// Errors from a link that is gone for good, as when the receiver closed
+// the connection.
+fn is_lost(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::NotConnected
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::UnexpectedEof
+    )
+}
+
+// Errors from connecting that come from the flags rather than the network,
+// so trying again would not help.
+fn is_permanent(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::InvalidInput
+            | io::ErrorKind::AddrInUse
+            | io::ErrorKind::AddrNotAvailable
+            | io::ErrorKind::PermissionDenied
+            | io::ErrorKind::Unsupported
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{MemoryPeer, MemoryTransport};
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(Duration::from_millis(500), Duration::from_secs(3));
+        let delays: Vec<_> = (0..5).map(|_| backoff.next_delay().as_millis()).collect();
+        assert_eq!(delays, [500, 1000, 2000, 3000, 3000]);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_millis(500));
+    }
+
+    async fn until(done: impl Fn() -> bool) {
+        let wait = async {
+            while !done() {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), wait).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reconnects() {
+        // The receiver is down at first, then each attempt reaches it.
+        let peers: Arc<Mutex<Vec<MemoryPeer>>> = Arc::default();
+        let opened = peers.clone();
+        let backoff = Backoff::new(Duration::from_millis(1), Duration::from_millis(10));
+        let attempts = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let transport = ReconnectingTransport::connect("receiver", backoff, move |_| {
+            let (opened, attempts) = (opened.clone(), attempts.clone());
+            async move {
+                if attempts.fetch_add(1, std::sync::atomic::Ordering::Relaxed) == 0 {
+                    return Err(io::ErrorKind::ConnectionRefused.into());
+                }
+                let (link, peer) = MemoryTransport::pair(8);
+                opened.lock().unwrap().push(peer);
+                Ok(Arc::new(link) as Arc<dyn AudioTransport>)
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(transport.send_frame(&[1]).unwrap_err().kind(), io::ErrorKind::NotConnected);
+
+        until(|| peers.lock().unwrap().len() == 1).await;
+        transport.send_frame(&[2]).unwrap();
+        assert_eq!(peers.lock().unwrap()[0].try_recv_frame(), Some(vec![2]));
+
+        // The receiver goes away; the stream carries on once it is back.
+        peers.lock().unwrap().clear();
+        assert_eq!(transport.send(&[3]).await.unwrap_err().kind(), io::ErrorKind::NotConnected);
+        until(|| peers.lock().unwrap().len() == 1).await;
+        transport.send(&[4]).await.unwrap();
+        let mut peer = peers.lock().unwrap().pop().unwrap();
+        assert_eq!(peer.recv_frame().await, Some(vec![4]));
+
+        peer.send_control(b"ack").unwrap();
+        assert_eq!(transport.recv_control().await.unwrap(), b"ack");
+        let stats = transport.stats();
+        assert_eq!((stats.frames_sent, stats.send_errors), (2, 2));
+    }
+
+    #[tokio::test]
+    async fn test_bad_address_fails() {
+        let result = ReconnectingTransport::connect("receiver", Backoff::default(), |_| async {
+            Err::<Arc<dyn AudioTransport>, _>(io::Error::new(io::ErrorKind::InvalidInput, "invalid address"))
+        })
+        .await;
+        assert_eq!(result.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidInput));
+    }
+}
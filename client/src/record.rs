@@ -0,0 +1,97 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::http::streaming_wav_header;
+
+// Where the RIFF and data chunk lengths sit in the header.
+const RIFF_LEN_OFFSET: u64 = 4;
+const DATA_LEN_OFFSET: u64 = 40;
+const HEADER_LEN: u32 = 44;
+
+/// A WAV file of 16-bit PCM being written. Clones share the file, so the
+/// capture can write to one while the recorder keeps another to `finish`,
+/// which fills in the lengths the header starts without.
+pub struct WavFile<W: Write + Seek = BufWriter<File>> {
+    inner: Arc<Mutex<Inner<W>>>,
+}
+
+struct Inner<W> {
+    out: W,
+    data_len: u64,
+}
+
+impl<W: Write + Seek> Clone for WavFile<W> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl WavFile {
+    pub fn create(path: &Path, sample_rate: u32, channels: u16) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?), sample_rate, channels)
+    }
+}
+
+impl<W: Write + Seek> WavFile<W> {
+    pub fn new(mut out: W, sample_rate: u32, channels: u16) -> io::Result<Self> {
+        out.write_all(&streaming_wav_header(sample_rate, channels))?;
+        Ok(Self {
+            inner: Arc::new(Mutex::new(Inner { out, data_len: 0 })),
+        })
+    }
+
+    /// Writes the final lengths into the header and flushes, returning how
+    /// many bytes of audio the file holds. WAV lengths stop at 4 GiB; a
+    /// longer recording keeps the "unknown length" header it started with.
+    pub fn finish(&self) -> io::Result<u64> {
+        let mut inner = self.inner.lock().unwrap();
+        let data_len = inner.data_len;
+        if let Ok(len) = u32::try_from(data_len + HEADER_LEN as u64 - 8) {
+            let out = &mut inner.out;
+            out.seek(SeekFrom::Start(RIFF_LEN_OFFSET))?;
+            out.write_all(&len.to_le_bytes())?;
+            out.seek(SeekFrom::Start(DATA_LEN_OFFSET))?;
+            out.write_all(&(data_len as u32).to_le_bytes())?;
+            out.seek(SeekFrom::End(0))?;
+        }
+        inner.out.flush()?;
+        Ok(data_len)
+    }
+}
+
+impl<W: Write + Seek> Write for WavFile<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut inner = self.inner.lock().unwrap();
+        let written = inner.out.write(buf)?;
+        inner.data_len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.lock().unwrap().out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_finish_fills_in_lengths() {
+        let mut wav = WavFile::new(Cursor::new(Vec::new()), 48000, 2).unwrap();
+        wav.clone().write_all(&[1, 0, 2, 0]).unwrap();
+        wav.write_all(&[3, 0, 4, 0]).unwrap();
+        assert_eq!(wav.finish().unwrap(), 8);
+
+        let bytes = wav.inner.lock().unwrap().out.get_ref().clone();
+        assert_eq!(bytes.len(), 44 + 8);
+        assert_eq!(&bytes[4..8], &(36u32 + 8).to_le_bytes());
+        assert_eq!(&bytes[40..44], &8u32.to_le_bytes());
+        assert_eq!(&bytes[44..], &[1, 0, 2, 0, 3, 0, 4, 0]);
+    }
+}
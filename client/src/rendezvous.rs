@@ -0,0 +1,598 @@
+//! Streaming between two machines that are both behind NATs. Each side
+//! registers with a small relay under a shared session code; the relay
+//! tells each the address it saw the other come from, and both send
+//! punches there to open their NATs. When the punches get through the
+//! audio goes direct, and otherwise the relay forwards it.
+//!
+//! Every rendezvous datagram starts with [`MAGIC`] and a type byte:
+//! register (role, then the code), peer (the partner's address), punch
+//! (whether it answers one, then the code) and keepalive (nothing).
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use tokio::net::UdpSocket;
+use tracing::{info, warn};
+
+/// Where a relay listens unless told otherwise.
+pub const DEFAULT_RELAY_PORT: u16 = 7400;
+/// Opens every rendezvous datagram, so the audio path can tell them from
+/// packets and skip them.
+pub const MAGIC: [u8; 4] = [0xa7, b'R', b'D', b'V'];
+const REGISTER: u8 = 1;
+const PEER: u8 = 2;
+const PUNCH: u8 = 3;
+const KEEPALIVE: u8 = 4;
+const MAX_CODE: usize = 64;
+const MIN_CODE: usize = 4;
+// The longest rendezvous datagram; anything longer is audio.
+const MAX_MESSAGE: usize = MAGIC.len() + 2 + MAX_CODE;
+const REGISTER_INTERVAL: Duration = Duration::from_secs(1);
+const PUNCH_INTERVAL: Duration = Duration::from_millis(200);
+// How long the sender punches before settling for the relay. The
+// receiver listens a while longer, since its peer message may come first.
+const PUNCH_WINDOW: Duration = Duration::from_secs(3);
+const RECEIVER_PUNCH_WINDOW: Duration = Duration::from_secs(5);
+/// How often each side refreshes its NAT mappings once streaming. Well
+/// under the 30 seconds some NATs forget UDP mappings after.
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+// A relay forgets endpoints it hasn't heard from in this long.
+const ENDPOINT_TIMEOUT: Duration = Duration::from_secs(60);
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+// Registrations a relay holds at once, so a flood can't exhaust it.
+const MAX_ENDPOINTS: usize = 4096;
+
+/// Which end of the stream an endpoint is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Sender,
+    Receiver,
+}
+
+impl Role {
+    fn index(self) -> usize {
+        match self {
+            Role::Sender => 0,
+            Role::Receiver => 1,
+        }
+    }
+}
+
+/// A rendezvous datagram.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    /// Joins `code` as `role`, or repeats it so the relay answers again.
+    Register { role: Role, code: String },
+    /// From the relay: where the partner's datagrams come from.
+    Peer(SocketAddr),
+    /// Between endpoints, to open the NATs on the way. `ack` marks the
+    /// answer to a punch, which proves the path works both ways.
+    Punch { ack: bool, code: String },
+    /// Keeps the NAT mappings and the relay's registration alive.
+    Keepalive,
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        match self {
+            Message::Register { role, code } => {
+                out.extend_from_slice(&[REGISTER, role.index() as u8]);
+                out.extend_from_slice(code.as_bytes());
+            }
+            Message::Peer(addr) => {
+                out.push(PEER);
+                match addr.ip() {
+                    IpAddr::V4(ip) => {
+                        out.push(4);
+                        out.extend_from_slice(&ip.octets());
+                    }
+                    IpAddr::V6(ip) => {
+                        out.push(6);
+                        out.extend_from_slice(&ip.octets());
+                    }
+                }
+                out.extend_from_slice(&addr.port().to_be_bytes());
+            }
+            Message::Punch { ack, code } => {
+                out.extend_from_slice(&[PUNCH, *ack as u8]);
+                out.extend_from_slice(code.as_bytes());
+            }
+            Message::Keepalive => out.push(KEEPALIVE),
+        }
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        if !is_message(bytes) {
+            return None;
+        }
+        let (&kind, body) = bytes[MAGIC.len()..].split_first()?;
+        match kind {
+            REGISTER => {
+                let (&role, code) = body.split_first()?;
+                let role = match role {
+                    0 => Role::Sender,
+                    1 => Role::Receiver,
+                    _ => return None,
+                };
+                Some(Message::Register {
+                    role,
+                    code: decode_code(code)?,
+                })
+            }
+            PEER => {
+                let (&family, body) = body.split_first()?;
+                let (ip, port) = match (family, body.len()) {
+                    (4, 6) => (IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&body[..4]).ok()?)), &body[4..]),
+                    (6, 18) => (IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&body[..16]).ok()?)), &body[16..]),
+                    _ => return None,
+                };
+                Some(Message::Peer(SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]]))))
+            }
+            PUNCH => {
+                let (&ack, code) = body.split_first()?;
+                Some(Message::Punch {
+                    ack: ack != 0,
+                    code: decode_code(code)?,
+                })
+            }
+            KEEPALIVE if body.is_empty() => Some(Message::Keepalive),
+            _ => None,
+        }
+    }
+}
+
+fn decode_code(bytes: &[u8]) -> Option<String> {
+    let code = std::str::from_utf8(bytes).ok()?;
+    parse_code(code).ok()
+}
+
+/// Whether a datagram is a rendezvous message rather than audio. The
+/// audio path drops these, as punches and keepalives keep arriving on the
+/// socket the audio uses.
+pub fn is_message(datagram: &[u8]) -> bool {
+    datagram.len() <= MAX_MESSAGE && datagram.starts_with(&MAGIC)
+}
+
+/// Checks a session code for --session: 4 to 64 printable ASCII
+/// characters, shared by the two ends.
+pub fn parse_code(s: &str) -> Result<String, String> {
+    if !(MIN_CODE..=MAX_CODE).contains(&s.len()) {
+        return Err(format!("session codes are {} to {} characters long", MIN_CODE, MAX_CODE));
+    }
+    if !s.bytes().all(|b| b.is_ascii_graphic()) {
+        return Err("session codes are printable ASCII without spaces".to_string());
+    }
+    Ok(s.to_string())
+}
+
+/// Parses a relay address for --relay, adding the default port when
+/// there is none.
+pub fn parse_relay(s: &str) -> Result<String, String> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Err("the relay address is empty".to_string());
+    }
+    if s.parse::<SocketAddr>().is_ok() {
+        return Ok(s.to_string());
+    }
+    if let Ok(ip) = s.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, DEFAULT_RELAY_PORT).to_string());
+    }
+    match s.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(s.to_string()),
+        Some(_) => Err(format!("invalid relay address '{}': use host[:port]", s)),
+        None => Ok(format!("{}:{}", s, DEFAULT_RELAY_PORT)),
+    }
+}
+
+/// A session to meet the other end in: the relay and the shared code.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Session {
+    /// The relay's host:port.
+    pub relay: String,
+    pub code: String,
+}
+
+/// Where the audio goes once the two ends have met.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Route {
+    /// Straight to the other end, through the holes the punches opened.
+    Direct(SocketAddr),
+    /// Through the relay at this address.
+    Relayed(SocketAddr),
+}
+
+impl Route {
+    pub fn addr(self) -> SocketAddr {
+        match self {
+            Route::Direct(addr) | Route::Relayed(addr) => addr,
+        }
+    }
+}
+
+/// Registers `socket` with the session's relay as `role`, waits for the
+/// other end to do the same, and punches through to it. The sender goes
+/// direct once a punch of its own is answered; the receiver takes audio
+/// from either path, so its route only says which one it expects.
+pub async fn meet(socket: &UdpSocket, session: &Session, role: Role) -> io::Result<Route> {
+    let local = socket.local_addr()?;
+    let relay = tokio::net::lookup_host(&session.relay)
+        .await?
+        .find(|addr| addr.is_ipv4() == local.is_ipv4())
+        .ok_or_else(|| {
+            let message = format!("the relay {} has no address this socket can reach", session.relay);
+            io::Error::new(io::ErrorKind::AddrNotAvailable, message)
+        })?;
+    let register = Message::Register {
+        role,
+        code: session.code.clone(),
+    }
+    .encode();
+    // Room for audio that arrives early, which Windows would otherwise
+    // report as an error.
+    let mut buf = vec![0u8; 65536];
+
+    info!("Waiting for the other end of session '{}' at relay {}", session.code, relay);
+    let peer = 'registered: loop {
+        socket.send_to(&register, relay).await?;
+        let deadline = tokio::time::Instant::now() + REGISTER_INTERVAL;
+        while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            let (len, from) = match received {
+                Ok(received) => received,
+                // An ICMP error for an earlier datagram; the relay may not be up yet.
+                Err(e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(e) => return Err(e),
+            };
+            if from != relay {
+                continue;
+            }
+            if let Some(Message::Peer(peer)) = Message::decode(&buf[..len]) {
+                break 'registered peer;
+            }
+        }
+    };
+    info!("The other end is at {}; punching through", peer);
+
+    let punch = Message::Punch {
+        ack: false,
+        code: session.code.clone(),
+    }
+    .encode();
+    let ack = Message::Punch {
+        ack: true,
+        code: session.code.clone(),
+    }
+    .encode();
+    let window = match role {
+        Role::Sender => PUNCH_WINDOW,
+        Role::Receiver => RECEIVER_PUNCH_WINDOW,
+    };
+    let end = tokio::time::Instant::now() + window;
+    let mut peer = peer;
+    let (mut heard, mut answered, mut acked) = (false, false, false);
+    while tokio::time::Instant::now() < end {
+        // A symmetric NAT may use another port towards the peer than
+        // towards the relay, so punches go to the last address heard.
+        let _ = socket.send_to(&punch, peer).await;
+        let deadline = (tokio::time::Instant::now() + PUNCH_INTERVAL).min(end);
+        while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            let Ok((len, from)) = received else {
+                continue;
+            };
+            if from.ip() != peer.ip() {
+                continue;
+            }
+            match Message::decode(&buf[..len]) {
+                Some(Message::Punch { ack: false, code }) if code == session.code => {
+                    peer = from;
+                    heard = true;
+                    let _ = socket.send_to(&ack, from).await;
+                    answered = true;
+                }
+                Some(Message::Punch { ack: true, code }) if code == session.code => {
+                    peer = from;
+                    heard = true;
+                    acked = true;
+                }
+                _ => {}
+            }
+        }
+        let done = match role {
+            Role::Sender => acked,
+            Role::Receiver => acked && answered,
+        };
+        if done {
+            break;
+        }
+    }
+    let route = match role {
+        Role::Sender if acked => Route::Direct(peer),
+        Role::Receiver if heard => Route::Direct(peer),
+        _ => Route::Relayed(relay),
+    };
+    match route {
+        Route::Direct(addr) => info!("Reached the other end directly at {}", addr),
+        Route::Relayed(addr) => warn!("Could not punch through to the other end; relaying through {}", addr),
+    }
+    Ok(route)
+}
+
+/// Splits `socket` into two handles on the same port: one for the audio,
+/// and one for [`spawn_keepalive`].
+pub fn clone_socket(socket: UdpSocket) -> io::Result<(UdpSocket, UdpSocket)> {
+    let socket = socket.into_std()?;
+    let clone = socket.try_clone()?;
+    Ok((UdpSocket::from_std(socket)?, UdpSocket::from_std(clone)?))
+}
+
+/// Sends a keepalive to each of `targets` every [`KEEPALIVE_INTERVAL`],
+/// so the NATs and the relay keep the way open while the audio pauses.
+/// A connected socket sends to its peer, whatever `targets` says.
+pub fn spawn_keepalive(socket: UdpSocket, targets: Vec<SocketAddr>) {
+    let keepalive = Message::Keepalive.encode();
+    tokio::spawn(async move {
+        let connected = socket.peer_addr().is_ok();
+        let mut interval = tokio::time::interval(KEEPALIVE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if connected {
+                let _ = socket.send(&keepalive).await;
+                continue;
+            }
+            for target in &targets {
+                let _ = socket.send_to(&keepalive, *target).await;
+            }
+        }
+    });
+}
+
+struct Endpoint {
+    code: String,
+    role: Role,
+    last_seen: Instant,
+}
+
+/// The relay's side: pairs endpoints by code and role, tells each where
+/// the other is, and forwards anything else between them.
+#[derive(Default)]
+pub struct Relay {
+    endpoints: HashMap<SocketAddr, Endpoint>,
+    // The sender's and the receiver's address in each session.
+    sessions: HashMap<String, [Option<SocketAddr>; 2]>,
+    last_sweep: Option<Instant>,
+}
+
+impl Relay {
+    /// Takes a datagram from `from` and returns what to send where.
+    pub fn handle(&mut self, datagram: &[u8], from: SocketAddr, now: Instant) -> Vec<(SocketAddr, Vec<u8>)> {
+        match self.last_sweep {
+            Some(last) if now.duration_since(last) < SWEEP_INTERVAL => {}
+            _ => {
+                self.sweep(now);
+                self.last_sweep = Some(now);
+            }
+        }
+        if !is_message(datagram) {
+            let Some(endpoint) = self.endpoints.get_mut(&from) else {
+                return Vec::new();
+            };
+            endpoint.last_seen = now;
+            let partner = self.partner(from);
+            return partner.map(|to| (to, datagram.to_vec())).into_iter().collect();
+        }
+        match Message::decode(datagram) {
+            Some(Message::Register { role, code }) => self.register(from, role, code, now),
+            Some(Message::Keepalive) => {
+                if let Some(endpoint) = self.endpoints.get_mut(&from) {
+                    endpoint.last_seen = now;
+                }
+                Vec::new()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Endpoints registered now.
+    pub fn len(&self) -> usize {
+        self.endpoints.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.endpoints.is_empty()
+    }
+
+    fn register(&mut self, from: SocketAddr, role: Role, code: String, now: Instant) -> Vec<(SocketAddr, Vec<u8>)> {
+        let known = self
+            .endpoints
+            .get(&from)
+            .is_some_and(|endpoint| endpoint.code == code && endpoint.role == role);
+        if !known {
+            self.forget(from);
+            if self.endpoints.len() >= MAX_ENDPOINTS {
+                return Vec::new();
+            }
+            let slots = self.sessions.entry(code.clone()).or_default();
+            // A new registration in the same role takes over, as when an
+            // end restarts behind a new NAT mapping.
+            let replaced = slots[role.index()].replace(from);
+            if let Some(replaced) = replaced {
+                self.endpoints.remove(&replaced);
+            }
+            info!("Endpoint {} joined session '{}' as {:?}", from, code, role);
+        }
+        self.endpoints.insert(
+            from,
+            Endpoint {
+                code,
+                role,
+                last_seen: now,
+            },
+        );
+        match self.partner(from) {
+            Some(partner) => vec![
+                (from, Message::Peer(partner).encode()),
+                (partner, Message::Peer(from).encode()),
+            ],
+            None => Vec::new(),
+        }
+    }
+
+    fn partner(&self, from: SocketAddr) -> Option<SocketAddr> {
+        let endpoint = self.endpoints.get(&from)?;
+        let other = match endpoint.role {
+            Role::Sender => Role::Receiver,
+            Role::Receiver => Role::Sender,
+        };
+        self.sessions.get(&endpoint.code)?[other.index()]
+    }
+
+    fn forget(&mut self, addr: SocketAddr) {
+        let Some(endpoint) = self.endpoints.remove(&addr) else {
+            return;
+        };
+        if let Some(slots) = self.sessions.get_mut(&endpoint.code) {
+            slots[endpoint.role.index()] = None;
+            if slots.iter().all(Option::is_none) {
+                self.sessions.remove(&endpoint.code);
+            }
+        }
+    }
+
+    fn sweep(&mut self, now: Instant) {
+        let stale: Vec<SocketAddr> = self
+            .endpoints
+            .iter()
+            .filter(|(_, endpoint)| now.duration_since(endpoint.last_seen) > ENDPOINT_TIMEOUT)
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in stale {
+            info!("Endpoint {} left", addr);
+            self.forget(addr);
+        }
+    }
+}
+
+/// Runs a relay on `socket` until it fails.
+pub async fn serve_relay(socket: UdpSocket) -> io::Result<()> {
+    let mut relay = Relay::default();
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            // An ICMP error for a datagram to an endpoint that went away.
+            Err(e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+            Err(e) => return Err(e),
+        };
+        for (to, datagram) in relay.handle(&buf[..len], from, Instant::now()) {
+            if let Err(e) = socket.send_to(&datagram, to).await {
+                warn!("Could not relay to {}: {}", to, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        let messages = [
+            Message::Register {
+                role: Role::Receiver,
+                code: "blue-otter-42".to_string(),
+            },
+            Message::Peer(addr("203.0.113.7:40000")),
+            Message::Peer(addr("[2001:db8::1]:9")),
+            Message::Punch {
+                ack: true,
+                code: "blue-otter-42".to_string(),
+            },
+            Message::Keepalive,
+        ];
+        for message in messages {
+            let encoded = message.encode();
+            assert!(is_message(&encoded));
+            assert_eq!(Message::decode(&encoded), Some(message));
+        }
+        assert_eq!(Message::decode(&[0, 0, 0, 0, 1, 2, 3]), None);
+        assert_eq!(Message::decode(&[0xa7, b'R', b'D', b'V', PEER, 4, 1, 2]), None);
+        assert!(!is_message(&[MAGIC.as_slice(), &[0; 200]].concat()));
+    }
+
+    #[test]
+    fn test_parse_code_and_relay() {
+        assert!(parse_code("blue-otter-42").is_ok());
+        assert!(parse_code("abc").is_err());
+        assert!(parse_code("has space").is_err());
+        assert_eq!(parse_relay("relay.example.com"), Ok("relay.example.com:7400".to_string()));
+        assert_eq!(parse_relay("relay.example.com:9000"), Ok("relay.example.com:9000".to_string()));
+        assert_eq!(parse_relay("203.0.113.7"), Ok("203.0.113.7:7400".to_string()));
+        assert_eq!(parse_relay("2001:db8::1"), Ok("[2001:db8::1]:7400".to_string()));
+        assert!(parse_relay("relay:port").is_err());
+    }
+
+    #[test]
+    fn test_relay_pairs_and_forwards() {
+        let mut relay = Relay::default();
+        let now = Instant::now();
+        let (sender, receiver, stranger) = (addr("198.51.100.1:5000"), addr("203.0.113.7:6000"), addr("192.0.2.9:1"));
+        let register = |role| {
+            Message::Register {
+                role,
+                code: "blue-otter-42".to_string(),
+            }
+            .encode()
+        };
+        assert!(relay.handle(&register(Role::Sender), sender, now).is_empty());
+        let out = relay.handle(&register(Role::Receiver), receiver, now);
+        assert_eq!(
+            out,
+            [
+                (receiver, Message::Peer(sender).encode()),
+                (sender, Message::Peer(receiver).encode())
+            ]
+        );
+        assert_eq!(relay.handle(&[1, 2, 3, 4, 5], sender, now), [(receiver, vec![1, 2, 3, 4, 5])]);
+        assert_eq!(relay.handle(&[9, 9, 9, 9], receiver, now), [(sender, vec![9, 9, 9, 9])]);
+        assert!(relay.handle(&[1, 2, 3, 4, 5], stranger, now).is_empty());
+
+        // Quiet endpoints are forgotten.
+        let later = now + ENDPOINT_TIMEOUT + SWEEP_INTERVAL;
+        relay.handle(&Message::Keepalive.encode(), receiver, later - SWEEP_INTERVAL);
+        assert!(relay.handle(&[1, 2, 3, 4, 5], sender, later).is_empty());
+        assert_eq!(relay.len(), 1);
+    }
+
+    #[test]
+    fn test_meet_over_localhost() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let relay_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let session = Session {
+                relay: relay_socket.local_addr().unwrap().to_string(),
+                code: "blue-otter-42".to_string(),
+            };
+            tokio::spawn(serve_relay(relay_socket));
+            let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let (sent, received) = tokio::join!(
+                meet(&sender, &session, Role::Sender),
+                meet(&receiver, &session, Role::Receiver)
+            );
+            assert_eq!(sent.unwrap(), Route::Direct(receiver.local_addr().unwrap()));
+            assert_eq!(received.unwrap(), Route::Direct(sender.local_addr().unwrap()));
+        });
+    }
+}
@@ -0,0 +1,233 @@
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// How often a waiting consumer looks for more data. The producer never
+// wakes it, so pushing takes no lock and makes no system call; the cost
+// is up to this much added latency.
+const POLL_INTERVAL: Duration = Duration::from_millis(2);
+
+// A byte queue between one producer and one consumer that takes no lock.
+// The positions only ever grow, and the slot for a position is that
+// position modulo the capacity. The producer may move the read position
+// on to make room, so both ends move it with compare-and-swap.
+struct Shared {
+    slots: Box<[AtomicU8]>,
+    // Bytes read and written so far, wrapping.
+    read: AtomicUsize,
+    written: AtomicUsize,
+    closed: AtomicBool,
+}
+
+impl Shared {
+    fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+}
+
+/// A ring of `capacity` bytes between one producer and one consumer, as
+/// between the audio callback and the task that sends what it captures.
+pub fn channel(capacity: usize) -> (Producer, Consumer) {
+    let shared = Arc::new(Shared {
+        slots: (0..capacity.max(1)).map(|_| AtomicU8::new(0)).collect(),
+        read: AtomicUsize::new(0),
+        written: AtomicUsize::new(0),
+        closed: AtomicBool::new(false),
+    });
+    (Producer { shared: shared.clone() }, Consumer { shared })
+}
+
+/// The writing end. Dropping it closes the ring once the consumer has
+/// read what is left.
+pub struct Producer {
+    shared: Arc<Shared>,
+}
+
+impl Producer {
+    /// Appends all of `bytes`, or nothing if they don't fit, so whole
+    /// blocks go in and samples stay aligned. Never blocks or allocates.
+    pub fn push(&mut self, bytes: &[u8]) -> bool {
+        let shared = &*self.shared;
+        let written = shared.written.load(Ordering::Relaxed);
+        let read = shared.read.load(Ordering::Acquire);
+        if shared.capacity() - written.wrapping_sub(read) < bytes.len() {
+            return false;
+        }
+        self.write(written, bytes);
+        true
+    }
+
+    /// Appends all of `bytes`, first discarding as many of the oldest bytes
+    /// waiting as it takes to make room, and returns how many that was.
+    /// Discarding a multiple of the sample size keeps the samples aligned
+    /// when every push is one. More than the ring holds is not appended and
+    /// counts as discarded. Never blocks or allocates.
+    pub fn push_evicting(&mut self, bytes: &[u8]) -> usize {
+        let shared = &*self.shared;
+        if bytes.len() > shared.capacity() {
+            return bytes.len();
+        }
+        let written = shared.written.load(Ordering::Relaxed);
+        let mut evicted = 0;
+        loop {
+            let read = shared.read.load(Ordering::Acquire);
+            let free = shared.capacity() - written.wrapping_sub(read);
+            if free >= bytes.len() {
+                break;
+            }
+            let excess = bytes.len() - free;
+            // Fails if the consumer read some meanwhile, making room itself.
+            if shared
+                .read
+                .compare_exchange(read, read.wrapping_add(excess), Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                evicted = excess;
+                break;
+            }
+        }
+        self.write(written, bytes);
+        evicted
+    }
+
+    fn write(&mut self, written: usize, bytes: &[u8]) {
+        let shared = &*self.shared;
+        for (i, &byte) in bytes.iter().enumerate() {
+            shared.slots[written.wrapping_add(i) % shared.capacity()].store(byte, Ordering::Relaxed);
+        }
+        shared
+            .written
+            .store(written.wrapping_add(bytes.len()), Ordering::Release);
+    }
+}
+
+impl Drop for Producer {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::Release);
+    }
+}
+
+/// The reading end.
+pub struct Consumer {
+    shared: Arc<Shared>,
+}
+
+impl Consumer {
+    /// Bytes waiting to be read.
+    pub fn len(&self) -> usize {
+        let read = self.shared.read.load(Ordering::Acquire);
+        self.shared.written.load(Ordering::Acquire).wrapping_sub(read)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Moves up to `max` waiting bytes into `out`, replacing what it held,
+    /// and returns how many there were.
+    pub fn try_pop(&mut self, out: &mut Vec<u8>, max: usize) -> usize {
+        let shared = &*self.shared;
+        loop {
+            let read = shared.read.load(Ordering::Acquire);
+            let count = shared.written.load(Ordering::Acquire).wrapping_sub(read).min(max);
+            out.clear();
+            let slot = |i: usize| &shared.slots[read.wrapping_add(i) % shared.capacity()];
+            out.extend((0..count).map(|i| slot(i).load(Ordering::Relaxed)));
+            // If the producer discarded these bytes meanwhile, they may
+            // have been overwritten, so start again from the new position.
+            if shared
+                .read
+                .compare_exchange(read, read.wrapping_add(count), Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+            {
+                return count;
+            }
+        }
+    }
+
+    /// Waits for bytes and moves up to `max` of them into `out`. False
+    /// once the producer is gone and everything it pushed has been read.
+    pub async fn pop(&mut self, out: &mut Vec<u8>, max: usize) -> bool {
+        loop {
+            // Read before the data, so a close seen here comes after the
+            // last push.
+            let closed = self.shared.closed.load(Ordering::Acquire);
+            if self.try_pop(out, max) > 0 {
+                return true;
+            }
+            if closed {
+                return false;
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_and_pop_wrap_around() {
+        let (mut producer, mut consumer) = channel(8);
+        let mut out = Vec::new();
+        for round in 0..10u8 {
+            assert!(producer.push(&[round, round + 1, round + 2]));
+            assert_eq!(consumer.len(), 3);
+            assert_eq!(consumer.try_pop(&mut out, 2), 2);
+            assert_eq!(out, [round, round + 1]);
+            assert_eq!(consumer.try_pop(&mut out, 8), 1);
+            assert_eq!(out, [round + 2]);
+        }
+        assert!(consumer.is_empty());
+    }
+
+    #[test]
+    fn test_full_ring_takes_nothing() {
+        let (mut producer, mut consumer) = channel(6);
+        assert!(producer.push(&[1, 2, 3, 4]));
+        assert!(!producer.push(&[5, 6, 7]));
+        assert!(producer.push(&[5, 6]));
+        let mut out = Vec::new();
+        consumer.try_pop(&mut out, 16);
+        assert_eq!(out, [1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn test_push_evicting_drops_the_oldest() {
+        let (mut producer, mut consumer) = channel(8);
+        assert_eq!(producer.push_evicting(&[1, 2, 3, 4]), 0);
+        assert_eq!(producer.push_evicting(&[5, 6, 7, 8]), 0);
+        assert_eq!(producer.push_evicting(&[9, 10]), 2);
+        assert_eq!(producer.push_evicting(&[0; 9]), 9);
+        let mut out = Vec::new();
+        consumer.try_pop(&mut out, 16);
+        assert_eq!(out, [3, 4, 5, 6, 7, 8, 9, 10]);
+    }
+
+    #[test]
+    fn test_pop_drains_after_close() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let (mut producer, mut consumer) = channel(64);
+        let writer = std::thread::spawn(move || {
+            for block in 0..20u8 {
+                while !producer.push(&[block; 4]) {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            }
+        });
+        let mut received = Vec::new();
+        runtime.block_on(async {
+            let mut out = Vec::new();
+            while consumer.pop(&mut out, 8).await {
+                received.extend_from_slice(&out);
+            }
+        });
+        writer.join().unwrap();
+        let expected: Vec<u8> = (0..20u8).flat_map(|block| [block; 4]).collect();
+        assert_eq!(received, expected);
+    }
+}
@@ -0,0 +1,326 @@
+use std::io::{self, Write};
+use std::net::UdpSocket;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::clock::system_micros;
+use crate::jitter::JitterBuffer;
+use crate::packet::SEQ_MASK;
+
+/// Where roc-recv conventionally takes source packets.
+pub const ROC_DEFAULT_PORT: u16 = 10001;
+// Roc's default packet encodings are the RFC 3551 static L16 types, which
+// fix the rate at 44.1kHz.
+pub const ROC_SAMPLE_RATE: u32 = 44_100;
+const RTP_PAYLOAD_L16_STEREO: u8 = 10;
+const RTP_PAYLOAD_L16_MONO: u8 = 11;
+const RTP_VERSION: u8 = 0x80;
+const RTP_HEADER_LEN: usize = 12;
+// 5ms at 44.1kHz, Roc's default packet length.
+const PACKET_FRAMES: usize = 220;
+// Sequence jumps beyond this are a restarted sender rather than losses.
+const MAX_GAP: u16 = 100;
+
+/// Linear-interpolating sample rate converter for interleaved s16. Keeps
+/// its phase between calls, so a stream can be fed in any block sizes.
+pub struct Resampler {
+    channels: usize,
+    step: f64,
+    // Read position relative to `last`, in input frames.
+    position: f64,
+    last: Vec<i16>,
+}
+
+impl Resampler {
+    pub fn new(from_rate: u32, to_rate: u32, channels: usize) -> Self {
+        Self {
+            channels,
+            step: from_rate as f64 / to_rate as f64,
+            position: 0.0,
+            last: vec![0; channels],
+        }
+    }
+
+    pub fn process(&mut self, input: &[i16], out: &mut Vec<i16>) {
+        let frames = input.len() / self.channels;
+        if frames == 0 {
+            return;
+        }
+        // Frame 0 is the last one of the previous block, then `input`.
+        let frame = |i: usize| {
+            if i == 0 {
+                &self.last[..]
+            } else {
+                &input[(i - 1) * self.channels..i * self.channels]
+            }
+        };
+        while self.position < frames as f64 {
+            let index = self.position as usize;
+            let fraction = self.position - index as f64;
+            let (a, b) = (frame(index), frame(index + 1));
+            out.extend(
+                a.iter()
+                    .zip(b)
+                    .map(|(&a, &b)| (a as f64 + (b as f64 - a as f64) * fraction).round() as i16),
+            );
+            self.position += self.step;
+        }
+        self.position -= frames as f64;
+        self.last
+            .copy_from_slice(&input[(frames - 1) * self.channels..frames * self.channels]);
+    }
+}
+
+/// Sends s16le PCM as bare RTP, the `rtp://` profile of the Roc toolkit,
+/// so `roc-recv` can play it through its own latency tuning and loss
+/// concealment. Audio is resampled to the 44.1kHz the profile fixes.
+pub struct RocWriter {
+    socket: UdpSocket,
+    channels: usize,
+    payload_type: u8,
+    resampler: Resampler,
+    pending: Vec<i16>,
+    packet: Vec<u8>,
+    seq: u16,
+    rtp_time: u32,
+    ssrc: u32,
+}
+
+impl RocWriter {
+    pub fn connect(addr: &str, sample_rate: u32, channels: u16) -> io::Result<Self> {
+        let payload_type = payload_type(channels as usize)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Roc's RTP profile carries mono or stereo"))?;
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(addr)?;
+        // Called from the audio callback; a full socket buffer drops the packet.
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            channels: channels as usize,
+            payload_type,
+            resampler: Resampler::new(sample_rate, ROC_SAMPLE_RATE, channels as usize),
+            pending: Vec::new(),
+            packet: Vec::with_capacity(RTP_HEADER_LEN + PACKET_FRAMES * channels as usize * 2),
+            seq: 0,
+            rtp_time: 0,
+            ssrc: system_micros() as u32,
+        })
+    }
+
+    fn send_packet(&mut self) -> io::Result<()> {
+        let len = PACKET_FRAMES * self.channels;
+        self.packet.clear();
+        self.packet.push(RTP_VERSION);
+        self.packet.push(self.payload_type);
+        self.packet.extend_from_slice(&self.seq.to_be_bytes());
+        self.packet.extend_from_slice(&self.rtp_time.to_be_bytes());
+        self.packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        self.packet
+            .extend(self.pending[..len].iter().flat_map(|sample| sample.to_be_bytes()));
+        self.pending.drain(..len);
+        self.seq = self.seq.wrapping_add(1);
+        self.rtp_time = self.rtp_time.wrapping_add(PACKET_FRAMES as u32);
+        self.socket.send(&self.packet).map(|_| ())
+    }
+}
+
+impl Write for RocWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let samples: Vec<i16> = buf.chunks_exact(2).map(|s| i16::from_le_bytes([s[0], s[1]])).collect();
+        self.resampler.process(&samples, &mut self.pending);
+        let mut first_error = None;
+        while self.pending.len() >= PACKET_FRAMES * self.channels {
+            // Keep sending the rest of the buffer; report the first failure.
+            if let Err(e) = self.send_packet() {
+                first_error.get_or_insert(e);
+            }
+        }
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Turns RTP from `roc-send` back into the frames our jitter buffer plays:
+/// resampled to the output rate and cut into `frame_frames` frames. Lost
+/// packets become silence so the timeline stays intact.
+pub struct RocDepacketizer {
+    channels: usize,
+    frame_frames: usize,
+    resampler: Resampler,
+    expected: Option<u16>,
+    pending: Vec<i16>,
+    seq: u32,
+}
+
+impl RocDepacketizer {
+    pub fn new(sample_rate: u32, channels: u16, frame_frames: usize) -> Self {
+        Self {
+            channels: channels as usize,
+            frame_frames,
+            resampler: Resampler::new(ROC_SAMPLE_RATE, sample_rate, channels as usize),
+            expected: None,
+            pending: Vec::new(),
+            seq: 0,
+        }
+    }
+
+    /// Takes one RTP packet and returns the frames it completed, as
+    /// sequence numbers and s16le payloads.
+    pub fn push(&mut self, datagram: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let Some((seq, kind, payload)) = parse_rtp(datagram) else {
+            return Vec::new();
+        };
+        if Some(kind) != payload_type(self.channels) {
+            return Vec::new();
+        }
+        let samples: Vec<i16> = payload
+            .chunks_exact(2)
+            .map(|s| i16::from_be_bytes([s[0], s[1]]))
+            .collect();
+        let gap = self.expected.map_or(0, |expected| seq.wrapping_sub(expected));
+        if gap > u16::MAX / 2 {
+            // Late or duplicate; its slot has already been filled.
+            return Vec::new();
+        }
+        if (1..=MAX_GAP).contains(&gap) {
+            let silence = vec![0i16; samples.len() * gap as usize];
+            self.resampler.process(&silence, &mut self.pending);
+        }
+        self.expected = Some(seq.wrapping_add(1));
+        self.resampler.process(&samples, &mut self.pending);
+
+        let len = self.frame_frames * self.channels;
+        let mut frames = Vec::new();
+        while self.pending.len() >= len {
+            let frame = self
+                .pending
+                .drain(..len)
+                .flat_map(|sample| sample.to_le_bytes())
+                .collect();
+            frames.push((self.seq, frame));
+            self.seq = (self.seq + 1) & SEQ_MASK;
+        }
+        frames
+    }
+}
+
+/// Reads `roc-send` packets off the socket into the jitter buffer.
+pub async fn receive(
+    socket: tokio::net::UdpSocket,
+    buffer: Arc<Mutex<JitterBuffer>>,
+    mut depacketizer: RocDepacketizer,
+) -> io::Result<()> {
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let (len, _) = socket.recv_from(&mut buf).await?;
+        let frames = depacketizer.push(&buf[..len]);
+        let mut jb = buffer.lock().unwrap();
+        for (seq, frame) in frames {
+            jb.push(seq, frame, Instant::now());
+        }
+    }
+}
+
+fn payload_type(channels: usize) -> Option<u8> {
+    match channels {
+        1 => Some(RTP_PAYLOAD_L16_MONO),
+        2 => Some(RTP_PAYLOAD_L16_STEREO),
+        _ => None,
+    }
+}
+
+// Sequence number, payload type and payload of an RTP packet, skipping
+// CSRCs, header extension and padding.
+pub(crate) fn parse_rtp(datagram: &[u8]) -> Option<(u16, u8, &[u8])> {
+    if datagram.len() < RTP_HEADER_LEN || datagram[0] >> 6 != 2 {
+        return None;
+    }
+    let mut start = RTP_HEADER_LEN + (datagram[0] & 0x0f) as usize * 4;
+    if datagram[0] & 0x10 != 0 {
+        let words = u16::from_be_bytes([*datagram.get(start + 2)?, *datagram.get(start + 3)?]) as usize;
+        start += 4 + words * 4;
+    }
+    let mut end = datagram.len();
+    if datagram[0] & 0x20 != 0 {
+        end = end.checked_sub(*datagram.last()? as usize)?;
+    }
+    let payload = datagram.get(start..end)?;
+    Some((
+        u16::from_be_bytes([datagram[2], datagram[3]]),
+        datagram[1] & 0x7f,
+        payload,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resampler() {
+        let mut resampler = Resampler::new(48_000, 44_100, 2);
+        let mut out = Vec::new();
+        for _ in 0..10 {
+            resampler.process(&[1000i16; 480 * 2], &mut out);
+        }
+        // 100ms in, 100ms out, and a constant stays constant once the
+        // initial silence is past.
+        assert!((out.len() as i64 / 2 - 4410).abs() <= 1);
+        assert!(out[2..].iter().all(|&s| s == 1000));
+
+        let mut upsampler = Resampler::new(1, 2, 1);
+        let mut out = Vec::new();
+        upsampler.process(&[100, 200], &mut out);
+        assert_eq!(out, vec![0, 50, 100, 150]);
+    }
+
+    #[test]
+    fn test_roundtrip_through_rtp() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut writer = RocWriter::connect(&receiver.local_addr().unwrap().to_string(), 48_000, 2).unwrap();
+        // 10ms at 48kHz is 441 frames at 44.1kHz: two packets.
+        let pcm: Vec<u8> = [500i16; 480 * 2].iter().flat_map(|s| s.to_le_bytes()).collect();
+        writer.write_all(&pcm).unwrap();
+
+        let mut depacketizer = RocDepacketizer::new(48_000, 2, 240);
+        let mut buf = [0u8; 2048];
+        let mut frames = Vec::new();
+        for expected_seq in 0..2u16 {
+            let len = receiver.recv(&mut buf).unwrap();
+            assert_eq!(len, RTP_HEADER_LEN + PACKET_FRAMES * 4);
+            assert_eq!(buf[1], RTP_PAYLOAD_L16_STEREO);
+            assert_eq!(&buf[2..4], &expected_seq.to_be_bytes());
+            frames.extend(depacketizer.push(&buf[..len]));
+        }
+        // 440 frames at 44.1kHz come back as one whole 240-frame frame.
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].0, 0);
+        assert_eq!(frames[0].1.len(), 240 * 4);
+    }
+
+    #[test]
+    fn test_lost_packets_become_silence() {
+        let packet = |seq: u16| {
+            let mut packet = vec![RTP_VERSION, RTP_PAYLOAD_L16_MONO];
+            packet.extend_from_slice(&seq.to_be_bytes());
+            packet.extend_from_slice(&[0; 8]);
+            packet.extend((0..441).flat_map(|_| 1000i16.to_be_bytes()));
+            packet
+        };
+        let mut depacketizer = RocDepacketizer::new(44_100, 1, 441);
+        assert_eq!(depacketizer.push(&packet(7)).len(), 1);
+        // Two lost packets: three frames, the middle two silent.
+        let frames = depacketizer.push(&packet(10));
+        assert_eq!(frames.iter().map(|(seq, _)| *seq).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert!(frames[1].1.iter().all(|&b| b == 0));
+        // A late packet is dropped.
+        assert!(depacketizer.push(&packet(9)).is_empty());
+        assert!(parse_rtp(&[0u8; 4]).is_none());
+    }
+}
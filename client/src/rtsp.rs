@@ -0,0 +1,537 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::clock::system_micros;
+
+pub const STREAM_PATH: &str = "/stream";
+// Dynamic payload type for L16/48000/2, which has no static assignment.
+const RTP_PAYLOAD_L16: u8 = 96;
+const RTP_VERSION: u8 = 0x80;
+// 5ms of 48kHz stereo s16 is 960 bytes, well inside a 1500-byte MTU.
+const PACKET_FRAMES: usize = 240;
+const PACKET_MS: usize = 5;
+// Packets queued per session before it counts as stalled and is dropped.
+const SESSION_QUEUE: usize = 256;
+const SESSION_TIMEOUT_SECS: u32 = 60;
+const MAX_REQUEST_BODY: usize = 64 * 1024;
+// The longest request line and headers read before giving up on the player.
+const MAX_REQUEST_HEAD: u64 = 8192;
+
+/// Serves the capture over RTSP as RTP L16 (big-endian PCM) at
+/// `rtsp://<host>:<port>/stream`. Players subscribe with the usual
+/// DESCRIBE/SETUP/PLAY exchange and get RTP over UDP, or interleaved on the
+/// RTSP connection when they ask for TCP. Sessions that fall behind are
+/// dropped instead of stalling the others.
+pub struct RtspServer {
+    sessions: Arc<Mutex<Vec<Subscriber>>>,
+    addr: SocketAddr,
+    channels: u16,
+    pending: Vec<u8>,
+    packet: Vec<u8>,
+    seq: u16,
+    rtp_time: u32,
+    ssrc: u32,
+}
+
+struct Subscriber {
+    session: String,
+    packets: SyncSender<Arc<[u8]>>,
+}
+
+// What one RTSP connection needs from the server.
+struct Config {
+    sessions: Arc<Mutex<Vec<Subscriber>>>,
+    sample_rate: u32,
+    channels: u16,
+    next_session: AtomicU32,
+}
+
+impl RtspServer {
+    pub fn serve(listener: TcpListener, sample_rate: u32, channels: u16) -> io::Result<Self> {
+        let addr = listener.local_addr()?;
+        let sessions = Arc::new(Mutex::new(Vec::new()));
+        let config = Arc::new(Config {
+            sessions: sessions.clone(),
+            sample_rate,
+            channels,
+            next_session: AtomicU32::new(system_micros() as u32),
+        });
+        thread::spawn(move || {
+            for connection in listener.incoming().flatten() {
+                let config = config.clone();
+                thread::spawn(move || {
+                    let peer = connection.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+                    if let Err(e) = handle_connection(connection, &config) {
+                        if e.kind() != io::ErrorKind::BrokenPipe && e.kind() != io::ErrorKind::ConnectionReset {
+                            warn!("RTSP connection from {} failed: {}", peer, e);
+                        }
+                    }
+                });
+            }
+        });
+        Ok(Self {
+            sessions,
+            addr,
+            channels,
+            pending: Vec::new(),
+            packet: Vec::new(),
+            seq: 0,
+            rtp_time: 0,
+            ssrc: system_micros() as u32,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn sessions(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    fn send_packet(&mut self) {
+        let len = PACKET_FRAMES * self.channels as usize * 2;
+        self.packet.clear();
+        self.packet.push(RTP_VERSION);
+        self.packet.push(RTP_PAYLOAD_L16);
+        self.packet.extend_from_slice(&self.seq.to_be_bytes());
+        self.packet.extend_from_slice(&self.rtp_time.to_be_bytes());
+        self.packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        // L16 is network byte order; the capture is little-endian.
+        self.packet
+            .extend(self.pending[..len].chunks_exact(2).flat_map(|sample| [sample[1], sample[0]]));
+        self.pending.drain(..len);
+        self.seq = self.seq.wrapping_add(1);
+        self.rtp_time = self.rtp_time.wrapping_add(PACKET_FRAMES as u32);
+
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.is_empty() {
+            return;
+        }
+        let packet: Arc<[u8]> = self.packet.as_slice().into();
+        sessions.retain(|subscriber| match subscriber.packets.try_send(packet.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                warn!("Dropping RTSP session {} that fell behind", subscriber.session);
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+}
+
+impl Write for RtspServer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        let len = PACKET_FRAMES * self.channels as usize * 2;
+        while self.pending.len() >= len {
+            self.send_packet();
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Request {
+    method: String,
+    uri: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+// Where a SETUP asked us to send RTP.
+#[derive(Debug, PartialEq, Eq)]
+enum Transport {
+    Udp { rtp_port: u16, rtcp_port: u16 },
+    Interleaved { channel: u8 },
+}
+
+// A session set up on this connection, waiting for PLAY.
+struct Setup {
+    session: String,
+    transport: Transport,
+}
+
+fn handle_connection(connection: TcpStream, config: &Config) -> io::Result<()> {
+    let local_ip = connection.local_addr()?.ip();
+    let peer_ip = connection.peer_addr()?.ip();
+    // Players send keepalives within the session timeout, so a connection
+    // silent for longer is gone or stuck.
+    connection.set_read_timeout(Some(Duration::from_secs(SESSION_TIMEOUT_SECS.into())))?;
+    let writer = Arc::new(Mutex::new(connection.try_clone()?));
+    let mut reader = BufReader::new(connection);
+    let mut setup: Option<Setup> = None;
+    let mut playing: Option<String> = None;
+
+    let result = loop {
+        let request = match read_request(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+        };
+        let cseq = request.header("CSeq").unwrap_or("0").to_string();
+        let path_ok = uri_path(&request.uri).is_some_and(|path| {
+            path.strip_prefix(STREAM_PATH)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        });
+        let (status, headers, body) = match request.method.as_str() {
+            "OPTIONS" => (
+                "200 OK",
+                "Public: OPTIONS, DESCRIBE, SETUP, PLAY, TEARDOWN, GET_PARAMETER\r\n".to_string(),
+                String::new(),
+            ),
+            "GET_PARAMETER" => ("200 OK", String::new(), String::new()),
+            "DESCRIBE" if path_ok => {
+                let base = format!("{}/", request.uri.trim_end_matches('/'));
+                (
+                    "200 OK",
+                    format!("Content-Base: {}\r\nContent-Type: application/sdp\r\n", base),
+                    sdp(local_ip, config.sample_rate, config.channels),
+                )
+            }
+            "SETUP" if path_ok => match request.header("Transport").and_then(parse_transport) {
+                Some(transport) => {
+                    let session = format!("{:08X}", config.next_session.fetch_add(1, Ordering::Relaxed));
+                    let reply = match &transport {
+                        Transport::Udp { rtp_port, rtcp_port } => {
+                            format!("RTP/AVP;unicast;client_port={}-{}", rtp_port, rtcp_port)
+                        }
+                        Transport::Interleaved { channel } => {
+                            format!("RTP/AVP/TCP;unicast;interleaved={}-{}", channel, channel + 1)
+                        }
+                    };
+                    let headers = format!(
+                        "Transport: {}\r\nSession: {};timeout={}\r\n",
+                        reply, session, SESSION_TIMEOUT_SECS
+                    );
+                    setup = Some(Setup { session, transport });
+                    ("200 OK", headers, String::new())
+                }
+                None => ("461 Unsupported Transport", String::new(), String::new()),
+            },
+            "PLAY" => match setup.take() {
+                Some(Setup { session, transport }) => {
+                    let (packets, receiver) = mpsc::sync_channel(SESSION_QUEUE);
+                    match start_sender(transport, peer_ip, &writer, receiver) {
+                        Ok(()) => {
+                            config.sessions.lock().unwrap().push(Subscriber {
+                                session: session.clone(),
+                                packets,
+                            });
+                            info!("RTSP session {} playing to {}", session, peer_ip);
+                            let headers = format!("Session: {}\r\nRange: npt=0.000-\r\n", session);
+                            playing = Some(session);
+                            ("200 OK", headers, String::new())
+                        }
+                        Err(e) => {
+                            warn!("RTSP session {} could not start: {}", session, e);
+                            ("500 Internal Server Error", String::new(), String::new())
+                        }
+                    }
+                }
+                None => ("455 Method Not Valid in This State", String::new(), String::new()),
+            },
+            "TEARDOWN" => {
+                if let Some(session) = playing.take() {
+                    remove_session(config, &session);
+                }
+                setup = None;
+                ("200 OK", String::new(), String::new())
+            }
+            "DESCRIBE" | "SETUP" => ("404 Not Found", String::new(), String::new()),
+            _ => ("501 Not Implemented", String::new(), String::new()),
+        };
+
+        let mut response = format!("RTSP/1.0 {}\r\nCSeq: {}\r\nServer: audio-client\r\n{}", status, cseq, headers);
+        if !body.is_empty() {
+            response.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        response.push_str("\r\n");
+        response.push_str(&body);
+        if let Err(e) = writer.lock().unwrap().write_all(response.as_bytes()) {
+            break Err(e);
+        }
+    };
+
+    // A player that drops the connection without TEARDOWN is done too.
+    if let Some(session) = playing {
+        remove_session(config, &session);
+    }
+    result
+}
+
+fn remove_session(config: &Config, session: &str) {
+    config.sessions.lock().unwrap().retain(|subscriber| subscriber.session != session);
+    info!("RTSP session {} ended", session);
+}
+
+// Forwards a session's packets until it is removed from the server.
+fn start_sender(
+    transport: Transport,
+    peer_ip: IpAddr,
+    connection: &Arc<Mutex<TcpStream>>,
+    packets: Receiver<Arc<[u8]>>,
+) -> io::Result<()> {
+    match transport {
+        Transport::Udp { rtp_port, .. } => {
+            let socket = UdpSocket::bind(match peer_ip {
+                IpAddr::V4(_) => "0.0.0.0:0",
+                IpAddr::V6(_) => "[::]:0",
+            })?;
+            socket.connect((peer_ip, rtp_port))?;
+            thread::spawn(move || {
+                for packet in packets {
+                    // UDP loss is the player's problem, like the main sink.
+                    let _ = socket.send(&packet);
+                }
+            });
+        }
+        Transport::Interleaved { channel } => {
+            let connection = connection.clone();
+            thread::spawn(move || {
+                for packet in packets {
+                    let mut frame = Vec::with_capacity(4 + packet.len());
+                    frame.push(b'$');
+                    frame.push(channel);
+                    frame.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+                    frame.extend_from_slice(&packet);
+                    if connection.lock().unwrap().write_all(&frame).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    }
+    Ok(())
+}
+
+// Reads the next request, skipping any interleaved RTCP the player sends
+// on the connection. Returns `None` when the player hangs up.
+fn read_request(reader: &mut impl BufRead) -> io::Result<Option<Request>> {
+    loop {
+        let buf = reader.fill_buf()?;
+        match buf.first() {
+            None => return Ok(None),
+            Some(b'$') => {
+                let mut header = [0u8; 4];
+                reader.read_exact(&mut header)?;
+                let len = u16::from_be_bytes([header[2], header[3]]) as u64;
+                io::copy(&mut reader.by_ref().take(len), &mut io::sink())?;
+            }
+            Some(b'\r') | Some(b'\n') => reader.consume(1),
+            Some(_) => break,
+        }
+    }
+
+    let mut budget = MAX_REQUEST_HEAD;
+    let mut line = String::new();
+    read_head_line(reader, &mut line, &mut budget)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(uri)) = (parts.next(), parts.next()) else {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed RTSP request line"));
+    };
+    let mut request = Request {
+        method: method.to_string(),
+        uri: uri.to_string(),
+        headers: Vec::new(),
+    };
+    loop {
+        line.clear();
+        if read_head_line(reader, &mut line, &mut budget)? == 0 {
+            break;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            request.headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    let length: usize = request.header("Content-Length").and_then(|l| l.parse().ok()).unwrap_or(0);
+    if length > MAX_REQUEST_BODY {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "oversized RTSP request body"));
+    }
+    io::copy(&mut reader.by_ref().take(length as u64), &mut io::sink())?;
+    Ok(Some(request))
+}
+
+// Reads a line of the request head into `line` out of the `budget` of
+// bytes the head has left. `InvalidData` once the head outgrows it.
+fn read_head_line(reader: &mut impl BufRead, line: &mut String, budget: &mut u64) -> io::Result<usize> {
+    let read = reader.by_ref().take(*budget).read_line(line)?;
+    *budget -= read as u64;
+    if *budget == 0 && !line.ends_with('\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "oversized RTSP request head"));
+    }
+    Ok(read)
+}
+
+fn uri_path(uri: &str) -> Option<&str> {
+    let rest = uri.strip_prefix("rtsp://")?;
+    Some(rest.find('/').map_or("/", |slash| &rest[slash..]))
+}
+
+// Picks the first transport we can serve from a SETUP's Transport header.
+fn parse_transport(header: &str) -> Option<Transport> {
+    header.split(',').find_map(|spec| {
+        let mut fields = spec.trim().split(';');
+        let profile = fields.next()?;
+        let params: Vec<(&str, &str)> = fields.filter_map(|field| field.split_once('=')).collect();
+        let param = |name: &str| params.iter().find(|(key, _)| *key == name).map(|(_, value)| *value);
+        match profile {
+            "RTP/AVP" | "RTP/AVP/UDP" => {
+                let ports = param("client_port")?;
+                let (rtp, rtcp) = ports.split_once('-').unwrap_or((ports, ""));
+                let rtp_port: u16 = rtp.parse().ok()?;
+                let rtcp_port = rtcp.parse().unwrap_or(rtp_port.wrapping_add(1));
+                Some(Transport::Udp { rtp_port, rtcp_port })
+            }
+            "RTP/AVP/TCP" => {
+                let channel = param("interleaved")
+                    .and_then(|channels| channels.split('-').next())
+                    .map_or(Some(0), |channel| channel.parse().ok())?;
+                Some(Transport::Interleaved { channel })
+            }
+            _ => None,
+        }
+    })
+}
+
+fn sdp(local_ip: IpAddr, sample_rate: u32, channels: u16) -> String {
+    let (family, addr) = match local_ip {
+        IpAddr::V4(ip) => ("IP4", ip.to_string()),
+        IpAddr::V6(ip) => ("IP6", ip.to_string()),
+    };
+    format!(
+        "v=0\r\no=- 0 0 IN {family} {addr}\r\ns=audio-client\r\nc=IN {family} {addr}\r\nt=0 0\r\n\
+         m=audio 0 RTP/AVP {pt}\r\na=rtpmap:{pt} L16/{rate}/{channels}\r\na=ptime:{ptime}\r\n\
+         a=control:trackID=0\r\na=sendonly\r\n",
+        family = family,
+        addr = addr,
+        pt = RTP_PAYLOAD_L16,
+        rate = sample_rate,
+        channels = channels,
+        ptime = PACKET_MS,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_transport() {
+        assert_eq!(
+            parse_transport("RTP/AVP;unicast;client_port=5000-5001"),
+            Some(Transport::Udp { rtp_port: 5000, rtcp_port: 5001 })
+        );
+        assert_eq!(
+            parse_transport("RTP/AVP/TCP;unicast;interleaved=2-3"),
+            Some(Transport::Interleaved { channel: 2 })
+        );
+        assert_eq!(
+            parse_transport("RTP/SAVP;unicast;client_port=1-2,RTP/AVP/TCP;unicast"),
+            Some(Transport::Interleaved { channel: 0 })
+        );
+        assert_eq!(parse_transport("RTP/AVP;multicast"), None);
+        assert_eq!(uri_path("rtsp://host:8554/stream/trackID=0"), Some("/stream/trackID=0"));
+        assert_eq!(uri_path("rtsp://host:8554"), Some("/"));
+    }
+
+    #[test]
+    fn test_read_request_skips_interleaved_data() {
+        let input = b"$\x01\x00\x03abcOPTIONS rtsp://h/stream RTSP/1.0\r\nCSeq: 2\r\nContent-Length: 2\r\n\r\nxy";
+        let mut reader = BufReader::new(&input[..]);
+        let request = read_request(&mut reader).unwrap().unwrap();
+        assert_eq!(request.method, "OPTIONS");
+        assert_eq!(request.header("cseq"), Some("2"));
+        assert!(read_request(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_read_request_rejects_oversized_head() {
+        let line = vec![b'A'; MAX_REQUEST_HEAD as usize + 1];
+        let error = read_request(&mut BufReader::new(&line[..])).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let mut headers = b"OPTIONS rtsp://h/stream RTSP/1.0\r\n".to_vec();
+        while headers.len() <= MAX_REQUEST_HEAD as usize {
+            headers.extend_from_slice(b"X-Padding: 0123456789\r\n");
+        }
+        let error = read_request(&mut BufReader::new(&headers[..])).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_interleaved_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut server = RtspServer::serve(listener, 48_000, 2).unwrap();
+        let mut player = TcpStream::connect(server.local_addr()).unwrap();
+        player.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(player.try_clone().unwrap());
+        let uri = format!("rtsp://{}{}", server.local_addr(), STREAM_PATH);
+
+        let mut exchange = |request: String| {
+            player.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                response.push_str(&line);
+            }
+            let length: usize = response
+                .lines()
+                .find_map(|line| line.strip_prefix("Content-Length: "))
+                .map_or(0, |l| l.parse().unwrap());
+            let mut body = vec![0u8; length];
+            reader.read_exact(&mut body).unwrap();
+            (response, String::from_utf8(body).unwrap())
+        };
+
+        let (response, sdp) = exchange(format!("DESCRIBE {} RTSP/1.0\r\nCSeq: 1\r\n\r\n", uri));
+        assert!(response.starts_with("RTSP/1.0 200 OK"));
+        assert!(sdp.contains("a=rtpmap:96 L16/48000/2"));
+        let (response, _) = exchange(format!(
+            "SETUP {}/trackID=0 RTSP/1.0\r\nCSeq: 2\r\nTransport: RTP/AVP/TCP;unicast;interleaved=0-1\r\n\r\n",
+            uri
+        ));
+        assert!(response.contains("interleaved=0-1"));
+        let session = response.lines().find_map(|l| l.strip_prefix("Session: ")).unwrap();
+        let session = session.split(';').next().unwrap().to_string();
+        let (response, _) = exchange(format!("PLAY {} RTSP/1.0\r\nCSeq: 3\r\nSession: {}\r\n\r\n", uri, session));
+        assert!(response.starts_with("RTSP/1.0 200 OK"));
+        assert_eq!(server.sessions(), 1);
+
+        let pcm: Vec<u8> = (0..PACKET_FRAMES * 2).flat_map(|i| (i as i16).to_le_bytes()).collect();
+        server.write_all(&pcm).unwrap();
+        let mut frame = vec![0u8; 4 + 12 + pcm.len()];
+        reader.read_exact(&mut frame).unwrap();
+        assert_eq!(&frame[..2], &[b'$', 0]);
+        assert_eq!(u16::from_be_bytes([frame[2], frame[3]]) as usize, 12 + pcm.len());
+        assert_eq!(frame[5], RTP_PAYLOAD_L16);
+        // Sample 1 in network byte order.
+        assert_eq!(&frame[18..20], &[0, 1]);
+    }
+}
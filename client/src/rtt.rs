@@ -0,0 +1,219 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+use tracing::{error, info, warn};
+
+use crate::clock::{self, system_micros, SyncedClock};
+use crate::playback;
+use crate::stats::StreamStats;
+
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+// A reply later than this counts the ping as lost.
+const PING_TIMEOUT: Duration = Duration::from_secs(1);
+/// Round trips the summary covers, a minute of pings.
+pub const WINDOW: usize = 60;
+// Pings lost in a row before the peer is reported unreachable.
+const LOST_WARNING: u64 = 5;
+
+/// The latest round trips to the receiver.
+#[derive(Default)]
+pub struct RttTracker {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    samples: VecDeque<Duration>,
+    lost: u64,
+    end_to_end: Option<Duration>,
+}
+
+/// Round trips over the last [`WINDOW`] replies. Jitter is the mean
+/// difference between consecutive round trips.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RttSummary {
+    pub min: Duration,
+    pub avg: Duration,
+    pub max: Duration,
+    pub jitter: Duration,
+    /// Pings that got no reply since the client started.
+    pub lost: u64,
+    /// How long audio takes from capture to the receiver's speaker, as of
+    /// the latest reply, when packets are stamped.
+    pub end_to_end: Option<Duration>,
+}
+
+impl RttTracker {
+    pub fn record(&self, rtt: Duration) {
+        let mut state = self.state.lock().unwrap();
+        if state.samples.len() == WINDOW {
+            state.samples.pop_front();
+        }
+        state.samples.push_back(rtt);
+    }
+
+    pub fn record_lost(&self) {
+        self.state.lock().unwrap().lost += 1;
+    }
+
+    pub fn record_end_to_end(&self, latency: Duration) {
+        self.state.lock().unwrap().end_to_end = Some(latency);
+    }
+
+    /// None until the first reply.
+    pub fn summary(&self) -> Option<RttSummary> {
+        let state = self.state.lock().unwrap();
+        let samples = &state.samples;
+        if samples.is_empty() {
+            return None;
+        }
+        let count = samples.len() as u32;
+        let steps: Duration = samples
+            .iter()
+            .zip(samples.iter().skip(1))
+            .map(|(a, b)| a.abs_diff(*b))
+            .sum();
+        Some(RttSummary {
+            min: *samples.iter().min().unwrap(),
+            avg: samples.iter().sum::<Duration>() / count,
+            max: *samples.iter().max().unwrap(),
+            jitter: steps / (count - 1).max(1),
+            lost: state.lost,
+            end_to_end: state.end_to_end,
+        })
+    }
+}
+
+impl fmt::Display for RttSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        write!(
+            f,
+            "min/avg/max/jitter {:.1}/{:.1}/{:.1}/{:.1} ms, {} lost",
+            ms(self.min),
+            ms(self.avg),
+            ms(self.max),
+            ms(self.jitter),
+            self.lost
+        )
+    }
+}
+
+/// Pings a receiver's control port every second for as long as the client
+/// runs. A ping is the clock sync time request, which every client answers,
+/// and its round trip leaves out the time the receiver took to reply. With
+/// the clock that stamps the packets, each ping also asks the receiver what
+/// it is playing.
+pub async fn ping(peer: String, stats: Arc<StreamStats>, playout_clock: Option<Arc<SyncedClock>>) {
+    let socket = match UdpSocket::bind("0.0.0.0:0").await {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("Error binding ping socket: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = socket.connect(&peer).await {
+        error!("Error resolving ping peer {}: {}", peer, e);
+        return;
+    }
+
+    let mut interval = tokio::time::interval(PING_INTERVAL);
+    let mut lost_in_row = 0;
+    let mut buf = [0u8; 64];
+    loop {
+        interval.tick().await;
+        match round_trip(&socket, &mut buf).await {
+            Some(rtt) => {
+                if stats.rtt().summary().is_none() || lost_in_row >= LOST_WARNING {
+                    info!("Round trip to {}: {:.1} ms", peer, rtt.as_secs_f64() * 1000.0);
+                }
+                lost_in_row = 0;
+                stats.rtt().record(rtt);
+                if let Some(clock) = &playout_clock {
+                    if let Some(latency) = end_to_end(&socket, &mut buf, clock).await {
+                        stats.rtt().record_end_to_end(latency);
+                    }
+                }
+            }
+            None => {
+                lost_in_row += 1;
+                if lost_in_row == LOST_WARNING {
+                    warn!("No ping replies from {}", peer);
+                }
+                stats.rtt().record_lost();
+            }
+        }
+    }
+}
+
+/// Pings the peer `socket` is connected to once, returning the round trip,
+/// or None when no reply comes within a second.
+pub(crate) async fn round_trip(socket: &UdpSocket, buf: &mut [u8]) -> Option<Duration> {
+    let t1 = system_micros();
+    socket.send(&clock::time_request(t1)).await.ok()?;
+    let deadline = tokio::time::Instant::now() + PING_TIMEOUT;
+    // Replies to earlier pings that came too late are skipped.
+    loop {
+        let len = tokio::time::timeout_at(deadline, socket.recv(buf)).await.ok()?.ok()?;
+        let t4 = system_micros();
+        if let Some((echo, t2, t3)) = clock::parse_time_response(&buf[..len]) {
+            if echo == t1 {
+                let sample = clock::offset_from_timestamps(t1 as i64, t2 as i64, t3 as i64, t4 as i64);
+                return Some(Duration::from_micros(sample.delay_micros.max(0) as u64));
+            }
+        }
+    }
+}
+
+// The receiver answers with the sender timestamp of the audio at its
+// speaker. Taking that moment as the middle of the exchange, the audio is
+// as far behind the sender's clock as the timestamp is.
+async fn end_to_end(socket: &UdpSocket, buf: &mut [u8], clock: &SyncedClock) -> Option<Duration> {
+    let t1 = clock.now_micros();
+    socket.send(&playback::playout_request(t1)).await.ok()?;
+    let deadline = tokio::time::Instant::now() + PING_TIMEOUT;
+    loop {
+        let len = tokio::time::timeout_at(deadline, socket.recv(buf)).await.ok()?.ok()?;
+        let t4 = clock.now_micros();
+        if let Some((echo, playing)) = playback::parse_playout_response(&buf[..len]) {
+            if echo == t1 {
+                let middle = t1 + t4.saturating_sub(t1) / 2;
+                return Some(Duration::from_micros(middle.saturating_sub(playing?)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let tracker = RttTracker::default();
+        assert_eq!(tracker.summary(), None);
+        for ms in [10, 14, 12] {
+            tracker.record(Duration::from_millis(ms));
+        }
+        tracker.record_lost();
+        let summary = tracker.summary().unwrap();
+        assert_eq!(summary.min, Duration::from_millis(10));
+        assert_eq!(summary.avg, Duration::from_millis(12));
+        assert_eq!(summary.max, Duration::from_millis(14));
+        assert_eq!(summary.jitter, Duration::from_millis(3));
+        assert_eq!(summary.lost, 1);
+        assert_eq!(summary.end_to_end, None);
+        assert_eq!(summary.to_string(), "min/avg/max/jitter 10.0/12.0/14.0/3.0 ms, 1 lost");
+
+        for _ in 0..WINDOW {
+            tracker.record(Duration::from_millis(20));
+        }
+        tracker.record_end_to_end(Duration::from_millis(85));
+        let summary = tracker.summary().unwrap();
+        assert_eq!(summary.min, Duration::from_millis(20));
+        assert_eq!(summary.end_to_end, Some(Duration::from_millis(85)));
+    }
+}
@@ -0,0 +1,152 @@
+use chrono::{DateTime, Duration, Local, NaiveTime, TimeZone};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::watch;
+use tracing::info;
+
+/// A point in time for `--start-at`/`--stop-at`: either an absolute RFC3339
+/// timestamp or a local wall-clock time (`HH:MM[:SS]`) that recurs daily.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScheduleTime {
+    At(DateTime<Local>),
+    Daily(NaiveTime),
+}
+
+impl FromStr for ScheduleTime {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(at) = DateTime::parse_from_rfc3339(s) {
+            return Ok(ScheduleTime::At(at.with_timezone(&Local)));
+        }
+        NaiveTime::parse_from_str(s, "%H:%M:%S")
+            .or_else(|_| NaiveTime::parse_from_str(s, "%H:%M"))
+            .map(ScheduleTime::Daily)
+            .map_err(|_| format!("expected HH:MM[:SS] or an RFC3339 timestamp, got '{}'", s))
+    }
+}
+
+impl ScheduleTime {
+    pub fn is_daily(&self) -> bool {
+        matches!(self, ScheduleTime::Daily(_))
+    }
+
+    /// The first occurrence at or after `now`. Absolute times are returned
+    /// as-is, even when already in the past.
+    pub fn next_after(&self, now: DateTime<Local>) -> DateTime<Local> {
+        match *self {
+            ScheduleTime::At(at) => at,
+            ScheduleTime::Daily(time) => {
+                let mut date = now.date_naive();
+                loop {
+                    // Skip days where the wall-clock time does not exist (DST gaps).
+                    if let Some(candidate) = Local.from_local_datetime(&date.and_time(time)).earliest() {
+                        if candidate >= now {
+                            return candidate;
+                        }
+                    }
+                    date += Duration::days(1);
+                }
+            }
+        }
+    }
+}
+
+/// Whether `now` falls inside the window opened by `start` and closed by
+/// `stop`, i.e. the window closes before it would next open.
+pub fn in_window(start: &ScheduleTime, stop: &ScheduleTime, now: DateTime<Local>) -> bool {
+    let next_stop = stop.next_after(now);
+    if next_stop <= now {
+        return false;
+    }
+    let next_start = start.next_after(now);
+    next_start <= now || next_stop < next_start
+}
+
+async fn sleep_until(at: DateTime<Local>) {
+    let wait = (at - Local::now()).to_std().unwrap_or_default();
+    tokio::time::sleep(wait).await;
+}
+
+/// Opens and closes `active` according to the schedule, and sets `began` to
+/// when the first window opened. Returns once the last window has closed;
+/// windows bounded by two daily times repeat forever, and without a stop
+/// time this never returns.
+pub async fn run(
+    start: Option<ScheduleTime>,
+    stop: Option<ScheduleTime>,
+    active: Arc<AtomicBool>,
+    began: watch::Sender<Option<std::time::Instant>>,
+) {
+    loop {
+        let now = Local::now();
+        if let Some(start) = start {
+            let open = match stop {
+                Some(stop) => in_window(&start, &stop, now),
+                None => start.next_after(now) <= now,
+            };
+            if !open {
+                let at = start.next_after(now);
+                info!("Waiting to start streaming at {}", at.format("%Y-%m-%d %H:%M:%S"));
+                sleep_until(at).await;
+            }
+        }
+
+        active.store(true, Ordering::Relaxed);
+        if began.borrow().is_none() {
+            began.send_replace(Some(std::time::Instant::now()));
+        }
+        let stop = match stop {
+            Some(stop) => stop,
+            None => return std::future::pending().await,
+        };
+        let at = stop.next_after(Local::now());
+        info!("Streaming until {}", at.format("%Y-%m-%d %H:%M:%S"));
+        sleep_until(at).await;
+        active.store(false, Ordering::Relaxed);
+
+        if !(stop.is_daily() && start.is_some_and(|s| s.is_daily())) {
+            return;
+        }
+        info!("Streaming window closed.");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local(h: u32, m: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 6, 12, h, m, 0).unwrap()
+    }
+
+    #[test]
+    fn test_parse_schedule_time() {
+        assert_eq!(
+            "20:30".parse::<ScheduleTime>().unwrap(),
+            ScheduleTime::Daily(NaiveTime::from_hms_opt(20, 30, 0).unwrap())
+        );
+        assert!(matches!(
+            "2024-06-12T20:30:00+00:00".parse::<ScheduleTime>().unwrap(),
+            ScheduleTime::At(_)
+        ));
+        assert!("tonight".parse::<ScheduleTime>().is_err());
+    }
+
+    #[test]
+    fn test_daily_next_after_rolls_over() {
+        let time: ScheduleTime = "08:00".parse().unwrap();
+        assert_eq!(time.next_after(local(7, 0)), local(8, 0));
+        assert_eq!(time.next_after(local(9, 0)), local(8, 0) + Duration::days(1));
+    }
+
+    #[test]
+    fn test_in_window() {
+        let start: ScheduleTime = "20:00".parse().unwrap();
+        let stop: ScheduleTime = "22:00".parse().unwrap();
+        assert!(in_window(&start, &stop, local(21, 0)));
+        assert!(!in_window(&start, &stop, local(19, 0)));
+        assert!(!in_window(&start, &stop, local(23, 0)));
+    }
+}
@@ -0,0 +1,361 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::task::JoinHandle;
+
+use crate::clock::SyncedClock;
+use crate::codec::{Codec, FrameDecoder, FrameEncoder, OpusOptions};
+use crate::jitter::JitterBuffer;
+use crate::playback::{self, Concealment, PlaybackCursor};
+use crate::sink::{Sink, SinkKind, SinkOptions, Transport};
+use crate::stats::{StreamStats, Summary};
+use crate::streamer::{deliver, encode_samples, CHANNELS, FRAMES_PER_BUFFER, SAMPLE_RATE};
+use crate::transport;
+
+/// The pitch of the self-test tone, in Hz.
+pub const TONE_FREQUENCY: f32 = 440.0;
+/// How much of the tone each path sends.
+pub const TONE_DURATION: Duration = Duration::from_millis(500);
+const TONE_LEVEL: f32 = 0.25;
+// How far the decoded tone may stray from what was sent and still pass.
+// Both codecs keep a steady sine well inside these.
+const FREQUENCY_TOLERANCE: f64 = 0.01;
+const LEVEL_TOLERANCE_DB: f64 = 1.5;
+// The share left out of the measurement at each end of the decoded audio,
+// where Opus is still settling and the tone starts and stops abruptly.
+const MARGIN: f64 = 0.1;
+// Time for the last packets to arrive after the tone ends.
+const SETTLE: Duration = Duration::from_millis(200);
+
+/// A sine tone at the same level in every channel.
+pub struct Tone {
+    step: f32,
+    level: f32,
+    phase: f32,
+}
+
+impl Tone {
+    /// `level` is the peak, from 0.0 to 1.0.
+    pub fn new(frequency: f32, level: f32) -> Self {
+        Self {
+            step: std::f32::consts::TAU * frequency / SAMPLE_RATE as f32,
+            level,
+            phase: 0.0,
+        }
+    }
+
+    /// Appends the next `frames` frames of interleaved samples.
+    pub fn fill(&mut self, samples: &mut Vec<f32>, frames: usize) {
+        for _ in 0..frames {
+            let sample = self.level * self.phase.sin();
+            samples.extend(std::iter::repeat_n(sample, CHANNELS as usize));
+            self.phase = (self.phase + self.step) % std::f32::consts::TAU;
+        }
+    }
+}
+
+/// A codec and transport for the self-test to send the tone over.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Path {
+    pub codec: Codec,
+    pub transport: Transport,
+}
+
+impl Path {
+    /// Every codec over every transport the self-test covers. SRT is left
+    /// out, as its receiver needs setting up to match the sender.
+    pub fn all() -> Vec<Path> {
+        [Codec::Pcm, Codec::Opus]
+            .into_iter()
+            .flat_map(|codec| {
+                [Transport::Udp, Transport::Tcp, Transport::Ws]
+                    .into_iter()
+                    .map(move |transport| Path { codec, transport })
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let codec = match self.codec {
+            Codec::Pcm => "pcm",
+            Codec::Opus => "opus",
+        };
+        let transport = match self.transport {
+            Transport::Udp => "udp",
+            Transport::Srt => "srt",
+            Transport::Tcp => "tcp",
+            Transport::Ws => "ws",
+        };
+        write!(f, "{} over {}", codec, transport)
+    }
+}
+
+/// The pitch and level of a decoded tone.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Measurement {
+    /// In Hz.
+    pub frequency: f64,
+    /// The RMS level, in dB below full scale.
+    pub level_db: f64,
+}
+
+/// Measures the tone in the first channel of interleaved samples, leaving
+/// out the ends. None when there is not a whole cycle of it.
+pub fn measure(samples: &[i16], channels: usize) -> Option<Measurement> {
+    let first: Vec<f64> = samples
+        .iter()
+        .step_by(channels)
+        .map(|&s| s as f64 / i16::MAX as f64)
+        .collect();
+    let skip = (first.len() as f64 * MARGIN) as usize;
+    let body = &first[skip..first.len() - skip];
+    // Upward zero crossings, placed between samples by interpolation.
+    let crossings: Vec<f64> = body
+        .windows(2)
+        .enumerate()
+        .filter(|(_, pair)| pair[0] < 0.0 && pair[1] >= 0.0)
+        .map(|(i, pair)| i as f64 + pair[0] / (pair[0] - pair[1]))
+        .collect();
+    if crossings.len() < 2 {
+        return None;
+    }
+    let span = crossings[crossings.len() - 1] - crossings[0];
+    let rms = (body.iter().map(|s| s * s).sum::<f64>() / body.len() as f64).sqrt();
+    Some(Measurement {
+        frequency: (crossings.len() - 1) as f64 * SAMPLE_RATE as f64 / span,
+        level_db: 20.0 * rms.log10(),
+    })
+}
+
+/// How the tone came back over one path.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Outcome {
+    pub path: Path,
+    /// Packets the sink took.
+    pub sent: u64,
+    /// Packets the sink dropped.
+    pub dropped: u64,
+    /// Packets the receiver got.
+    pub received: usize,
+    pub measured: Option<Measurement>,
+    /// What was wrong; empty when the path passed.
+    pub problems: Vec<String>,
+}
+
+impl Outcome {
+    fn judge(path: Path, sent: Summary, received: usize, measured: Option<Measurement>) -> Self {
+        let mut problems = Vec::new();
+        if sent.drops > 0 {
+            problems.push(format!("the sink dropped {} packets", sent.drops));
+        }
+        if (received as u64) < sent.packets_sent {
+            problems.push(format!("{} packets were lost", sent.packets_sent - received as u64));
+        }
+        let expected_db = 20.0 * (TONE_LEVEL as f64 / std::f64::consts::SQRT_2).log10();
+        match measured {
+            None => problems.push("no tone came back".to_string()),
+            Some(measured) => {
+                let expected = TONE_FREQUENCY as f64;
+                if (measured.frequency - expected).abs() > expected * FREQUENCY_TOLERANCE {
+                    problems.push(format!(
+                        "the tone came back at {:.1} Hz instead of {:.1} Hz",
+                        measured.frequency, expected
+                    ));
+                }
+                if (measured.level_db - expected_db).abs() > LEVEL_TOLERANCE_DB {
+                    problems.push(format!(
+                        "the tone came back at {:.1} dBFS instead of {:.1} dBFS",
+                        measured.level_db, expected_db
+                    ));
+                }
+            }
+        }
+        Self {
+            path,
+            sent: sent.packets_sent,
+            dropped: sent.drops,
+            received,
+            measured,
+            problems,
+        }
+    }
+
+    pub fn passed(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verdict = if self.passed() { "PASS" } else { "FAIL" };
+        write!(
+            f,
+            "{} {}: {} of {} packets",
+            verdict, self.path, self.received, self.sent
+        )?;
+        if let Some(measured) = self.measured {
+            write!(f, ", {:.1} Hz at {:.1} dBFS", measured.frequency, measured.level_db)?;
+        }
+        if !self.passed() {
+            write!(f, " ({})", self.problems.join("; "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Sends `duration` of the test tone over `path` to a receiver on this
+/// machine, through the packetization and transports a stream uses, and
+/// measures what the receiver decodes. Errors are for a receiver or sink
+/// that could not be set up at all; anything that gets through is judged
+/// in the outcome.
+pub async fn run(path: Path, duration: Duration) -> Result<Outcome, Box<dyn Error>> {
+    let frame_frames = path.codec.frame_frames().unwrap_or(FRAMES_PER_BUFFER as usize);
+    let frame_duration = Duration::from_secs_f64(frame_frames as f64 / SAMPLE_RATE as f64);
+    // Playback starts once the whole tone is in, so the target only has to
+    // keep the buffer from trimming it: over half the packets, and fewer
+    // than all of them.
+    let buffer = Arc::new(Mutex::new(JitterBuffer::new(frame_duration, duration.mul_f64(0.75))));
+    let (receiver, addr) = listen(path.transport, buffer.clone(), Arc::new(SyncedClock::new())).await?;
+    let sent = send(path, &addr, duration).await;
+    receiver.abort();
+    let sent = sent?;
+
+    let received = buffer.lock().unwrap().depth();
+    let decoder = FrameDecoder::new(path.codec, SAMPLE_RATE, CHANNELS, OpusOptions::default())?;
+    let mut cursor = PlaybackCursor::new(
+        frame_frames * CHANNELS as usize,
+        CHANNELS as usize,
+        Concealment::Silence,
+        decoder,
+    );
+    let mut decoded = vec![0; received * frame_frames * CHANNELS as usize];
+    cursor.fill_i16(&buffer, &mut decoded);
+    let measured = measure(&decoded, CHANNELS as usize);
+    Ok(Outcome::judge(path, sent, received, measured))
+}
+
+// Starts a receiver for `transport` on a free localhost port, returning its
+// task and address.
+async fn listen(
+    transport: Transport,
+    buffer: Arc<Mutex<JitterBuffer>>,
+    clock: Arc<SyncedClock>,
+) -> io::Result<(JoinHandle<io::Result<()>>, String)> {
+    match transport {
+        Transport::Udp => {
+            let socket = UdpSocket::bind("127.0.0.1:0").await?;
+            let addr = socket.local_addr()?.to_string();
+            Ok((tokio::spawn(playback::receive(socket, buffer, clock)), addr))
+        }
+        Transport::Tcp | Transport::Ws => {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let addr = listener.local_addr()?.to_string();
+            let receiver = match transport {
+                Transport::Tcp => tokio::spawn(transport::receive_tcp(listener, buffer, clock)),
+                _ => tokio::spawn(transport::receive_ws(listener, buffer, clock)),
+            };
+            Ok((receiver, addr))
+        }
+        Transport::Srt => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the self-test does not cover SRT",
+        )),
+    }
+}
+
+// Sends the tone to `addr`, paced like a capture, and waits for the last of
+// it to arrive before closing the sink.
+async fn send(path: Path, addr: &str, duration: Duration) -> Result<Summary, Box<dyn Error>> {
+    let options = SinkOptions {
+        transport: path.transport,
+        ..SinkOptions::default()
+    };
+    let sink = Sink::connect(SinkKind::Udp, addr, options).await?;
+    let mut encoder = FrameEncoder::new(path.codec, SAMPLE_RATE, CHANNELS, OpusOptions::default())?;
+    let stats = StreamStats::default();
+    let mut tone = Tone::new(TONE_FREQUENCY, TONE_LEVEL);
+    let mut samples = Vec::with_capacity(FRAMES_PER_BUFFER as usize * CHANNELS as usize);
+    let mut buffer = Vec::new();
+    let block = Duration::from_secs_f64(FRAMES_PER_BUFFER as f64 / SAMPLE_RATE as f64);
+    let mut ticks = tokio::time::interval(block);
+    for _ in 0..(duration.as_secs_f64() / block.as_secs_f64()).ceil() as usize {
+        ticks.tick().await;
+        samples.clear();
+        tone.fill(&mut samples, FRAMES_PER_BUFFER as usize);
+        encode_samples(&samples, 1.0, &mut buffer);
+        encoder.encode(&buffer, |packet| {
+            deliver(&sink, packet, &stats);
+        });
+    }
+    tokio::time::sleep(SETTLE).await;
+    Ok(stats.summary(duration))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(seconds: f64) -> Vec<i16> {
+        let mut tone = Tone::new(TONE_FREQUENCY, TONE_LEVEL);
+        let mut samples = Vec::new();
+        tone.fill(&mut samples, (seconds * SAMPLE_RATE as f64) as usize);
+        samples.iter().map(|&s| (s * i16::MAX as f32) as i16).collect()
+    }
+
+    #[test]
+    fn test_measure() {
+        let measured = measure(&tone(0.5), CHANNELS as usize).unwrap();
+        assert!((measured.frequency - 440.0).abs() < 0.5, "{:?}", measured);
+        assert!((measured.level_db + 15.05).abs() < 0.1, "{:?}", measured);
+        assert_eq!(measure(&[0; 4800], CHANNELS as usize), None);
+        assert_eq!(measure(&[], CHANNELS as usize), None);
+    }
+
+    #[test]
+    fn test_judge() {
+        let path = Path {
+            codec: Codec::Opus,
+            transport: Transport::Ws,
+        };
+        let sent = Summary {
+            packets_sent: 50,
+            ..Summary::default()
+        };
+        let good = Measurement {
+            frequency: 440.5,
+            level_db: -15.5,
+        };
+        let outcome = Outcome::judge(path, sent, 50, Some(good));
+        assert!(outcome.passed());
+        assert_eq!(
+            outcome.to_string(),
+            "PASS opus over ws: 50 of 50 packets, 440.5 Hz at -15.5 dBFS"
+        );
+
+        let outcome = Outcome::judge(path, sent, 48, None);
+        assert_eq!(
+            outcome.to_string(),
+            "FAIL opus over ws: 48 of 50 packets (2 packets were lost; no tone came back)"
+        );
+    }
+
+    #[test]
+    fn test_self_test_over_udp() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let path = Path {
+            codec: Codec::Pcm,
+            transport: Transport::Udp,
+        };
+        let outcome = runtime.block_on(run(path, Duration::from_millis(300))).unwrap();
+        assert!(outcome.passed(), "{}", outcome);
+    }
+}
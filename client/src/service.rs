@@ -0,0 +1,158 @@
+use std::ffi::OsString;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use tokio::sync::{watch, Notify};
+use windows_service::service::{
+    ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode, ServiceInfo,
+    ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+use windows_service::{define_windows_service, service_dispatcher};
+
+pub const SERVICE_NAME: &str = "AudioStreamer";
+const DISPLAY_NAME: &str = "Audio Streamer";
+const DESCRIPTION: &str = "Captures system audio and streams it to an audio server";
+// How long the Service Control Manager should wait for a start or stop.
+const WAIT_HINT: Duration = Duration::from_secs(10);
+// How long uninstall waits for a running service to stop before removing it.
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Body = Box<dyn FnOnce() -> Result<(), String> + Send>;
+
+static BODY: Mutex<Option<Body>> = Mutex::new(None);
+static STATUS: OnceLock<ServiceStatusHandle> = OnceLock::new();
+static STOP: OnceLock<Notify> = OnceLock::new();
+static PAUSED: OnceLock<watch::Sender<bool>> = OnceLock::new();
+
+/// Registers the service to start at boot, before anyone logs in, running
+/// `program` with `arguments` as LocalSystem.
+pub fn install(program: OsString, arguments: Vec<OsString>) -> windows_service::Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CREATE_SERVICE)?;
+    let info = ServiceInfo {
+        name: SERVICE_NAME.into(),
+        display_name: DISPLAY_NAME.into(),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable: program.into(),
+        launch_arguments: arguments,
+        dependencies: vec![],
+        account_name: None,
+        account_password: None,
+    };
+    let service = manager.create_service(&info, ServiceAccess::CHANGE_CONFIG)?;
+    service.set_description(DESCRIPTION)
+}
+
+/// Stops the service if it is running and removes it.
+pub fn uninstall() -> windows_service::Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let access = ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE;
+    let service = manager.open_service(SERVICE_NAME, access)?;
+    if service.query_status()?.current_state != ServiceState::Stopped {
+        service.stop()?;
+        let started = std::time::Instant::now();
+        while service.query_status()?.current_state != ServiceState::Stopped && started.elapsed() < STOP_TIMEOUT {
+            std::thread::sleep(Duration::from_millis(200));
+        }
+    }
+    service.delete()
+}
+
+/// Hands the process to the Service Control Manager, which calls back to
+/// run `body` as the service, and returns once it has stopped. While it
+/// runs, [`stop_requested`] and [`watch_paused`] carry the manager's
+/// requests to it.
+pub fn run(body: impl FnOnce() -> Result<(), String> + Send + 'static) -> windows_service::Result<()> {
+    *BODY.lock().unwrap() = Some(Box::new(body));
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+}
+
+/// Reports the service as running, once the stream has started.
+pub fn set_running() {
+    report(ServiceState::Running, ServiceExitCode::Win32(0));
+}
+
+/// Resolves when the Service Control Manager asks the service to stop, or
+/// the system shuts down. Never resolves outside a service.
+pub async fn stop_requested() {
+    stop().notified().await
+}
+
+/// Whether the Service Control Manager has paused the service.
+pub fn watch_paused() -> watch::Receiver<bool> {
+    paused().subscribe()
+}
+
+/// Whether the process is running as the service.
+pub fn is_service() -> bool {
+    STATUS.get().is_some()
+}
+
+fn stop() -> &'static Notify {
+    STOP.get_or_init(Notify::new)
+}
+
+fn paused() -> &'static watch::Sender<bool> {
+    PAUSED.get_or_init(|| watch::channel(false).0)
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+// The body's errors have nowhere to go but the exit code, since a service
+// has no console.
+fn service_main(_arguments: Vec<OsString>) {
+    let Ok(status) = service_control_handler::register(SERVICE_NAME, handle_control) else {
+        return;
+    };
+    let _ = STATUS.set(status);
+    report(ServiceState::StartPending, ServiceExitCode::Win32(0));
+    let body = BODY.lock().unwrap().take();
+    let exit_code = match body.map_or(Ok(()), |body| body()) {
+        Ok(()) => ServiceExitCode::Win32(0),
+        Err(_) => ServiceExitCode::ServiceSpecific(1),
+    };
+    report(ServiceState::Stopped, exit_code);
+}
+
+fn handle_control(control: ServiceControl) -> ServiceControlHandlerResult {
+    match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            report(ServiceState::StopPending, ServiceExitCode::Win32(0));
+            stop().notify_one();
+        }
+        ServiceControl::Pause => {
+            paused().send_replace(true);
+            report(ServiceState::Paused, ServiceExitCode::Win32(0));
+        }
+        ServiceControl::Continue => {
+            paused().send_replace(false);
+            report(ServiceState::Running, ServiceExitCode::Win32(0));
+        }
+        ServiceControl::Interrogate => {}
+        _ => return ServiceControlHandlerResult::NotImplemented,
+    }
+    ServiceControlHandlerResult::NoError
+}
+
+fn report(state: ServiceState, exit_code: ServiceExitCode) {
+    let Some(status) = STATUS.get() else {
+        return;
+    };
+    let controls_accepted = match state {
+        ServiceState::StartPending | ServiceState::StopPending | ServiceState::Stopped => ServiceControlAccept::empty(),
+        _ => ServiceControlAccept::STOP | ServiceControlAccept::PAUSE_CONTINUE | ServiceControlAccept::SHUTDOWN,
+    };
+    let pending = matches!(state, ServiceState::StartPending | ServiceState::StopPending);
+    let _ = status.set_service_status(ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted,
+        exit_code,
+        checkpoint: 0,
+        wait_hint: if pending { WAIT_HINT } else { Duration::ZERO },
+        process_id: None,
+    });
+}
@@ -0,0 +1,823 @@
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::airplay;
+use crate::cast;
+use crate::clock::SyncedClock;
+use crate::dlna;
+use crate::hls::HlsWriter;
+use crate::icecast::{IcecastTarget, IcecastWriter};
+use crate::impair::{ImpairedTransport, Impairment};
+use crate::packet::{self, Packet};
+use crate::proxy::Proxy;
+use crate::qos::SocketOptions;
+use crate::reconnect::{Backoff, ReconnectingTransport};
+use crate::rendezvous::Session;
+use crate::roc::RocWriter;
+use crate::rtsp::{self, RtspServer};
+use crate::srt::{self, SrtLink, SrtOptions};
+use crate::transport::{AudioTransport, FanOutTransport, TcpTransport, UdpTransport, WsTransport};
+use crate::vban::VbanWriter;
+
+// Snapcast's default pipe source location.
+const SNAPCAST_DEFAULT_FIFO: &str = "/tmp/snapfifo";
+// Frames a stream sink holds for its writer thread, about 0.3s at 512
+// frames each. More than that and the reader is falling behind.
+const STREAM_QUEUE_FRAMES: usize = 32;
+const HLS_DEFAULT_PORT: u16 = 8090;
+const RTSP_DEFAULT_PORT: u16 = 8554;
+const NDI_DEFAULT_NAME: &str = "audio-client";
+// Raw PCM sinks carry the client's fixed s16le 48kHz stereo format.
+const PCM_SAMPLE_RATE: u32 = 48_000;
+const PCM_CHANNELS: u16 = 2;
+// Copies of the end-of-stream marker sent, in case one is lost.
+const END_MARKERS: usize = 3;
+
+/// Where the client delivers processed audio, written as `udp`, `stdout`,
+/// `snapcast`, `snapcast:<fifo path>`, `snapcast:tcp://<host>:<port>`,
+/// `airplay`, `airplay:<speaker name | host:port>`, `cast`,
+/// `cast:<device name>`, `dlna`, `dlna:<renderer name>`,
+/// `icecast://[user[:password]@]host[:port]/mount`, `hls`, `hls:<port>`,
+/// `rtsp`, `rtsp:<port>`, `ndi` or `ndi:<source name>`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SinkKind {
+    /// Stream to the server over UDP
+    Udp,
+    /// Write raw s16le PCM to standard output
+    Stdout,
+    /// Feed raw s16le PCM to a Snapcast server's pipe or TCP stream source
+    Snapcast(SnapcastTarget),
+    /// Stream to an AirPlay speaker, found by name (or the first one) via mDNS
+    AirPlay(Option<String>),
+    /// Serve a WAV stream and play it on a Google Cast device, found by name
+    /// (or the first one) via mDNS
+    Cast(Option<String>),
+    /// Serve a WAV stream and play it on a DLNA/UPnP media renderer, found by
+    /// name (or the first one) via SSDP
+    Dlna(Option<String>),
+    /// Publish Ogg/Opus to an Icecast mount
+    Icecast(IcecastTarget),
+    /// Serve an HLS live stream and player page on this port for browsers
+    Hls(u16),
+    /// Serve RTP L16 to RTSP players on this port
+    Rtsp(u16),
+    /// Publish an NDI audio source under this name (needs the `ndi` feature)
+    Ndi(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SnapcastTarget {
+    Pipe(PathBuf),
+    Tcp(String),
+}
+
+impl SinkKind {
+    /// Sinks that take raw PCM and hand it to another program or encode it
+    /// for their own protocol, so they can't use our Opus frames.
+    pub fn is_raw_pcm(&self) -> bool {
+        !matches!(self, SinkKind::Udp)
+    }
+}
+
+impl FromStr for SinkKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, target) = match s.split_once(':') {
+            Some((kind, target)) => (kind, Some(target)),
+            None => (s, None),
+        };
+        match (kind.to_lowercase().as_str(), target) {
+            ("udp", None) => Ok(SinkKind::Udp),
+            ("stdout", None) => Ok(SinkKind::Stdout),
+            ("snapcast", None) => Ok(SinkKind::Snapcast(SnapcastTarget::Pipe(SNAPCAST_DEFAULT_FIFO.into()))),
+            ("snapcast", Some(target)) => match target.strip_prefix("tcp://") {
+                Some(addr) if !addr.is_empty() => Ok(SinkKind::Snapcast(SnapcastTarget::Tcp(addr.to_string()))),
+                Some(_) => Err("snapcast TCP target needs <host>:<port>".to_string()),
+                None if !target.is_empty() => Ok(SinkKind::Snapcast(SnapcastTarget::Pipe(target.into()))),
+                None => Err("snapcast pipe target needs a path".to_string()),
+            },
+            ("airplay", None) => Ok(SinkKind::AirPlay(None)),
+            ("airplay", Some(target)) if !target.is_empty() => Ok(SinkKind::AirPlay(Some(target.to_string()))),
+            ("cast", None) => Ok(SinkKind::Cast(None)),
+            ("cast", Some(name)) if !name.is_empty() => Ok(SinkKind::Cast(Some(name.to_string()))),
+            ("dlna", None) => Ok(SinkKind::Dlna(None)),
+            ("dlna", Some(name)) if !name.is_empty() => Ok(SinkKind::Dlna(Some(name.to_string()))),
+            ("icecast", Some(_)) => Ok(SinkKind::Icecast(s.parse()?)),
+            ("hls", None) => Ok(SinkKind::Hls(HLS_DEFAULT_PORT)),
+            ("hls", Some(port)) => port.parse().map(SinkKind::Hls).map_err(|_| format!("invalid HLS port '{}'", port)),
+            ("rtsp", None) => Ok(SinkKind::Rtsp(RTSP_DEFAULT_PORT)),
+            ("rtsp", Some(port)) => port.parse().map(SinkKind::Rtsp).map_err(|_| format!("invalid RTSP port '{}'", port)),
+            ("ndi", None) => Ok(SinkKind::Ndi(NDI_DEFAULT_NAME.to_string())),
+            ("ndi", Some(name)) if !name.is_empty() => Ok(SinkKind::Ndi(name.to_string())),
+            _ => Err(format!(
+                "unknown sink '{}' (expected udp, stdout, snapcast[:target], airplay[:speaker], cast[:device], \
+                 dlna[:renderer], icecast://..., hls[:port], rtsp[:port] or ndi[:name])",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SinkKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SinkKind::Udp => write!(f, "udp"),
+            SinkKind::Stdout => write!(f, "stdout"),
+            SinkKind::Snapcast(SnapcastTarget::Pipe(path)) => write!(f, "snapcast:{}", path.display()),
+            SinkKind::Snapcast(SnapcastTarget::Tcp(addr)) => write!(f, "snapcast:tcp://{}", addr),
+            SinkKind::AirPlay(None) => write!(f, "airplay"),
+            SinkKind::AirPlay(Some(target)) => write!(f, "airplay:{}", target),
+            SinkKind::Cast(None) => write!(f, "cast"),
+            SinkKind::Cast(Some(name)) => write!(f, "cast:{}", name),
+            SinkKind::Dlna(None) => write!(f, "dlna"),
+            SinkKind::Dlna(Some(name)) => write!(f, "dlna:{}", name),
+            SinkKind::Icecast(target) => write!(f, "{}", target),
+            SinkKind::Hls(port) => write!(f, "hls:{}", port),
+            SinkKind::Rtsp(port) => write!(f, "rtsp:{}", port),
+            SinkKind::Ndi(name) => write!(f, "ndi:{}", name),
+        }
+    }
+}
+
+/// How `--sink udp` packets reach the server.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Transport {
+    /// Plain datagrams; losses are concealed or repaired by redundancy
+    #[default]
+    Udp,
+    /// SRT, which retransmits losses within its latency window and can encrypt
+    Srt,
+    /// Length-prefixed packets over a TCP connection, which never loses any but delays them instead
+    Tcp,
+    /// Binary WebSocket messages, for networks that only let HTTP through
+    Ws,
+}
+
+/// The packet format `--sink udp` sends.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Protocol {
+    /// This client's sequenced packets, for the server or a `--listen` receiver
+    #[default]
+    Native,
+    /// VBAN audio packets, for Voicemeeter and VBAN Receptor
+    Vban,
+    /// Bare RTP L16 at 44.1kHz, for roc-recv's `rtp://` endpoints
+    Roc,
+}
+
+#[derive(Clone, Default)]
+pub struct SinkOptions {
+    /// Piggy-back the previous frame in each UDP packet.
+    pub redundancy: bool,
+    /// Stamp UDP packets with the capture time on this clock.
+    pub clock: Option<Arc<SyncedClock>>,
+    /// What carries the packets. SRT needs `srt` set as well.
+    pub transport: Transport,
+    pub srt: Option<SrtOptions>,
+    /// Send VBAN packets under this stream name instead of our own format.
+    pub vban_stream: Option<String>,
+    /// Send RTP for a Roc receiver instead of our own format.
+    pub roc: bool,
+    /// Lose, delay or reorder UDP packets on purpose, for testing.
+    pub impairment: Impairment,
+    /// Packets the sender task gathers before handing them to the
+    /// transport together, in one system call on Linux. Each is still
+    /// stamped when it is packetized. 0 or 1 sends each packet at once.
+    pub batch_packets: usize,
+    /// How the UDP, TCP and WebSocket transports set up their sockets.
+    pub socket: SocketOptions,
+    /// Reach the receiver through this proxy. Applies to the TCP and
+    /// WebSocket transports.
+    pub proxy: Option<Proxy>,
+    /// Find the receiver through a relay instead of at the server address.
+    /// Applies to the UDP transport.
+    pub rendezvous: Option<Session>,
+    /// Also send every packet to these receivers, each over a transport of
+    /// its own. Applies to the UDP, TCP and WebSocket transports.
+    pub fan_out: Vec<String>,
+    /// Space the packets out by the audio they carry rather than sending
+    /// them as fast as they come, for Wi-Fi that loses bursts.
+    pub pace: bool,
+    /// Keep trying, with backoff, to reach a receiver that is down or has
+    /// dropped the connection, dropping packets meanwhile, instead of
+    /// failing. Applies to every transport.
+    pub reconnect: bool,
+}
+
+/// Sequenced packet delivery over any [`AudioTransport`]. When redundancy
+/// is on, each packet also carries the previous frame so a single loss can
+/// be repaired by the next packet.
+pub struct UdpSink {
+    transport: Arc<dyn AudioTransport>,
+    seq: AtomicU32,
+    redundancy: AtomicBool,
+    previous: Mutex<Vec<u8>>,
+    // Reused for each datagram the audio callback sends, so it doesn't
+    // allocate one per packet.
+    datagram: Mutex<Vec<u8>>,
+    clock: Option<Arc<SyncedClock>>,
+    batch_packets: usize,
+    batch: Mutex<Batch>,
+    pace: bool,
+}
+
+// Datagrams waiting for the batch to fill, in buffers kept from one batch
+// to the next so the sender task doesn't allocate one per packet.
+#[derive(Default)]
+struct Batch {
+    datagrams: Vec<Vec<u8>>,
+    len: usize,
+    // When its first datagram was packetized.
+    started: Option<Instant>,
+}
+
+impl UdpSink {
+    pub fn set_redundancy(&self, enabled: bool) {
+        self.redundancy.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn redundancy(&self) -> bool {
+        self.redundancy.load(Ordering::Relaxed)
+    }
+
+    fn next_datagram(&self, frame: &[u8], datagram: &mut Vec<u8>) {
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        datagram.reserve(packet::HEADER_LEN + 11 + frame.len() * 2);
+        let mut previous = self.previous.lock().unwrap();
+        let packet = Packet {
+            seq,
+            timestamp: self.clock.as_ref().map(|clock| clock.now_micros()),
+            payload: frame,
+            redundant: (self.redundancy() && !previous.is_empty()).then_some(previous.as_slice()),
+            paused: false,
+            ended: false,
+        };
+        packet::encode(&packet, datagram);
+        previous.clear();
+        previous.extend_from_slice(frame);
+    }
+
+    // Takes no sequence number, so the stream resumes where it stopped.
+    fn pause_datagram(&self) -> Vec<u8> {
+        let packet = Packet {
+            seq: self.seq.load(Ordering::Relaxed),
+            paused: true,
+            ..Packet::default()
+        };
+        let mut datagram = Vec::new();
+        packet::encode(&packet, &mut datagram);
+        datagram
+    }
+
+    fn end_datagram(&self) -> Vec<u8> {
+        let packet = Packet {
+            seq: self.seq.load(Ordering::Relaxed),
+            ended: true,
+            ..Packet::default()
+        };
+        let mut datagram = Vec::new();
+        packet::encode(&packet, &mut datagram);
+        datagram
+    }
+}
+
+#[derive(Clone)]
+pub enum Sink {
+    Udp(Arc<UdpSink>),
+    Stdout,
+    /// A byte stream another program reads raw PCM from.
+    Stream(Arc<Mutex<Box<dyn Write + Send>>>),
+}
+
+impl Sink {
+    #[tracing::instrument(name = "send", skip_all, fields(sink = %kind, server = server_addr))]
+    pub async fn connect(kind: SinkKind, server_addr: &str, options: SinkOptions) -> io::Result<Self> {
+        match kind {
+            SinkKind::Udp if options.vban_stream.is_some() => {
+                let stream_name = options.vban_stream.unwrap_or_default();
+                let writer = VbanWriter::connect(server_addr, &stream_name, PCM_SAMPLE_RATE, PCM_CHANNELS)?;
+                info!("Sending VBAN stream '{}' to {}", stream_name, server_addr);
+                Ok(Sink::Stream(Arc::new(Mutex::new(Box::new(writer)))))
+            }
+            SinkKind::Udp if options.roc => {
+                let writer = RocWriter::connect(server_addr, PCM_SAMPLE_RATE, PCM_CHANNELS)?;
+                info!("Sending Roc RTP stream to {}", server_addr);
+                Ok(Sink::Stream(Arc::new(Mutex::new(Box::new(writer)))))
+            }
+            SinkKind::Udp => {
+                let mut transport = open_transport(server_addr, &options).await?;
+                if !options.fan_out.is_empty() {
+                    let mut destinations = vec![(server_addr.to_string(), transport)];
+                    for addr in &options.fan_out {
+                        destinations.push((addr.clone(), open_transport(addr, &options).await?));
+                    }
+                    info!("Sending every packet to {} receivers", destinations.len());
+                    transport = Arc::new(FanOutTransport::new(destinations));
+                }
+                if options.impairment.is_active() {
+                    let transport = ImpairedTransport::new(transport, options.impairment);
+                    return Ok(Sink::over(Arc::new(transport), &options));
+                }
+                Ok(Sink::over(transport, &options))
+            }
+            SinkKind::Stdout => Ok(Sink::Stdout),
+            SinkKind::Snapcast(target) => {
+                let writer: Box<dyn Write + Send> = match target {
+                    // Blocks until snapserver has the fifo open for reading.
+                    SnapcastTarget::Pipe(path) => Box::new(OpenOptions::new().write(true).open(path)?),
+                    SnapcastTarget::Tcp(addr) => {
+                        let stream = TcpStream::connect(addr)?;
+                        stream.set_nodelay(true)?;
+                        Box::new(stream)
+                    }
+                };
+                let writer: Box<dyn Write + Send> = Box::new(QueuedWriter::spawn(writer));
+                Ok(Sink::Stream(Arc::new(Mutex::new(writer))))
+            }
+            SinkKind::AirPlay(target) => {
+                let speaker = match target.as_deref() {
+                    Some(target) if is_host_port(target) => airplay::resolve(target)?,
+                    name => airplay::discover(name)?,
+                };
+                if !speaker.unencrypted {
+                    warn!("{} may require encrypted AirPlay, which is not supported", speaker.name);
+                }
+                info!("Streaming to AirPlay speaker {} ({})", speaker.name, speaker.addr);
+                let writer = airplay::RaopWriter::connect(speaker.addr, PCM_SAMPLE_RATE)?;
+                Ok(Sink::Stream(Arc::new(Mutex::new(Box::new(writer)))))
+            }
+            SinkKind::Cast(name) => {
+                let device = cast::discover(name.as_deref())?;
+                info!("Casting to {} ({})", device.name, device.addr);
+                let stream = cast::start(&device, PCM_SAMPLE_RATE, PCM_CHANNELS)?;
+                Ok(Sink::Stream(Arc::new(Mutex::new(Box::new(stream)))))
+            }
+            SinkKind::Dlna(name) => {
+                let renderer = dlna::discover(name.as_deref())?;
+                info!("Streaming to DLNA renderer {}", renderer.name);
+                let stream = dlna::start(&renderer, PCM_SAMPLE_RATE, PCM_CHANNELS)?;
+                Ok(Sink::Stream(Arc::new(Mutex::new(Box::new(stream)))))
+            }
+            SinkKind::Icecast(target) => {
+                let writer = IcecastWriter::connect(&target, PCM_CHANNELS)?;
+                info!("Publishing to {}", target);
+                Ok(Sink::Stream(Arc::new(Mutex::new(Box::new(writer)))))
+            }
+            SinkKind::Hls(port) => {
+                let writer = HlsWriter::serve(TcpListener::bind(("0.0.0.0", port))?, PCM_CHANNELS)?;
+                info!("Serving HLS at http://<this host>:{}/ (playlist: /stream.m3u8)", writer.local_addr().port());
+                Ok(Sink::Stream(Arc::new(Mutex::new(Box::new(writer)))))
+            }
+            SinkKind::Rtsp(port) => {
+                let server = RtspServer::serve(TcpListener::bind(("0.0.0.0", port))?, PCM_SAMPLE_RATE, PCM_CHANNELS)?;
+                info!("Serving RTSP at rtsp://<this host>:{}{}", server.local_addr().port(), rtsp::STREAM_PATH);
+                Ok(Sink::Stream(Arc::new(Mutex::new(Box::new(server)))))
+            }
+            #[cfg(feature = "ndi")]
+            SinkKind::Ndi(name) => {
+                let sender = crate::ndi::NdiSender::new(&name, PCM_SAMPLE_RATE, PCM_CHANNELS)?;
+                info!("Publishing NDI source '{}'", name);
+                Ok(Sink::Stream(Arc::new(Mutex::new(Box::new(sender)))))
+            }
+            #[cfg(not(feature = "ndi"))]
+            SinkKind::Ndi(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "this build has no NDI support; rebuild with `cargo build --release --features ndi`",
+            )),
+        }
+    }
+
+    /// Sends sequenced packets over `transport`, for transports this crate
+    /// doesn't open itself, such as a program's own or a test double.
+    pub fn over(transport: Arc<dyn AudioTransport>, options: &SinkOptions) -> Self {
+        Sink::Udp(Arc::new(UdpSink {
+            transport,
+            seq: AtomicU32::new(0),
+            redundancy: AtomicBool::new(options.redundancy),
+            previous: Mutex::new(Vec::new()),
+            datagram: Mutex::new(Vec::new()),
+            clock: options.clock.clone(),
+            batch_packets: options.batch_packets,
+            batch: Mutex::new(Batch::default()),
+            pace: options.pace,
+        }))
+    }
+
+    /// Whether the capture path should space out what it sends here, as
+    /// [`SinkOptions::pace`] asks.
+    pub fn is_paced(&self) -> bool {
+        matches!(self, Sink::Udp(udp) if udp.pace)
+    }
+
+    /// Delivers a frame without waiting; safe to call from the audio callback.
+    /// UDP frames are dropped if the socket would block.
+    pub fn try_send(&self, frame: &[u8]) -> io::Result<()> {
+        match self {
+            Sink::Udp(udp) => {
+                let mut datagram = udp.datagram.lock().unwrap();
+                udp.next_datagram(frame, &mut datagram);
+                udp.transport.send_frame(&datagram)
+            }
+            Sink::Stdout => write_stdout(frame),
+            Sink::Stream(writer) => writer.lock().unwrap().write_all(frame),
+        }
+    }
+
+    /// Consumes a sequence number without sending, so the receiver treats
+    /// the slot as lost rather than stalling.
+    pub fn skip(&self) {
+        if let Sink::Udp(udp) = self {
+            udp.seq.fetch_add(1, Ordering::Relaxed);
+            udp.previous.lock().unwrap().clear();
+        }
+    }
+
+    /// Delivers a frame, waiting for room rather than dropping it. With
+    /// `batch_packets` set, UDP frames are packetized now and held until
+    /// the batch is full, so an error may belong to earlier frames.
+    pub async fn send(&self, frame: &[u8]) -> io::Result<()> {
+        match self {
+            Sink::Udp(udp) => {
+                let full = {
+                    let mut batch = udp.batch.lock().unwrap();
+                    let batch = &mut *batch;
+                    if batch.len == batch.datagrams.len() {
+                        batch.datagrams.push(Vec::new());
+                    }
+                    let datagram = &mut batch.datagrams[batch.len];
+                    datagram.clear();
+                    udp.next_datagram(frame, datagram);
+                    batch.len += 1;
+                    batch.started.get_or_insert_with(Instant::now);
+                    batch.len >= udp.batch_packets
+                };
+                if full {
+                    self.flush().await
+                } else {
+                    Ok(())
+                }
+            }
+            Sink::Stdout => write_stdout(frame),
+            Sink::Stream(writer) => writer.lock().unwrap().write_all(frame),
+        }
+    }
+
+    /// Sends the frames a partly filled batch holds.
+    pub async fn flush(&self) -> io::Result<()> {
+        let Sink::Udp(udp) = self else {
+            return Ok(());
+        };
+        // Taken out, as the lock can't be held across the send.
+        let (datagrams, len) = {
+            let mut batch = udp.batch.lock().unwrap();
+            batch.started = None;
+            (std::mem::take(&mut batch.datagrams), std::mem::take(&mut batch.len))
+        };
+        let sent = match len {
+            0 => Ok(()),
+            1 => udp.transport.send(&datagrams[0]).await,
+            len => udp.transport.send_batch(&datagrams[..len]).await,
+        };
+        // The buffers go back for the next batch, unless one has started
+        // in the meantime.
+        let mut batch = udp.batch.lock().unwrap();
+        if batch.datagrams.is_empty() {
+            batch.datagrams = datagrams;
+        }
+        sent
+    }
+
+    /// When a partly filled batch should go out without waiting for the
+    /// rest of its packets: a batch's worth of `packet_length` after its
+    /// first packet. `None` with no batch waiting.
+    pub fn batch_due(&self, packet_length: Duration) -> Option<Instant> {
+        let Sink::Udp(udp) = self else {
+            return None;
+        };
+        let started = udp.batch.lock().unwrap().started?;
+        Some(started + packet_length * udp.batch_packets.max(1) as u32)
+    }
+
+    /// Tells a `--listen` receiver that no audio follows until the stream
+    /// resumes, so it plays out its buffer instead of concealing a loss.
+    /// Other sinks just stop getting data.
+    pub async fn announce_pause(&self) -> io::Result<()> {
+        match self {
+            Sink::Udp(udp) => {
+                // The audio before the pause goes first.
+                let flushed = self.flush().await;
+                udp.transport.send(&udp.pause_datagram()).await.and(flushed)
+            }
+            Sink::Stdout | Sink::Stream(_) => Ok(()),
+        }
+    }
+
+    /// Tells a `--listen` receiver that the stream is over, after the audio
+    /// already sent, so it fades out and is ready for the next sender.
+    /// The marker goes out more than once, as a lost one would leave the
+    /// receiver concealing a loss instead. Other sinks have what they were
+    /// given flushed to their output.
+    pub async fn announce_end(&self) -> io::Result<()> {
+        match self {
+            Sink::Udp(udp) => {
+                let flushed = self.flush().await;
+                let datagram = udp.end_datagram();
+                let mut sent = Ok(());
+                for _ in 0..END_MARKERS {
+                    sent = sent.and(udp.transport.send(&datagram).await);
+                }
+                sent.and(flushed)
+            }
+            Sink::Stdout => io::stdout().flush(),
+            Sink::Stream(writer) => writer.lock().unwrap().flush(),
+        }
+    }
+
+    /// Sends to `server_addr` from now on. Only plain UDP can move without
+    /// reconnecting; other sinks report `Unsupported`.
+    pub async fn set_destination(&self, server_addr: &str) -> io::Result<()> {
+        match self {
+            Sink::Udp(udp) => udp.transport.set_destination(server_addr).await,
+            Sink::Stdout | Sink::Stream(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "this sink has no destination to change",
+            )),
+        }
+    }
+
+    /// What carries the packets of a sink that sends them.
+    pub fn transport(&self) -> Option<Arc<dyn AudioTransport>> {
+        self.udp().map(|udp| udp.transport.clone())
+    }
+
+    pub fn udp(&self) -> Option<&UdpSink> {
+        match self {
+            Sink::Udp(udp) => Some(udp),
+            Sink::Stdout | Sink::Stream(_) => None,
+        }
+    }
+}
+
+/// Hands what is written to a thread that writes it on, so a reader that
+/// falls behind can't block the audio callback. Writes fail with
+/// `WouldBlock` while the queue is full, dropping the frame, and with
+/// `BrokenPipe` once the writer has failed.
+pub struct QueuedWriter {
+    queue: SyncSender<Vec<u8>>,
+    // Buffers the thread has written, to reuse rather than allocate.
+    spare: Receiver<Vec<u8>>,
+}
+
+impl QueuedWriter {
+    pub fn spawn(mut writer: Box<dyn Write + Send>) -> Self {
+        let (queue, frames) = mpsc::sync_channel::<Vec<u8>>(STREAM_QUEUE_FRAMES);
+        let (done, spare) = mpsc::channel();
+        std::thread::spawn(move || {
+            for frame in frames {
+                if let Err(e) = writer.write_all(&frame).and_then(|()| writer.flush()) {
+                    warn!("Error writing to the sink: {}", e);
+                    return;
+                }
+                let _ = done.send(frame);
+            }
+        });
+        Self { queue, spare }
+    }
+}
+
+impl Write for QueuedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut frame = self.spare.try_recv().unwrap_or_default();
+        frame.clear();
+        frame.extend_from_slice(buf);
+        match self.queue.try_send(frame) {
+            Ok(()) => Ok(buf.len()),
+            Err(TrySendError::Full(_)) => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "the sink's reader is falling behind",
+            )),
+            Err(TrySendError::Disconnected(_)) => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+async fn open_transport(server_addr: &str, options: &SinkOptions) -> io::Result<Arc<dyn AudioTransport>> {
+    if !options.reconnect {
+        return open_link(server_addr, options).await;
+    }
+    let options = options.clone();
+    let transport = ReconnectingTransport::connect(server_addr, Backoff::default(), move |addr| {
+        let options = options.clone();
+        async move { open_link(&addr, &options).await }
+    })
+    .await?;
+    Ok(Arc::new(transport))
+}
+
+async fn open_link(server_addr: &str, options: &SinkOptions) -> io::Result<Arc<dyn AudioTransport>> {
+    Ok(match options.transport {
+        Transport::Udp => match &options.rendezvous {
+            Some(session) => Arc::new(UdpTransport::rendezvous(session, &options.socket).await?),
+            None => Arc::new(UdpTransport::connect(server_addr, &options.socket).await?),
+        },
+        Transport::Srt => {
+            let srt_options = options
+                .srt
+                .as_ref()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "the SRT transport needs its options"))?;
+            let local_port = server_addr.rsplit_once(':').and_then(|(_, port)| port.parse().ok()).unwrap_or(0);
+            let socket = srt::connect(srt_options, server_addr, local_port).await?;
+            info!("SRT connection established");
+            Arc::new(SrtLink::spawn(socket))
+        }
+        Transport::Tcp => Arc::new(TcpTransport::connect(server_addr, &options.socket, options.proxy.as_ref()).await?),
+        Transport::Ws => Arc::new(WsTransport::connect(server_addr, &options.socket, options.proxy.as_ref()).await?),
+    })
+}
+
+fn is_host_port(target: &str) -> bool {
+    matches!(target.rsplit_once(':'), Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok())
+}
+
+fn write_stdout(frame: &[u8]) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    stdout.write_all(frame)?;
+    stdout.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MemoryTransport;
+
+    #[test]
+    fn test_sink_over_transport() {
+        let (transport, mut peer) = MemoryTransport::pair(8);
+        let options = SinkOptions {
+            redundancy: true,
+            ..SinkOptions::default()
+        };
+        let sink = Sink::over(Arc::new(transport), &options);
+        sink.try_send(&[1, 2]).unwrap();
+        sink.skip();
+        sink.try_send(&[3, 4]).unwrap();
+        sink.try_send(&[5, 6]).unwrap();
+        let sent: Vec<_> = std::iter::from_fn(|| peer.try_recv_frame()).collect();
+        let packets: Vec<_> = sent.iter().map(|datagram| packet::decode(datagram).unwrap()).collect();
+        assert_eq!(packets.iter().map(|packet| packet.seq).collect::<Vec<_>>(), [0, 2, 3]);
+        // The skipped slot leaves nothing to repeat.
+        assert_eq!(packets[1].redundant, None);
+        assert_eq!(packets[2].redundant, Some(&[3u8, 4][..]));
+        assert_eq!(packets[2].payload, [5, 6]);
+    }
+
+    #[test]
+    fn test_batched_send() {
+        let (transport, mut peer) = MemoryTransport::pair(8);
+        let options = SinkOptions {
+            batch_packets: 3,
+            ..SinkOptions::default()
+        };
+        let sink = Sink::over(Arc::new(transport), &options);
+        let mut received = || std::iter::from_fn(|| peer.try_recv_frame()).count();
+        futures::executor::block_on(async {
+            sink.send(&[1]).await.unwrap();
+            sink.send(&[2]).await.unwrap();
+            assert_eq!(received(), 0);
+            sink.send(&[3]).await.unwrap();
+            assert_eq!(received(), 3);
+            sink.send(&[4]).await.unwrap();
+            assert!(sink.batch_due(Duration::from_millis(10)).is_some());
+            sink.announce_pause().await.unwrap();
+            assert_eq!(sink.batch_due(Duration::from_millis(10)), None);
+        });
+        let sent: Vec<_> = std::iter::from_fn(|| peer.try_recv_frame()).collect();
+        let packets: Vec<_> = sent.iter().map(|datagram| packet::decode(datagram).unwrap()).collect();
+        assert_eq!(packets.len(), 2);
+        assert_eq!((packets[0].seq, packets[0].payload), (3, &[4u8][..]));
+        assert!(packets[1].paused);
+    }
+
+    #[test]
+    fn test_announce_end() {
+        let (transport, mut peer) = MemoryTransport::pair(8);
+        let options = SinkOptions {
+            batch_packets: 3,
+            ..SinkOptions::default()
+        };
+        let sink = Sink::over(Arc::new(transport), &options);
+        futures::executor::block_on(async {
+            sink.send(&[1]).await.unwrap();
+            sink.announce_end().await.unwrap();
+        });
+        let sent: Vec<_> = std::iter::from_fn(|| peer.try_recv_frame()).collect();
+        let packets: Vec<_> = sent.iter().map(|datagram| packet::decode(datagram).unwrap()).collect();
+        // The batch goes out ahead of the markers, which follow its last frame.
+        assert_eq!((packets[0].seq, packets[0].payload), (0, &[1u8][..]));
+        assert_eq!(packets.len(), 1 + END_MARKERS);
+        assert!(packets[1..].iter().all(|packet| packet.ended && packet.seq == 1));
+    }
+
+    #[test]
+    fn test_parse_sink_kind() {
+        assert_eq!("udp".parse(), Ok(SinkKind::Udp));
+        assert_eq!("stdout".parse(), Ok(SinkKind::Stdout));
+        assert_eq!(
+            "snapcast".parse(),
+            Ok(SinkKind::Snapcast(SnapcastTarget::Pipe("/tmp/snapfifo".into())))
+        );
+        assert_eq!(
+            "snapcast:/run/snapserver/fifo".parse(),
+            Ok(SinkKind::Snapcast(SnapcastTarget::Pipe("/run/snapserver/fifo".into())))
+        );
+        let tcp: SinkKind = "snapcast:tcp://snap.local:4953".parse().unwrap();
+        assert_eq!(tcp, SinkKind::Snapcast(SnapcastTarget::Tcp("snap.local:4953".to_string())));
+        assert_eq!(tcp.to_string(), "snapcast:tcp://snap.local:4953");
+
+        assert_eq!("airplay".parse(), Ok(SinkKind::AirPlay(None)));
+        assert_eq!(
+            "airplay:Living Room".parse(),
+            Ok(SinkKind::AirPlay(Some("Living Room".to_string())))
+        );
+        assert_eq!("cast:Kitchen".parse(), Ok(SinkKind::Cast(Some("Kitchen".to_string()))));
+        assert_eq!("dlna".parse(), Ok(SinkKind::Dlna(None)));
+        let icecast: SinkKind = "icecast://source:pw@radio:8000/live.opus".parse().unwrap();
+        assert_eq!(icecast.to_string(), "icecast://source@radio:8000/live.opus");
+        assert_eq!("hls".parse(), Ok(SinkKind::Hls(8090)));
+        assert_eq!("hls:9000".parse(), Ok(SinkKind::Hls(9000)));
+        assert_eq!("rtsp".parse(), Ok(SinkKind::Rtsp(8554)));
+        assert_eq!("ndi:Desktop".parse(), Ok(SinkKind::Ndi("Desktop".to_string())));
+        assert!(is_host_port("192.168.1.20:7000"));
+        assert!(!is_host_port("Living Room"));
+    }
+
+    // Lets through as many writes as `gate` has permits, then blocks.
+    struct Gated {
+        written: Arc<Mutex<Vec<u8>>>,
+        gate: mpsc::Receiver<()>,
+    }
+
+    impl Write for Gated {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.gate.recv().map_err(|_| io::ErrorKind::BrokenPipe)?;
+            self.written.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_queued_writer_does_not_block() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let (permits, gate) = mpsc::channel();
+        let mut writer = QueuedWriter::spawn(Box::new(Gated {
+            written: written.clone(),
+            gate,
+        }));
+        // The reader is stuck: writes queue up and then fail at once.
+        for i in 0..=STREAM_QUEUE_FRAMES {
+            let _ = writer.write_all(&[i as u8]);
+        }
+        assert_eq!(writer.write_all(&[0]).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+        permits.send(()).unwrap();
+        while written.lock().unwrap().is_empty() {
+            std::thread::yield_now();
+        }
+        assert_eq!(*written.lock().unwrap(), [0]);
+
+        // The reader goes away.
+        drop(permits);
+        while writer.write_all(&[0]).map_err(|e| e.kind()) != Err(io::ErrorKind::BrokenPipe) {
+            std::thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn test_parse_sink_kind_invalid() {
+        assert!("tcp".parse::<SinkKind>().is_err());
+        assert!("udp:foo".parse::<SinkKind>().is_err());
+        assert!("snapcast:".parse::<SinkKind>().is_err());
+        assert!("snapcast:tcp://".parse::<SinkKind>().is_err());
+        assert!("airplay:".parse::<SinkKind>().is_err());
+        assert!("icecast://radio".parse::<SinkKind>().is_err());
+        assert!("hls:web".parse::<SinkKind>().is_err());
+    }
+}
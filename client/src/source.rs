@@ -0,0 +1,171 @@
+use std::fmt;
+use std::io::Read;
+use std::str::FromStr;
+
+/// Where the client pulls audio from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum SourceKind {
+    /// Capture from an audio input device
+    Device,
+    /// Read raw interleaved PCM from standard input
+    Stdin,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleEncoding {
+    S16Le,
+    F32Le,
+}
+
+impl SampleEncoding {
+    pub fn bytes_per_sample(self) -> usize {
+        match self {
+            SampleEncoding::S16Le => 2,
+            SampleEncoding::F32Le => 4,
+        }
+    }
+}
+
+/// Layout of raw PCM read from stdin, written as `<encoding>:<rate>:<channels>`
+/// (e.g. `s16le:48000:2`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StdinFormat {
+    pub encoding: SampleEncoding,
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+impl FromStr for StdinFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        if parts.len() != 3 {
+            return Err(format!("expected <encoding>:<rate>:<channels>, got '{}'", s));
+        }
+
+        let encoding = match parts[0].to_lowercase().as_str() {
+            "s16le" => SampleEncoding::S16Le,
+            "f32le" => SampleEncoding::F32Le,
+            other => return Err(format!("unsupported sample encoding '{}'", other)),
+        };
+        let sample_rate: u32 = parts[1]
+            .parse()
+            .map_err(|_| format!("invalid sample rate '{}'", parts[1]))?;
+        let channels: u16 = parts[2]
+            .parse()
+            .map_err(|_| format!("invalid channel count '{}'", parts[2]))?;
+        if sample_rate == 0 || channels == 0 {
+            return Err("sample rate and channel count must be non-zero".to_string());
+        }
+
+        Ok(StdinFormat {
+            encoding,
+            sample_rate,
+            channels,
+        })
+    }
+}
+
+impl fmt::Display for StdinFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let encoding = match self.encoding {
+            SampleEncoding::S16Le => "s16le",
+            SampleEncoding::F32Le => "f32le",
+        };
+        write!(f, "{}:{}:{}", encoding, self.sample_rate, self.channels)
+    }
+}
+
+/// Reads fixed-size blocks of raw PCM from a byte stream and yields them as
+/// interleaved stereo `f32` samples, upmixing mono input.
+pub struct PcmReader<R: Read> {
+    inner: R,
+    format: StdinFormat,
+    raw: Vec<u8>,
+}
+
+impl<R: Read> PcmReader<R> {
+    pub fn new(inner: R, format: StdinFormat, frames_per_buffer: usize) -> Self {
+        let block = frames_per_buffer * format.channels as usize * format.encoding.bytes_per_sample();
+        Self {
+            inner,
+            format,
+            raw: vec![0u8; block],
+        }
+    }
+
+    /// Fills `out` with the next block of stereo samples. Returns `Ok(false)`
+    /// once the input is exhausted; a trailing partial block is zero-padded.
+    pub fn read_block(&mut self, out: &mut Vec<f32>) -> std::io::Result<bool> {
+        let mut filled = 0;
+        while filled < self.raw.len() {
+            match self.inner.read(&mut self.raw[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        if filled == 0 {
+            return Ok(false);
+        }
+        self.raw[filled..].iter_mut().for_each(|b| *b = 0);
+
+        out.clear();
+        let width = self.format.encoding.bytes_per_sample();
+        let channels = self.format.channels as usize;
+        for frame in self.raw.chunks_exact(width * channels) {
+            let left = decode_sample(&frame[..width], self.format.encoding);
+            let right = if channels > 1 {
+                decode_sample(&frame[width..2 * width], self.format.encoding)
+            } else {
+                left
+            };
+            out.push(left);
+            out.push(right);
+        }
+        Ok(true)
+    }
+}
+
+fn decode_sample(bytes: &[u8], encoding: SampleEncoding) -> f32 {
+    match encoding {
+        SampleEncoding::S16Le => i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / i16::MAX as f32,
+        SampleEncoding::F32Le => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_stdin_format() {
+        let format: StdinFormat = "s16le:48000:2".parse().unwrap();
+        assert_eq!(format.encoding, SampleEncoding::S16Le);
+        assert_eq!(format.sample_rate, 48000);
+        assert_eq!(format.channels, 2);
+        assert_eq!(format.to_string(), "s16le:48000:2");
+    }
+
+    #[test]
+    fn test_parse_stdin_format_invalid() {
+        assert!("s16le:48000".parse::<StdinFormat>().is_err());
+        assert!("u8:48000:2".parse::<StdinFormat>().is_err());
+        assert!("s16le:abc:2".parse::<StdinFormat>().is_err());
+        assert!("s16le:48000:0".parse::<StdinFormat>().is_err());
+    }
+
+    #[test]
+    fn test_pcm_reader_upmixes_mono() {
+        let format: StdinFormat = "s16le:48000:1".parse().unwrap();
+        let input: Vec<u8> = [i16::MAX, 0].iter().flat_map(|s| s.to_le_bytes()).collect();
+        let mut reader = PcmReader::new(&input[..], format, 2);
+        let mut out = Vec::new();
+
+        assert!(reader.read_block(&mut out).unwrap());
+        assert_eq!(out, vec![1.0, 1.0, 0.0, 0.0]);
+        assert!(!reader.read_block(&mut out).unwrap());
+    }
+}
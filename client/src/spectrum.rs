@@ -0,0 +1,142 @@
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::sync::Mutex;
+
+const CHANNELS: usize = 2;
+// Frames averaged into each analysed sample. Averaging pairs is a rough
+// low-pass that halves the rate to 24 kHz, which still reaches 12 kHz.
+const DECIMATION: usize = 2;
+const RATE: f32 = 48_000.0 / DECIMATION as f32;
+/// Samples in each transform, a power of two: about 85 ms of audio.
+pub const WINDOW: usize = 2048;
+/// The lowest frequency shown; a window this short resolves little below it.
+pub const LOWEST_HZ: f32 = 40.0;
+/// The highest frequency shown, half the decimated rate.
+pub const HIGHEST_HZ: f32 = RATE / 2.0;
+// The quietest band the display shows.
+const FLOOR_DB: f32 = -80.0;
+
+/// A mono, decimated copy of the stereo s16le PCM being sent, holding the
+/// latest window for the dashboard's spectrum.
+#[derive(Default)]
+pub struct SpectrumTap {
+    samples: Mutex<VecDeque<f32>>,
+}
+
+impl SpectrumTap {
+    pub fn record(&self, pcm: &[u8]) {
+        let mut samples = self.samples.lock().unwrap();
+        for group in pcm.chunks_exact(2 * CHANNELS * DECIMATION) {
+            let sum: f32 = group
+                .chunks_exact(2)
+                .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]) as f32)
+                .sum();
+            if samples.len() == WINDOW {
+                samples.pop_front();
+            }
+            samples.push_back(sum / (CHANNELS * DECIMATION) as f32 / i16::MAX as f32);
+        }
+    }
+
+    /// The latest window in `count` bands spaced evenly in pitch from
+    /// [`LOWEST_HZ`] to [`HIGHEST_HZ`], each from 0.0 at -80 dBFS to 1.0 at
+    /// full scale. All zero until a whole window has been recorded.
+    pub fn bands(&self, count: usize) -> Vec<f32> {
+        let samples: Vec<f32> = self.samples.lock().unwrap().iter().copied().collect();
+        if samples.len() < WINDOW {
+            return vec![0.0; count];
+        }
+        bands(&magnitudes(&samples), count)
+    }
+}
+
+// The magnitude of each bin up to half the rate, after a Hann window,
+// scaled so a full-scale sine reads 1.0 in its bin.
+fn magnitudes(samples: &[f32]) -> Vec<f32> {
+    let n = samples.len();
+    let mut re: Vec<f32> = samples
+        .iter()
+        .enumerate()
+        .map(|(i, sample)| sample * (0.5 - 0.5 * (2.0 * PI * i as f32 / n as f32).cos()))
+        .collect();
+    let mut im = vec![0.0; n];
+    fft(&mut re, &mut im);
+    (0..n / 2).map(|k| re[k].hypot(im[k]) * 4.0 / n as f32).collect()
+}
+
+// An in-place radix-2 transform; the length must be a power of two.
+fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = (angle * k as f32).sin_cos();
+                let (a, b) = (start + k, start + k + len / 2);
+                let (tr, ti) = (re[b] * cos - im[b] * sin, re[b] * sin + im[b] * cos);
+                re[b] = re[a] - tr;
+                im[b] = im[a] - ti;
+                re[a] += tr;
+                im[a] += ti;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+// Each band takes its loudest bin, so a tone shows at its full level
+// however many bins the band spans.
+fn bands(magnitudes: &[f32], count: usize) -> Vec<f32> {
+    let bin_hz = HIGHEST_HZ / magnitudes.len() as f32;
+    let ratio = (HIGHEST_HZ / LOWEST_HZ).powf(1.0 / count as f32);
+    (0..count)
+        .map(|i| {
+            let low = (LOWEST_HZ * ratio.powi(i as i32) / bin_hz) as usize;
+            let high = (LOWEST_HZ * ratio.powi(i as i32 + 1) / bin_hz) as usize;
+            let peak = magnitudes[low..high.clamp(low + 1, magnitudes.len())]
+                .iter()
+                .fold(0.0f32, |peak, &magnitude| peak.max(magnitude));
+            ((20.0 * peak.log10()).max(FLOOR_DB) - FLOOR_DB) / -FLOOR_DB
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A full-scale sine on both channels at the stream's 48 kHz.
+    fn tone(hz: f32, frames: usize) -> Vec<u8> {
+        (0..frames)
+            .flat_map(|i| {
+                let sample = ((2.0 * PI * hz * i as f32 / 48_000.0).sin() * i16::MAX as f32) as i16;
+                [sample.to_le_bytes(), sample.to_le_bytes()].concat()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_bands() {
+        let tap = SpectrumTap::default();
+        assert_eq!(tap.bands(4), vec![0.0; 4]);
+        tap.record(&tone(1000.0, WINDOW * DECIMATION));
+        let bands = tap.bands(24);
+        let band = (24.0 * (1000.0 / LOWEST_HZ).ln() / (HIGHEST_HZ / LOWEST_HZ).ln()) as usize;
+        assert!(bands[band] > 0.95, "{:?}", bands);
+        assert!(bands[0] < 0.3 && bands[23] < 0.3, "{:?}", bands);
+    }
+}
@@ -0,0 +1,152 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::{SinkExt, TryStreamExt};
+use srt_tokio::SrtSocket;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{info, warn, Instrument};
+
+use crate::clock::SyncedClock;
+use crate::jitter::JitterBuffer;
+use crate::playback;
+use crate::transport::{AudioTransport, Counters, TransportStats};
+
+// AES-128, the SRT default when a passphrase is set.
+const KEY_SIZE: u16 = 16;
+// Packets queued for the sending task before new ones are dropped; a
+// second of 512-frame buffers.
+const SEND_QUEUE: usize = 96;
+
+/// Which side of an SRT connection this end takes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum SrtMode {
+    /// Connect out to the peer
+    Caller,
+    /// Wait for the peer to connect
+    Listener,
+}
+
+#[derive(Clone, Debug)]
+pub struct SrtOptions {
+    pub mode: SrtMode,
+    /// How long the receiver holds packets for retransmission before
+    /// giving up on them; both ends negotiate the larger of their values.
+    pub latency: Duration,
+    /// Encrypts the stream when set; both ends must use the same one.
+    pub passphrase: Option<String>,
+}
+
+/// Checks a passphrase against SRT's 10 to 79 character limit.
+pub fn parse_passphrase(s: &str) -> Result<String, String> {
+    if (10..=79).contains(&s.chars().count()) {
+        Ok(s.to_string())
+    } else {
+        Err("SRT passphrases must be 10 to 79 characters".to_string())
+    }
+}
+
+/// Opens an SRT connection: a caller connects to `remote`, a listener
+/// waits on `local_port` for its peer.
+pub async fn connect(options: &SrtOptions, remote: &str, local_port: u16) -> io::Result<SrtSocket> {
+    let mut builder = SrtSocket::builder().latency(options.latency);
+    if let Some(passphrase) = &options.passphrase {
+        builder = builder.encryption(KEY_SIZE, passphrase.clone());
+    }
+    match options.mode {
+        SrtMode::Caller => {
+            info!("Connecting to SRT listener at {}", remote);
+            builder.call(remote, None).await
+        }
+        SrtMode::Listener => {
+            info!("Waiting for an SRT caller on port {}", local_port);
+            builder.listen_on(format!(":{}", local_port).as_str()).await
+        }
+    }
+}
+
+/// The sending half of an SRT connection. Packets go through a bounded
+/// queue to a task that owns the socket, so the audio callback never waits
+/// on retransmissions.
+pub struct SrtLink {
+    queue: mpsc::Sender<Bytes>,
+    counters: Counters,
+}
+
+impl SrtLink {
+    /// The sending task carries the caller's span.
+    pub fn spawn(mut socket: SrtSocket) -> Self {
+        let (queue, mut packets) = mpsc::channel::<Bytes>(SEND_QUEUE);
+        let send = async move {
+            while let Some(packet) = packets.recv().await {
+                if let Err(e) = socket.send((Instant::now(), packet)).await {
+                    warn!("SRT connection closed: {}", e);
+                    return;
+                }
+            }
+            let _ = socket.close().await;
+        };
+        tokio::spawn(send.in_current_span());
+        Self {
+            queue,
+            counters: Counters::default(),
+        }
+    }
+}
+
+impl AudioTransport for SrtLink {
+    fn send_frame(&self, frame: &[u8]) -> io::Result<()> {
+        let result = match self.queue.try_send(Bytes::copy_from_slice(frame)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(TrySendError::Closed(_)) => Err(io::ErrorKind::NotConnected.into()),
+        };
+        self.counters.record(&result, frame.len());
+        result
+    }
+
+    fn send<'a>(&'a self, frame: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let result = self
+                .queue
+                .send(Bytes::copy_from_slice(frame))
+                .await
+                .map_err(|_| io::ErrorKind::NotConnected.into());
+            self.counters.record(&result, frame.len());
+            result
+        })
+    }
+
+    // The sending task owns the socket, so the link carries audio one way.
+    fn recv_control(&self) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        Box::pin(async { Err(io::Error::new(io::ErrorKind::Unsupported, "SRT carries audio one way")) })
+    }
+
+    fn stats(&self) -> TransportStats {
+        self.counters.snapshot()
+    }
+}
+
+/// Reads packets off an SRT connection into the jitter buffer until the
+/// peer hangs up.
+pub async fn receive(mut socket: SrtSocket, buffer: Arc<Mutex<JitterBuffer>>, clock: Arc<SyncedClock>) -> io::Result<()> {
+    while let Some((_, datagram)) = socket.try_next().await? {
+        playback::accept(&datagram, &buffer, &clock);
+    }
+    info!("SRT peer closed the connection");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_passphrase() {
+        assert_eq!(parse_passphrase("correct horse"), Ok("correct horse".to_string()));
+        assert!(parse_passphrase("short").is_err());
+        assert!(parse_passphrase(&"x".repeat(80)).is_err());
+    }
+}
@@ -0,0 +1,355 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::meter::{ChannelLevel, LevelMeter};
+use crate::rtt::RttTracker;
+use crate::spectrum::SpectrumTap;
+use crate::usage;
+
+/// Counters updated from the capture path as frames are handed to the sink.
+#[derive(Default)]
+pub struct StreamStats {
+    bytes_sent: AtomicU64,
+    packets_sent: AtomicU64,
+    drops: AtomicU64,
+    // Failures since the last packet the sink took.
+    failing: AtomicU64,
+    buffered_micros: AtomicU64,
+    underruns: AtomicU64,
+    overruns: AtomicU64,
+    queue_drops: AtomicU64,
+    reconnects: AtomicU64,
+    volume_changes: AtomicU64,
+    device_errors: AtomicU64,
+    // The capture buffer the stream runs with, 0 before one is built.
+    buffer_frames: AtomicU32,
+    levels: LevelMeter,
+    spectrum: SpectrumTap,
+    rtt: RttTracker,
+    callback_frames: Mutex<Option<(usize, usize)>>,
+}
+
+impl StreamStats {
+    pub fn record_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a packet the sink failed to take, returning how many have
+    /// failed in a row, this one included.
+    pub fn record_drop(&self) -> u64 {
+        self.drops.fetch_add(1, Ordering::Relaxed);
+        self.failing.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Ends a run of failed sends, returning how long it was.
+    pub fn end_drops(&self) -> u64 {
+        self.failing.swap(0, Ordering::Relaxed)
+    }
+
+    /// Counts a capture callback that came late enough for the host to have
+    /// lost input before it.
+    pub fn record_underrun(&self) {
+        self.underruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a capture callback that took longer than the audio it carried,
+    /// so the host's buffer was filling faster than it was emptied.
+    pub fn record_overrun(&self) {
+        self.overruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts frames of captured audio lost because the sender task had
+    /// fallen behind and its queue was full.
+    pub fn record_queue_drop(&self, frames: usize) {
+        self.queue_drops.fetch_add(frames as u64, Ordering::Relaxed);
+    }
+
+    /// Counts the capture reopening on another device after losing its own.
+    pub fn record_reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a volume or mute change from a controller.
+    pub fn record_volume_change(&self) {
+        self.volume_changes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts an error the audio backend reported on the capture stream.
+    pub fn record_device_error(&self) {
+        self.device_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Notes the buffer size, in frames, the capture stream was built with.
+    pub fn record_buffer_frames(&self, frames: u32) {
+        self.buffer_frames.store(frames, Ordering::Relaxed);
+    }
+
+    /// Notes how many frames a capture callback delivered.
+    pub fn record_callback(&self, frames: usize) {
+        let mut range = self.callback_frames.lock().unwrap();
+        let (min, max) = range.unwrap_or((frames, frames));
+        *range = Some((min.min(frames), max.max(frames)));
+    }
+
+    /// The smallest and largest capture callbacks since the previous call.
+    pub fn take_callback_frames(&self) -> Option<(usize, usize)> {
+        self.callback_frames.lock().unwrap().take()
+    }
+
+    /// Measures a buffer of stereo s16le PCM on its way to the sink, for the
+    /// level meter and the spectrum.
+    pub fn record_levels(&self, pcm: &[u8]) {
+        self.levels.record(pcm);
+        self.spectrum.record(pcm);
+    }
+
+    /// The peak and RMS levels of each channel since the previous call.
+    pub fn take_levels(&self) -> [ChannelLevel; 2] {
+        self.levels.take()
+    }
+
+    /// The spectrum of the latest audio sent, in `count` bands.
+    pub fn spectrum(&self, count: usize) -> Vec<f32> {
+        self.spectrum.bands(count)
+    }
+
+    /// Round trips to the receiver measured by --ping.
+    pub fn rtt(&self) -> &RttTracker {
+        &self.rtt
+    }
+
+    /// Sets how much captured audio is waiting to be sent.
+    pub fn record_buffered(&self, buffered: Duration) {
+        self.buffered_micros
+            .store(buffered.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn buffered(&self) -> Duration {
+        Duration::from_micros(self.buffered_micros.load(Ordering::Relaxed))
+    }
+
+    pub fn summary(&self, elapsed: Duration) -> Summary {
+        Summary {
+            elapsed,
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            packets_sent: self.packets_sent.load(Ordering::Relaxed),
+            drops: self.drops.load(Ordering::Relaxed),
+            underruns: self.underruns.load(Ordering::Relaxed),
+            overruns: self.overruns.load(Ordering::Relaxed),
+            queue_drops: self.queue_drops.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            volume_changes: self.volume_changes.load(Ordering::Relaxed),
+            device_errors: self.device_errors.load(Ordering::Relaxed),
+            buffer_frames: Some(self.buffer_frames.load(Ordering::Relaxed)).filter(|&frames| frames > 0),
+            cpu_time: usage::cpu_time(),
+            resident_bytes: usage::resident_bytes(),
+        }
+    }
+}
+
+/// The stream's counters so far. Drops are network-side problems, packets
+/// the sink couldn't send; underruns and overruns are audio-side ones, in
+/// the capture callbacks. Queue drops are frames of audio lost in between,
+/// when the network held up sending for longer than the queue holds. The
+/// capture buffer is the size the stream ended with, which may have grown
+/// from the one asked for; there is none without a capture device. The
+/// process's own CPU and memory use show whether it is saturating a small
+/// machine.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Summary {
+    pub elapsed: Duration,
+    pub bytes_sent: u64,
+    pub packets_sent: u64,
+    pub drops: u64,
+    pub underruns: u64,
+    pub overruns: u64,
+    pub queue_drops: u64,
+    pub reconnects: u64,
+    pub volume_changes: u64,
+    pub device_errors: u64,
+    pub buffer_frames: Option<u32>,
+    pub cpu_time: Option<Duration>,
+    pub resident_bytes: Option<u64>,
+}
+
+impl Summary {
+    pub fn average_bitrate_kbps(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs == 0.0 {
+            return 0.0;
+        }
+        self.bytes_sent as f64 * 8.0 / secs / 1000.0
+    }
+
+    /// The share of a core used over the whole run.
+    pub fn average_cpu_percent(&self) -> Option<f64> {
+        usage::cpu_percent(Some(Duration::ZERO), self.cpu_time, self.elapsed)
+    }
+}
+
+/// One line for --stats-interval: the totals, the bitrate since `before`,
+/// the callback sizes and timing problems seen when capturing from a
+/// device, and the process's CPU and memory use.
+pub fn interval_line(before: &Summary, after: &Summary, callback_frames: Option<(usize, usize)>) -> String {
+    let wall = after.elapsed.saturating_sub(before.elapsed);
+    let secs = wall.as_secs_f64();
+    let bytes = after.bytes_sent.saturating_sub(before.bytes_sent);
+    let kbps = if secs > 0.0 {
+        bytes as f64 * 8.0 / secs / 1000.0
+    } else {
+        0.0
+    };
+    let mut line = format!(
+        "Sent {} packets, {:.1} MB; {:.1} kbps; {} send failures ({} new)",
+        after.packets_sent,
+        after.bytes_sent as f64 / 1e6,
+        kbps,
+        after.drops,
+        after.drops.saturating_sub(before.drops)
+    );
+    match callback_frames {
+        Some((min, max)) if min == max => line.push_str(&format!("; callbacks of {} frames", min)),
+        Some((min, max)) => line.push_str(&format!("; callbacks of {}-{} frames", min, max)),
+        None => {}
+    }
+    if callback_frames.is_some() || after.underruns + after.overruns > 0 {
+        line.push_str(&format!(
+            "; {} capture underruns ({} new), {} overruns ({} new)",
+            after.underruns,
+            after.underruns.saturating_sub(before.underruns),
+            after.overruns,
+            after.overruns.saturating_sub(before.overruns)
+        ));
+    }
+    if after.queue_drops > 0 {
+        line.push_str(&format!(
+            "; {} frames dropped from the send queue ({} new)",
+            after.queue_drops,
+            after.queue_drops.saturating_sub(before.queue_drops)
+        ));
+    }
+    if let Some(cpu) = usage::cpu_percent(before.cpu_time, after.cpu_time, wall) {
+        line.push_str(&format!("; CPU {:.1}%", cpu));
+    }
+    if let Some(bytes) = after.resident_bytes {
+        line.push_str(&format!("; RSS {:.1} MB", bytes as f64 / 1e6));
+    }
+    line
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Session summary:")?;
+        writeln!(f, "  Duration:        {:.1}s", self.elapsed.as_secs_f64())?;
+        writeln!(f, "  Bytes sent:      {}", self.bytes_sent)?;
+        writeln!(f, "  Packets sent:    {}", self.packets_sent)?;
+        writeln!(f, "  Average bitrate: {:.1} kbps", self.average_bitrate_kbps())?;
+        writeln!(f, "  Dropped packets: {}", self.drops)?;
+        writeln!(f, "  Underruns:       {}", self.underruns)?;
+        writeln!(f, "  Overruns:        {}", self.overruns)?;
+        writeln!(f, "  Queue drops:     {} frames", self.queue_drops)?;
+        writeln!(f, "  Reconnects:      {}", self.reconnects)?;
+        writeln!(f, "  Volume changes:  {}", self.volume_changes)?;
+        write!(f, "  Device errors:   {}", self.device_errors)?;
+        if let Some(frames) = self.buffer_frames {
+            write!(f, "\n  Capture buffer:  {} frames", frames)?;
+        }
+        if let Some(cpu) = self.average_cpu_percent() {
+            write!(f, "\n  CPU:             {:.1}% of a core", cpu)?;
+        }
+        if let Some(bytes) = self.resident_bytes {
+            write!(f, "\n  Memory:          {:.1} MB", bytes as f64 / 1e6)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary_counts_and_bitrate() {
+        let stats = StreamStats::default();
+        stats.record_sent(1000);
+        stats.record_sent(1000);
+        assert_eq!(stats.record_drop(), 1);
+        assert_eq!(stats.record_drop(), 2);
+        assert_eq!(stats.end_drops(), 2);
+        assert_eq!(stats.end_drops(), 0);
+        stats.record_underrun();
+        stats.record_overrun();
+        stats.record_queue_drop(512);
+        stats.record_reconnect();
+        stats.record_volume_change();
+        stats.record_volume_change();
+        assert_eq!(stats.summary(Duration::ZERO).buffer_frames, None);
+        stats.record_buffer_frames(1024);
+
+        let summary = stats.summary(Duration::from_secs(2));
+        assert_eq!(summary.bytes_sent, 2000);
+        assert_eq!(summary.packets_sent, 2);
+        assert_eq!(summary.drops, 2);
+        assert_eq!((summary.underruns, summary.overruns, summary.queue_drops), (1, 1, 512));
+        assert_eq!(
+            (summary.reconnects, summary.volume_changes, summary.device_errors),
+            (1, 2, 0)
+        );
+        assert_eq!(summary.buffer_frames, Some(1024));
+        assert_eq!(summary.average_bitrate_kbps(), 8.0);
+    }
+
+    #[test]
+    fn test_interval_line() {
+        // Leaves out this process's real CPU and memory readings.
+        let counters = |stats: &StreamStats, secs| Summary {
+            cpu_time: None,
+            resident_bytes: None,
+            ..stats.summary(Duration::from_secs(secs))
+        };
+        let stats = StreamStats::default();
+        let before = counters(&stats, 10);
+        stats.record_sent(125_000);
+        stats.record_drop();
+        stats.record_callback(512);
+        stats.record_callback(480);
+        stats.record_underrun();
+        let after = counters(&stats, 20);
+        assert_eq!(
+            interval_line(&before, &after, stats.take_callback_frames()),
+            "Sent 1 packets, 0.1 MB; 100.0 kbps; 1 send failures (1 new); callbacks of 480-512 frames; \
+             1 capture underruns (1 new), 0 overruns (0 new)"
+        );
+        assert_eq!(stats.take_callback_frames(), None);
+        stats.record_callback(512);
+        assert!(interval_line(&after, &after, stats.take_callback_frames())
+            .ends_with("; callbacks of 512 frames; 1 capture underruns (0 new), 0 overruns (0 new)"));
+        stats.record_queue_drop(480);
+        assert!(interval_line(&after, &counters(&stats, 30), None)
+            .ends_with("0 overruns (0 new); 480 frames dropped from the send queue (480 new)"));
+        let stdin = Summary::default();
+        assert!(interval_line(&stdin, &stdin, None).ends_with("send failures (0 new)"));
+
+        let before = Summary {
+            elapsed: Duration::from_secs(10),
+            cpu_time: Some(Duration::from_secs(1)),
+            ..stdin
+        };
+        let after = Summary {
+            elapsed: Duration::from_secs(20),
+            cpu_time: Some(Duration::from_millis(2500)),
+            resident_bytes: Some(24_100_000),
+            ..stdin
+        };
+        assert!(interval_line(&before, &after, None).ends_with("(0 new); CPU 15.0%; RSS 24.1 MB"));
+    }
+
+    #[test]
+    fn test_summary_zero_elapsed() {
+        let summary = StreamStats::default().summary(Duration::ZERO);
+        assert_eq!(summary.average_bitrate_kbps(), 0.0);
+    }
+}
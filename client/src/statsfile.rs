@@ -0,0 +1,285 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use chrono::{Local, SecondsFormat};
+use serde::Serialize;
+
+use crate::stats::Summary;
+use crate::usage;
+
+const CSV_HEADER: &str =
+    "time,elapsed_s,bitrate_kbps,packets_sent,send_failures,loss_percent,buffered_ms,underruns,overruns,rtt_ms,rtt_jitter_ms,end_to_end_ms,cpu_percent,rss_mb";
+
+/// How a [`StatsFile`] writes its rows, chosen by the file's extension.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StatsFormat {
+    /// Comma-separated values under a header line.
+    Csv,
+    /// One JSON object per line, for a `.json` or `.jsonl` file.
+    Json,
+}
+
+impl StatsFormat {
+    pub fn for_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json" | "jsonl") => StatsFormat::Json,
+            _ => StatsFormat::Csv,
+        }
+    }
+}
+
+/// One interval of the stream. Counts cover the interval, not the whole
+/// run, and round trips are missing without --ping.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct StatsRow {
+    /// Local time at the end of the interval, in RFC 3339.
+    pub time: String,
+    /// Seconds since the stream started.
+    pub elapsed_s: f64,
+    pub bitrate_kbps: f64,
+    pub packets_sent: u64,
+    pub send_failures: u64,
+    /// Of the packets the interval tried to send.
+    pub loss_percent: f64,
+    /// Captured audio waiting to be sent at the end of the interval.
+    pub buffered_ms: f64,
+    pub underruns: u64,
+    pub overruns: u64,
+    pub rtt_ms: Option<f64>,
+    pub rtt_jitter_ms: Option<f64>,
+    pub end_to_end_ms: Option<f64>,
+    /// Of one core, so two busy cores read 200.
+    pub cpu_percent: Option<f64>,
+    pub rss_mb: Option<f64>,
+}
+
+impl StatsRow {
+    /// The traffic between two looks at the counters, ending now.
+    pub fn between(before: &Summary, after: &Summary) -> Self {
+        let wall = after.elapsed.saturating_sub(before.elapsed);
+        let secs = wall.as_secs_f64();
+        let bytes = after.bytes_sent.saturating_sub(before.bytes_sent);
+        let sent = after.packets_sent.saturating_sub(before.packets_sent);
+        let failed = after.drops.saturating_sub(before.drops);
+        Self {
+            time: Local::now().to_rfc3339_opts(SecondsFormat::Secs, false),
+            elapsed_s: after.elapsed.as_secs_f64(),
+            bitrate_kbps: if secs > 0.0 {
+                bytes as f64 * 8.0 / secs / 1000.0
+            } else {
+                0.0
+            },
+            packets_sent: sent,
+            send_failures: failed,
+            loss_percent: if sent + failed > 0 {
+                failed as f64 * 100.0 / (sent + failed) as f64
+            } else {
+                0.0
+            },
+            underruns: after.underruns.saturating_sub(before.underruns),
+            overruns: after.overruns.saturating_sub(before.overruns),
+            cpu_percent: usage::cpu_percent(before.cpu_time, after.cpu_time, wall),
+            rss_mb: after.resident_bytes.map(|bytes| bytes as f64 / 1e6),
+            ..Self::default()
+        }
+    }
+
+    fn csv(&self) -> String {
+        let optional = |value: Option<f64>| value.map(|value| format!("{:.3}", value)).unwrap_or_default();
+        format!(
+            "{},{:.1},{:.1},{},{},{:.2},{:.1},{},{},{},{},{},{},{}",
+            self.time,
+            self.elapsed_s,
+            self.bitrate_kbps,
+            self.packets_sent,
+            self.send_failures,
+            self.loss_percent,
+            self.buffered_ms,
+            self.underruns,
+            self.overruns,
+            optional(self.rtt_ms),
+            optional(self.rtt_jitter_ms),
+            optional(self.end_to_end_ms),
+            self.cpu_percent.map(|cpu| format!("{:.1}", cpu)).unwrap_or_default(),
+            self.rss_mb.map(|mb| format!("{:.1}", mb)).unwrap_or_default()
+        )
+    }
+}
+
+/// The whole session as --summary-file writes it when the client stops.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SessionRecord {
+    /// Local time the session ended, in RFC 3339.
+    pub ended: String,
+    pub duration_s: f64,
+    pub bytes_sent: u64,
+    pub packets_sent: u64,
+    pub average_bitrate_kbps: f64,
+    pub send_errors: u64,
+    pub underruns: u64,
+    pub overruns: u64,
+    pub queue_drops: u64,
+    pub reconnects: u64,
+    pub volume_changes: u64,
+    pub device_errors: u64,
+    pub buffer_frames: Option<u32>,
+    pub cpu_percent: Option<f64>,
+    pub resident_bytes: Option<u64>,
+}
+
+impl SessionRecord {
+    pub fn new(summary: &Summary) -> Self {
+        Self {
+            ended: Local::now().to_rfc3339_opts(SecondsFormat::Secs, false),
+            duration_s: summary.elapsed.as_secs_f64(),
+            bytes_sent: summary.bytes_sent,
+            packets_sent: summary.packets_sent,
+            average_bitrate_kbps: summary.average_bitrate_kbps(),
+            send_errors: summary.drops,
+            underruns: summary.underruns,
+            overruns: summary.overruns,
+            queue_drops: summary.queue_drops,
+            reconnects: summary.reconnects,
+            volume_changes: summary.volume_changes,
+            device_errors: summary.device_errors,
+            buffer_frames: summary.buffer_frames,
+            cpu_percent: summary.average_cpu_percent(),
+            resident_bytes: summary.resident_bytes,
+        }
+    }
+
+    /// Writes the record to `path` as a JSON object, replacing the file.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut file = File::create(path)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        writeln!(file)
+    }
+}
+
+/// A stats history that appends across runs, so an overnight session can
+/// be charted afterwards.
+pub struct StatsFile {
+    file: File,
+    format: StatsFormat,
+}
+
+impl StatsFile {
+    /// Opens `path` for appending, creating it if needed. A new or empty
+    /// CSV file starts with the header.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        let format = StatsFormat::for_path(path);
+        if format == StatsFormat::Csv && file.metadata()?.len() == 0 {
+            writeln!(file, "{}", CSV_HEADER)?;
+        }
+        Ok(Self { file, format })
+    }
+
+    pub fn append(&mut self, row: &StatsRow) -> io::Result<()> {
+        let line = match self.format {
+            StatsFormat::Csv => row.csv(),
+            StatsFormat::Json => serde_json::to_string(row)?,
+        };
+        writeln!(self.file, "{}", line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn summary(secs: u64, bytes_sent: u64, packets_sent: u64, drops: u64) -> Summary {
+        Summary {
+            elapsed: Duration::from_secs(secs),
+            bytes_sent,
+            packets_sent,
+            drops,
+            ..Summary::default()
+        }
+    }
+
+    #[test]
+    fn test_row_between() {
+        let before = summary(10, 1000, 10, 0);
+        let after = Summary {
+            underruns: 2,
+            cpu_time: Some(Duration::from_secs(2)),
+            resident_bytes: Some(24_100_000),
+            ..summary(20, 1_921_000, 100, 10)
+        };
+        let row = StatsRow::between(&before, &after);
+        assert_eq!(row.elapsed_s, 20.0);
+        assert_eq!(row.bitrate_kbps, 1536.0);
+        assert_eq!((row.packets_sent, row.send_failures), (90, 10));
+        assert_eq!(row.loss_percent, 10.0);
+        assert_eq!((row.underruns, row.overruns), (2, 0));
+        assert_eq!(row.cpu_percent, None);
+        assert_eq!(row.rss_mb, Some(24.1));
+        let before = Summary {
+            cpu_time: Some(Duration::from_secs(1)),
+            ..before
+        };
+        assert_eq!(StatsRow::between(&before, &after).cpu_percent, Some(10.0));
+        assert_eq!(row.rtt_ms, None);
+    }
+
+    #[test]
+    fn test_session_record() {
+        let summary = Summary {
+            volume_changes: 3,
+            device_errors: 1,
+            ..summary(20, 1_920_000, 100, 2)
+        };
+        let record = SessionRecord::new(&summary);
+        assert_eq!(record.average_bitrate_kbps, 768.0);
+        assert_eq!(
+            (record.send_errors, record.volume_changes, record.device_errors),
+            (2, 3, 1)
+        );
+
+        let path = std::env::temp_dir().join(format!("audio-streamer-summary-{}.json", std::process::id()));
+        record.write(&path).unwrap();
+        let written: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(written["duration_s"], 20.0);
+        assert_eq!(written["packets_sent"], 100);
+        assert_eq!(written["reconnects"], 0);
+        assert!(written["buffer_frames"].is_null());
+        assert!(written["cpu_percent"].is_null());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_append() {
+        let dir = std::env::temp_dir().join(format!("audio-streamer-statsfile-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let row = StatsRow {
+            time: "2026-10-16T03:00:00+02:00".to_string(),
+            elapsed_s: 10.0,
+            bitrate_kbps: 1536.0,
+            packets_sent: 938,
+            rtt_ms: Some(2.5),
+            ..StatsRow::default()
+        };
+
+        let csv = dir.join("stats.csv");
+        StatsFile::open(&csv).unwrap().append(&row).unwrap();
+        StatsFile::open(&csv).unwrap().append(&row).unwrap();
+        let line = "2026-10-16T03:00:00+02:00,10.0,1536.0,938,0,0.00,0.0,0,0,2.500,,,,";
+        assert_eq!(
+            std::fs::read_to_string(&csv).unwrap(),
+            format!("{}\n{}\n{}\n", CSV_HEADER, line, line)
+        );
+        assert_eq!(CSV_HEADER.split(',').count(), line.split(',').count());
+
+        let json = dir.join("stats.jsonl");
+        StatsFile::open(&json).unwrap().append(&row).unwrap();
+        let written: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&json).unwrap()).unwrap();
+        assert_eq!(written["packets_sent"], 938);
+        assert_eq!(written["rtt_ms"], 2.5);
+        assert!(written["end_to_end_ms"].is_null());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,1773 @@
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use futures::Stream;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::codec::{Codec, FrameEncoder, OpusOptions};
+use crate::control::{self, ControlState, Controls, DeviceSwitch, Status};
+use crate::convert;
+use crate::error::{Error, ErrorKind};
+use crate::hook::FrameHooks;
+use crate::latency::LatencyBudget;
+use crate::notifications;
+use crate::realtime;
+use crate::reconnect::Backoff;
+use crate::ring;
+use crate::select_device;
+use crate::sink::{Sink, SinkKind, SinkOptions};
+use crate::stats::{StreamStats, Summary};
+use crate::web::WebStream;
+
+pub const SAMPLE_RATE: u32 = 48000;
+pub const CHANNELS: u16 = 2;
+pub const FRAMES_PER_BUFFER: u32 = 512;
+pub const SERVER_AUDIO_PORT: u16 = 8080;
+
+// How many frames a slow reader of StreamerClient::frames can fall behind
+// before it misses some, about 0.7s at FRAMES_PER_BUFFER.
+const FRAME_BACKLOG: usize = 64;
+// The most one capture buffer holds as 16-bit PCM.
+const BLOCK_BYTES: usize = FRAMES_PER_BUFFER as usize * CHANNELS as usize * 2;
+// Capture buffers the ring to the sender task holds, about 0.3s at
+// FRAMES_PER_BUFFER. More than that and the sender is falling behind.
+const RING_BUFFERS: usize = 32;
+// How long the device callback waits for room in the ring under
+// OverflowPolicy::Block, and how often it looks.
+const OVERFLOW_WAIT: Duration = Duration::from_millis(5);
+const OVERFLOW_POLL: Duration = Duration::from_micros(500);
+// How long a stopped capture waits for its queued audio to be sent.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+// A capture stream that runs this long without failing starts the count of
+// restarts over.
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+/// Restarts of a failing capture stream allowed before giving up, unless
+/// the stream runs cleanly for a minute in between.
+pub const DEFAULT_STREAM_RESTARTS: u32 = 10;
+/// The largest capture buffer, in frames, the stream grows to by default.
+pub const DEFAULT_MAX_BUFFER_FRAMES: u32 = 4096;
+// Callbacks that must miss audio within MISS_WINDOW before the capture
+// buffer grows, so a single hiccup doesn't.
+const MISSES_BEFORE_GROWING: u32 = 3;
+const MISS_WINDOW: Duration = Duration::from_secs(10);
+
+/// Applies the client volume and packs samples as 16-bit little-endian PCM,
+/// replacing what `buffer` held. Reusing the buffer saves allocating.
+pub fn encode_samples(samples: &[f32], vol: f32, buffer: &mut Vec<u8>) {
+    buffer.clear();
+    convert::extend_f32(samples, vol, buffer);
+}
+
+/// [`encode_samples`] for 16-bit input.
+pub fn encode_i16_samples(samples: &[i16], vol: f32, buffer: &mut Vec<u8>) {
+    buffer.clear();
+    convert::extend_i16(samples, vol, buffer);
+}
+
+/// Hands a frame to the sink from the capture path. UDP send errors count
+/// as drops, logged when they start and stop. False once the sink's output
+/// has closed, as when the program reading stdout has gone away. `None` is
+/// a slot skipped by DTX.
+pub fn deliver(sink: &Sink, packet: Option<&[u8]>, stats: &StreamStats) -> bool {
+    match packet {
+        Some(buffer) => record_delivery(sink.try_send(buffer), buffer.len(), stats),
+        None => {
+            sink.skip();
+            true
+        }
+    }
+}
+
+/// [`deliver`] for the sender task: waits for room in the socket rather
+/// than dropping the frame.
+pub async fn deliver_waiting(sink: &Sink, packet: Option<&[u8]>, stats: &StreamStats) -> bool {
+    match packet {
+        Some(buffer) => record_delivery(sink.send(buffer).await, buffer.len(), stats),
+        None => {
+            sink.skip();
+            true
+        }
+    }
+}
+
+fn record_delivery(result: std::io::Result<()>, len: usize, stats: &StreamStats) -> bool {
+    match result {
+        Ok(()) => {
+            stats.record_sent(len);
+            let dropped = stats.end_drops();
+            if dropped > 0 {
+                info!("Sink recovered after {} dropped packets", dropped);
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => return false,
+        Err(e) => {
+            if stats.record_drop() == 1 {
+                warn!("Dropping packets the sink can't send: {}", e);
+            }
+        }
+    }
+    true
+}
+
+/// Captured audio the encoder holds until it has a whole frame.
+pub fn pending_audio(encoder: &FrameEncoder) -> Duration {
+    Duration::from_secs_f64(encoder.pending_frames() as f64 / SAMPLE_RATE as f64)
+}
+
+/// The stream every capture asks its device for, with buffers of `frames`.
+pub fn capture_config(frames: u32) -> cpal::StreamConfig {
+    cpal::StreamConfig {
+        channels: CHANNELS,
+        sample_rate: cpal::SampleRate(SAMPLE_RATE),
+        buffer_size: cpal::BufferSize::Fixed(frames),
+    }
+}
+
+/// The capture buffer size asked of the device. It starts at the size
+/// asked for and doubles, up to a cap, when the stream can't keep to it:
+/// when the device refuses it, or when callbacks keep missing audio.
+#[derive(Debug)]
+pub struct BufferSizer {
+    frames: AtomicU32,
+    max: u32,
+}
+
+impl BufferSizer {
+    /// Starts at `frames`, growing to at most `max`.
+    pub fn new(frames: u32, max: u32) -> Self {
+        Self {
+            frames: AtomicU32::new(frames),
+            max: max.max(frames),
+        }
+    }
+
+    pub fn frames(&self) -> u32 {
+        self.frames.load(Ordering::Relaxed)
+    }
+
+    /// Doubles the size within the cap, returning the new one, or `None`
+    /// once it is at the cap.
+    pub fn grow(&self) -> Option<u32> {
+        let frames = self.frames();
+        if frames >= self.max {
+            return None;
+        }
+        let grown = (frames * 2).min(self.max);
+        self.frames.store(grown, Ordering::Relaxed);
+        Some(grown)
+    }
+
+    // Grows to the smallest buffer `supported` allows, if the cap does.
+    fn fit(&self, supported: &cpal::SupportedBufferSize) {
+        if let cpal::SupportedBufferSize::Range { min, .. } = *supported {
+            if self.frames() < min && min <= self.max {
+                self.frames.store(min, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// [`FRAMES_PER_BUFFER`], growing to at most [`DEFAULT_MAX_BUFFER_FRAMES`].
+impl Default for BufferSizer {
+    fn default() -> Self {
+        Self::new(FRAMES_PER_BUFFER, DEFAULT_MAX_BUFFER_FRAMES)
+    }
+}
+
+// Tells a buffer too small for the host, whose callbacks keep missing
+// audio, from a one-off hiccup.
+#[derive(Default)]
+struct MissCounter {
+    count: u32,
+    since: Option<Instant>,
+}
+
+impl MissCounter {
+    // Notes a callback that missed audio at `now`. True once enough have
+    // within the window, which then starts over.
+    fn missed(&mut self, now: Instant) -> bool {
+        if self.since.is_none_or(|since| now.saturating_duration_since(since) > MISS_WINDOW) {
+            self.since = Some(now);
+            self.count = 0;
+        }
+        self.count += 1;
+        if self.count < MISSES_BEFORE_GROWING {
+            return false;
+        }
+        self.since = None;
+        true
+    }
+}
+
+/// A block of captured audio as the client sends it: after the volume,
+/// as interleaved 16-bit samples.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AudioFrame {
+    /// When the capture callback delivered the block.
+    pub captured: SystemTime,
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub samples: Vec<i16>,
+}
+
+impl AudioFrame {
+    /// A frame from 16-bit little-endian PCM in the client's format.
+    pub fn from_pcm(pcm: &[u8], captured: SystemTime) -> Self {
+        Self {
+            captured,
+            sample_rate: SAMPLE_RATE,
+            channels: CHANNELS,
+            samples: pcm
+                .chunks_exact(2)
+                .map(|pair| i16::from_le_bytes([pair[0], pair[1]]))
+                .collect(),
+        }
+    }
+
+    /// Writes the samples to `pcm` as 16-bit little-endian PCM, replacing
+    /// what it held.
+    pub fn to_pcm(&self, pcm: &mut Vec<u8>) {
+        pcm.clear();
+        pcm.extend(self.samples.iter().flat_map(|sample| sample.to_le_bytes()));
+    }
+
+    /// Samples per channel.
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.channels as usize
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs_f64(self.frames() as f64 / self.sample_rate as f64)
+    }
+}
+
+// Spaces out the blocks the sender task takes from the ring by the audio
+// they hold, so a callback that delivers several at once doesn't put them
+// on the network back to back. It runs slightly faster than the audio, so
+// a device clock a little ahead of ours can't build up a backlog.
+#[derive(Default)]
+struct Pacer {
+    next: Option<tokio::time::Instant>,
+}
+
+impl Pacer {
+    async fn wait(&mut self, bytes: usize) {
+        let now = tokio::time::Instant::now();
+        let due = self.next.filter(|next| *next > now);
+        if let Some(due) = due {
+            tokio::time::sleep_until(due).await;
+        }
+        let frames = bytes / (CHANNELS as usize * 2);
+        let length = Duration::from_secs_f64(frames as f64 / SAMPLE_RATE as f64);
+        self.next = Some(due.unwrap_or(now) + length * 15 / 16);
+    }
+}
+
+/// What a running capture tells the loop in [`run_capture`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CaptureEvent {
+    /// The device under the stream went away.
+    DeviceLost,
+    /// The audio backend reported an error on the stream.
+    StreamFailed,
+    /// The sink's output closed, so there is nothing left to stream to.
+    OutputClosed,
+    /// Callbacks keep missing audio, so the capture buffer should grow.
+    BufferTooSmall,
+}
+
+/// What the device callback does with a block when the queue to the task
+/// that sends it is full, because the network can't keep up.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OverflowPolicy {
+    /// Drop the oldest audio waiting to make room, so what is sent stays current
+    DropOldest,
+    /// Drop the new block, keeping the audio already waiting
+    #[default]
+    DropNewest,
+    /// Hold up the capture for up to 5 ms waiting for room, then drop the new block
+    Block,
+}
+
+/// Everything a capture stream feeds, shared so the stream can be rebuilt
+/// on another device without interrupting the encoder or the listeners.
+#[derive(Clone)]
+pub struct Capture {
+    pub sink: Sink,
+    pub web: Option<Arc<WebStream>>,
+    pub encoder: Arc<Mutex<FrameEncoder>>,
+    pub controls: Arc<Controls>,
+    pub active: Arc<AtomicBool>,
+    pub stats: Arc<StreamStats>,
+    pub events: mpsc::UnboundedSender<CaptureEvent>,
+    /// Gets each block of audio sent, for [`StreamerClient::frames`].
+    pub frames: Option<broadcast::Sender<AudioFrame>>,
+    /// Run on each block before anything else sees it.
+    pub hooks: Arc<FrameHooks>,
+    /// What becomes of a block the device callback has no room to queue.
+    pub overflow: OverflowPolicy,
+    /// The buffer size the stream is built with, grown as it needs.
+    pub buffer: Arc<BufferSizer>,
+    /// The most audio the sender task takes from the ring at once, in
+    /// frames, and so the most a PCM packet carries; at most
+    /// [`FRAMES_PER_BUFFER`].
+    pub packet_frames: usize,
+}
+
+impl Capture {
+    // Counts an underrun when a callback's audio starts well after the end
+    // of the previous callback's, so the host dropped input in between, and
+    // notes the callback's size. Asks for a larger buffer when underruns
+    // keep coming.
+    fn track_timing(
+        &self,
+        last: &mut Option<(cpal::StreamInstant, Duration)>,
+        misses: &mut MissCounter,
+        info: &cpal::InputCallbackInfo,
+        samples: usize,
+    ) {
+        let start = info.timestamp().capture;
+        if let Some((previous, length)) = *last {
+            if start.duration_since(&previous).is_some_and(|gap| gap > length * 3 / 2) {
+                self.stats.record_underrun();
+                if misses.missed(Instant::now()) {
+                    let _ = self.events.send(CaptureEvent::BufferTooSmall);
+                }
+            }
+        }
+        let frames = samples / CHANNELS as usize;
+        self.stats.record_callback(frames);
+        let length = Duration::from_secs_f64(frames as f64 / SAMPLE_RATE as f64);
+        *last = Some((start, length));
+    }
+
+    // Counts an overrun when a callback that began at `began` took longer
+    // than the audio it carried.
+    fn track_duration(&self, began: Instant, samples: usize) {
+        let length = Duration::from_secs_f64(samples as f64 / CHANNELS as f64 / SAMPLE_RATE as f64);
+        if began.elapsed() > length {
+            self.stats.record_overrun();
+        }
+    }
+
+    fn is_sending(&self) -> bool {
+        self.active.load(Ordering::Relaxed) && !self.controls.is_paused()
+    }
+
+    /// Feeds one block of interleaved samples through the capture path at
+    /// once: the volume, the hooks, the listeners and the sink, which is
+    /// sent to without waiting. Nothing is sent while paused or outside
+    /// the schedule. `buffer` holds the converted block; keeping it between
+    /// calls means the path doesn't allocate once it has run a few times.
+    /// The device callback queues its blocks for [`Capture::spawn_sender`]
+    /// instead.
+    pub fn push_samples(&self, data: &[f32], buffer: &mut Vec<u8>) {
+        if !self.is_sending() {
+            return;
+        }
+        encode_samples(data, self.controls.state.effective(), buffer);
+        self.send(buffer);
+    }
+
+    /// [`Capture::push_samples`] for 16-bit input.
+    pub fn push_i16_samples(&self, data: &[i16], buffer: &mut Vec<u8>) {
+        if !self.is_sending() {
+            return;
+        }
+        encode_i16_samples(data, self.controls.state.effective(), buffer);
+        self.send(buffer);
+    }
+
+    /// Applies the volume to a block and queues it for the sender task,
+    /// which does the rest of the capture path. Takes no lock and doesn't
+    /// allocate once `buffer` has grown, so the device callback calls
+    /// this. When the ring is full the overflow policy decides what audio
+    /// is lost, and every frame lost is counted.
+    pub fn queue_samples(&self, data: &[f32], buffer: &mut Vec<u8>, ring: &mut ring::Producer) {
+        if !self.is_sending() {
+            return;
+        }
+        encode_samples(data, self.controls.state.effective(), buffer);
+        self.queue(buffer, ring);
+    }
+
+    /// [`Capture::queue_samples`] for 16-bit input.
+    pub fn queue_i16_samples(&self, data: &[i16], buffer: &mut Vec<u8>, ring: &mut ring::Producer) {
+        if !self.is_sending() {
+            return;
+        }
+        encode_i16_samples(data, self.controls.state.effective(), buffer);
+        self.queue(buffer, ring);
+    }
+
+    fn queue(&self, buffer: &[u8], ring: &mut ring::Producer) {
+        let dropped = match self.overflow {
+            OverflowPolicy::DropOldest => ring.push_evicting(buffer),
+            OverflowPolicy::DropNewest if ring.push(buffer) => 0,
+            OverflowPolicy::DropNewest => buffer.len(),
+            OverflowPolicy::Block => {
+                let deadline = Instant::now() + OVERFLOW_WAIT;
+                loop {
+                    if ring.push(buffer) {
+                        break 0;
+                    }
+                    if Instant::now() >= deadline {
+                        break buffer.len();
+                    }
+                    std::thread::sleep(OVERFLOW_POLL);
+                }
+            }
+        };
+        if dropped > 0 {
+            self.stats.record_queue_drop(dropped / (CHANNELS as usize * 2));
+        }
+    }
+
+    /// Starts the task that sends what the device callback queues, and
+    /// returns the ring to queue into. The task hooks, encodes and sends
+    /// each block, waiting out socket backpressure rather than dropping
+    /// packets, and ends once the ring is dropped and drained, sending any
+    /// batch the sink still holds. Must be called on a Tokio runtime.
+    pub fn spawn_sender(&self) -> (ring::Producer, JoinHandle<()>) {
+        let (producer, mut consumer) = ring::channel(BLOCK_BYTES * RING_BUFFERS);
+        let packet_bytes = self.packet_frames.clamp(1, FRAMES_PER_BUFFER as usize) * CHANNELS as usize * 2;
+        let capture = self.clone();
+        let sender = tokio::spawn(
+            async move {
+                let mut buffer = Vec::with_capacity(BLOCK_BYTES);
+                let mut packets = Vec::new();
+                let mut pacer = capture.sink.is_paced().then(Pacer::default);
+                let packet_frames = packet_bytes / (CHANNELS as usize * 2);
+                let packet_length = Duration::from_secs_f64(packet_frames as f64 / SAMPLE_RATE as f64);
+                loop {
+                    // Blocks go in whole and are a multiple of the frame
+                    // size, so taking at most a packet keeps the channels
+                    // aligned.
+                    let pop = consumer.pop(&mut buffer, packet_bytes);
+                    let popped = match capture.sink.batch_due(packet_length) {
+                        Some(due) => tokio::time::timeout_at(due.into(), pop).await,
+                        None => Ok(pop.await),
+                    };
+                    match popped {
+                        Ok(true) => {}
+                        Ok(false) => break,
+                        // A partly filled batch has waited as long as a
+                        // full one takes, so it goes out as it is.
+                        Err(_) => {
+                            if let Err(e) = capture.sink.flush().await {
+                                warn!("Error sending a partly filled batch: {}", e);
+                            }
+                            continue;
+                        }
+                    }
+                    if let Some(pacer) = &mut pacer {
+                        pacer.wait(buffer.len()).await;
+                    }
+                    if !capture.send_waiting(&mut buffer, &mut packets).await {
+                        return;
+                    }
+                }
+                if let Err(e) = capture.sink.flush().await {
+                    warn!("Error sending the last packets: {}", e);
+                }
+            }
+            .instrument(info_span!("sender")),
+        );
+        (producer, sender)
+    }
+
+    // The hooks and the listeners, which see each block before it is
+    // encoded.
+    fn prepare(&self, buffer: &mut Vec<u8>) {
+        self.hooks.process_pcm(buffer);
+        if let Some(web) = &self.web {
+            web.push(buffer);
+        }
+        if let Some(frames) = self.frames.as_ref().filter(|frames| frames.receiver_count() > 0) {
+            let _ = frames.send(AudioFrame::from_pcm(buffer, SystemTime::now()));
+        }
+        self.stats.record_levels(buffer);
+    }
+
+    fn send(&self, buffer: &mut Vec<u8>) {
+        if buffer.is_empty() {
+            return;
+        }
+        self.prepare(buffer);
+        let mut encoder = self.encoder.lock().unwrap();
+        encoder.encode(buffer, |packet| {
+            if !deliver(&self.sink, packet, &self.stats) {
+                let _ = self.events.send(CaptureEvent::OutputClosed);
+            }
+        });
+        self.stats.record_buffered(pending_audio(&encoder));
+    }
+
+    // Sends a block from the sender task, with `packets` to gather the
+    // encoder's output in so the lock isn't held across the sends. False
+    // once the sink's output has closed.
+    async fn send_waiting(&self, buffer: &mut Vec<u8>, packets: &mut Vec<Option<Vec<u8>>>) -> bool {
+        self.prepare(buffer);
+        {
+            let mut encoder = self.encoder.lock().unwrap();
+            encoder.encode(buffer, |packet| packets.push(packet.map(<[u8]>::to_vec)));
+            self.stats.record_buffered(pending_audio(&encoder));
+        }
+        for packet in packets.drain(..) {
+            if !deliver_waiting(&self.sink, packet.as_deref(), &self.stats).await {
+                let _ = self.events.send(CaptureEvent::OutputClosed);
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Builds a stopped input stream on `device` that feeds `capture`. Must be
+/// called on a Tokio runtime, which runs the stream's sender task.
+pub fn build_capture(device: &cpal::Device, capture: &Capture) -> Result<cpal::Stream, Box<dyn std::error::Error>> {
+    build_capture_with_sender(device, capture).map(|(stream, _sender)| stream)
+}
+
+/// [`build_capture`], with the stream's sender task, which finishes
+/// sending once the stream is dropped. The buffer grows to the smallest
+/// the device supports, and on past sizes it refuses, within the cap.
+pub fn build_capture_with_sender(
+    device: &cpal::Device,
+    capture: &Capture,
+) -> Result<(cpal::Stream, JoinHandle<()>), Box<dyn std::error::Error>> {
+    let supported = device.default_input_config()?;
+    capture.buffer.fit(supported.buffer_size());
+    loop {
+        let frames = capture.buffer.frames();
+        match build_stream(device, capture, supported.sample_format(), frames) {
+            Ok(built) => {
+                capture.stats.record_buffer_frames(frames);
+                return Ok(built);
+            }
+            Err(e) if is_unsupported_config(e.as_ref()) => {
+                let grown = capture.buffer.grow().ok_or(e)?;
+                warn!("The device refused {}-frame capture buffers; trying {}", frames, grown);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Whether the device refused the stream's configuration, which for the
+// client's fixed format and rate usually means the buffer size.
+fn is_unsupported_config(e: &(dyn std::error::Error + 'static)) -> bool {
+    matches!(e.downcast_ref::<cpal::BuildStreamError>(), Some(cpal::BuildStreamError::StreamConfigNotSupported))
+}
+
+fn build_stream(
+    device: &cpal::Device,
+    capture: &Capture,
+    sample_format: cpal::SampleFormat,
+    frames: u32,
+) -> Result<(cpal::Stream, JoinHandle<()>), Box<dyn std::error::Error>> {
+    let config = capture_config(frames);
+    let events = capture.events.clone();
+    let stats = capture.stats.clone();
+    // The callbacks run on the host's audio thread, so each enters the span.
+    let span = info_span!("capture", device = %device.name().unwrap_or_default());
+    let err_span = span.clone();
+    let err_fn = move |err: cpal::StreamError| {
+        let _entered = err_span.enter();
+        warn!("Stream error: {}", err);
+        stats.record_device_error();
+        let event = match err {
+            cpal::StreamError::DeviceNotAvailable => CaptureEvent::DeviceLost,
+            _ => CaptureEvent::StreamFailed,
+        };
+        let _ = events.send(event);
+    };
+    let capture = capture.clone();
+    let mut last = None;
+    let mut misses = MissCounter::default();
+    let mut promoted = false;
+    // Sized for the buffers asked for; the callbacks only grow it if the
+    // host hands them more.
+    let mut buffer = Vec::with_capacity(frames as usize * CHANNELS as usize * 2);
+    // The callbacks only convert and queue; the network is the sender's.
+    let (mut ring, sender) = capture.spawn_sender();
+
+    let stream = match sample_format {
+        cpal::SampleFormat::F32 => device.build_input_stream(
+            &config,
+            move |data: &[f32], info: &cpal::InputCallbackInfo| {
+                realtime::promote_once(&mut promoted);
+                let began = Instant::now();
+                capture.track_timing(&mut last, &mut misses, info, data.len());
+                let _entered = span.enter();
+                capture.queue_samples(data, &mut buffer, &mut ring);
+                capture.track_duration(began, data.len());
+            },
+            err_fn,
+            None,
+        )?,
+        cpal::SampleFormat::I16 => device.build_input_stream(
+            &config,
+            move |data: &[i16], info: &cpal::InputCallbackInfo| {
+                realtime::promote_once(&mut promoted);
+                let began = Instant::now();
+                capture.track_timing(&mut last, &mut misses, info, data.len());
+                let _entered = span.enter();
+                capture.queue_i16_samples(data, &mut buffer, &mut ring);
+                capture.track_duration(began, data.len());
+            },
+            err_fn,
+            None,
+        )?,
+        _ => {
+            let message = format!("Unsupported sample format: {:?}", sample_format);
+            return Err(Error::new(ErrorKind::Device, message).into());
+        }
+    };
+    Ok((stream, sender))
+}
+
+/// Builds a stopped input stream on the input device called `name`.
+pub fn open_capture(
+    host: &cpal::Host,
+    name: &str,
+    capture: &Capture,
+) -> Result<cpal::Stream, Box<dyn std::error::Error>> {
+    let devices: Vec<_> = host.devices()?.collect();
+    let device = select_device(&devices, None, Some(name)).ok_or("no such input device")?;
+    build_capture(device, capture)
+}
+
+// Moves the capture to the requested device and reports the result to the
+// controller. The current stream keeps running if the new device cannot be
+// opened.
+fn switch_capture(host: &cpal::Host, switch: DeviceSwitch, capture: &Capture, stream: &mut cpal::Stream) {
+    let result = open_capture(host, &switch.name, capture).and_then(|new_stream| {
+        // Replacing the old stream drops and so stops it.
+        *stream = new_stream;
+        if !capture.controls.is_paused() {
+            stream.play()?;
+        }
+        Ok(())
+    });
+    if result.is_ok() {
+        info!("Using audio input: {}", switch.name);
+        capture.controls.set_device(&switch.name);
+    }
+    switch.finish(result.map_err(|e| e.to_string()));
+}
+
+// Rebuilds the stream on its device with a larger capture buffer, within
+// the cap, after its callbacks kept missing audio. The current stream keeps
+// running if the new one can't be built.
+fn grow_capture(host: &cpal::Host, capture: &Capture, stream: &mut cpal::Stream) {
+    let Some(frames) = capture.buffer.grow() else {
+        return;
+    };
+    let device = capture.controls.report().device;
+    warn!("Capture callbacks keep missing audio; growing the buffer to {} frames", frames);
+    let result = open_capture(host, &device, capture).and_then(|new_stream| {
+        *stream = new_stream;
+        if !capture.controls.is_paused() {
+            stream.play()?;
+        }
+        Ok(())
+    });
+    if let Err(e) = result {
+        warn!("Could not rebuild the capture stream on {}: {}", device, e);
+    }
+}
+
+// Rebuilds the capture stream after it failed: on the same device unless
+// that one went away, and otherwise on the first device in `names` that
+// opens, or the automatic choice without names. False if none opened.
+fn restart_capture(
+    host: &cpal::Host,
+    names: &[String],
+    capture: &Capture,
+    stream: &mut cpal::Stream,
+    lost: bool,
+) -> bool {
+    let current = capture.controls.report().device;
+    let mut candidates = if lost { Vec::new() } else { vec![current.clone()] };
+    if names.is_empty() {
+        let devices: Vec<_> = host.devices().map(|devices| devices.collect()).unwrap_or_default();
+        candidates.extend(select_device(&devices, None, None).and_then(|device| device.name().ok()));
+    } else {
+        candidates.extend(names.iter().cloned());
+    }
+    let mut tried = Vec::new();
+    for name in candidates {
+        if tried.contains(&name) || (lost && name == current) {
+            continue;
+        }
+        tried.push(name.clone());
+        match open_capture(host, &name, capture) {
+            Ok(new_stream) => {
+                // Replacing the failed stream drops it.
+                *stream = new_stream;
+                if !capture.controls.is_paused() {
+                    if let Err(e) = stream.play() {
+                        error!("Error starting audio input {}: {}", name, e);
+                        continue;
+                    }
+                }
+                info!("Using audio input: {}", name);
+                capture.controls.set_device(&name);
+                capture.stats.record_reconnect();
+                if name != current {
+                    notifications::show(
+                        "Audio input lost",
+                        &format!("{} went away; now capturing from {}", current, name),
+                    );
+                }
+                return true;
+            }
+            Err(e) => warn!("Could not open audio input {}: {}", name, e),
+        }
+    }
+    false
+}
+
+/// Limits how often [`run_capture`] rebuilds a failing capture stream, so
+/// a driver that keeps failing ends the capture rather than restarting it
+/// forever. While no device opens, the attempts are spaced out further
+/// each time, from a second up to half a minute, and only the failure
+/// that started them counts as a restart, so an unplugged interface is
+/// waited for however long it stays away.
+pub struct StreamWatchdog {
+    limit: u32,
+    restarts: u32,
+    last: Option<Instant>,
+    backoff: Backoff,
+}
+
+impl StreamWatchdog {
+    /// Allows `limit` restarts in a row; a stream that runs cleanly for a
+    /// minute starts the count over.
+    pub fn new(limit: u32) -> Self {
+        Self {
+            limit,
+            restarts: 0,
+            last: None,
+            backoff: Backoff::new(Duration::from_secs(1), Duration::from_secs(30)),
+        }
+    }
+
+    /// Counts a restart at `now`, or returns false once the limit is used up.
+    pub fn restart(&mut self, now: Instant) -> bool {
+        if self.last.is_some_and(|last| now.saturating_duration_since(last) >= STABLE_AFTER) {
+            self.restarts = 0;
+        }
+        self.last = Some(now);
+        self.restarts += 1;
+        self.restarts <= self.limit
+    }
+
+    /// How long to wait before the next attempt, after one found no device.
+    pub fn retry_delay(&mut self) -> Duration {
+        self.backoff.next_delay()
+    }
+
+    /// The stream is running again.
+    pub fn recovered(&mut self) {
+        self.backoff.reset();
+    }
+}
+
+impl Default for StreamWatchdog {
+    fn default() -> Self {
+        Self::new(DEFAULT_STREAM_RESTARTS)
+    }
+}
+
+/// The first of `names` that is present, or the device the index or the
+/// automatic choice gives without names.
+pub fn first_available<'a>(
+    devices: &'a [cpal::Device],
+    index: Option<usize>,
+    names: &[String],
+) -> Option<&'a cpal::Device> {
+    if index.is_some() || names.is_empty() {
+        return select_device(devices, index, None);
+    }
+    names.iter().find_map(|name| {
+        let device = select_device(devices, None, Some(name));
+        if device.is_none() {
+            warn!("Audio input {} not found", name);
+        }
+        device
+    })
+}
+
+/// Keeps `stream` capturing until `stop` resolves or the sink's output
+/// closes: it moves to the device each switch asks for and stops the
+/// stream while the controls are paused. When the stream fails it is torn
+/// down and rebuilt, on another of `names` if its device went away, for as
+/// long as `watchdog` allows; past that the capture ends with an error.
+#[allow(clippy::too_many_arguments)]
+pub async fn run_capture(
+    host: &cpal::Host,
+    names: &[String],
+    capture: &Capture,
+    stream: &mut cpal::Stream,
+    events: &mut mpsc::UnboundedReceiver<CaptureEvent>,
+    device_switches: &mut mpsc::Receiver<DeviceSwitch>,
+    mut watchdog: StreamWatchdog,
+    stop: impl Future<Output = ()>,
+) -> Result<(), Error> {
+    let mut paused = capture.controls.watch_paused();
+    // When to try again while the stream is down and no device opened.
+    let mut retry: Option<tokio::time::Instant> = None;
+    tokio::pin!(stop);
+    loop {
+        let failure = tokio::select! {
+            _ = &mut stop => break,
+            Some(switch) = device_switches.recv() => {
+                switch_capture(host, switch, capture, stream);
+                continue;
+            }
+            Some(event) = events.recv() => match event {
+                CaptureEvent::OutputClosed => {
+                    info!("Output closed, stopping.");
+                    break;
+                }
+                // Only the timer restarts a stream that is already down.
+                _ if retry.is_some() => continue,
+                CaptureEvent::BufferTooSmall => {
+                    grow_capture(host, capture, stream);
+                    continue;
+                }
+                failure => Some(failure),
+            },
+            _ = tokio::time::sleep_until(retry.unwrap_or_else(tokio::time::Instant::now)), if retry.is_some() => None,
+            Ok(()) = paused.changed() => {
+                let result = if *paused.borrow_and_update() { stream.pause() } else { stream.play() };
+                // The callbacks also check the paused state, so a host that
+                // cannot stop its stream still sends nothing.
+                if let Err(e) = result {
+                    error!("Error pausing or resuming the capture stream: {}", e);
+                }
+                continue;
+            }
+        };
+        // A failing stream tends to report it more than once.
+        let mut closed = false;
+        while let Ok(event) = events.try_recv() {
+            closed |= event == CaptureEvent::OutputClosed;
+        }
+        if closed {
+            info!("Output closed, stopping.");
+            break;
+        }
+        let lost = failure == Some(CaptureEvent::DeviceLost);
+        match failure {
+            Some(CaptureEvent::DeviceLost) => {
+                warn!("Audio input {} is no longer available", capture.controls.report().device)
+            }
+            Some(_) => warn!("Restarting the capture stream after an error"),
+            None => {}
+        }
+        // Retries on the timer are still the same restart.
+        if failure.is_some() && !watchdog.restart(Instant::now()) {
+            let message = format!("The capture stream kept failing after {} restarts; giving up", watchdog.limit);
+            return Err(Error::new(ErrorKind::Device, message));
+        }
+        if restart_capture(host, names, capture, stream, lost) {
+            retry = None;
+            watchdog.recovered();
+            continue;
+        }
+        let delay = watchdog.retry_delay();
+        if retry.is_none() {
+            let device = capture.controls.report().device;
+            notifications::show(
+                "Audio input lost",
+                &format!("{} failed and no audio input could be opened", device),
+            );
+        }
+        warn!("No audio input could be opened; trying again in {}", humantime::format_duration(delay));
+        retry = Some(tokio::time::Instant::now() + delay);
+    }
+    Ok(())
+}
+
+/// Ends a capture for good once [`run_capture`] returns. The stream stops
+/// and its sender task sends the blocks still queued, within a couple of
+/// seconds. The audio the encoder holds back for a whole frame follows,
+/// and then the sink announces the end of the stream, so a receiver fades
+/// out and frees the session instead of waiting on a stream that paused.
+pub async fn finish_capture(
+    capture: Capture,
+    stream: cpal::Stream,
+    events: &mut mpsc::UnboundedReceiver<CaptureEvent>,
+) {
+    let (sink, encoder, stats) = (capture.sink.clone(), capture.encoder.clone(), capture.stats.clone());
+    // Each sender task holds a Capture, and so a sender of `events`, until
+    // it has sent the last of its ring.
+    drop(stream);
+    drop(capture);
+    let drained = tokio::time::timeout(DRAIN_TIMEOUT, async { while events.recv().await.is_some() {} }).await;
+    if drained.is_err() {
+        warn!("Gave up sending the last of the captured audio");
+    }
+    end_stream(&sink, &encoder, &stats).await;
+}
+
+/// Sends what `encoder` still holds and announces the end of the stream.
+pub async fn end_stream(sink: &Sink, encoder: &Mutex<FrameEncoder>, stats: &StreamStats) {
+    let mut packets = Vec::new();
+    encoder.lock().unwrap().finish(|packet| packets.push(packet.map(<[u8]>::to_vec)));
+    for packet in packets {
+        deliver_waiting(sink, packet.as_deref(), stats).await;
+    }
+    if let Err(e) = sink.announce_end().await {
+        warn!("Error announcing the end of the stream: {}", e);
+    }
+}
+
+/// Sets up a [`StreamerClient`]: which input to capture, where to send it
+/// and how to encode it. Without a device it captures from a loopback
+/// input if there is one, and without a destination it streams to a
+/// server on this machine.
+///
+/// ```no_run
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// use audio_client::codec::Codec;
+/// use audio_client::StreamerClient;
+///
+/// let client = StreamerClient::builder()
+///     .device("BlackHole 2ch")
+///     .destination("192.168.1.20:8080")
+///     .codec(Codec::Opus)
+///     .spawn()
+///     .await?;
+/// client.set_volume(0.5).await;
+/// let summary = client.shutdown().await;
+/// println!("{}", summary);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct StreamerClientBuilder {
+    devices: Vec<String>,
+    destination: String,
+    sink: SinkKind,
+    sink_options: SinkOptions,
+    codec: Codec,
+    opus_options: OpusOptions,
+    volume: f32,
+    muted: bool,
+    max_stream_restarts: u32,
+    overflow: OverflowPolicy,
+    buffer_frames: u32,
+    max_buffer_frames: u32,
+    packet_frames: u32,
+    hooks: Arc<FrameHooks>,
+}
+
+impl Default for StreamerClientBuilder {
+    fn default() -> Self {
+        Self {
+            devices: Vec::new(),
+            destination: format!("127.0.0.1:{}", SERVER_AUDIO_PORT),
+            sink: SinkKind::Udp,
+            sink_options: SinkOptions::default(),
+            codec: Codec::Pcm,
+            opus_options: OpusOptions::default(),
+            volume: 1.0,
+            muted: false,
+            max_stream_restarts: DEFAULT_STREAM_RESTARTS,
+            overflow: OverflowPolicy::default(),
+            buffer_frames: FRAMES_PER_BUFFER,
+            max_buffer_frames: DEFAULT_MAX_BUFFER_FRAMES,
+            packet_frames: FRAMES_PER_BUFFER,
+            hooks: Arc::default(),
+        }
+    }
+}
+
+impl StreamerClientBuilder {
+    /// Captures from the input device with this name. Naming more than one
+    /// uses the first that is present, and the others when it goes away.
+    pub fn device(mut self, name: impl Into<String>) -> Self {
+        self.devices.push(name.into());
+        self
+    }
+
+    /// The server's audio address, as host:port.
+    pub fn destination(mut self, addr: impl Into<String>) -> Self {
+        self.destination = addr.into();
+        self
+    }
+
+    /// Where the audio goes, as for --sink. The default sends it over UDP
+    /// to the destination.
+    pub fn sink(mut self, sink: SinkKind) -> Self {
+        self.sink = sink;
+        self
+    }
+
+    pub fn sink_options(mut self, options: SinkOptions) -> Self {
+        self.sink_options = options;
+        self
+    }
+
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    pub fn opus_options(mut self, options: OpusOptions) -> Self {
+        self.opus_options = options;
+        self
+    }
+
+    /// The starting volume, from 0.0 to 1.0.
+    pub fn volume(mut self, level: f32) -> Self {
+        self.volume = level;
+        self
+    }
+
+    pub fn muted(mut self, muted: bool) -> Self {
+        self.muted = muted;
+        self
+    }
+
+    /// How many times in a row a failing capture stream is rebuilt before
+    /// the capture stops (default: 10).
+    pub fn max_stream_restarts(mut self, limit: u32) -> Self {
+        self.max_stream_restarts = limit;
+        self
+    }
+
+    /// What to drop when the network can't keep up with the capture
+    /// (default: the newest audio).
+    pub fn overflow_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.overflow = policy;
+        self
+    }
+
+    /// The capture buffer to ask the device for, in frames (default: 512).
+    pub fn buffer_frames(mut self, frames: u32) -> Self {
+        self.buffer_frames = frames;
+        self
+    }
+
+    /// The largest the capture buffer grows to when the device refuses a
+    /// size or callbacks keep missing audio (default: 4096 frames).
+    pub fn max_buffer_frames(mut self, frames: u32) -> Self {
+        self.max_buffer_frames = frames;
+        self
+    }
+
+    /// Sizes the capture buffer and the packets from a budget for the
+    /// whole pipeline; the receiver takes the budget's jitter buffering.
+    pub fn latency(mut self, budget: LatencyBudget) -> Self {
+        self.buffer_frames = budget.buffer_frames;
+        self.packet_frames = budget.packet_frames;
+        self
+    }
+
+    /// Runs `hook` on each block of audio before it is sent, after the
+    /// volume and any hooks added before it. The hook runs on the task
+    /// that sends the audio, so it should not block.
+    pub fn hook(self, hook: impl FnMut(&mut AudioFrame) + Send + 'static) -> Self {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+
+    /// Connects the sink and starts capturing. The capture stream lives on
+    /// a thread of its own, since audio hosts don't let streams move
+    /// between threads, and runs until the returned client shuts it down
+    /// or is dropped.
+    pub async fn spawn(self) -> Result<StreamerClient, Box<dyn std::error::Error>> {
+        if !(0.0..=1.0).contains(&self.volume) {
+            return Err(Error::new(ErrorKind::Usage, "Volume must be between 0.0 and 1.0").into());
+        }
+        if self.codec != Codec::Pcm && self.sink.is_raw_pcm() {
+            let message = format!("The {} sink takes raw PCM; use the pcm codec", self.sink);
+            return Err(Error::new(ErrorKind::Usage, message).into());
+        }
+        let encoder = FrameEncoder::new(self.codec, SAMPLE_RATE, CHANNELS, self.opus_options)?;
+        let sink = Sink::connect(self.sink, &self.destination, self.sink_options).await?;
+        let stats = Arc::new(StreamStats::default());
+        let mut controls = Controls::new(ControlState::new(self.volume), sink.clone(), stats.clone());
+        controls.state.set_muted(self.muted);
+        let mut device_switches = controls.accept_device_switches();
+        let controls = Arc::new(controls);
+        let follow = controls.clone();
+        tokio::spawn(async move { follow.follow_transport().await });
+        let (events, mut capture_events) = mpsc::unbounded_channel();
+        let (frames, _) = broadcast::channel(FRAME_BACKLOG);
+        let capture = Capture {
+            sink,
+            web: None,
+            encoder: Arc::new(Mutex::new(encoder)),
+            controls: controls.clone(),
+            active: Arc::new(AtomicBool::new(true)),
+            stats: stats.clone(),
+            events,
+            frames: Some(frames.clone()),
+            hooks: self.hooks.clone(),
+            overflow: self.overflow,
+            buffer: Arc::new(BufferSizer::new(self.buffer_frames, self.max_buffer_frames)),
+            packet_frames: self.packet_frames as usize,
+        };
+
+        let (opened, ready) = oneshot::channel();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let names = self.devices;
+        let watchdog = StreamWatchdog::new(self.max_stream_restarts);
+        let runtime = tokio::runtime::Handle::current();
+        let thread = std::thread::spawn(move || {
+            // The streams' sender tasks run on the caller's runtime.
+            let _runtime = runtime.enter();
+            let host = cpal::default_host();
+            let stream = host
+                .devices()
+                .map_err(|e| Box::new(e) as Box<dyn std::error::Error>)
+                .and_then(|devices| {
+                    let devices: Vec<_> = devices.collect();
+                    let device = first_available(&devices, None, &names)
+                        .ok_or_else(|| Error::new(ErrorKind::NoDevice, "No suitable input device found"))?;
+                    let name = device.name()?;
+                    let stream = build_capture(device, &capture)?;
+                    stream.play()?;
+                    Ok((name, stream))
+                });
+            let mut stream = match stream {
+                Ok((name, stream)) => {
+                    info!("Using audio input: {}", name);
+                    capture.controls.set_device(&name);
+                    let _ = opened.send(Ok(()));
+                    stream
+                }
+                Err(e) => {
+                    let _ = opened.send(Err(Error::new(ErrorKind::of(e.as_ref()), e.to_string())));
+                    return;
+                }
+            };
+            let stop = async {
+                // Dropping the client stops the capture as well.
+                let _ = stopped.await;
+            };
+            runtime.block_on(async {
+                let captured = run_capture(
+                    &host,
+                    &names,
+                    &capture,
+                    &mut stream,
+                    &mut capture_events,
+                    &mut device_switches,
+                    watchdog,
+                    stop,
+                )
+                .await;
+                if let Err(e) = captured {
+                    error!("{}", e);
+                }
+                finish_capture(capture, stream, &mut capture_events).await;
+            });
+        });
+        match ready.await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) => return Err("The capture thread stopped before starting".into()),
+        }
+        Ok(StreamerClient {
+            controls,
+            stats,
+            frames,
+            hooks: self.hooks,
+            started: Instant::now(),
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+/// A running streamer, for programs that embed the client rather than
+/// run it. Volume and mute go through the same controls as the control
+/// channel, so they count in the session summary too.
+pub struct StreamerClient {
+    controls: Arc<Controls>,
+    stats: Arc<StreamStats>,
+    frames: broadcast::Sender<AudioFrame>,
+    hooks: Arc<FrameHooks>,
+    started: Instant,
+    stop: Option<oneshot::Sender<()>>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl StreamerClient {
+    pub fn builder() -> StreamerClientBuilder {
+        StreamerClientBuilder::default()
+    }
+
+    /// Sets the volume, from 0.0 to 1.0. Anything else is rejected.
+    pub async fn set_volume(&self, level: f32) -> Status {
+        self.controls.apply(control::Message::SetVolume(level)).await
+    }
+
+    pub fn volume(&self) -> f32 {
+        self.controls.state.level()
+    }
+
+    pub async fn set_muted(&self, muted: bool) -> Status {
+        self.controls.apply(control::Message::Mute(muted)).await
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.controls.state.is_muted()
+    }
+
+    /// The controls the client runs with, to serve them over IPC, OSC or
+    /// MQTT as the command-line client does, or to pause and switch
+    /// devices.
+    pub fn controls(&self) -> Arc<Controls> {
+        self.controls.clone()
+    }
+
+    pub fn stats(&self) -> Arc<StreamStats> {
+        self.stats.clone()
+    }
+
+    /// Adds a hook to the running capture, after those already there.
+    pub fn add_hook(&self, hook: impl FnMut(&mut AudioFrame) + Send + 'static) {
+        self.hooks.push(Box::new(hook));
+    }
+
+    /// The audio from here on, block by block as it is sent. Nothing is
+    /// captured while paused, and muted audio arrives as silence. A reader
+    /// that falls behind skips the blocks it missed, and the stream ends
+    /// once the client is shut down or dropped.
+    pub fn frames(&self) -> impl Stream<Item = AudioFrame> {
+        futures::stream::unfold(self.frames.subscribe(), |mut frames| async move {
+            loop {
+                match frames.recv().await {
+                    Ok(frame) => return Some((frame, frames)),
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("Frame reader fell behind and skipped {} blocks", skipped)
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+
+    /// The session summary so far.
+    pub fn summary(&self) -> Summary {
+        self.stats.summary(self.started.elapsed())
+    }
+
+    /// Stops the capture and waits for its thread to finish, returning the
+    /// session summary.
+    pub async fn shutdown(mut self) -> Summary {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = tokio::task::spawn_blocking(move || thread.join()).await;
+        }
+        self.summary()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SyncedClock;
+    use crate::codec::FrameDecoder;
+    use crate::jitter::JitterBuffer;
+    use crate::packet;
+    use crate::playback::{self, Concealment, PlaybackCursor};
+    use crate::transport::{AudioTransport, MemoryPeer, MemoryTransport, TransportStats};
+    use futures::future::BoxFuture;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::io;
+
+    // The whole path in one process: samples in as from a device, through
+    // the volume, hooks, encoder and packetizer, over a MemoryTransport,
+    // and out of a receiver's jitter buffer and playback cursor. Protocol
+    // changes get their end-to-end tests here. Packets reach the receiver
+    // in one burst, so it holds `depth` of them before playing rather than
+    // trimming the burst as excess latency.
+    struct Loopback {
+        capture: Capture,
+        peer: MemoryPeer,
+        buffer: Mutex<JitterBuffer>,
+        clock: SyncedClock,
+        cursor: PlaybackCursor,
+        _events: mpsc::UnboundedReceiver<CaptureEvent>,
+    }
+
+    // A capture feeding `sink` with `codec`, as a device callback would.
+    fn capture(sink: Sink, codec: Codec) -> (Capture, mpsc::UnboundedReceiver<CaptureEvent>) {
+        let stats = Arc::new(StreamStats::default());
+        let (events, receiver) = mpsc::unbounded_channel();
+        let capture = Capture {
+            sink: sink.clone(),
+            web: None,
+            encoder: Arc::new(Mutex::new(
+                FrameEncoder::new(codec, SAMPLE_RATE, CHANNELS, OpusOptions::default()).unwrap(),
+            )),
+            controls: Arc::new(Controls::new(ControlState::new(1.0), sink, stats.clone())),
+            active: Arc::new(AtomicBool::new(true)),
+            stats,
+            events,
+            frames: None,
+            hooks: Arc::default(),
+            overflow: OverflowPolicy::default(),
+            buffer: Arc::default(),
+            packet_frames: FRAMES_PER_BUFFER as usize,
+        };
+        (capture, receiver)
+    }
+
+    impl Loopback {
+        fn new(codec: Codec, options: SinkOptions, depth: usize) -> Self {
+            let (transport, peer) = MemoryTransport::pair(64);
+            let (capture, _events) = capture(Sink::over(Arc::new(transport), &options), codec);
+            let frame_frames = codec.frame_frames().unwrap_or(FRAMES_PER_BUFFER as usize);
+            let frame_duration = Duration::from_secs_f64(frame_frames as f64 / SAMPLE_RATE as f64);
+            let decoder = FrameDecoder::new(codec, SAMPLE_RATE, CHANNELS, OpusOptions::default()).unwrap();
+            Self {
+                capture,
+                peer,
+                buffer: Mutex::new(JitterBuffer::new(frame_duration, frame_duration * depth as u32)),
+                clock: SyncedClock::new(),
+                cursor: PlaybackCursor::new(
+                    frame_frames * CHANNELS as usize,
+                    CHANNELS as usize,
+                    Concealment::Silence,
+                    decoder,
+                ),
+                _events,
+            }
+        }
+
+        // Hands the receiver every packet sent so far, except those whose
+        // sequence numbers `lost` picks, and returns how many it got.
+        fn deliver(&mut self, lost: impl Fn(u32) -> bool) -> usize {
+            let mut delivered = 0;
+            while let Some(datagram) = self.peer.try_recv_frame() {
+                if packet::decode(&datagram).is_some_and(|packet| lost(packet.seq)) {
+                    continue;
+                }
+                playback::accept(&datagram, &self.buffer, &self.clock);
+                delivered += 1;
+            }
+            delivered
+        }
+
+        fn push(&self, blocks: &[Vec<f32>]) {
+            let mut buffer = Vec::new();
+            blocks
+                .iter()
+                .for_each(|block| self.capture.push_samples(block, &mut buffer));
+        }
+
+        fn play(&mut self, frames: usize) -> Vec<i16> {
+            let mut out = vec![0; frames * CHANNELS as usize];
+            self.cursor.fill_i16(&self.buffer, &mut out);
+            out
+        }
+    }
+
+    // Device-sized blocks of a stereo sine, left and right in opposite phase.
+    fn sine_blocks(count: usize) -> Vec<Vec<f32>> {
+        let frames = FRAMES_PER_BUFFER as usize;
+        (0..count)
+            .map(|block| {
+                (0..frames)
+                    .flat_map(|i| {
+                        let t = (block * frames + i) as f32 / SAMPLE_RATE as f32;
+                        let sample = 0.5 * (2.0 * std::f32::consts::PI * 440.0 * t).sin();
+                        [sample, -sample]
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn expected(blocks: &[Vec<f32>], vol: f32) -> Vec<i16> {
+        let mut pcm = Vec::new();
+        blocks
+            .iter()
+            .flat_map(|block| {
+                encode_samples(block, vol, &mut pcm);
+                AudioFrame::from_pcm(&pcm, SystemTime::UNIX_EPOCH).samples
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_loopback_pcm() {
+        let mut loopback = Loopback::new(Codec::Pcm, SinkOptions::default(), 4);
+        let blocks = sine_blocks(4);
+        loopback.push(&blocks);
+        assert_eq!(loopback.deliver(|_| false), 4);
+        assert_eq!(loopback.play(4 * FRAMES_PER_BUFFER as usize), expected(&blocks, 1.0));
+
+        // Volume and hooks apply before the audio leaves.
+        let mut loopback = Loopback::new(Codec::Pcm, SinkOptions::default(), 4);
+        loopback.capture.controls.state.set_level(0.5);
+        loopback
+            .capture
+            .hooks
+            .push(Box::new(|frame| frame.samples.iter_mut().for_each(|s| *s = -*s)));
+        loopback.push(&blocks);
+        assert_eq!(loopback.deliver(|_| false), 4);
+        let inverted: Vec<i16> = expected(&blocks, 0.5).iter().map(|s| -s).collect();
+        assert_eq!(loopback.play(4 * FRAMES_PER_BUFFER as usize), inverted);
+    }
+
+    #[test]
+    fn test_loopback_loss() {
+        let frames = FRAMES_PER_BUFFER as usize * CHANNELS as usize;
+        let blocks = sine_blocks(3);
+        let mut loopback = Loopback::new(Codec::Pcm, SinkOptions::default(), 2);
+        loopback.push(&blocks);
+        assert_eq!(loopback.deliver(|seq| seq == 1), 2);
+        let played = loopback.play(3 * FRAMES_PER_BUFFER as usize);
+        let sent = expected(&blocks, 1.0);
+        assert_eq!(played[..frames], sent[..frames]);
+        assert!(played[frames..2 * frames].iter().all(|&s| s == 0));
+        assert_eq!(played[2 * frames..], sent[2 * frames..]);
+
+        // With redundancy the next packet carries the lost block.
+        let options = SinkOptions {
+            redundancy: true,
+            ..SinkOptions::default()
+        };
+        let mut loopback = Loopback::new(Codec::Pcm, options, 2);
+        loopback.push(&blocks);
+        loopback.deliver(|seq| seq == 1);
+        assert_eq!(loopback.play(3 * FRAMES_PER_BUFFER as usize), sent);
+    }
+
+    #[test]
+    fn test_loopback_opus() {
+        let mut loopback = Loopback::new(Codec::Opus, SinkOptions::default(), 21);
+        let blocks = sine_blocks(20);
+        loopback.push(&blocks);
+        // 20 blocks of 512 frames make 21 whole Opus frames of 480.
+        assert_eq!(loopback.deliver(|_| false), 21);
+        let played = loopback.play(21 * crate::codec::OPUS_FRAME_FRAMES);
+        let rms =
+            |samples: &[i16]| (samples.iter().map(|&s| (s as f64).powi(2)).sum::<f64>() / samples.len() as f64).sqrt();
+        // Past the codec's startup, the tone comes through at about its level.
+        let sent = rms(&expected(&blocks, 1.0));
+        let received = rms(&played[played.len() / 2..]);
+        assert!(
+            (received / sent - 1.0).abs() < 0.2,
+            "sent {:.0}, received {:.0}",
+            sent,
+            received
+        );
+    }
+
+    #[test]
+    fn test_loopback_pause_and_control() {
+        let mut loopback = Loopback::new(Codec::Pcm, SinkOptions::default(), 1);
+        let controls = loopback.capture.controls.clone();
+        futures::executor::block_on(controls.apply(control::Message::Pause(true)));
+        loopback.push(&sine_blocks(1));
+        // Only the announcement goes out, and the receiver drains for it.
+        let announcement = loopback.peer.try_recv_frame().unwrap();
+        assert!(packet::decode(&announcement).unwrap().paused);
+        assert_eq!(loopback.peer.try_recv_frame(), None);
+
+        // Control messages the receiver sends back reach the controls.
+        let Loopback { peer, .. } = loopback;
+        peer.send_control(&control::Message::Pause(false).encode(1)).unwrap();
+        peer.send_control(&control::Message::SetVolume(0.25).encode(2)).unwrap();
+        drop(peer);
+        futures::executor::block_on(controls.follow_transport());
+        assert!(!controls.is_paused());
+        assert_eq!(controls.state.level(), 0.25);
+    }
+
+    #[test]
+    fn test_encode_samples() {
+        let mut buffer = vec![0xff];
+        let decode = |buffer: &[u8]| -> Vec<i16> {
+            buffer
+                .chunks(2)
+                .map(|pair| i16::from_le_bytes([pair[0], pair[1]]))
+                .collect()
+        };
+        encode_samples(&[0.0, 1.0, -2.0, 0.5], 0.5, &mut buffer);
+        assert_eq!(decode(&buffer), [0, i16::MAX / 2, -i16::MAX, i16::MAX / 4]);
+        encode_i16_samples(&[0, i16::MAX, i16::MIN, 1000], 0.5, &mut buffer);
+        assert_eq!(decode(&buffer), [0, i16::MAX / 2, -i16::MAX / 2 - 1, 500]);
+    }
+
+    // Counts the allocations each thread makes, so a test can check that a
+    // path doesn't allocate whatever the other tests are doing.
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    // Takes packets and sends them nowhere.
+    struct Discard;
+
+    impl AudioTransport for Discard {
+        fn send_frame(&self, _frame: &[u8]) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn recv_control(&self) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+            Box::pin(futures::future::pending())
+        }
+
+        fn stats(&self) -> TransportStats {
+            TransportStats::default()
+        }
+    }
+
+    #[test]
+    fn test_capture_path_does_not_allocate() {
+        let options = SinkOptions {
+            redundancy: true,
+            ..SinkOptions::default()
+        };
+        let blocks = sine_blocks(20);
+        let ints: Vec<Vec<i16>> = blocks
+            .iter()
+            .map(|block| block.iter().map(|&s| (s * i16::MAX as f32) as i16).collect())
+            .collect();
+        for codec in [Codec::Pcm, Codec::Opus] {
+            let (capture, _events) = capture(Sink::over(Arc::new(Discard), &options), codec);
+            capture
+                .hooks
+                .push(Box::new(|frame| frame.samples.iter_mut().for_each(|s| *s /= 2)));
+            let mut buffer = Vec::new();
+            let (mut ring, _consumer) = ring::channel(BLOCK_BYTES * RING_BUFFERS);
+            // The first blocks size the buffers and fill the meters' windows.
+            blocks.iter().for_each(|block| capture.push_samples(block, &mut buffer));
+            let before = ALLOCATIONS.with(Cell::get);
+            blocks.iter().for_each(|block| capture.push_samples(block, &mut buffer));
+            ints.iter()
+                .for_each(|block| capture.push_i16_samples(block, &mut buffer));
+            // What the device callbacks do: queueing, until the ring is full.
+            ints.iter()
+                .for_each(|block| capture.queue_i16_samples(block, &mut buffer, &mut ring));
+            blocks
+                .iter()
+                .for_each(|block| capture.queue_samples(block, &mut buffer, &mut ring));
+            assert_eq!(ALLOCATIONS.with(Cell::get), before, "{:?}", codec);
+        }
+    }
+
+    #[test]
+    fn test_sender_waits_out_backpressure() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        // Room for two packets, so sending without waiting would drop most.
+        let (transport, mut peer) = MemoryTransport::pair(2);
+        let (capture, _events) = capture(Sink::over(Arc::new(transport), &SinkOptions::default()), Codec::Pcm);
+        let block = vec![0.25; FRAMES_PER_BUFFER as usize * CHANNELS as usize];
+        let received = runtime.block_on(async {
+            let (mut ring, sender) = capture.spawn_sender();
+            let mut buffer = Vec::new();
+            for _ in 0..10 {
+                capture.queue_samples(&block, &mut buffer, &mut ring);
+            }
+            drop(ring);
+            let mut received = Vec::new();
+            while received.len() < 10 {
+                let datagram = peer.recv_frame().await.unwrap();
+                received.push(packet::decode(&datagram).unwrap().seq);
+            }
+            sender.await.unwrap();
+            received
+        });
+        assert_eq!(received, (0..10).collect::<Vec<_>>());
+        let summary = capture.stats.summary(Duration::from_secs(1));
+        assert_eq!((summary.drops, summary.overruns), (0, 0));
+    }
+
+    #[test]
+    fn test_paced_sender_spaces_out_blocks() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let (transport, mut peer) = MemoryTransport::pair(16);
+        let options = SinkOptions {
+            pace: true,
+            ..SinkOptions::default()
+        };
+        let (capture, _events) = capture(Sink::over(Arc::new(transport), &options), Codec::Pcm);
+        let block = vec![0.25; FRAMES_PER_BUFFER as usize * CHANNELS as usize];
+        let spread = runtime.block_on(async {
+            let (mut ring, sender) = capture.spawn_sender();
+            let mut buffer = Vec::new();
+            // Four blocks at once, as from a callback four blocks long.
+            for _ in 0..4 {
+                capture.queue_samples(&block, &mut buffer, &mut ring);
+            }
+            drop(ring);
+            peer.recv_frame().await.unwrap();
+            let first = Instant::now();
+            for _ in 0..3 {
+                peer.recv_frame().await.unwrap();
+            }
+            sender.await.unwrap();
+            first.elapsed()
+        });
+        // Three gaps of nearly a block, about 10.7ms each.
+        assert!(spread >= Duration::from_millis(29), "{:?}", spread);
+    }
+
+    #[test]
+    fn test_sender_flushes_a_partial_batch() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let (transport, mut peer) = MemoryTransport::pair(16);
+        let options = SinkOptions {
+            batch_packets: 4,
+            ..SinkOptions::default()
+        };
+        let (capture, _events) = capture(Sink::over(Arc::new(transport), &options), Codec::Pcm);
+        let block = vec![0.25; FRAMES_PER_BUFFER as usize * CHANNELS as usize];
+        runtime.block_on(async {
+            let (mut ring, sender) = capture.spawn_sender();
+            let mut buffer = Vec::new();
+            capture.queue_samples(&block, &mut buffer, &mut ring);
+            // No more audio comes, but the ring stays open; the packet goes
+            // out once four packets' worth of time has passed.
+            let datagram = tokio::time::timeout(Duration::from_secs(1), peer.recv_frame())
+                .await
+                .expect("the partial batch was held back");
+            assert_eq!(packet::decode(&datagram.unwrap()).unwrap().seq, 0);
+            drop(ring);
+            sender.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_audio_frame_from_pcm() {
+        let pcm: Vec<u8> = [1i16, -1, 300, -300]
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect();
+        let frame = AudioFrame::from_pcm(&pcm, SystemTime::UNIX_EPOCH);
+        assert_eq!(frame.samples, [1, -1, 300, -300]);
+        assert_eq!(frame.frames(), 2);
+        assert_eq!(frame.duration(), Duration::from_secs_f64(2.0 / 48000.0));
+    }
+
+    #[test]
+    fn test_capture_publishes_frames() {
+        let sink = Sink::Stream(Arc::new(Mutex::new(Box::new(std::io::sink()))));
+        let (frames, mut received) = broadcast::channel(FRAME_BACKLOG);
+        let (mut capture, _events) = capture(sink, Codec::Pcm);
+        capture.frames = Some(frames);
+        let mut buffer = Vec::new();
+        encode_samples(&[0.5, -0.5], 1.0, &mut buffer);
+        capture.send(&mut buffer);
+        let frame = received.try_recv().unwrap();
+        assert_eq!(frame.samples, [i16::MAX / 2, -(i16::MAX / 2)]);
+        assert_eq!((frame.sample_rate, frame.channels), (SAMPLE_RATE, CHANNELS));
+
+        // Listeners get the audio as the hooks leave it.
+        capture.hooks.push(Box::new(|frame| frame.samples.reverse()));
+        encode_samples(&[0.5, -0.5], 1.0, &mut buffer);
+        capture.send(&mut buffer);
+        let frame = received.try_recv().unwrap();
+        assert_eq!(frame.samples, [-(i16::MAX / 2), i16::MAX / 2]);
+    }
+
+    #[test]
+    fn test_builder_rejects_bad_settings() {
+        // Both are refused before anything connects or opens.
+        let spawn = |builder: StreamerClientBuilder| futures::executor::block_on(builder.spawn()).err().unwrap();
+        let error = spawn(StreamerClient::builder().volume(1.5));
+        assert_eq!(ErrorKind::of(error.as_ref()), ErrorKind::Usage);
+        let error = spawn(StreamerClient::builder().sink(SinkKind::Stdout).codec(Codec::Opus));
+        assert_eq!(ErrorKind::of(error.as_ref()), ErrorKind::Usage);
+    }
+
+    #[test]
+    fn test_stream_watchdog() {
+        let start = Instant::now();
+        let mut watchdog = StreamWatchdog::new(2);
+        assert!(watchdog.restart(start));
+        assert!(watchdog.restart(start + Duration::from_secs(1)));
+        assert!(!watchdog.restart(start + Duration::from_secs(2)));
+
+        // A stream that ran cleanly for a minute starts the count over.
+        let later = start + Duration::from_secs(2) + STABLE_AFTER;
+        assert!(watchdog.restart(later));
+
+        assert_eq!(watchdog.retry_delay(), Duration::from_secs(1));
+        assert_eq!(watchdog.retry_delay(), Duration::from_secs(2));
+        watchdog.recovered();
+        assert_eq!(watchdog.retry_delay(), Duration::from_secs(1));
+
+        // Waiting for a device, however long, leaves the restart allowed.
+        let mut watchdog = StreamWatchdog::new(1);
+        for _ in 0..100 {
+            assert!(watchdog.retry_delay() <= Duration::from_secs(30));
+        }
+        assert!(watchdog.restart(start));
+    }
+
+    #[test]
+    fn test_buffer_sizer() {
+        let sizer = BufferSizer::new(512, 1536);
+        sizer.fit(&cpal::SupportedBufferSize::Range { min: 256, max: 4096 });
+        assert_eq!(sizer.frames(), 512);
+        sizer.fit(&cpal::SupportedBufferSize::Range { min: 600, max: 4096 });
+        assert_eq!(sizer.frames(), 600);
+        assert_eq!(sizer.grow(), Some(1200));
+        assert_eq!(sizer.grow(), Some(1536));
+        assert_eq!(sizer.grow(), None);
+        // A device that takes nothing within the cap keeps the size.
+        let sizer = BufferSizer::new(512, 1024);
+        sizer.fit(&cpal::SupportedBufferSize::Range { min: 2048, max: 4096 });
+        assert_eq!(sizer.frames(), 512);
+    }
+
+    #[test]
+    fn test_miss_counter() {
+        let start = Instant::now();
+        let mut misses = MissCounter::default();
+        assert!(!misses.missed(start));
+        assert!(!misses.missed(start + Duration::from_secs(1)));
+        assert!(misses.missed(start + Duration::from_secs(2)));
+        // Misses spread out further than the window never add up.
+        let later = start + Duration::from_secs(3);
+        for i in 0..5 {
+            assert!(!misses.missed(later + (MISS_WINDOW + Duration::from_secs(1)) * i));
+        }
+    }
+}
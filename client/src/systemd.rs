@@ -0,0 +1,184 @@
+use std::io;
+use std::time::Duration;
+
+/// How long systemd waits for a watchdog ping in the generated unit before
+/// restarting the client.
+pub const WATCHDOG_SEC: u64 = 30;
+
+/// Tells the service manager about the client's state, e.g. "READY=1" or
+/// "WATCHDOG=1", when it runs as a Type=notify service. Returns whether a
+/// message was sent; outside systemd there is nowhere to send it.
+pub fn notify(state: &str) -> io::Result<bool> {
+    #[cfg(unix)]
+    {
+        match std::env::var_os("NOTIFY_SOCKET") {
+            Some(path) => send_notification(&path, state).map(|()| true),
+            None => Ok(false),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = state;
+        Ok(false)
+    }
+}
+
+// A path starting with '@' names a socket in the abstract namespace.
+#[cfg(unix)]
+fn send_notification(path: &std::ffi::OsStr, state: &str) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixDatagram;
+
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => send_abstract(&socket, name, state),
+        None => socket.send_to(state.as_bytes(), path).map(drop),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send_abstract(socket: &std::os::unix::net::UnixDatagram, name: &[u8], state: &str) -> io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+
+    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+    socket.send_to_addr(state.as_bytes(), &addr).map(drop)
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn send_abstract(_: &std::os::unix::net::UnixDatagram, _: &[u8], _: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "abstract notification sockets need Linux",
+    ))
+}
+
+/// How often to ping the watchdog: half the WatchdogSec= systemd set for
+/// this process, so a single late ping does not trip it.
+pub fn watchdog_interval() -> Option<Duration> {
+    // Without WATCHDOG_PID the setting applies to whoever reads it.
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
+/// The datagram socket systemd opened for this process from a .socket unit
+/// with ListenDatagram=, taken in place of binding the control port. Only
+/// the first caller gets it.
+pub fn activated_udp_socket() -> Option<std::net::UdpSocket> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::io::FromRawFd;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        // The first descriptor systemd passes, after stdin, stdout and stderr.
+        const LISTEN_FDS_START: libc::c_int = 3;
+        static TAKEN: AtomicBool = AtomicBool::new(false);
+
+        let pid: u32 = std::env::var("LISTEN_PID").ok()?.parse().ok()?;
+        let count: u32 = std::env::var("LISTEN_FDS").ok()?.parse().ok()?;
+        if pid != std::process::id() || count == 0 || !is_datagram(LISTEN_FDS_START) {
+            return None;
+        }
+        if TAKEN.swap(true, Ordering::SeqCst) {
+            return None;
+        }
+        Some(unsafe { std::net::UdpSocket::from_raw_fd(LISTEN_FDS_START) })
+    }
+    #[cfg(not(unix))]
+    {
+        None
+    }
+}
+
+#[cfg(unix)]
+fn is_datagram(fd: libc::c_int) -> bool {
+    let mut kind: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut kind as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    result == 0 && kind == libc::SOCK_DGRAM
+}
+
+/// A service unit that runs `command`, the program and its arguments, as a
+/// Type=notify service with a watchdog, restarting it when it fails.
+pub fn unit_file(command: &[String]) -> String {
+    let exec_start: Vec<_> = command.iter().map(|arg| quote(arg)).collect();
+    format!(
+        "[Unit]
+Description=Audio streamer client
+Wants=network-online.target
+After=network-online.target sound.target
+
+[Service]
+Type=notify
+ExecStart={}
+WatchdogSec={}
+Restart=on-failure
+RestartSec=5
+
+[Install]
+WantedBy=default.target
+",
+        exec_start.join(" "),
+        WATCHDOG_SEC
+    )
+}
+
+// systemd's command-line quoting: arguments with spaces, quotes or
+// backslashes go in double quotes with backslash escapes, and % and $ are
+// doubled so they are not taken as specifiers or variables.
+fn quote(arg: &str) -> String {
+    let escaped = arg.replace('%', "%%").replace('$', "$$");
+    if !escaped.is_empty() && !escaped.contains(|c: char| c.is_whitespace() || matches!(c, '"' | '\'' | '\\')) {
+        return escaped;
+    }
+    format!("\"{}\"", escaped.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("--server"), "--server");
+        assert_eq!(quote("Scarlett 2i2 USB"), "\"Scarlett 2i2 USB\"");
+        assert_eq!(quote("say \"hi\""), "\"say \\\"hi\\\"\"");
+        assert_eq!(quote("50%"), "50%%");
+        assert_eq!(quote("$HOME"), "$$HOME");
+        assert_eq!(quote(""), "\"\"");
+    }
+
+    #[test]
+    fn test_unit_file() {
+        let command = ["/usr/bin/audio-client", "stream", "--device-name", "USB Audio"].map(String::from);
+        let unit = unit_file(&command);
+        assert!(unit.contains("\nType=notify\n"));
+        assert!(unit.contains("\nExecStart=/usr/bin/audio-client stream --device-name \"USB Audio\"\n"));
+        assert!(unit.contains("\nWatchdogSec=30\n"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_send_notification() {
+        let path = std::env::temp_dir().join(format!("audio-streamer-notify-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+        send_notification(path.as_os_str(), "READY=1\nSTATUS=Streaming").unwrap();
+        let mut buf = [0u8; 64];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1\nSTATUS=Streaming");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
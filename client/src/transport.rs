@@ -0,0 +1,1055 @@
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use futures::future::BoxFuture;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UdpSocket};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{info, warn, Instrument};
+
+use crate::clock::SyncedClock;
+use crate::http;
+use crate::jitter::JitterBuffer;
+use crate::playback;
+use crate::proxy::Proxy;
+use crate::qos::SocketOptions;
+use crate::rendezvous::{self, Role, Session};
+use crate::websocket::{self, OPCODE_BINARY, OPCODE_CLOSE};
+
+// Frames queued for a TCP or WebSocket connection's writer, or sends for
+// a fan-out destination, before new ones are dropped; a second of
+// 512-frame buffers.
+const SEND_QUEUE: usize = 96;
+// The longest frame a stream transport accepts, well above any packet.
+const MAX_FRAME: usize = 1 << 16;
+// The longest handshake head read before giving up on the peer.
+const MAX_HEAD: usize = 8192;
+// The most datagrams handed to the system in one call.
+#[cfg(target_os = "linux")]
+const MAX_BATCH: usize = 64;
+/// Where WebSocket senders connect on a `--listen` receiver.
+pub const WS_PATH: &str = "/audio";
+
+/// What a transport has carried since it was opened.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TransportStats {
+    pub frames_sent: u64,
+    pub bytes_sent: u64,
+    /// Frames that could not be sent or queued.
+    pub send_errors: u64,
+    pub control_received: u64,
+}
+
+impl std::ops::Add for TransportStats {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            frames_sent: self.frames_sent + other.frames_sent,
+            bytes_sent: self.bytes_sent + other.bytes_sent,
+            send_errors: self.send_errors + other.send_errors,
+            control_received: self.control_received + other.control_received,
+        }
+    }
+}
+
+impl std::ops::AddAssign for TransportStats {
+    fn add_assign(&mut self, other: Self) {
+        *self = *self + other;
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Counters {
+    frames_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    send_errors: AtomicU64,
+    control_received: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) fn record(&self, result: &io::Result<()>, len: usize) {
+        if result.is_ok() {
+            self.frames_sent.fetch_add(1, Ordering::Relaxed);
+            self.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+        } else {
+            self.send_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> TransportStats {
+        TransportStats {
+            frames_sent: self.frames_sent.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            send_errors: self.send_errors.load(Ordering::Relaxed),
+            control_received: self.control_received.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Carries the sink's packets to the receiver, and control messages from
+/// it where the link runs both ways. The capture only sees this trait, so
+/// a new transport, or a test double, needs no change there.
+pub trait AudioTransport: Send + Sync {
+    /// Sends one packet without waiting; the audio callback calls this.
+    /// A transport that would block drops the packet with `WouldBlock`.
+    fn send_frame(&self, frame: &[u8]) -> io::Result<()>;
+
+    /// Sends one packet, waiting for room rather than dropping it.
+    fn send<'a>(&'a self, frame: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move { self.send_frame(frame) })
+    }
+
+    /// Sends several packets in order, waiting for room. Transports that
+    /// can hand them to the system in one call do; the rest send them one
+    /// by one. Every packet is tried, and the first error is returned.
+    fn send_batch<'a>(&'a self, frames: &'a [Vec<u8>]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let mut result = Ok(());
+            for frame in frames {
+                let sent = self.send(frame).await;
+                if result.is_ok() {
+                    result = sent;
+                }
+            }
+            result
+        })
+    }
+
+    /// Waits for the next control message the peer sends back. Transports
+    /// that only carry audio one way report `Unsupported`.
+    fn recv_control(&self) -> BoxFuture<'_, io::Result<Vec<u8>>>;
+
+    fn stats(&self) -> TransportStats;
+
+    /// What each receiver was sent, by address, for a transport that sends
+    /// to several; empty for the rest.
+    fn destinations(&self) -> Vec<(String, TransportStats)> {
+        Vec::new()
+    }
+
+    /// Sends to `server_addr` from now on, if the transport can move
+    /// without reconnecting.
+    fn set_destination<'a>(&'a self, _server_addr: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "this transport cannot change its destination",
+            ))
+        })
+    }
+}
+
+/// Plain datagrams on a connected socket.
+pub struct UdpTransport {
+    socket: UdpSocket,
+    counters: Counters,
+}
+
+impl UdpTransport {
+    pub async fn connect(server_addr: &str, options: &SocketOptions) -> io::Result<Self> {
+        let socket = UdpSocket::bind(options.local_addr()).await?;
+        socket.connect(server_addr).await?;
+        options.apply(&socket, socket.local_addr());
+        Ok(Self {
+            socket,
+            counters: Counters::default(),
+        })
+    }
+
+    /// Meets the receiver of `session` at its relay and sends straight to
+    /// it if a hole can be punched, or through the relay if not. The NATs
+    /// on the way are kept open while the audio pauses.
+    pub async fn rendezvous(session: &Session, options: &SocketOptions) -> io::Result<Self> {
+        let socket = UdpSocket::bind(options.local_addr()).await?;
+        let route = rendezvous::meet(&socket, session, Role::Sender).await?;
+        socket.connect(route.addr()).await?;
+        options.apply(&socket, socket.local_addr());
+        let (socket, keepalive) = rendezvous::clone_socket(socket)?;
+        rendezvous::spawn_keepalive(keepalive, vec![route.addr()]);
+        Ok(Self {
+            socket,
+            counters: Counters::default(),
+        })
+    }
+}
+
+impl AudioTransport for UdpTransport {
+    fn send_frame(&self, frame: &[u8]) -> io::Result<()> {
+        let result = self.socket.try_send(frame).map(|_| ());
+        self.counters.record(&result, frame.len());
+        result
+    }
+
+    fn send<'a>(&'a self, frame: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let result = self.socket.send(frame).await.map(|_| ());
+            self.counters.record(&result, frame.len());
+            result
+        })
+    }
+
+    // One sendmmsg call per batch on Linux.
+    #[cfg(target_os = "linux")]
+    fn send_batch<'a>(&'a self, frames: &'a [Vec<u8>]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let mut result = Ok(());
+            let mut sent = 0;
+            while sent < frames.len() {
+                let rest = &frames[sent..];
+                match self
+                    .socket
+                    .async_io(tokio::io::Interest::WRITABLE, || send_mmsg(&self.socket, rest))
+                    .await
+                {
+                    Ok(count) => {
+                        for frame in &rest[..count] {
+                            self.counters.record(&Ok(()), frame.len());
+                        }
+                        sent += count;
+                    }
+                    // The packet the system stopped at is lost; the rest
+                    // are tried again.
+                    Err(e) => {
+                        let failed = Err(e);
+                        self.counters.record(&failed, rest[0].len());
+                        if result.is_ok() {
+                            result = failed;
+                        }
+                        sent += 1;
+                    }
+                }
+            }
+            result
+        })
+    }
+
+    fn recv_control(&self) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        Box::pin(async move {
+            let mut buf = vec![0u8; MAX_FRAME];
+            loop {
+                match self.socket.recv(&mut buf).await {
+                    // Late punches and keepalives from a rendezvous peer.
+                    Ok(len) if rendezvous::is_message(&buf[..len]) => continue,
+                    Ok(len) => {
+                        self.counters.control_received.fetch_add(1, Ordering::Relaxed);
+                        buf.truncate(len);
+                        return Ok(buf);
+                    }
+                    // A connected socket hears of the server being down
+                    // this way; the stream goes on regardless.
+                    Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+        })
+    }
+
+    fn stats(&self) -> TransportStats {
+        self.counters.snapshot()
+    }
+
+    fn set_destination<'a>(&'a self, server_addr: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(self.socket.connect(server_addr))
+    }
+}
+
+// Sends as many of `frames` as the system takes in one call on a
+// connected socket, at most MAX_BATCH, and returns how many it took.
+#[cfg(target_os = "linux")]
+fn send_mmsg(socket: &UdpSocket, frames: &[Vec<u8>]) -> io::Result<usize> {
+    use std::os::fd::AsRawFd;
+
+    let frames = &frames[..frames.len().min(MAX_BATCH)];
+    let mut iovecs: Vec<libc::iovec> = frames
+        .iter()
+        .map(|frame| libc::iovec {
+            iov_base: frame.as_ptr() as *mut libc::c_void,
+            iov_len: frame.len(),
+        })
+        .collect();
+    let mut messages: Vec<libc::mmsghdr> = iovecs
+        .iter_mut()
+        .map(|iovec| {
+            // Zeroed, the header has no address, so the connected one is used.
+            let mut message: libc::mmsghdr = unsafe { std::mem::zeroed() };
+            message.msg_hdr.msg_iov = iovec;
+            message.msg_hdr.msg_iovlen = 1;
+            message
+        })
+        .collect();
+    let sent = unsafe { libc::sendmmsg(socket.as_raw_fd(), messages.as_mut_ptr(), messages.len() as _, 0) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sent as usize)
+}
+
+// The sending half of a TCP connection: a task that owns the writer and
+// takes frames from a bounded queue, so the audio callback never waits on
+// the network.
+struct Writer {
+    queue: mpsc::Sender<Vec<u8>>,
+    counters: Counters,
+}
+
+impl Writer {
+    // `encode` frames each packet for the wire.
+    fn spawn(mut out: tokio::net::tcp::OwnedWriteHalf, encode: fn(&[u8]) -> Vec<u8>) -> Self {
+        let (queue, mut frames) = mpsc::channel::<Vec<u8>>(SEND_QUEUE);
+        let write = async move {
+            while let Some(frame) = frames.recv().await {
+                if let Err(e) = out.write_all(&encode(&frame)).await {
+                    warn!("Connection to the receiver closed: {}", e);
+                    return;
+                }
+            }
+        };
+        tokio::spawn(write.in_current_span());
+        Self {
+            queue,
+            counters: Counters::default(),
+        }
+    }
+
+    fn try_send(&self, frame: &[u8]) -> io::Result<()> {
+        let result = match self.queue.try_send(frame.to_vec()) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(TrySendError::Closed(_)) => Err(io::ErrorKind::NotConnected.into()),
+        };
+        self.counters.record(&result, frame.len());
+        result
+    }
+
+    async fn send(&self, frame: &[u8]) -> io::Result<()> {
+        let result = self
+            .queue
+            .send(frame.to_vec())
+            .await
+            .map_err(|_| io::ErrorKind::NotConnected.into());
+        self.counters.record(&result, frame.len());
+        result
+    }
+}
+
+// Connects to `server_addr` from the local address in `options`, if there
+// is one, trying each of the server's addresses in the same family.
+pub(crate) async fn connect_tcp(server_addr: &str, options: &SocketOptions) -> io::Result<TcpStream> {
+    let Some(local) = options.bind else {
+        return TcpStream::connect(server_addr).await;
+    };
+    let mut last_error = None;
+    for addr in tokio::net::lookup_host(server_addr).await? {
+        if addr.is_ipv4() != local.is_ipv4() {
+            continue;
+        }
+        let socket = if local.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        socket.bind(local)?;
+        match socket.connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        let message = format!("{} has no address that {} can reach", server_addr, local.ip());
+        io::Error::new(io::ErrorKind::AddrNotAvailable, message)
+    }))
+}
+
+// Connects to `server_addr` directly, or through `proxy` if there is one.
+async fn connect_stream(server_addr: &str, options: &SocketOptions, proxy: Option<&Proxy>) -> io::Result<TcpStream> {
+    match proxy {
+        Some(proxy) => {
+            let stream = proxy.connect(server_addr, options).await?;
+            info!("Connected to {} through {}", server_addr, proxy);
+            Ok(stream)
+        }
+        None => connect_tcp(server_addr, options).await,
+    }
+}
+
+/// Length-prefixed frames over TCP: each packet follows its length as a
+/// 32-bit big-endian number. The network loses nothing, so a slow link
+/// shows up as delay until the queue fills and new packets are dropped.
+pub struct TcpTransport {
+    writer: Writer,
+    reader: tokio::sync::Mutex<OwnedReadHalf>,
+}
+
+impl TcpTransport {
+    pub async fn connect(server_addr: &str, options: &SocketOptions, proxy: Option<&Proxy>) -> io::Result<Self> {
+        let stream = connect_stream(server_addr, options, proxy).await?;
+        stream.set_nodelay(true)?;
+        options.apply(&stream, stream.local_addr());
+        info!("TCP connection to {} established", server_addr);
+        let (reader, writer) = stream.into_split();
+        Ok(Self {
+            writer: Writer::spawn(writer, length_prefixed),
+            reader: tokio::sync::Mutex::new(reader),
+        })
+    }
+}
+
+impl AudioTransport for TcpTransport {
+    fn send_frame(&self, frame: &[u8]) -> io::Result<()> {
+        self.writer.try_send(frame)
+    }
+
+    fn send<'a>(&'a self, frame: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(self.writer.send(frame))
+    }
+
+    fn recv_control(&self) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        Box::pin(async move {
+            let message = read_length_prefixed(&mut *self.reader.lock().await).await?;
+            self.writer.counters.control_received.fetch_add(1, Ordering::Relaxed);
+            Ok(message)
+        })
+    }
+
+    fn stats(&self) -> TransportStats {
+        self.writer.counters.snapshot()
+    }
+}
+
+/// Binary WebSocket messages, one packet each, to `ws://<server>/audio`,
+/// for networks that only let HTTP through.
+pub struct WsTransport {
+    writer: Writer,
+    // Bytes read past the last whole frame are kept for the next.
+    reader: tokio::sync::Mutex<(OwnedReadHalf, Vec<u8>)>,
+}
+
+impl WsTransport {
+    pub async fn connect(server_addr: &str, options: &SocketOptions, proxy: Option<&Proxy>) -> io::Result<Self> {
+        let mut stream = connect_stream(server_addr, options, proxy).await?;
+        stream.set_nodelay(true)?;
+        options.apply(&stream, stream.local_addr());
+        let key = websocket::client_key();
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            WS_PATH, server_addr, key
+        );
+        stream.write_all(request.as_bytes()).await?;
+        let head = read_head(&mut stream).await?;
+        let (status, headers) = split_head(&head);
+        if status.split_whitespace().nth(1) != Some("101") {
+            let message = format!("the receiver refused the WebSocket connection: {}", status);
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused, message));
+        }
+        if header(&headers, "Sec-WebSocket-Accept") != Some(websocket::accept_key(&key).as_str()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "bad WebSocket handshake reply",
+            ));
+        }
+        info!("WebSocket connection to {} established", server_addr);
+        let (reader, writer) = stream.into_split();
+        Ok(Self {
+            writer: Writer::spawn(writer, websocket::client_binary),
+            reader: tokio::sync::Mutex::new((reader, Vec::new())),
+        })
+    }
+}
+
+impl AudioTransport for WsTransport {
+    fn send_frame(&self, frame: &[u8]) -> io::Result<()> {
+        self.writer.try_send(frame)
+    }
+
+    fn send<'a>(&'a self, frame: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(self.writer.send(frame))
+    }
+
+    fn recv_control(&self) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        Box::pin(async move {
+            let mut reader = self.reader.lock().await;
+            let (stream, buf) = &mut *reader;
+            loop {
+                let frame = read_ws_frame(stream, buf).await?;
+                if frame.opcode == OPCODE_BINARY {
+                    self.writer.counters.control_received.fetch_add(1, Ordering::Relaxed);
+                    return Ok(frame.payload);
+                }
+            }
+        })
+    }
+
+    fn stats(&self) -> TransportStats {
+        self.writer.counters.snapshot()
+    }
+}
+
+/// Every packet to several receivers at once, each over its own transport
+/// with its own counters. A send succeeds if any receiver got the packet,
+/// so one that is down does not count as a drop for the others. Waiting
+/// sends go through a queue and task per receiver, so one that stalls
+/// drops its own packets without holding up the rest. Control messages
+/// from all of them come through as one stream.
+pub struct FanOutTransport {
+    destinations: Vec<Destination>,
+    control: tokio::sync::Mutex<mpsc::UnboundedReceiver<Vec<u8>>>,
+}
+
+// One receiver of a fan-out, with the queue its task sends from.
+struct Destination {
+    addr: String,
+    transport: Arc<dyn AudioTransport>,
+    queue: mpsc::Sender<Vec<Vec<u8>>>,
+    // Packets dropped because the queue was full.
+    dropped: AtomicU64,
+}
+
+impl Destination {
+    // Queues `frames` to be sent in one batch, or drops them all if the
+    // destination is too far behind.
+    fn enqueue(&self, frames: Vec<Vec<u8>>) -> io::Result<()> {
+        let count = frames.len() as u64;
+        match self.queue.try_send(frames) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(count, Ordering::Relaxed);
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+            Err(TrySendError::Closed(_)) => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+
+    fn stats(&self) -> TransportStats {
+        let dropped = TransportStats {
+            send_errors: self.dropped.load(Ordering::Relaxed),
+            ..TransportStats::default()
+        };
+        self.transport.stats() + dropped
+    }
+}
+
+impl FanOutTransport {
+    /// Takes each destination's address, for its stats, and transport.
+    /// Must be called on a Tokio runtime, as each transport gets a task
+    /// reading its control messages.
+    pub fn new(destinations: Vec<(String, Arc<dyn AudioTransport>)>) -> Self {
+        let (control, received) = mpsc::unbounded_channel();
+        for (addr, transport) in &destinations {
+            let (addr, transport, control) = (addr.clone(), transport.clone(), control.clone());
+            tokio::spawn(async move {
+                loop {
+                    match transport.recv_control().await {
+                        Ok(message) => {
+                            if control.send(message).is_err() {
+                                return;
+                            }
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::Unsupported => return,
+                        Err(e) => {
+                            warn!("Stopped reading control from {}: {}", addr, e);
+                            return;
+                        }
+                    }
+                }
+            });
+        }
+        let destinations = destinations
+            .into_iter()
+            .map(|(addr, transport)| {
+                let (queue, mut batches) = mpsc::channel::<Vec<Vec<u8>>>(SEND_QUEUE);
+                let sender = transport.clone();
+                // Errors are in the transport's own counters.
+                tokio::spawn(async move {
+                    while let Some(frames) = batches.recv().await {
+                        let _ = sender.send_batch(&frames).await;
+                    }
+                });
+                Destination {
+                    addr,
+                    transport,
+                    queue,
+                    dropped: AtomicU64::new(0),
+                }
+            })
+            .collect();
+        Self {
+            destinations,
+            control: tokio::sync::Mutex::new(received),
+        }
+    }
+
+    // Ok if any destination took or queued the packet, or else the first error.
+    fn any_sent(results: impl IntoIterator<Item = io::Result<()>>) -> io::Result<()> {
+        let mut first_error = None;
+        for result in results {
+            match result {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+}
+
+impl AudioTransport for FanOutTransport {
+    fn send_frame(&self, frame: &[u8]) -> io::Result<()> {
+        let results: Vec<_> = self
+            .destinations
+            .iter()
+            .map(|destination| destination.transport.send_frame(frame))
+            .collect();
+        Self::any_sent(results)
+    }
+
+    fn send<'a>(&'a self, frame: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        let results: Vec<_> = self
+            .destinations
+            .iter()
+            .map(|destination| destination.enqueue(vec![frame.to_vec()]))
+            .collect();
+        Box::pin(async move { Self::any_sent(results) })
+    }
+
+    fn send_batch<'a>(&'a self, frames: &'a [Vec<u8>]) -> BoxFuture<'a, io::Result<()>> {
+        let results: Vec<_> = self
+            .destinations
+            .iter()
+            .map(|destination| destination.enqueue(frames.to_vec()))
+            .collect();
+        Box::pin(async move { Self::any_sent(results) })
+    }
+
+    fn recv_control(&self) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        Box::pin(async move {
+            self.control
+                .lock()
+                .await
+                .recv()
+                .await
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "no receiver sends control any more"))
+        })
+    }
+
+    /// The destinations' counters added together.
+    fn stats(&self) -> TransportStats {
+        self.destinations
+            .iter()
+            .fold(TransportStats::default(), |total, destination| total + destination.stats())
+    }
+
+    fn destinations(&self) -> Vec<(String, TransportStats)> {
+        self.destinations
+            .iter()
+            .map(|destination| (destination.addr.clone(), destination.stats()))
+            .collect()
+    }
+}
+
+/// An in-process link, for tests and for programs that take the packets
+/// themselves. The [`MemoryPeer`] from [`MemoryTransport::pair`] receives
+/// what is sent and can answer with control messages. Once its queue of
+/// `capacity` packets is full, new ones are dropped as on a congested
+/// network.
+pub struct MemoryTransport {
+    frames: mpsc::Sender<Vec<u8>>,
+    control: tokio::sync::Mutex<mpsc::UnboundedReceiver<Vec<u8>>>,
+    counters: Counters,
+}
+
+/// The receiving end of a [`MemoryTransport`].
+pub struct MemoryPeer {
+    frames: mpsc::Receiver<Vec<u8>>,
+    control: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl MemoryTransport {
+    pub fn pair(capacity: usize) -> (Self, MemoryPeer) {
+        let (frames, received) = mpsc::channel(capacity);
+        let (control, controls) = mpsc::unbounded_channel();
+        let transport = Self {
+            frames,
+            control: tokio::sync::Mutex::new(controls),
+            counters: Counters::default(),
+        };
+        let peer = MemoryPeer {
+            frames: received,
+            control,
+        };
+        (transport, peer)
+    }
+}
+
+impl AudioTransport for MemoryTransport {
+    fn send_frame(&self, frame: &[u8]) -> io::Result<()> {
+        let result = match self.frames.try_send(frame.to_vec()) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(io::ErrorKind::WouldBlock.into()),
+            // Nothing reads the packets any more, as with a closed pipe.
+            Err(TrySendError::Closed(_)) => Err(io::ErrorKind::BrokenPipe.into()),
+        };
+        self.counters.record(&result, frame.len());
+        result
+    }
+
+    fn send<'a>(&'a self, frame: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let result = self
+                .frames
+                .send(frame.to_vec())
+                .await
+                .map_err(|_| io::ErrorKind::BrokenPipe.into());
+            self.counters.record(&result, frame.len());
+            result
+        })
+    }
+
+    fn recv_control(&self) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        Box::pin(async move {
+            let message = self
+                .control
+                .lock()
+                .await
+                .recv()
+                .await
+                .ok_or(io::ErrorKind::NotConnected)?;
+            self.counters.control_received.fetch_add(1, Ordering::Relaxed);
+            Ok(message)
+        })
+    }
+
+    fn stats(&self) -> TransportStats {
+        self.counters.snapshot()
+    }
+}
+
+impl MemoryPeer {
+    /// The next packet, waiting for one to be sent. `None` once the
+    /// transport is gone and every packet has been read.
+    pub async fn recv_frame(&mut self) -> Option<Vec<u8>> {
+        self.frames.recv().await
+    }
+
+    /// The next packet if one is waiting.
+    pub fn try_recv_frame(&mut self) -> Option<Vec<u8>> {
+        self.frames.try_recv().ok()
+    }
+
+    /// Sends a control message back, as a receiver would.
+    pub fn send_control(&self, message: &[u8]) -> io::Result<()> {
+        self.control
+            .send(message.to_vec())
+            .map_err(|_| io::ErrorKind::NotConnected.into())
+    }
+}
+
+fn length_prefixed(frame: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(frame.len() + 4);
+    out.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+    out.extend_from_slice(frame);
+    out
+}
+
+async fn read_length_prefixed(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Vec<u8>> {
+    let len = stream.read_u32().await? as usize;
+    if len > MAX_FRAME {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too large"));
+    }
+    let mut frame = vec![0u8; len];
+    stream.read_exact(&mut frame).await?;
+    Ok(frame)
+}
+
+// The next binary frame; pings, pongs and text are skipped. A close
+// frame, or the peer hanging up, ends the stream with UnexpectedEof.
+async fn read_ws_frame(stream: &mut (impl AsyncRead + Unpin), buf: &mut Vec<u8>) -> io::Result<websocket::Frame> {
+    let mut chunk = [0u8; 4096];
+    loop {
+        while let Some(frame) = websocket::take_frame(buf)? {
+            if frame.opcode == OPCODE_CLOSE {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            if frame.opcode == OPCODE_BINARY {
+                return Ok(frame);
+            }
+        }
+        let len = stream.read(&mut chunk).await?;
+        if len == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..len]);
+    }
+}
+
+// Reads an HTTP head a byte at a time, so nothing after it is consumed.
+pub(crate) async fn read_head(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<String> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "HTTP head too long"));
+        }
+        head.push(stream.read_u8().await?);
+    }
+    String::from_utf8(head).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "HTTP head is not UTF-8"))
+}
+
+// The first line of an HTTP head, and its headers.
+fn split_head(head: &str) -> (&str, Vec<(&str, &str)>) {
+    let mut lines = head.lines();
+    let first = lines.next().unwrap_or_default();
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim(), value.trim()))
+        .collect();
+    (first, headers)
+}
+
+fn header<'a>(headers: &[(&str, &'a str)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| *value)
+}
+
+/// Accepts TCP senders on `listener` and reads their frames into the
+/// jitter buffer, one connection at a time being the usual case.
+pub async fn receive_tcp(
+    listener: TcpListener,
+    buffer: Arc<Mutex<JitterBuffer>>,
+    clock: Arc<SyncedClock>,
+) -> io::Result<()> {
+    loop {
+        let (mut stream, peer) = listener.accept().await?;
+        stream.set_nodelay(true)?;
+        info!("TCP sender {} connected", peer);
+        let (buffer, clock) = (buffer.clone(), clock.clone());
+        tokio::spawn(async move {
+            while let Ok(frame) = read_length_prefixed(&mut stream).await {
+                playback::accept(&frame, &buffer, &clock);
+            }
+            info!("TCP sender {} disconnected", peer);
+        });
+    }
+}
+
+/// Accepts WebSocket senders at [`WS_PATH`] on `listener` and reads their
+/// binary messages into the jitter buffer.
+pub async fn receive_ws(
+    listener: TcpListener,
+    buffer: Arc<Mutex<JitterBuffer>>,
+    clock: Arc<SyncedClock>,
+) -> io::Result<()> {
+    loop {
+        let (mut stream, peer) = listener.accept().await?;
+        let (buffer, clock) = (buffer.clone(), clock.clone());
+        tokio::spawn(async move {
+            if let Err(e) = accept_ws(&mut stream).await {
+                warn!("WebSocket sender {} failed the handshake: {}", peer, e);
+                return;
+            }
+            info!("WebSocket sender {} connected", peer);
+            let mut buf = Vec::new();
+            while let Ok(frame) = read_ws_frame(&mut stream, &mut buf).await {
+                playback::accept(&frame.payload, &buffer, &clock);
+            }
+            info!("WebSocket sender {} disconnected", peer);
+        });
+    }
+}
+
+// Answers a sender's opening handshake, or refuses anything else.
+async fn accept_ws(stream: &mut TcpStream) -> io::Result<()> {
+    let head = read_head(stream).await?;
+    let (request_line, headers) = split_head(&head);
+    let upgrade = header(&headers, "Upgrade").is_some_and(|u| u.eq_ignore_ascii_case("websocket"));
+    let key = header(&headers, "Sec-WebSocket-Key");
+    match (http::parse_request_line(request_line), key) {
+        (Some(("GET", WS_PATH)), Some(key)) if upgrade => {
+            let response = format!(
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                 Sec-WebSocket-Accept: {}\r\n\r\n",
+                websocket::accept_key(key)
+            );
+            stream.write_all(response.as_bytes()).await?;
+            stream.set_nodelay(true)
+        }
+        _ => {
+            stream
+                .write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")
+                .await?;
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a WebSocket upgrade to /audio",
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_length_prefixed() {
+        let framed = length_prefixed(&[9, 8, 7]);
+        assert_eq!(framed, [0, 0, 0, 3, 9, 8, 7]);
+        let read = futures::executor::block_on(read_length_prefixed(&mut &framed[..])).unwrap();
+        assert_eq!(read, [9, 8, 7]);
+        let too_large = (MAX_FRAME as u32 + 1).to_be_bytes();
+        assert!(futures::executor::block_on(read_length_prefixed(&mut &too_large[..])).is_err());
+    }
+
+    #[test]
+    fn test_split_head() {
+        let head = "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nsec-websocket-accept: abc=\r\n\r\n";
+        let (status, headers) = split_head(head);
+        assert_eq!(status, "HTTP/1.1 101 Switching Protocols");
+        assert_eq!(header(&headers, "Sec-WebSocket-Accept"), Some("abc="));
+        assert_eq!(header(&headers, "Origin"), None);
+    }
+
+    #[test]
+    fn test_memory_transport() {
+        let (transport, mut peer) = MemoryTransport::pair(2);
+        transport.send_frame(&[1]).unwrap();
+        futures::executor::block_on(transport.send(&[2])).unwrap();
+        let full = transport.send_frame(&[3]).unwrap_err();
+        assert_eq!(full.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(peer.try_recv_frame(), Some(vec![1]));
+        assert_eq!(futures::executor::block_on(peer.recv_frame()), Some(vec![2]));
+        assert_eq!(peer.try_recv_frame(), None);
+
+        peer.send_control(&[9, 9]).unwrap();
+        assert_eq!(futures::executor::block_on(transport.recv_control()).unwrap(), [9, 9]);
+        let stats = transport.stats();
+        assert_eq!(
+            (stats.frames_sent, stats.send_errors, stats.control_received),
+            (2, 1, 1)
+        );
+
+        drop(peer);
+        let closed = transport.send_frame(&[4]).unwrap_err();
+        assert_eq!(closed.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn test_udp_send_batch() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let transport =
+                UdpTransport::connect(&receiver.local_addr().unwrap().to_string(), &SocketOptions::default())
+                    .await
+                    .unwrap();
+            // More than one system call takes on Linux.
+            let frames: Vec<Vec<u8>> = (0..100u16).map(|i| i.to_be_bytes().to_vec()).collect();
+            transport.send_batch(&frames).await.unwrap();
+            let mut buf = [0u8; 16];
+            for frame in &frames {
+                let len = receiver.recv(&mut buf).await.unwrap();
+                assert_eq!(&buf[..len], frame.as_slice());
+            }
+            assert_eq!(transport.stats().frames_sent, frames.len() as u64);
+        });
+    }
+
+    #[test]
+    fn test_fan_out_transport() {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let (kitchen, mut kitchen_peer) = MemoryTransport::pair(4);
+            let (office, office_peer) = MemoryTransport::pair(1);
+            let transport = FanOutTransport::new(vec![
+                ("kitchen:8080".to_string(), Arc::new(kitchen) as Arc<dyn AudioTransport>),
+                ("office:8080".to_string(), Arc::new(office)),
+            ]);
+            transport.send_frame(&[1]).unwrap();
+            // The office queue is full, but the kitchen still takes it.
+            transport.send_frame(&[2]).unwrap();
+            assert_eq!(kitchen_peer.try_recv_frame(), Some(vec![1]));
+            assert_eq!(kitchen_peer.try_recv_frame(), Some(vec![2]));
+
+            let destinations = transport.destinations();
+            assert_eq!(destinations[0].0, "kitchen:8080");
+            assert_eq!((destinations[0].1.frames_sent, destinations[0].1.send_errors), (2, 0));
+            assert_eq!(destinations[1].0, "office:8080");
+            assert_eq!((destinations[1].1.frames_sent, destinations[1].1.send_errors), (1, 1));
+            assert_eq!((transport.stats().frames_sent, transport.stats().send_errors), (3, 1));
+
+            office_peer.send_control(&[7]).unwrap();
+            assert_eq!(transport.recv_control().await.unwrap(), [7]);
+
+            // The send fails only when every destination does.
+            drop(kitchen_peer);
+            drop(office_peer);
+            assert!(transport.send_frame(&[3]).is_err());
+        });
+    }
+
+    // A receiver that takes nothing and never frees up room.
+    struct Stalled;
+
+    impl AudioTransport for Stalled {
+        fn send_frame(&self, _frame: &[u8]) -> io::Result<()> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+
+        fn send<'a>(&'a self, _frame: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+            Box::pin(futures::future::pending())
+        }
+
+        fn recv_control(&self) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+            Box::pin(async { Err(io::ErrorKind::Unsupported.into()) })
+        }
+
+        fn stats(&self) -> TransportStats {
+            TransportStats::default()
+        }
+    }
+
+    #[test]
+    fn test_fan_out_stalled_destination() {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+        runtime.block_on(async {
+            let (kitchen, mut kitchen_peer) = MemoryTransport::pair(1024);
+            let transport = FanOutTransport::new(vec![
+                ("stalled:8080".to_string(), Arc::new(Stalled) as Arc<dyn AudioTransport>),
+                ("kitchen:8080".to_string(), Arc::new(kitchen)),
+            ]);
+            let count = SEND_QUEUE as u8 * 2;
+            let sends = async {
+                for i in 0..count {
+                    transport.send(&[i]).await.unwrap();
+                    tokio::task::yield_now().await;
+                }
+            };
+            tokio::time::timeout(std::time::Duration::from_secs(5), sends)
+                .await
+                .expect("a stalled destination held up the send");
+            for i in 0..count {
+                assert_eq!(kitchen_peer.recv_frame().await, Some(vec![i]));
+            }
+
+            let destinations = transport.destinations();
+            assert!(destinations[0].1.send_errors > 0);
+            assert_eq!((destinations[1].1.frames_sent, destinations[1].1.send_errors), (count as u64, 0));
+        });
+    }
+
+    #[test]
+    fn test_counters() {
+        let counters = Counters::default();
+        counters.record(&Ok(()), 100);
+        counters.record(&Err(io::ErrorKind::WouldBlock.into()), 100);
+        let stats = counters.snapshot();
+        assert_eq!((stats.frames_sent, stats.bytes_sent, stats.send_errors), (1, 100, 1));
+    }
+}
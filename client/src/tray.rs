@@ -0,0 +1,296 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tao::event::{Event, StartCause};
+use tao::event_loop::{ControlFlow, EventLoopBuilder, EventLoopProxy};
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+use tracing::warn;
+use tray_icon::menu::{CheckMenuItem, Menu, MenuEvent, MenuId, MenuItem, PredefinedMenuItem, Submenu};
+use tray_icon::{Icon, TrayIcon, TrayIconBuilder};
+
+use crate::control::{Controls, Message, Report};
+
+// How often the icon, tooltip and check marks catch up with the client.
+const REFRESH: Duration = Duration::from_millis(500);
+// Menus have no sliders, so the volume is picked from these steps.
+const VOLUME_STEPS: [f32; 5] = [0.0, 0.25, 0.5, 0.75, 1.0];
+const ICON_SIZE: u32 = 32;
+
+/// How the stream is doing, shown by the icon's colour.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Health {
+    Streaming,
+    /// Paused or muted, so nothing is heard.
+    Silent,
+    /// Packets were dropped since the last look, e.g. the server is unreachable.
+    Failing,
+}
+
+enum TrayEvent {
+    Attach(Attachment),
+    Menu(MenuEvent),
+    Exit(i32),
+}
+
+struct Attachment {
+    controls: Arc<Controls>,
+    devices: Vec<String>,
+    quit: mpsc::Sender<()>,
+    runtime: Handle,
+}
+
+static PROXY: Mutex<Option<EventLoopProxy<TrayEvent>>> = Mutex::new(None);
+
+/// Runs the tray's event loop on the calling thread, which has to be the
+/// main thread on macOS, and `body` on another. The icon appears once the
+/// stream calls [`attach`]. Exits the process with `body`'s status when it
+/// returns.
+pub fn run(body: impl FnOnce() -> i32 + Send + 'static) -> ! {
+    let event_loop = EventLoopBuilder::<TrayEvent>::with_user_event().build();
+    let proxy = event_loop.create_proxy();
+    *PROXY.lock().unwrap() = Some(proxy.clone());
+    let menu_proxy = proxy.clone();
+    MenuEvent::set_event_handler(Some(move |event| {
+        let _ = menu_proxy.send_event(TrayEvent::Menu(event));
+    }));
+    std::thread::spawn(move || {
+        let code = body();
+        let _ = proxy.send_event(TrayEvent::Exit(code));
+    });
+
+    let mut tray: Option<Tray> = None;
+    event_loop.run(move |event, _, control_flow| {
+        *control_flow = ControlFlow::WaitUntil(Instant::now() + REFRESH);
+        match event {
+            Event::UserEvent(TrayEvent::Attach(attachment)) => match Tray::new(attachment) {
+                Ok(built) => tray = Some(built),
+                Err(e) => warn!("Tray icon unavailable: {}", e),
+            },
+            Event::UserEvent(TrayEvent::Menu(event)) => {
+                if let Some(tray) = &tray {
+                    tray.select(&event.id);
+                }
+            }
+            Event::UserEvent(TrayEvent::Exit(code)) => {
+                tray = None;
+                *control_flow = ControlFlow::ExitWithCode(code);
+            }
+            Event::NewEvents(StartCause::ResumeTimeReached { .. }) => {
+                if let Some(tray) = &mut tray {
+                    tray.refresh();
+                }
+            }
+            _ => {}
+        }
+    })
+}
+
+/// Shows the running stream in the tray, if there is one: `devices` fill
+/// the device menu and Quit sends on `quit`. Call it from the runtime.
+pub fn attach(controls: Arc<Controls>, devices: Vec<String>, quit: mpsc::Sender<()>) {
+    if let Some(proxy) = PROXY.lock().unwrap().as_ref() {
+        let attachment = Attachment {
+            controls,
+            devices,
+            quit,
+            runtime: Handle::current(),
+        };
+        let _ = proxy.send_event(TrayEvent::Attach(attachment));
+    }
+}
+
+struct Tray {
+    icon: TrayIcon,
+    attachment: Attachment,
+    status: MenuItem,
+    mute: CheckMenuItem,
+    volumes: Vec<CheckMenuItem>,
+    devices: Vec<CheckMenuItem>,
+    quit: MenuItem,
+    health: Health,
+    drops: u64,
+}
+
+impl Tray {
+    fn new(attachment: Attachment) -> Result<Self, Box<dyn std::error::Error>> {
+        let report = attachment.controls.report();
+        let status = MenuItem::new("Starting", false, None);
+        let mute = CheckMenuItem::new("Mute", true, report.muted, None);
+        let volume_menu = Submenu::new("Volume", true);
+        let volumes: Vec<_> = VOLUME_STEPS
+            .iter()
+            .map(|step| CheckMenuItem::new(format!("{:.0}%", step * 100.0), true, false, None))
+            .collect();
+        for item in &volumes {
+            volume_menu.append(item)?;
+        }
+        let device_menu = Submenu::new("Input Device", true);
+        let devices: Vec<_> = attachment
+            .devices
+            .iter()
+            .map(|name| CheckMenuItem::new(name, true, *name == report.device, None))
+            .collect();
+        for item in &devices {
+            device_menu.append(item)?;
+        }
+        let quit = MenuItem::new("Quit", true, None);
+        let menu = Menu::new();
+        menu.append_items(&[
+            &status,
+            &PredefinedMenuItem::separator(),
+            &mute,
+            &volume_menu,
+            &device_menu,
+            &PredefinedMenuItem::separator(),
+            &quit,
+        ])?;
+        let icon = TrayIconBuilder::new()
+            .with_menu(Box::new(menu))
+            .with_icon(icon(Health::Streaming)?)
+            .with_tooltip("Audio Streamer")
+            .build()?;
+        let mut tray = Self {
+            icon,
+            attachment,
+            status,
+            mute,
+            volumes,
+            devices,
+            quit,
+            health: Health::Streaming,
+            drops: report.drops,
+        };
+        tray.refresh();
+        Ok(tray)
+    }
+
+    fn select(&self, id: &MenuId) {
+        let controls = &self.attachment.controls;
+        let message = if id == self.mute.id() {
+            Message::Mute(!controls.state.is_muted())
+        } else if let Some(i) = self.volumes.iter().position(|item| item.id() == id) {
+            Message::SetVolume(VOLUME_STEPS[i])
+        } else if let Some(i) = self.devices.iter().position(|item| item.id() == id) {
+            Message::SwitchDevice(self.attachment.devices[i].clone())
+        } else if id == self.quit.id() {
+            let _ = self.attachment.quit.try_send(());
+            return;
+        } else {
+            return;
+        };
+        let controls = controls.clone();
+        self.attachment.runtime.spawn(async move {
+            controls.apply(message).await;
+        });
+    }
+
+    // Check marks are set here rather than on click, so they also follow
+    // changes from the other control channels.
+    fn refresh(&mut self) {
+        let report = self.attachment.controls.report();
+        let (health, text) = status(&report, self.drops);
+        self.drops = report.drops;
+        self.status.set_text(&text);
+        let _ = self.icon.set_tooltip(Some(format!("Audio Streamer: {}", text)));
+        if health != self.health {
+            if let Ok(icon) = icon(health) {
+                let _ = self.icon.set_icon(Some(icon));
+            }
+            self.health = health;
+        }
+        self.mute.set_checked(report.muted);
+        let nearest = nearest_step(report.volume);
+        for (i, item) in self.volumes.iter().enumerate() {
+            item.set_checked(i == nearest);
+        }
+        for (name, item) in self.attachment.devices.iter().zip(&self.devices) {
+            item.set_checked(*name == report.device);
+        }
+    }
+}
+
+/// The stream's health and a one-line status, given the drop count at the
+/// previous look.
+pub fn status(report: &Report, drops_before: u64) -> (Health, String) {
+    if report.drops > drops_before {
+        let dropped = report.drops - drops_before;
+        return (Health::Failing, format!("Sending failed ({} packets dropped)", dropped));
+    }
+    if report.paused {
+        return (Health::Silent, "Paused".to_string());
+    }
+    let state = if report.muted { "Muted" } else { "Streaming" };
+    let health = if report.muted {
+        Health::Silent
+    } else {
+        Health::Streaming
+    };
+    (health, format!("{}, {:.0} kbps", state, report.bitrate_kbps))
+}
+
+fn nearest_step(volume: f32) -> usize {
+    (0..VOLUME_STEPS.len())
+        .min_by(|&a, &b| {
+            (VOLUME_STEPS[a] - volume)
+                .abs()
+                .total_cmp(&(VOLUME_STEPS[b] - volume).abs())
+        })
+        .unwrap_or(0)
+}
+
+// A filled circle in the health's colour.
+fn icon(health: Health) -> Result<Icon, tray_icon::BadIcon> {
+    let color = match health {
+        Health::Streaming => [0x2e, 0xa0, 0x43],
+        Health::Silent => [0x8a, 0x8a, 0x8a],
+        Health::Failing => [0xd0, 0x3b, 0x2f],
+    };
+    let center = (ICON_SIZE as f32 - 1.0) / 2.0;
+    let radius = ICON_SIZE as f32 / 2.0 - 1.0;
+    let mut rgba = Vec::with_capacity((ICON_SIZE * ICON_SIZE * 4) as usize);
+    for y in 0..ICON_SIZE {
+        for x in 0..ICON_SIZE {
+            let distance = (x as f32 - center).hypot(y as f32 - center);
+            let alpha = if distance <= radius { 0xff } else { 0 };
+            rgba.extend_from_slice(&[color[0], color[1], color[2], alpha]);
+        }
+    }
+    Icon::from_rgba(rgba, ICON_SIZE, ICON_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status() {
+        let report = Report {
+            bitrate_kbps: 1536.4,
+            drops: 3,
+            ..Report::default()
+        };
+        assert_eq!(
+            status(&report, 3),
+            (Health::Streaming, "Streaming, 1536 kbps".to_string())
+        );
+        assert_eq!(
+            status(&report, 1),
+            (Health::Failing, "Sending failed (2 packets dropped)".to_string())
+        );
+        let muted = Report {
+            muted: true,
+            ..report.clone()
+        };
+        assert_eq!(status(&muted, 3).0, Health::Silent);
+        let paused = Report { paused: true, ..report };
+        assert_eq!(status(&paused, 3), (Health::Silent, "Paused".to_string()));
+    }
+
+    #[test]
+    fn test_nearest_step() {
+        assert_eq!(nearest_step(0.0), 0);
+        assert_eq!(nearest_step(0.3), 1);
+        assert_eq!(nearest_step(0.9), 4);
+    }
+}
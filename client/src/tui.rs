@@ -0,0 +1,436 @@
+use std::collections::VecDeque;
+use std::io::{self, Stderr, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::execute;
+use ratatui::crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Style};
+use ratatui::widgets::{Block, Borders, Gauge, Paragraph, Sparkline};
+use ratatui::{Frame, Terminal};
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use crate::control::{Controls, Message, Report};
+use crate::keyboard::{self, Key};
+use crate::meter::{self, ChannelLevel};
+use crate::spectrum;
+use crate::stats::{StreamStats, Summary};
+
+// How often the screen is redrawn and the keyboard checked.
+const REFRESH: Duration = Duration::from_millis(200);
+// How often a point is added to the bitrate graph.
+const SAMPLE_PERIOD: Duration = Duration::from_secs(1);
+// Bitrate points kept, more than any terminal is wide.
+const HISTORY: usize = 512;
+// Log messages kept for the log pane.
+const LOG_LINES: usize = 200;
+
+static LOG: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+static SHOWING: AtomicBool = AtomicBool::new(false);
+
+type Term = Terminal<CrosstermBackend<Stderr>>;
+
+/// Takes the log messages while the dashboard has the terminal, which
+/// shows the latest of them, and passes them to stderr before and after.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LogLines;
+
+impl Write for LogLines {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !SHOWING.load(Ordering::Relaxed) {
+            return io::stderr().write(buf);
+        }
+        let mut log = LOG.lock().unwrap();
+        for line in String::from_utf8_lossy(buf).lines() {
+            if log.len() == LOG_LINES {
+                log.pop_front();
+            }
+            log.push_back(line.to_string());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// What the dashboard shows and acts on.
+pub struct Sources {
+    pub controls: Arc<Controls>,
+    pub stats: Arc<StreamStats>,
+    /// The sink and where it sends to.
+    pub destination: String,
+    /// The input device after the given one, for `d`.
+    pub next_device: fn(&str) -> Option<String>,
+    /// Told when the user quits.
+    pub quit: mpsc::Sender<()>,
+}
+
+/// The running dashboard. Dropping it hands the terminal back.
+pub struct Dashboard {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Dashboard {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Takes over the terminal on stderr with the dashboard, drawn from its
+/// own thread. Call it from the runtime.
+pub fn start(sources: Sources) -> io::Result<Dashboard> {
+    let mut terminal = enter()?;
+    let runtime = Handle::current();
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = stop.clone();
+    let thread = std::thread::spawn(move || {
+        let result = run(&mut terminal, &sources, &runtime, &stopped);
+        leave(&mut terminal);
+        if let Err(e) = result {
+            warn!("Dashboard stopped: {}", e);
+        }
+    });
+    Ok(Dashboard {
+        stop,
+        thread: Some(thread),
+    })
+}
+
+fn enter() -> io::Result<Term> {
+    enable_raw_mode()?;
+    let mut stderr = io::stderr();
+    if let Err(e) = execute!(stderr, EnterAlternateScreen) {
+        let _ = disable_raw_mode();
+        return Err(e);
+    }
+    SHOWING.store(true, Ordering::Relaxed);
+    Terminal::new(CrosstermBackend::new(stderr))
+}
+
+fn leave(terminal: &mut Term) {
+    SHOWING.store(false, Ordering::Relaxed);
+    let _ = disable_raw_mode();
+    let _ = execute!(terminal.backend_mut(), LeaveAlternateScreen);
+    let _ = terminal.show_cursor();
+}
+
+fn run(terminal: &mut Term, sources: &Sources, runtime: &Handle, stop: &AtomicBool) -> io::Result<()> {
+    let started = Instant::now();
+    let mut previous = sources.stats.summary(Duration::ZERO);
+    let mut sampled = started;
+    let mut view = View {
+        destination: sources.destination.clone(),
+        report: sources.controls.report(),
+        summary: previous,
+        levels: Default::default(),
+        spectrum: Vec::new(),
+        bitrate: VecDeque::new(),
+        last: Sample::default(),
+    };
+    while !stop.load(Ordering::Relaxed) {
+        if event::poll(REFRESH)? {
+            if let Event::Key(key) = event::read()? {
+                match key_action(key) {
+                    Some(Key::Quit) => {
+                        let _ = sources.quit.try_send(());
+                        return Ok(());
+                    }
+                    Some(key) => {
+                        if let Some(message) = message(key, sources) {
+                            runtime.block_on(sources.controls.apply(message));
+                        }
+                    }
+                    None => {}
+                }
+            }
+        }
+        view.summary = sources.stats.summary(started.elapsed());
+        if sampled.elapsed() >= SAMPLE_PERIOD {
+            view.push(Sample::between(&previous, &view.summary));
+            previous = view.summary;
+            sampled = Instant::now();
+        }
+        view.report = sources.controls.report();
+        view.levels = sources.stats.take_levels();
+        // A band for each column inside the spectrum's border.
+        view.spectrum = sources
+            .stats
+            .spectrum(terminal.size()?.width.saturating_sub(2) as usize);
+        terminal.draw(|frame| view.draw(frame))?;
+    }
+    Ok(())
+}
+
+// Arrow keys also change the volume, and Ctrl+C quits, since the raw
+// terminal no longer turns it into a signal.
+fn key_action(key: KeyEvent) -> Option<Key> {
+    if key.kind != KeyEventKind::Press {
+        return None;
+    }
+    match key.code {
+        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => Some(Key::Quit),
+        KeyCode::Char(c) if c.is_ascii() => Key::from_byte(c as u8),
+        KeyCode::Up | KeyCode::Right => Some(Key::VolumeUp),
+        KeyCode::Down | KeyCode::Left => Some(Key::VolumeDown),
+        KeyCode::Esc => Some(Key::Quit),
+        _ => None,
+    }
+}
+
+fn message(key: Key, sources: &Sources) -> Option<Message> {
+    let controls = &sources.controls;
+    let level = controls.state.level();
+    match key {
+        Key::VolumeUp => Some(Message::SetVolume((level + keyboard::VOLUME_STEP).min(1.0))),
+        Key::VolumeDown => Some(Message::SetVolume((level - keyboard::VOLUME_STEP).max(0.0))),
+        Key::Mute => Some(Message::Mute(!controls.state.is_muted())),
+        Key::Pause => Some(Message::Pause(!controls.is_paused())),
+        Key::NextDevice => (sources.next_device)(&controls.report().device).map(Message::SwitchDevice),
+        Key::Quit => None,
+    }
+}
+
+/// The traffic over one sampling period.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Sample {
+    pub kbps: f64,
+    pub lost: u64,
+    /// Of the packets the period tried to send.
+    pub loss_percent: f64,
+}
+
+impl Sample {
+    /// The traffic between two looks at the counters.
+    pub fn between(before: &Summary, after: &Summary) -> Self {
+        let secs = after.elapsed.saturating_sub(before.elapsed).as_secs_f64();
+        let bytes = after.bytes_sent.saturating_sub(before.bytes_sent);
+        let sent = after.packets_sent.saturating_sub(before.packets_sent);
+        let lost = after.drops.saturating_sub(before.drops);
+        let attempted = sent + lost;
+        Self {
+            kbps: if secs > 0.0 {
+                bytes as f64 * 8.0 / secs / 1000.0
+            } else {
+                0.0
+            },
+            lost,
+            loss_percent: if attempted > 0 {
+                lost as f64 * 100.0 / attempted as f64
+            } else {
+                0.0
+            },
+        }
+    }
+}
+
+/// The stream's state in a word, given the last period's traffic.
+pub fn state(report: &Report, last: &Sample) -> &'static str {
+    if last.lost > 0 {
+        "Sending failed"
+    } else if report.paused {
+        "Paused"
+    } else if report.muted {
+        "Muted"
+    } else {
+        "Streaming"
+    }
+}
+
+struct View {
+    destination: String,
+    report: Report,
+    summary: Summary,
+    levels: [ChannelLevel; 2],
+    spectrum: Vec<f32>,
+    bitrate: VecDeque<u64>,
+    last: Sample,
+}
+
+impl View {
+    fn push(&mut self, sample: Sample) {
+        if self.bitrate.len() == HISTORY {
+            self.bitrate.pop_front();
+        }
+        self.bitrate.push_back(sample.kbps.round() as u64);
+        self.last = sample;
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [status, bitrate, levels, spectrum, log, help] = Layout::vertical([
+            Constraint::Length(8),
+            Constraint::Length(7),
+            Constraint::Length(4),
+            Constraint::Length(10),
+            Constraint::Min(3),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        self.draw_status(frame, status);
+        self.draw_bitrate(frame, bitrate);
+        self.draw_levels(frame, levels);
+        self.draw_spectrum(frame, spectrum);
+        draw_log(frame, log);
+        frame.render_widget(Paragraph::new(keyboard::HELP), help);
+    }
+
+    fn draw_status(&self, frame: &mut Frame, area: Rect) {
+        let report = &self.report;
+        let device = if report.device.is_empty() {
+            "stdin"
+        } else {
+            &report.device
+        };
+        let lines = [
+            format!("Device:      {}", device),
+            format!("Destination: {}", self.destination),
+            format!("State:       {}", state(report, &self.last)),
+            format!(
+                "Volume:      {:.0}%{}",
+                report.volume * 100.0,
+                if report.muted { " (muted)" } else { "" }
+            ),
+            format!(
+                "Sent:        {} packets, {:.1} MB; lost {} in the last second ({:.1}%), {} in all",
+                self.summary.packets_sent,
+                self.summary.bytes_sent as f64 / 1e6,
+                self.last.lost,
+                self.last.loss_percent,
+                self.summary.drops
+            ),
+            format!("Latency:     {:.0} ms buffered before sending", report.buffered_ms),
+        ];
+        let block = Block::default().borders(Borders::ALL).title(" audio-client ");
+        frame.render_widget(Paragraph::new(lines.join("\n")).block(block), area);
+    }
+
+    fn draw_bitrate(&self, frame: &mut Frame, area: Rect) {
+        let title = format!(
+            " Bitrate: {:.0} kbps, {:.0} kbps average ",
+            self.last.kbps,
+            self.summary.average_bitrate_kbps()
+        );
+        // The newest points, as many as fit.
+        let width = area.width.saturating_sub(2) as usize;
+        let points: Vec<u64> = self
+            .bitrate
+            .iter()
+            .skip(self.bitrate.len().saturating_sub(width))
+            .copied()
+            .collect();
+        let graph = Sparkline::default()
+            .block(Block::default().borders(Borders::ALL).title(title))
+            .data(&points)
+            .style(Style::default().fg(Color::Cyan));
+        frame.render_widget(graph, area);
+    }
+
+    fn draw_levels(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::default().borders(Borders::ALL).title(" Levels ");
+        let inner = block.inner(area);
+        frame.render_widget(block, area);
+        let rows = Layout::vertical([Constraint::Length(1); 2]).split(inner);
+        for ((level, name), row) in self.levels.iter().zip(["L", "R"]).zip(rows.iter()) {
+            let peak = meter::db(level.peak);
+            let color = if peak > -1.0 { Color::Red } else { Color::Green };
+            let gauge = Gauge::default()
+                .ratio(meter::scale(level.rms).clamp(0.0, 1.0) as f64)
+                .label(format!("{} {:>3.0} dB peak", name, peak))
+                .gauge_style(Style::default().fg(color));
+            frame.render_widget(gauge, *row);
+        }
+    }
+
+    fn draw_spectrum(&self, frame: &mut Frame, area: Rect) {
+        let title = format!(
+            " Spectrum, {:.0} Hz to {:.0} kHz ",
+            spectrum::LOWEST_HZ,
+            spectrum::HIGHEST_HZ / 1000.0
+        );
+        let bars: Vec<u64> = self.spectrum.iter().map(|band| (band * 100.0).round() as u64).collect();
+        let graph = Sparkline::default()
+            .block(Block::default().borders(Borders::ALL).title(title))
+            .data(&bars)
+            .max(100)
+            .style(Style::default().fg(Color::Magenta));
+        frame.render_widget(graph, area);
+    }
+}
+
+fn draw_log(frame: &mut Frame, area: Rect) {
+    let log = LOG.lock().unwrap();
+    let height = area.height.saturating_sub(2) as usize;
+    let lines: Vec<_> = log.iter().skip(log.len().saturating_sub(height)).cloned().collect();
+    let block = Block::default().borders(Borders::ALL).title(" Log ");
+    frame.render_widget(Paragraph::new(lines.join("\n")).block(block), area);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(secs: u64, bytes_sent: u64, packets_sent: u64, drops: u64) -> Summary {
+        Summary {
+            elapsed: Duration::from_secs(secs),
+            bytes_sent,
+            packets_sent,
+            drops,
+            ..Summary::default()
+        }
+    }
+
+    #[test]
+    fn test_sample_between() {
+        let sample = Sample::between(&summary(1, 1000, 10, 0), &summary(2, 193_000, 100, 10));
+        assert_eq!(sample.kbps, 1536.0);
+        assert_eq!(sample.lost, 10);
+        assert_eq!(sample.loss_percent, 10.0);
+        assert_eq!(
+            Sample::between(&summary(2, 0, 0, 0), &summary(2, 0, 0, 0)),
+            Sample::default()
+        );
+    }
+
+    #[test]
+    fn test_key_action() {
+        let press = |code, modifiers| KeyEvent::new(code, modifiers);
+        assert_eq!(
+            key_action(press(KeyCode::Char('m'), KeyModifiers::NONE)),
+            Some(Key::Mute)
+        );
+        assert_eq!(key_action(press(KeyCode::Up, KeyModifiers::NONE)), Some(Key::VolumeUp));
+        assert_eq!(
+            key_action(press(KeyCode::Char('c'), KeyModifiers::CONTROL)),
+            Some(Key::Quit)
+        );
+        assert_eq!(key_action(press(KeyCode::Char('x'), KeyModifiers::NONE)), None);
+    }
+
+    #[test]
+    fn test_state() {
+        let report = Report::default();
+        assert_eq!(state(&report, &Sample::default()), "Streaming");
+        let lost = Sample {
+            lost: 3,
+            ..Sample::default()
+        };
+        assert_eq!(state(&report, &lost), "Sending failed");
+        let paused = Report {
+            paused: true,
+            ..Report::default()
+        };
+        assert_eq!(state(&paused, &Sample::default()), "Paused");
+    }
+}
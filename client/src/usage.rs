@@ -0,0 +1,79 @@
+use std::time::Duration;
+
+/// CPU time the process has used so far, user and system together. None
+/// where the platform has no cheap way to ask.
+pub fn cpu_time() -> Option<Duration> {
+    #[cfg(unix)]
+    {
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+            return None;
+        }
+        let duration =
+            |time: libc::timeval| Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64);
+        Some(duration(usage.ru_utime) + duration(usage.ru_stime))
+    }
+    #[cfg(not(unix))]
+    {
+        None
+    }
+}
+
+/// The process's resident memory in bytes. Only Linux reports the current
+/// size rather than the peak, so elsewhere this is None.
+pub fn resident_bytes() -> Option<u64> {
+    #[cfg(target_os = "linux")]
+    {
+        let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        parse_statm(&statm, u64::try_from(page_size).ok()?)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        None
+    }
+}
+
+/// The share of one core used between two readings of [`cpu_time`] taken
+/// `wall` apart, like top's %CPU: a process busy on two cores reads 200.
+pub fn cpu_percent(before: Option<Duration>, after: Option<Duration>, wall: Duration) -> Option<f64> {
+    if wall.is_zero() {
+        return None;
+    }
+    let used = after?.saturating_sub(before?);
+    Some(used.as_secs_f64() * 100.0 / wall.as_secs_f64())
+}
+
+// /proc/self/statm counts pages: the total size, then the resident set.
+fn parse_statm(statm: &str, page_size: u64) -> Option<u64> {
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some(pages * page_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cpu_percent() {
+        let second = Duration::from_secs(1);
+        let half = Some(Duration::from_millis(500));
+        assert_eq!(cpu_percent(Some(Duration::ZERO), half, second), Some(50.0));
+        assert_eq!(cpu_percent(Some(Duration::ZERO), half, second / 4), Some(200.0));
+        assert_eq!(cpu_percent(None, half, second), None);
+        assert_eq!(cpu_percent(half, half, Duration::ZERO), None);
+    }
+
+    #[test]
+    fn test_parse_statm() {
+        assert_eq!(parse_statm("5210 1234 812 203 0 1090 0\n", 4096), Some(1234 * 4096));
+        assert_eq!(parse_statm("5210", 4096), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_readings() {
+        assert!(cpu_time().is_some());
+        assert!(resident_bytes().unwrap() > 0);
+    }
+}
@@ -0,0 +1,138 @@
+use std::io::{self, Write};
+use std::net::UdpSocket;
+
+pub const VBAN_DEFAULT_PORT: u16 = 6980;
+const HEADER_LEN: usize = 28;
+const STREAM_NAME_LEN: usize = 16;
+// The spec caps a packet at 256 samples per channel.
+const MAX_PACKET_FRAMES: usize = 256;
+// Audio sub-protocol (top three bits zero) and 16-bit integer PCM.
+const PROTOCOL_AUDIO: u8 = 0x00;
+const DATATYPE_INT16: u8 = 0x01;
+// Sample rates in the order of their header index.
+const SAMPLE_RATES: [u32; 21] = [
+    6000, 12000, 24000, 48000, 96000, 192000, 384000, 8000, 16000, 32000, 64000, 128000, 256000, 512000, 11025,
+    22050, 44100, 88200, 176400, 352800, 705600,
+];
+
+/// Checks a VBAN stream name, which receivers match on: at most 16 ASCII
+/// characters.
+pub fn parse_stream_name(s: &str) -> Result<String, String> {
+    if s.is_empty() || s.len() > STREAM_NAME_LEN || !s.is_ascii() {
+        return Err("VBAN stream names are 1 to 16 ASCII characters".to_string());
+    }
+    Ok(s.to_string())
+}
+
+/// Sends s16le PCM as VBAN audio packets, as VB-Audio Voicemeeter and VBAN
+/// Receptor expect. Each write is split into packets of up to 256 frames.
+pub struct VbanWriter {
+    socket: UdpSocket,
+    header: [u8; HEADER_LEN],
+    frame_bytes: usize,
+    pending: Vec<u8>,
+    packet: Vec<u8>,
+    counter: u32,
+}
+
+impl VbanWriter {
+    pub fn connect(addr: &str, stream_name: &str, sample_rate: u32, channels: u16) -> io::Result<Self> {
+        let header = header(stream_name, sample_rate, channels)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("VBAN has no {} Hz rate", sample_rate)))?;
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(addr)?;
+        // Called from the audio callback; a full socket buffer drops the packet.
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            header,
+            frame_bytes: channels as usize * 2,
+            pending: Vec::new(),
+            packet: Vec::with_capacity(HEADER_LEN + MAX_PACKET_FRAMES * channels as usize * 2),
+            counter: 0,
+        })
+    }
+
+    fn send_packet(&mut self, frames: usize) -> io::Result<()> {
+        let len = frames * self.frame_bytes;
+        self.packet.clear();
+        self.packet.extend_from_slice(&self.header);
+        self.packet[5] = (frames - 1) as u8;
+        self.packet[24..28].copy_from_slice(&self.counter.to_le_bytes());
+        self.packet.extend_from_slice(&self.pending[..len]);
+        self.pending.drain(..len);
+        self.counter = self.counter.wrapping_add(1);
+        self.socket.send(&self.packet).map(|_| ())
+    }
+}
+
+impl Write for VbanWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        let mut first_error = None;
+        while self.pending.len() >= self.frame_bytes {
+            let frames = (self.pending.len() / self.frame_bytes).min(MAX_PACKET_FRAMES);
+            // Keep sending the rest of the buffer; report the first failure.
+            if let Err(e) = self.send_packet(frames) {
+                first_error.get_or_insert(e);
+            }
+        }
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// The packet header with the per-packet sample count and frame counter
+// left zero.
+fn header(stream_name: &str, sample_rate: u32, channels: u16) -> Option<[u8; HEADER_LEN]> {
+    let rate_index = SAMPLE_RATES.iter().position(|&rate| rate == sample_rate)? as u8;
+    let mut header = [0u8; HEADER_LEN];
+    header[..4].copy_from_slice(b"VBAN");
+    header[4] = PROTOCOL_AUDIO | rate_index;
+    header[6] = (channels - 1) as u8;
+    header[7] = DATATYPE_INT16;
+    let name = &stream_name.as_bytes()[..stream_name.len().min(STREAM_NAME_LEN)];
+    header[8..8 + name.len()].copy_from_slice(name);
+    Some(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header() {
+        let header = header("Stream1", 48_000, 2).unwrap();
+        assert_eq!(&header[..4], b"VBAN");
+        assert_eq!(header[4], 3);
+        assert_eq!(header[6], 1);
+        assert_eq!(header[7], DATATYPE_INT16);
+        assert_eq!(&header[8..16], b"Stream1\0");
+        assert!(self::header("Stream1", 47_000, 2).is_none());
+        assert!(parse_stream_name("Desktop").is_ok());
+        assert!(parse_stream_name("a name that is far too long").is_err());
+    }
+
+    #[test]
+    fn test_packets_split_at_256_frames() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut writer = VbanWriter::connect(&receiver.local_addr().unwrap().to_string(), "Stream1", 48_000, 2).unwrap();
+        writer.write_all(&vec![0u8; 300 * 4]).unwrap();
+
+        let mut buf = [0u8; 2048];
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(len, HEADER_LEN + 256 * 4);
+        assert_eq!(buf[5], 255);
+        assert_eq!(&buf[24..28], &0u32.to_le_bytes());
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(len, HEADER_LEN + 44 * 4);
+        assert_eq!(buf[5], 43);
+        assert_eq!(&buf[24..28], &1u32.to_le_bytes());
+    }
+}
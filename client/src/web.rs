@@ -0,0 +1,297 @@
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::Arc;
+use std::thread;
+
+use opus::{Application, Bitrate, Channels};
+use tracing::warn;
+
+use crate::clock::system_micros;
+use crate::http::{self, Broadcast, LiveStream, Request, Response, StreamInfo};
+use crate::ogg::{self, OggOpusWriter};
+use crate::websocket;
+
+pub const STREAM_PATH: &str = "/stream";
+pub const WEBSOCKET_PATH: &str = "/ws";
+const OPUS_BITRATE: i32 = 128_000;
+// Five 20ms Opus frames to an Ogg page.
+const PACKETS_PER_PAGE: usize = 5;
+// Capture buffers queued for the server thread before new ones are dropped.
+const STREAM_QUEUE: usize = 64;
+
+/// What `--http-listen` serves at `/stream`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum WebFormat {
+    /// Uncompressed WAV, for any browser or `curl | aplay`
+    Wav,
+    /// Ogg/Opus at 128 kbps, for listeners on slower links
+    Ogg,
+}
+
+/// Serves the capture to HTTP listeners alongside the main sink: the
+/// encoded stream at `/stream`, raw PCM over a WebSocket at `/ws`, and a
+/// page at `/` that plays the WebSocket feed through Web Audio. Buffers
+/// are handed to a server thread, so the audio callback only pays for a
+/// copy; they are dropped if that thread falls behind.
+pub struct WebStream {
+    queue: SyncSender<Vec<u8>>,
+    addr: SocketAddr,
+}
+
+impl WebStream {
+    pub fn serve(listener: TcpListener, format: WebFormat, sample_rate: u32, channels: u16) -> io::Result<Self> {
+        let (queue, blocks) = mpsc::sync_channel::<Vec<u8>>(STREAM_QUEUE);
+        let addr = listener.local_addr()?;
+        let pcm = Broadcast::default();
+        let stream = match format {
+            WebFormat::Wav => {
+                let info = stream_info("audio/wav", http::streaming_wav_header(sample_rate, channels));
+                let stream = LiveStream::new(info, addr);
+                let (live, pcm) = (stream.clone(), pcm.clone());
+                thread::spawn(move || {
+                    for block in blocks {
+                        pcm.send(&block);
+                        live.broadcast(&block);
+                    }
+                });
+                stream
+            }
+            WebFormat::Ogg => {
+                let serial = system_micros() as u32;
+                let stream = LiveStream::new(
+                    stream_info("audio/ogg", ogg::header_pages(serial, channels as u8)),
+                    addr,
+                );
+                let opus_channels = if channels == 1 {
+                    Channels::Mono
+                } else {
+                    Channels::Stereo
+                };
+                let mut encoder =
+                    opus::Encoder::new(sample_rate, opus_channels, Application::Audio).map_err(io::Error::other)?;
+                encoder
+                    .set_bitrate(Bitrate::Bits(OPUS_BITRATE))
+                    .map_err(io::Error::other)?;
+                let mut ogg = OggOpusWriter::continuing(stream.clone(), serial, PACKETS_PER_PAGE);
+                let pcm = pcm.clone();
+                thread::spawn(move || {
+                    let blocks = blocks.into_iter().inspect(|block| pcm.send(block));
+                    if let Err(e) = ogg::encode_blocks(blocks, &mut encoder, &mut ogg, channels as usize) {
+                        warn!("HTTP stream encoder stopped: {}", e);
+                    }
+                });
+                stream
+            }
+        };
+
+        let format = format!("{{\"sampleRate\":{},\"channels\":{}}}", sample_rate, channels);
+        thread::spawn(move || {
+            for connection in listener.incoming().flatten() {
+                let (stream, pcm, format) = (stream.clone(), pcm.clone(), format.clone());
+                thread::spawn(move || http::report(handle_connection(connection, &stream, &pcm, &format)));
+            }
+        });
+        Ok(Self { queue, addr })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Queues a buffer of s16le capture PCM for listeners.
+    pub fn push(&self, pcm: &[u8]) {
+        let _ = self.queue.try_send(pcm.to_vec());
+    }
+}
+
+fn handle_connection(mut connection: TcpStream, stream: &LiveStream, pcm: &Broadcast, format: &str) -> io::Result<()> {
+    let Some(request) = http::read_request(&connection)? else {
+        return http::write_status(&mut connection, "400 Bad Request");
+    };
+    match request.path.as_str() {
+        STREAM_PATH => stream.attach(connection, &request),
+        WEBSOCKET_PATH => feed_websocket(connection, &request, pcm, format),
+        "/" | "/index.html" => {
+            let page = Response {
+                content_type: "text/html; charset=utf-8",
+                body: Arc::from(INDEX_HTML.as_bytes()),
+            };
+            http::respond(&mut connection, &request, Some(page))
+        }
+        _ => http::respond(&mut connection, &request, None),
+    }
+}
+
+// Sends the stream format as a text message, then each capture buffer as
+// a binary message of s16le PCM.
+fn feed_websocket(mut connection: TcpStream, request: &Request, pcm: &Broadcast, format: &str) -> io::Result<()> {
+    if !websocket::accept(&mut connection, request)? {
+        return Ok(());
+    }
+    websocket::write_text(&mut connection, format)?;
+    for chunk in pcm.subscribe() {
+        websocket::write_binary(&mut connection, &chunk)?;
+    }
+    Ok(())
+}
+
+fn stream_info(content_type: &str, header: Vec<u8>) -> StreamInfo {
+    StreamInfo {
+        path: STREAM_PATH.to_string(),
+        content_type: content_type.to_string(),
+        header,
+        extra_headers: vec![("Access-Control-Allow-Origin".to_string(), "*".to_string())],
+    }
+}
+
+// Schedules each message as an AudioBuffer right after the previous one,
+// restarting `latency` ahead of the clock when playback runs dry or drifts
+// too far behind the live edge.
+const INDEX_HTML: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>audio-client</title>
+<style>
+body { font-family: sans-serif; max-width: 32em; margin: 2em auto; padding: 0 1em; }
+meter, input[type=range] { width: 100%; }
+</style>
+</head>
+<body>
+<h1>audio-client</h1>
+<p><button id="play">Play</button> <span id="status">Stopped</span></p>
+<p><label for="latency">Latency: <span id="latency-value"></span> ms</label>
+<input id="latency" type="range" min="50" max="1000" step="10" value="200"></p>
+<p><label for="level">Level</label> <meter id="level" min="0" max="1" low="0.7" high="0.9" optimum="0.3" value="0"></meter></p>
+<script>
+const $ = (id) => document.getElementById(id);
+let socket = null, context = null, format = null, next = 0, peak = 0;
+
+function showLatency() { $("latency-value").textContent = $("latency").value; }
+$("latency").oninput = showLatency;
+showLatency();
+
+function stop(message) {
+  if (socket) socket.close();
+  if (context) context.close();
+  socket = context = null;
+  $("play").textContent = "Play";
+  $("status").textContent = message;
+}
+
+function play(data) {
+  const latency = $("latency").value / 1000;
+  const samples = new Int16Array(data);
+  const frames = samples.length / format.channels;
+  if (frames < 1) return;
+  const buffer = context.createBuffer(format.channels, frames, format.sampleRate);
+  for (let c = 0; c < format.channels; c++) {
+    const out = buffer.getChannelData(c);
+    for (let i = 0; i < frames; i++) {
+      out[i] = samples[i * format.channels + c] / 32768;
+      peak = Math.max(peak, Math.abs(out[i]));
+    }
+  }
+  const now = context.currentTime;
+  if (next < now || next > now + latency + 0.25) next = now + latency;
+  const source = context.createBufferSource();
+  source.buffer = buffer;
+  source.connect(context.destination);
+  source.start(next);
+  next += buffer.duration;
+}
+
+function meter() {
+  $("level").value = peak;
+  peak *= 0.85;
+  if (context) requestAnimationFrame(meter);
+}
+
+$("play").onclick = () => {
+  if (socket) return stop("Stopped");
+  const scheme = location.protocol === "https:" ? "wss:" : "ws:";
+  socket = new WebSocket(scheme + "//" + location.host + "/ws");
+  socket.binaryType = "arraybuffer";
+  $("play").textContent = "Stop";
+  $("status").textContent = "Connecting";
+  socket.onmessage = (event) => {
+    if (typeof event.data === "string") {
+      format = JSON.parse(event.data);
+      context = new AudioContext({ sampleRate: format.sampleRate });
+      next = 0;
+      $("status").textContent = "Playing " + format.sampleRate + " Hz, " + format.channels + " ch";
+      meter();
+    } else if (context) {
+      play(event.data);
+    }
+  };
+  socket.onclose = () => { if (socket) stop("Disconnected"); };
+};
+</script>
+</body>
+</html>
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_wav_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = WebStream::serve(listener, WebFormat::Wav, 48_000, 2).unwrap();
+        let mut client = TcpStream::connect(stream.local_addr()).unwrap();
+        client.write_all(b"GET /stream HTTP/1.1\r\nHost: test\r\n\r\n").unwrap();
+
+        // Keep pushing until the listener is subscribed and data arrives.
+        let mut received = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut buf = [0u8; 4096];
+        client.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        while !received.windows(4).any(|w| w == [1, 2, 3, 4]) && Instant::now() < deadline {
+            stream.push(&[1, 2, 3, 4]);
+            if let Ok(len) = client.read(&mut buf) {
+                received.extend_from_slice(&buf[..len]);
+            }
+        }
+        let text = String::from_utf8_lossy(&received);
+        assert!(text.starts_with("HTTP/1.1 200 OK"));
+        assert!(text.contains("Content-Type: audio/wav"));
+        assert!(received.windows(4).any(|w| w == b"RIFF"));
+        assert!(received.windows(4).any(|w| w == [1, 2, 3, 4]));
+    }
+
+    #[test]
+    fn test_websocket_feed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = WebStream::serve(listener, WebFormat::Wav, 48_000, 2).unwrap();
+        let mut client = TcpStream::connect(stream.local_addr()).unwrap();
+        client
+            .write_all(
+                b"GET /ws HTTP/1.1\r\nHost: test\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                  Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            )
+            .unwrap();
+
+        let mut received = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut buf = [0u8; 4096];
+        client.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        while !received.windows(6).any(|w| w == [0x82, 4, 1, 2, 3, 4]) && Instant::now() < deadline {
+            stream.push(&[1, 2, 3, 4]);
+            if let Ok(len) = client.read(&mut buf) {
+                received.extend_from_slice(&buf[..len]);
+            }
+        }
+        let text = String::from_utf8_lossy(&received);
+        assert!(text.starts_with("HTTP/1.1 101 Switching Protocols"));
+        assert!(text.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+        assert!(text.contains("{\"sampleRate\":48000,\"channels\":2}"));
+        assert!(received.windows(6).any(|w| w == [0x82, 4, 1, 2, 3, 4]));
+    }
+}
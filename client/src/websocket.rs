@@ -0,0 +1,253 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Write};
+use std::net::TcpStream;
+
+use crate::http::{self, Request};
+
+// RFC 6455 section 1.3: appended to the client's key before hashing.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const OPCODE_TEXT: u8 = 0x1;
+pub const OPCODE_BINARY: u8 = 0x2;
+pub const OPCODE_CLOSE: u8 = 0x8;
+const FINAL_FRAGMENT: u8 = 0x80;
+const MASKED: u8 = 0x80;
+// Larger frames than any audio or control message are refused rather
+// than buffered.
+const MAX_PAYLOAD: usize = 1 << 20;
+
+/// Completes the opening handshake for an upgrade `request`, or answers
+/// 400 and returns false if it is not one. Afterwards the connection
+/// carries frames written with [`write_text`] and [`write_binary`].
+pub fn accept(connection: &mut TcpStream, request: &Request) -> io::Result<bool> {
+    let upgrade = request
+        .header("Upgrade")
+        .is_some_and(|u| u.eq_ignore_ascii_case("websocket"));
+    let Some(key) = request
+        .header("Sec-WebSocket-Key")
+        .filter(|_| upgrade && request.method == "GET")
+    else {
+        http::write_status(connection, "400 Bad Request")?;
+        return Ok(false);
+    };
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    );
+    connection.write_all(response.as_bytes())?;
+    connection.set_nodelay(true)?;
+    Ok(true)
+}
+
+pub fn write_text(out: &mut impl Write, text: &str) -> io::Result<()> {
+    write_frame(out, OPCODE_TEXT, text.as_bytes())
+}
+
+pub fn write_binary(out: &mut impl Write, payload: &[u8]) -> io::Result<()> {
+    write_frame(out, OPCODE_BINARY, payload)
+}
+
+// Server frames are never masked.
+fn write_frame(out: &mut impl Write, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    out.write_all(&encode_frame(opcode, payload, None))
+}
+
+/// A binary frame as a client sends it, masked with a fresh key.
+pub fn client_binary(payload: &[u8]) -> Vec<u8> {
+    encode_frame(
+        OPCODE_BINARY,
+        payload,
+        Some(random_u64().to_be_bytes()[..4].try_into().unwrap()),
+    )
+}
+
+fn encode_frame(opcode: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+    frame.push(FINAL_FRAGMENT | opcode);
+    let masked = if mask.is_some() { MASKED } else { 0 };
+    match payload.len() {
+        len @ 0..=125 => frame.push(masked | len as u8),
+        len @ 126..=0xffff => {
+            frame.push(masked | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(masked | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    match mask {
+        Some(mask) => {
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(byte, key)| byte ^ key));
+        }
+        None => frame.extend_from_slice(payload),
+    }
+    frame
+}
+
+/// A frame read off a connection, unmasked.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+/// Takes the first frame off the front of `buf` once all of it has
+/// arrived. Fragmented messages aren't reassembled; nothing we exchange
+/// is large enough to be split.
+pub fn take_frame(buf: &mut Vec<u8>) -> io::Result<Option<Frame>> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let (mut at, len) = match buf[1] & 0x7f {
+        126 if buf.len() >= 4 => (4, u16::from_be_bytes([buf[2], buf[3]]) as usize),
+        127 if buf.len() >= 10 => (10, u64::from_be_bytes(buf[2..10].try_into().unwrap()) as usize),
+        126 | 127 => return Ok(None),
+        len => (2, len as usize),
+    };
+    if len > MAX_PAYLOAD {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "WebSocket frame too large"));
+    }
+    let mask = if buf[1] & MASKED != 0 {
+        if buf.len() < at + 4 {
+            return Ok(None);
+        }
+        at += 4;
+        Some([buf[at - 4], buf[at - 3], buf[at - 2], buf[at - 1]])
+    } else {
+        None
+    };
+    if buf.len() < at + len {
+        return Ok(None);
+    }
+    let mut payload = buf[at..at + len].to_vec();
+    if let Some(mask) = mask {
+        payload
+            .iter_mut()
+            .zip(mask.iter().cycle())
+            .for_each(|(byte, key)| *byte ^= key);
+    }
+    let opcode = buf[0] & 0x0f;
+    buf.drain(..at + len);
+    Ok(Some(Frame { opcode, payload }))
+}
+
+/// A fresh Sec-WebSocket-Key for a client handshake.
+pub fn client_key() -> String {
+    let mut key = [0u8; 16];
+    key[..8].copy_from_slice(&random_u64().to_be_bytes());
+    key[8..].copy_from_slice(&random_u64().to_be_bytes());
+    http::base64(&key)
+}
+
+// Each RandomState is seeded afresh, which is unpredictable enough for
+// handshake keys and masks.
+pub(crate) fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+/// The Sec-WebSocket-Accept value that answers a client's key.
+pub fn accept_key(key: &str) -> String {
+    http::base64(&sha1(format!("{}{}", key.trim(), ACCEPT_GUID).as_bytes()))
+}
+
+// SHA-1 (FIPS 180-4), needed only for the handshake.
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476, 0xc3d2_e1f0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks_exact(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ed9_eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1b_bcdc),
+                _ => (b ^ c ^ d, 0xca62_c1d6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (state, value) in h.iter_mut().zip([a, b, c, d, e]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (chunk, word) in digest.chunks_exact_mut(4).zip(h) {
+        chunk.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accept_key() {
+        // The example handshake from RFC 6455 section 1.3.
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(
+            sha1(b"abc"),
+            [
+                0xa9, 0x99, 0x3e, 0x36, 0x47, 0x06, 0x81, 0x6a, 0xba, 0x3e, 0x25, 0x71, 0x78, 0x50, 0xc2, 0x6c, 0x9c,
+                0xd0, 0xd8, 0x9d
+            ]
+        );
+    }
+
+    #[test]
+    fn test_frame_lengths() {
+        let mut out = Vec::new();
+        write_text(&mut out, "hi").unwrap();
+        assert_eq!(out, [0x81, 2, b'h', b'i']);
+
+        out.clear();
+        write_binary(&mut out, &[0; 300]).unwrap();
+        assert_eq!(&out[..4], &[0x82, 126, 0x01, 0x2c]);
+        assert_eq!(out.len(), 304);
+    }
+
+    #[test]
+    fn test_take_frame() {
+        let mut buf = client_binary(&[1, 2, 3]);
+        assert_eq!(buf[1], MASKED | 3);
+        buf.extend_from_slice(&encode_frame(OPCODE_BINARY, &[7; 300], None));
+        let partial = buf.split_off(buf.len() - 10);
+        let frame = take_frame(&mut buf).unwrap().unwrap();
+        assert_eq!(
+            frame,
+            Frame {
+                opcode: OPCODE_BINARY,
+                payload: vec![1, 2, 3]
+            }
+        );
+        assert_eq!(take_frame(&mut buf).unwrap(), None);
+        buf.extend_from_slice(&partial);
+        assert_eq!(take_frame(&mut buf).unwrap().unwrap().payload, vec![7; 300]);
+        assert!(buf.is_empty());
+    }
+}
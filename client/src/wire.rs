@@ -0,0 +1,177 @@
+//! One entry point to every parser that reads bytes off the network, for
+//! the fuzz targets in `fuzz/` and the tests below. The parsers live with
+//! their protocols, and each is a pure function over a byte slice: socket
+//! code only gathers bytes and hands them over. Malformed input must come
+//! back as None or an error, never a panic, since a panic on a stray
+//! datagram takes down the stream thread that received it.
+
+use crate::{cast, clock, control, http, mqtt, osc, packet, playback, rendezvous, roc, websocket};
+
+/// A format the client parses from its peers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Audio packets, over any transport.
+    Audio,
+    /// Control messages and the replies to them.
+    Control,
+    /// Clock sync requests on a receiver's control port.
+    TimeRequest,
+    /// Answers to clock sync requests.
+    TimeResponse,
+    /// Playout latency requests on a receiver's control port.
+    PlayoutRequest,
+    /// Answers to playout latency requests.
+    PlayoutResponse,
+    /// WebSocket frames, from the WebSocket transport and the dashboard.
+    WebSocket,
+    /// Request heads to the HTTP listeners and the dashboard.
+    HttpRequest,
+    /// Responses from Cast, DLNA and other LAN devices.
+    HttpResponse,
+    /// OSC packets on the OSC control port.
+    Osc,
+    /// RTP packets in the Roc format.
+    Rtp,
+    /// MQTT packets from the broker, a type byte then the body.
+    Mqtt,
+    /// Cast channel messages, without their length prefix.
+    Cast,
+    /// Registrations, punches and keepalives, at a relay and between the
+    /// ends it introduces.
+    Rendezvous,
+}
+
+impl Format {
+    pub const ALL: [Format; 14] = [
+        Format::Audio,
+        Format::Control,
+        Format::TimeRequest,
+        Format::TimeResponse,
+        Format::PlayoutRequest,
+        Format::PlayoutResponse,
+        Format::WebSocket,
+        Format::HttpRequest,
+        Format::HttpResponse,
+        Format::Osc,
+        Format::Rtp,
+        Format::Mqtt,
+        Format::Cast,
+        Format::Rendezvous,
+    ];
+}
+
+/// Runs the parser for `format` over `bytes` and returns whether it
+/// accepted them. A stream of WebSocket frames is taken apart frame by
+/// frame, as the transport does.
+pub fn parse(format: Format, bytes: &[u8]) -> bool {
+    match format {
+        Format::Audio => packet::decode(bytes).is_some(),
+        Format::Control => control::Message::decode(bytes).is_ok(),
+        Format::TimeRequest => clock::time_response(bytes, 0, 0).is_some(),
+        Format::TimeResponse => clock::parse_time_response(bytes).is_some(),
+        Format::PlayoutRequest => playback::playout_response(bytes, None).is_some(),
+        Format::PlayoutResponse => playback::parse_playout_response(bytes).is_some(),
+        Format::WebSocket => {
+            let mut buf = bytes.to_vec();
+            let mut frames = 0;
+            while let Ok(Some(_)) = websocket::take_frame(&mut buf) {
+                frames += 1;
+            }
+            frames > 0
+        }
+        Format::HttpRequest => std::str::from_utf8(bytes).ok().and_then(http::parse_request).is_some(),
+        Format::HttpResponse => http::parse_response(bytes).is_some(),
+        Format::Osc => osc::parse_packet(bytes).is_some(),
+        Format::Rtp => roc::parse_rtp(bytes).is_some(),
+        Format::Mqtt => bytes
+            .split_first()
+            .is_some_and(|(&kind, body)| mqtt::parse_publish(kind, body).is_some()),
+        Format::Cast => cast::decode_message(bytes).is_some(),
+        Format::Rendezvous => rendezvous::Message::decode(bytes).is_some(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::Message;
+    use crate::packet::Packet;
+
+    // Well-formed input for each format, for the mutations to start from.
+    fn seeds() -> Vec<Vec<u8>> {
+        let mut audio = Vec::new();
+        packet::encode(
+            &Packet {
+                seq: 7,
+                timestamp: Some(1),
+                payload: &[1, 2, 3, 4],
+                redundant: Some(&[5, 6]),
+                paused: false,
+                ended: false,
+            },
+            &mut audio,
+        );
+        vec![
+            audio,
+            Message::SetVolume(0.5).encode(3),
+            Message::SwitchDevice("Line In".to_string()).encode(4),
+            clock::time_request(1).to_vec(),
+            playback::playout_request(1).to_vec(),
+            websocket::client_binary(&[1, 2, 3]),
+            b"GET /ws HTTP/1.1\r\nUpgrade: websocket\r\n\r\n".to_vec(),
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n".to_vec(),
+            b"#bundle\0\0\0\0\0\0\0\0\x01\0\0\0\x10/volume\0,f\0\0\x3f\0\0\0".to_vec(),
+            vec![0x80, 10, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2],
+            vec![0x32, 0, 1, b't', 0, 9, 1],
+            vec![0x12, 2, b'a', b'b', 0x32, 1, b'x'],
+            rendezvous::Message::Peer("203.0.113.7:40000".parse().unwrap()).encode(),
+        ]
+    }
+
+    // A cheap stand-in for the fuzzer: every prefix of every seed, and each
+    // seed with bytes flipped, read as every format.
+    #[test]
+    fn test_malformed_input_never_panics() {
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let mut inputs = Vec::new();
+        for seed in seeds() {
+            inputs.extend((0..=seed.len()).map(|len| seed[..len].to_vec()));
+            for _ in 0..200 {
+                let mut mutated = seed.clone();
+                let at = next() as usize % mutated.len();
+                mutated[at] ^= next() as u8 | 1;
+                inputs.push(mutated);
+            }
+        }
+        inputs.extend((0..500).map(|_| (0..next() % 64).map(|_| next() as u8).collect()));
+        for format in Format::ALL {
+            for input in &inputs {
+                parse(format, input);
+            }
+        }
+    }
+
+    #[test]
+    fn test_seeds_parse() {
+        let seeds = seeds();
+        assert!(parse(Format::Audio, &seeds[0]));
+        assert!(parse(Format::Control, &seeds[1]));
+        assert!(parse(Format::TimeRequest, &seeds[3]));
+        assert!(parse(Format::PlayoutRequest, &seeds[4]));
+        assert!(parse(Format::WebSocket, &seeds[5]));
+        assert!(parse(Format::HttpRequest, &seeds[6]));
+        assert!(parse(Format::HttpResponse, &seeds[7]));
+        assert!(parse(Format::Osc, &seeds[8]));
+        assert!(parse(Format::Rtp, &seeds[9]));
+        assert!(parse(Format::Mqtt, &seeds[10]));
+        assert!(parse(Format::Cast, &seeds[11]));
+        assert!(parse(Format::Rendezvous, &seeds[12]));
+        assert!(!parse(Format::Control, &seeds[0]));
+    }
+}